@@ -0,0 +1,561 @@
+//! Calls to the built-in REST API of Orthanc.
+//!
+//! ```ignore
+//! let api = OrthancApi::new(context);
+//! let study: Study = api.get_json(&format!("/studies/{}", id))?;
+//! for series in &study.series {
+//!     let series: Series = api.get_json(&format!("/series/{}", series))?;
+//! }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    ffi::{c_void, CString},
+    fmt,
+    os::raw::c_char,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    _OrthancPluginService, context::Context, rest::RestError, OrthancPluginErrorCode,
+    OrthancPluginMemoryBuffer,
+};
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// Orthanc answered the call with an error code.
+    Orthanc {
+        method: &'static str,
+        uri: String,
+        code: OrthancPluginErrorCode,
+    },
+    /// A header of the call contains a NUL byte, the call is not sent.
+    Header {
+        method: &'static str,
+        uri: String,
+        name: String,
+    },
+    /// The answer is not the expected JSON document.
+    Json {
+        method: &'static str,
+        uri: String,
+        error: serde_json::Error,
+    },
+}
+
+impl ApiError {
+    /// Orthanc error code of the call, `None` when the answer could not be parsed.
+    pub fn code(&self) -> Option<OrthancPluginErrorCode> {
+        match self {
+            Self::Orthanc { code, .. } => Some(*code),
+            Self::Header { .. } | Self::Json { .. } => None,
+        }
+    }
+
+    /// Whether the resource does not exist, e.g. because it was deleted meanwhile.
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self.code(),
+            Some(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_UnknownResource)
+                | Some(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InexistentItem)
+        )
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Orthanc { method, uri, code } => write!(
+                f,
+                "{} {} failed with Orthanc error code {}",
+                method, uri, code
+            ),
+            Self::Header { method, uri, name } => write!(
+                f,
+                "{} {} - header '{}' contains a NUL byte",
+                method, uri, name
+            ),
+            Self::Json { method, uri, error } => {
+                write!(f, "{} {} - invalid answer - {}", method, uri, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Orthanc { .. } | Self::Header { .. } => None,
+            Self::Json { error, .. } => Some(error),
+        }
+    }
+}
+
+impl From<ApiError> for RestError {
+    fn from(e: ApiError) -> Self {
+        let status = match e.code() {
+            _ if e.is_not_found() => 404,
+            _ if matches!(e, ApiError::Header { .. }) => 400,
+            Some(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadRequest)
+            | Some(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_UriSyntax)
+            | Some(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadFileFormat) => 400,
+            Some(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Unauthorized) => 403,
+            _ => 500,
+        };
+        RestError::new(status, e.to_string())
+    }
+}
+
+#[repr(C)]
+struct RestApiGetParams {
+    target: *mut OrthancPluginMemoryBuffer,
+    uri: *const c_char,
+}
+
+#[repr(C)]
+struct RestApiGet2Params {
+    target: *mut OrthancPluginMemoryBuffer,
+    uri: *const c_char,
+    headers_count: u32,
+    headers_keys: *const *const c_char,
+    headers_values: *const *const c_char,
+    after_plugins: i32,
+}
+
+#[repr(C)]
+struct RestApiPostPutParams {
+    target: *mut OrthancPluginMemoryBuffer,
+    uri: *const c_char,
+    body: *const c_void,
+    body_size: u32,
+}
+
+/// Memory buffer filled by Orthanc, released when dropped.
+struct MemoryBuffer {
+    context: Context,
+    buffer: OrthancPluginMemoryBuffer,
+}
+
+impl MemoryBuffer {
+    fn new(context: Context) -> Self {
+        Self {
+            context,
+            buffer: OrthancPluginMemoryBuffer {
+                data: std::ptr::null_mut(),
+                size: 0,
+            },
+        }
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        if self.buffer.data.is_null() {
+            return Vec::new();
+        }
+        unsafe {
+            std::slice::from_raw_parts(self.buffer.data as *const u8, self.buffer.size as usize)
+        }
+        .to_vec()
+    }
+}
+
+impl Drop for MemoryBuffer {
+    fn drop(&mut self) {
+        if !self.buffer.data.is_null() {
+            unsafe { self.context.free(self.buffer.data) };
+        }
+    }
+}
+
+/// Client of the built-in REST API of Orthanc.
+///
+/// By default calls bypass the REST routes of plugins, like `RestApiGet`. Calls of a client
+/// built with [`OrthancApi::after_plugins`] are also served by the routes of plugins.
+#[derive(Debug, Copy, Clone)]
+pub struct OrthancApi {
+    context: Context,
+    after_plugins: bool,
+}
+
+impl OrthancApi {
+    pub fn new(context: Context) -> Self {
+        Self {
+            context,
+            after_plugins: false,
+        }
+    }
+
+    pub fn after_plugins(self) -> Self {
+        Self {
+            after_plugins: true,
+            ..self
+        }
+    }
+
+    pub fn get(&self, uri: &str) -> Result<Vec<u8>, ApiError> {
+        let service = if self.after_plugins {
+            crate::_OrthancPluginService__OrthancPluginService_RestApiGetAfterPlugins
+        } else {
+            crate::_OrthancPluginService__OrthancPluginService_RestApiGet
+        };
+
+        let c_uri = uri_string("GET", uri)?;
+        let mut buffer = MemoryBuffer::new(self.context);
+        let params = RestApiGetParams {
+            target: &mut buffer.buffer,
+            uri: c_uri.as_ptr(),
+        };
+        self.call("GET", uri, service, &params as *const _ as *const c_void)?;
+
+        Ok(buffer.to_vec())
+    }
+
+    /// GET with additional HTTP headers, e.g. `Accept`.
+    pub fn get_with_headers(
+        &self,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<Vec<u8>, ApiError> {
+        let c_uri = uri_string("GET", uri)?;
+        let (keys, values): (Vec<_>, Vec<_>) = headers
+            .iter()
+            .map(|(name, value)| header_strings("GET", uri, name, value))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .unzip();
+        let key_ptrs: Vec<_> = keys.iter().map(|key| key.as_ptr()).collect();
+        let value_ptrs: Vec<_> = values.iter().map(|value| value.as_ptr()).collect();
+
+        let mut buffer = MemoryBuffer::new(self.context);
+        let params = RestApiGet2Params {
+            target: &mut buffer.buffer,
+            uri: c_uri.as_ptr(),
+            headers_count: key_ptrs.len() as u32,
+            headers_keys: key_ptrs.as_ptr(),
+            headers_values: value_ptrs.as_ptr(),
+            after_plugins: self.after_plugins as i32,
+        };
+        self.call(
+            "GET",
+            uri,
+            crate::_OrthancPluginService__OrthancPluginService_RestApiGet2,
+            &params as *const _ as *const c_void,
+        )?;
+
+        Ok(buffer.to_vec())
+    }
+
+    pub fn post(&self, uri: &str, body: &[u8]) -> Result<Vec<u8>, ApiError> {
+        let service = if self.after_plugins {
+            crate::_OrthancPluginService__OrthancPluginService_RestApiPostAfterPlugins
+        } else {
+            crate::_OrthancPluginService__OrthancPluginService_RestApiPost
+        };
+        self.post_put("POST", service, uri, body)
+    }
+
+    pub fn put(&self, uri: &str, body: &[u8]) -> Result<Vec<u8>, ApiError> {
+        let service = if self.after_plugins {
+            crate::_OrthancPluginService__OrthancPluginService_RestApiPutAfterPlugins
+        } else {
+            crate::_OrthancPluginService__OrthancPluginService_RestApiPut
+        };
+        self.post_put("PUT", service, uri, body)
+    }
+
+    pub fn delete(&self, uri: &str) -> Result<(), ApiError> {
+        let service = if self.after_plugins {
+            crate::_OrthancPluginService__OrthancPluginService_RestApiDeleteAfterPlugins
+        } else {
+            crate::_OrthancPluginService__OrthancPluginService_RestApiDelete
+        };
+
+        let c_uri = uri_string("DELETE", uri)?;
+        self.call("DELETE", uri, service, c_uri.as_ptr() as *const c_void)
+    }
+
+    pub fn get_json<T: DeserializeOwned>(&self, uri: &str) -> Result<T, ApiError> {
+        parse_json("GET", uri, &self.get(uri)?)
+    }
+
+    /// GET a JSON document, `None` if the resource does not exist (anymore).
+    pub fn find_json<T: DeserializeOwned>(&self, uri: &str) -> Result<Option<T>, ApiError> {
+        match self.get_json(uri) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn post_json<B, T>(&self, uri: &str, body: &B) -> Result<T, ApiError>
+    where
+        B: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        let body = serialize_json("POST", uri, body)?;
+        parse_json("POST", uri, &self.post(uri, &body)?)
+    }
+
+    pub fn put_json<B, T>(&self, uri: &str, body: &B) -> Result<T, ApiError>
+    where
+        B: Serialize + ?Sized,
+        T: DeserializeOwned,
+    {
+        let body = serialize_json("PUT", uri, body)?;
+        parse_json("PUT", uri, &self.put(uri, &body)?)
+    }
+
+    fn post_put(
+        &self,
+        method: &'static str,
+        service: _OrthancPluginService,
+        uri: &str,
+        body: &[u8],
+    ) -> Result<Vec<u8>, ApiError> {
+        let c_uri = uri_string(method, uri)?;
+        let mut buffer = MemoryBuffer::new(self.context);
+        let params = RestApiPostPutParams {
+            target: &mut buffer.buffer,
+            uri: c_uri.as_ptr(),
+            body: body.as_ptr() as *const c_void,
+            body_size: body.len() as u32,
+        };
+        self.call(method, uri, service, &params as *const _ as *const c_void)?;
+
+        Ok(buffer.to_vec())
+    }
+
+    fn call(
+        &self,
+        method: &'static str,
+        uri: &str,
+        service: _OrthancPluginService,
+        params: *const c_void,
+    ) -> Result<(), ApiError> {
+        let code = unsafe { self.context.invoke(service, params) };
+        if code != crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success {
+            return Err(ApiError::Orthanc {
+                method,
+                uri: uri.to_owned(),
+                code,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn uri_string(method: &'static str, value: &str) -> Result<CString, ApiError> {
+    CString::new(value).map_err(|_| ApiError::Orthanc {
+        method,
+        uri: value.replace('\0', ""),
+        code: crate::OrthancPluginErrorCode_OrthancPluginErrorCode_UriSyntax,
+    })
+}
+
+/// Name and value of a header of a call to `uri`.
+fn header_strings(
+    method: &'static str,
+    uri: &str,
+    name: &str,
+    value: &str,
+) -> Result<(CString, CString), ApiError> {
+    let error = || ApiError::Header {
+        method,
+        uri: uri.replace('\0', ""),
+        name: name.replace('\0', ""),
+    };
+    Ok((
+        CString::new(name).map_err(|_| error())?,
+        CString::new(value).map_err(|_| error())?,
+    ))
+}
+
+fn parse_json<T: DeserializeOwned>(
+    method: &'static str,
+    uri: &str,
+    content: &[u8],
+) -> Result<T, ApiError> {
+    serde_json::from_slice(content).map_err(|error| ApiError::Json {
+        method,
+        uri: uri.to_owned(),
+        error,
+    })
+}
+
+fn serialize_json<T: Serialize + ?Sized>(
+    method: &'static str,
+    uri: &str,
+    value: &T,
+) -> Result<Vec<u8>, ApiError> {
+    serde_json::to_vec(value).map_err(|error| ApiError::Json {
+        method,
+        uri: uri.to_owned(),
+        error,
+    })
+}
+
+/// Main DICOM tags of a resource, by tag name.
+pub type MainDicomTags = BTreeMap<String, String>;
+
+/// `/patients/{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Patient {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(default)]
+    pub is_stable: bool,
+    #[serde(default)]
+    pub last_update: Option<String>,
+    #[serde(default)]
+    pub main_dicom_tags: MainDicomTags,
+    #[serde(default)]
+    pub studies: Vec<String>,
+}
+
+/// `/studies/{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Study {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(default)]
+    pub is_stable: bool,
+    #[serde(default)]
+    pub last_update: Option<String>,
+    #[serde(default)]
+    pub main_dicom_tags: MainDicomTags,
+    #[serde(default)]
+    pub patient_main_dicom_tags: MainDicomTags,
+    pub parent_patient: String,
+    #[serde(default)]
+    pub series: Vec<String>,
+}
+
+/// `/series/{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Series {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(default)]
+    pub is_stable: bool,
+    #[serde(default)]
+    pub last_update: Option<String>,
+    #[serde(default)]
+    pub main_dicom_tags: MainDicomTags,
+    pub parent_study: String,
+    #[serde(default)]
+    pub instances: Vec<String>,
+    #[serde(default)]
+    pub expected_number_of_instances: Option<u32>,
+    /// `Unknown`, `Missing`, `Complete` or `Inconsistent`.
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
+/// `/instances/{id}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Instance {
+    #[serde(rename = "ID")]
+    pub id: String,
+    #[serde(default)]
+    pub main_dicom_tags: MainDicomTags,
+    pub parent_series: String,
+    #[serde(default)]
+    pub file_size: u64,
+    #[serde(default)]
+    pub file_uuid: Option<String>,
+    #[serde(default)]
+    pub index_in_series: Option<u32>,
+}
+
+/// `/{level}/{id}/attachments/{name}/info`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AttachmentInfo {
+    pub uuid: String,
+    pub content_type: u32,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    #[serde(rename = "CompressedMD5", default)]
+    pub compressed_md5: Option<String>,
+    #[serde(rename = "UncompressedMD5", default)]
+    pub uncompressed_md5: Option<String>,
+}
+
+impl OrthancApi {
+    pub fn patient(&self, id: &str) -> Result<Patient, ApiError> {
+        self.get_json(&format!("/patients/{}", id))
+    }
+
+    pub fn study(&self, id: &str) -> Result<Study, ApiError> {
+        self.get_json(&format!("/studies/{}", id))
+    }
+
+    pub fn series(&self, id: &str) -> Result<Series, ApiError> {
+        self.get_json(&format!("/series/{}", id))
+    }
+
+    pub fn instance(&self, id: &str) -> Result<Instance, ApiError> {
+        self.get_json(&format!("/instances/{}", id))
+    }
+
+    /// DICOM file of an instance.
+    pub fn instance_file(&self, id: &str) -> Result<Vec<u8>, ApiError> {
+        self.get(&format!("/instances/{}/file", id))
+    }
+
+    /// Names of the attachments of a resource, `level` is e.g. `instances`.
+    pub fn attachments(&self, level: &str, id: &str) -> Result<Vec<String>, ApiError> {
+        self.get_json(&format!("/{}/{}/attachments", level, id))
+    }
+
+    pub fn attachment_info(
+        &self,
+        level: &str,
+        id: &str,
+        name: &str,
+    ) -> Result<AttachmentInfo, ApiError> {
+        self.get_json(&format!("/{}/{}/attachments/{}/info", level, id, name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Calls failing before they reach Orthanc never use the context.
+    fn api() -> OrthancApi {
+        OrthancApi::new(unsafe { Context::from_raw(std::ptr::null_mut()) })
+    }
+
+    #[test]
+    fn headers_with_nul_bytes_are_refused() {
+        let e = api()
+            .get_with_headers(
+                "/instances",
+                &[("accept", "application/json"), ("x-\0", "1")],
+            )
+            .unwrap_err();
+        assert!(matches!(&e, ApiError::Header { name, .. } if name == "x-"));
+        assert_eq!(e.code(), None);
+        assert_eq!(RestError::from(e).status, 400);
+
+        let e = api()
+            .get_with_headers("/instances", &[("accept", "application/\0json")])
+            .unwrap_err();
+        assert!(matches!(&e, ApiError::Header { name, .. } if name == "accept"));
+    }
+
+    #[test]
+    fn uris_with_nul_bytes_are_refused() {
+        let e = api().get("/instances/\0").unwrap_err();
+        assert_eq!(
+            e.code(),
+            Some(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_UriSyntax)
+        );
+        assert_eq!(RestError::from(e).status, 400);
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    ffi::{c_void, CStr, CString},
+    fmt,
+};
+
+use crate::{
+    _OrthancPluginService, _OrthancPluginService__OrthancPluginService_LogError,
+    _OrthancPluginService__OrthancPluginService_LogInfo,
+    _OrthancPluginService__OrthancPluginService_LogWarning, OrthancPluginContext,
+    OrthancPluginErrorCode,
+};
+
+/// Context handed to a plugin by Orthanc, valid until the plugin is finalized.
+#[derive(Debug, Copy, Clone)]
+pub struct Context(*mut OrthancPluginContext);
+
+//
+// Orthanc services can be invoked from any thread
+//
+unsafe impl Send for Context {}
+unsafe impl Sync for Context {}
+
+impl Context {
+    /// # Safety
+    ///
+    /// `context` must be the context passed by Orthanc to `OrthancPluginInitialize`.
+    pub unsafe fn from_raw(context: *mut OrthancPluginContext) -> Self {
+        Self(context)
+    }
+
+    pub fn as_ptr(&self) -> *mut OrthancPluginContext {
+        self.0
+    }
+
+    /// Version of the running Orthanc, e.g. `1.11.0` or `mainline`.
+    pub fn orthanc_version(&self) -> String {
+        unsafe { CStr::from_ptr((*self.0).orthancVersion) }
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Whether the running Orthanc is at least version `major.minor.revision`.
+    ///
+    /// Services are only available from the Orthanc version that introduced them, calling them
+    /// on an older Orthanc is undefined behavior.
+    pub fn check_version(&self, major: u32, minor: u32, revision: u32) -> bool {
+        is_compatible(&self.orthanc_version(), (major, minor, revision))
+    }
+
+    /// Invoke an Orthanc service.
+    ///
+    /// # Safety
+    ///
+    /// `params` must point to the parameters expected by `service`.
+    pub unsafe fn invoke(
+        &self,
+        service: _OrthancPluginService,
+        params: *const c_void,
+    ) -> OrthancPluginErrorCode {
+        let invoke = (*self.0)
+            .InvokeService
+            .expect("Orthanc context without InvokeService");
+        invoke(self.0, service, params)
+    }
+
+    /// Release memory allocated by Orthanc, e.g. the content of an `OrthancPluginMemoryBuffer`.
+    ///
+    /// # Safety
+    ///
+    /// `data` must have been allocated by Orthanc and not be used afterwards.
+    pub unsafe fn free(&self, data: *mut c_void) {
+        if let Some(free) = (*self.0).Free {
+            free(data);
+        }
+    }
+
+    pub fn log_error(&self, message: &str) {
+        self.log(
+            _OrthancPluginService__OrthancPluginService_LogError,
+            message,
+        );
+    }
+
+    pub fn log_warning(&self, message: &str) {
+        self.log(
+            _OrthancPluginService__OrthancPluginService_LogWarning,
+            message,
+        );
+    }
+
+    pub fn log_info(&self, message: &str) {
+        self.log(_OrthancPluginService__OrthancPluginService_LogInfo, message);
+    }
+
+    fn log(&self, service: _OrthancPluginService, message: &str) {
+        let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+        unsafe {
+            self.invoke(service, message.as_ptr() as *const c_void);
+        }
+    }
+}
+
+/// Error code answered by an Orthanc service.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OrthancError(pub OrthancPluginErrorCode);
+
+impl OrthancError {
+    pub fn code(&self) -> OrthancPluginErrorCode {
+        self.0
+    }
+}
+
+impl fmt::Display for OrthancError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Orthanc error code {}", self.0)
+    }
+}
+
+impl std::error::Error for OrthancError {}
+
+/// `Ok` for `OrthancPluginErrorCode_Success`, the error code otherwise.
+pub fn check(code: OrthancPluginErrorCode) -> Result<(), OrthancError> {
+    match code {
+        crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success => Ok(()),
+        code => Err(OrthancError(code)),
+    }
+}
+
+/// Whether Orthanc `orthanc_version` is at least `required`, the `mainline` build always is.
+pub fn is_compatible(orthanc_version: &str, required: (u32, u32, u32)) -> bool {
+    if orthanc_version == "mainline" {
+        return true;
+    }
+
+    let mut numbers = orthanc_version
+        .split('.')
+        .map(|number| number.parse::<u32>().ok());
+    match (numbers.next(), numbers.next(), numbers.next()) {
+        (Some(Some(major)), Some(Some(minor)), Some(Some(revision))) => {
+            (major, minor, revision) >= required
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_versions_are_compatible() {
+        assert!(is_compatible("1.9.0", (1, 9, 0)));
+        assert!(is_compatible("0.0.0", (0, 0, 0)));
+    }
+
+    #[test]
+    fn older_versions_are_not_compatible() {
+        assert!(!is_compatible("1.8.2", (1, 9, 0)));
+        assert!(!is_compatible("1.9.0", (1, 9, 1)));
+        assert!(!is_compatible("0.12.5", (1, 0, 0)));
+    }
+
+    #[test]
+    fn newer_versions_are_compatible() {
+        assert!(is_compatible("1.9.1", (1, 9, 0)));
+        assert!(is_compatible("1.12.0", (1, 11, 3)));
+        assert!(is_compatible("2.0.0", (1, 12, 9)));
+        //
+        // Compared as numbers, not as strings
+        //
+        assert!(is_compatible("1.10.0", (1, 9, 0)));
+    }
+
+    #[test]
+    fn mainline_is_always_compatible() {
+        assert!(is_compatible("mainline", (1, 12, 0)));
+        assert!(is_compatible("mainline", (u32::MAX, u32::MAX, u32::MAX)));
+    }
+
+    #[test]
+    fn malformed_versions_are_not_compatible() {
+        assert!(!is_compatible("", (0, 0, 0)));
+        assert!(!is_compatible("1.9", (1, 0, 0)));
+        assert!(!is_compatible("1.x.0", (1, 0, 0)));
+        assert!(!is_compatible("Mainline", (0, 0, 0)));
+    }
+}
@@ -0,0 +1,293 @@
+//! Filters of the DICOM instances and HTTP requests received by Orthanc.
+//!
+//! Filters run synchronously with the core of Orthanc, they must not call its REST API.
+//!
+//! ```ignore
+//! struct LocalOnly;
+//!
+//! impl HttpRequestFilter for LocalOnly {
+//!     fn allow(&self, request: &IncomingHttpRequest) -> Result<bool, Box<dyn Error>> {
+//!         Ok(request.ip() == "127.0.0.1")
+//!     }
+//! }
+//!
+//! filter::register_http_request_filter(context, LocalOnly)?;
+//! ```
+
+use std::{
+    error::Error,
+    ffi::{c_void, CStr},
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::OnceLock,
+};
+
+use crate::{
+    context::{check, Context, OrthancError},
+    instance::DicomInstance,
+    rest::{pairs, Method},
+    OrthancPluginDicomInstance, OrthancPluginHttpMethod,
+};
+
+/// DIMSE status `Refused: Not Authorized`, answered for instances rejected by a C-STORE filter.
+pub const DIMSE_REFUSED_NOT_AUTHORIZED: u16 = 0x0124;
+
+/// Decides whether Orthanc stores an incoming DICOM instance.
+///
+/// Metadata of the instance is not available yet.
+pub trait DicomInstanceFilter: Send + Sync + 'static {
+    /// Whether to store the instance, an error rejects it.
+    fn accept(&self, instance: &DicomInstance) -> Result<bool, Box<dyn Error>>;
+
+    /// DIMSE status answered when an instance received by C-STORE is rejected.
+    fn rejection_status(&self, _instance: &DicomInstance) -> u16 {
+        DIMSE_REFUSED_NOT_AUTHORIZED
+    }
+}
+
+/// HTTP request received by Orthanc, valid for the duration of the filter.
+pub struct IncomingHttpRequest<'a> {
+    method: Option<Method>,
+    uri: &'a str,
+    ip: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+    arguments: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> IncomingHttpRequest<'a> {
+    pub fn method(&self) -> Option<Method> {
+        self.method
+    }
+
+    pub fn uri(&self) -> &'a str {
+        self.uri
+    }
+
+    /// IP address of the client.
+    pub fn ip(&self) -> &'a str {
+        self.ip
+    }
+
+    /// HTTP headers of the request, their names are lowercase.
+    pub fn headers(&self) -> &[(&'a str, &'a str)] {
+        &self.headers
+    }
+
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    /// GET arguments of the request.
+    pub fn arguments(&self) -> &[(&'a str, &'a str)] {
+        &self.arguments
+    }
+
+    pub fn argument(&self, name: &str) -> Option<&'a str> {
+        self.arguments
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+/// Decides whether Orthanc serves an incoming HTTP request, answering 403 otherwise.
+pub trait HttpRequestFilter: Send + Sync + 'static {
+    /// Whether to serve the request, an error rejects it.
+    fn allow(&self, request: &IncomingHttpRequest) -> Result<bool, Box<dyn Error>>;
+}
+
+static DICOM_INSTANCE_FILTER: OnceLock<(Context, Box<dyn DicomInstanceFilter>)> = OnceLock::new();
+static CSTORE_INSTANCE_FILTER: OnceLock<(Context, Box<dyn DicomInstanceFilter>)> = OnceLock::new();
+static HTTP_REQUEST_FILTER: OnceLock<(Context, Box<dyn HttpRequestFilter>)> = OnceLock::new();
+
+#[repr(C)]
+struct IncomingDicomInstanceFilterParams {
+    callback: crate::OrthancPluginIncomingDicomInstanceFilter,
+}
+
+#[repr(C)]
+struct IncomingCStoreInstanceFilterParams {
+    callback: crate::OrthancPluginIncomingCStoreInstanceFilter,
+}
+
+#[repr(C)]
+struct IncomingHttpRequestFilter2Params {
+    callback: crate::OrthancPluginIncomingHttpRequestFilter2,
+}
+
+/// Filter the instances received from any source, at most one filter per plugin.
+///
+/// Requires Orthanc 1.6.1.
+pub fn register_dicom_instance_filter<F: DicomInstanceFilter>(
+    context: Context,
+    filter: F,
+) -> Result<(), OrthancError> {
+    if !context.check_version(1, 6, 1) {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+        ));
+    }
+    if DICOM_INSTANCE_FILTER
+        .set((context, Box::new(filter)))
+        .is_err()
+    {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = IncomingDicomInstanceFilterParams {
+        callback: Some(dicom_instance_filter),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterIncomingDicomInstanceFilter,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+/// Filter the instances received by C-STORE, answering their rejection status to the modality.
+///
+/// Requires Orthanc 1.10.0, at most one filter per plugin.
+pub fn register_cstore_instance_filter<F: DicomInstanceFilter>(
+    context: Context,
+    filter: F,
+) -> Result<(), OrthancError> {
+    if !context.check_version(1, 10, 0) {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+        ));
+    }
+    if CSTORE_INSTANCE_FILTER
+        .set((context, Box::new(filter)))
+        .is_err()
+    {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = IncomingCStoreInstanceFilterParams {
+        callback: Some(cstore_instance_filter),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterIncomingCStoreInstanceFilter,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+/// Filter the HTTP requests received by Orthanc, at most one filter per plugin.
+pub fn register_http_request_filter<F: HttpRequestFilter>(
+    context: Context,
+    filter: F,
+) -> Result<(), OrthancError> {
+    if HTTP_REQUEST_FILTER
+        .set((context, Box::new(filter)))
+        .is_err()
+    {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = IncomingHttpRequestFilter2Params {
+        callback: Some(http_request_filter),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterIncomingHttpRequestFilter2,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+/// Run a filter, rejecting on errors and panics: 1 to accept, 0 to reject.
+fn run_filter(
+    context: &Context,
+    kind: &str,
+    filter: impl FnOnce() -> Result<bool, Box<dyn Error>>,
+) -> i32 {
+    match catch_unwind(AssertUnwindSafe(filter)) {
+        Ok(Ok(accepted)) => accepted as i32,
+        Ok(Err(e)) => {
+            context.log_error(&format!("{} filter failed, rejecting - {}", kind, e));
+            0
+        }
+        Err(_) => {
+            context.log_error(&format!("{} filter panicked, rejecting", kind));
+            0
+        }
+    }
+}
+
+extern "C" fn dicom_instance_filter(instance: *const OrthancPluginDicomInstance) -> i32 {
+    let (context, filter) = match DICOM_INSTANCE_FILTER.get() {
+        Some(registered) => registered,
+        None => return -1,
+    };
+    let instance = unsafe { DicomInstance::from_raw(*context, instance) };
+
+    run_filter(context, "DICOM instance", || filter.accept(&instance))
+}
+
+extern "C" fn cstore_instance_filter(
+    dimse_status: *mut u16,
+    instance: *const OrthancPluginDicomInstance,
+) -> i32 {
+    let (context, filter) = match CSTORE_INSTANCE_FILTER.get() {
+        Some(registered) => registered,
+        None => return -1,
+    };
+    let instance = unsafe { DicomInstance::from_raw(*context, instance) };
+
+    let accepted = run_filter(context, "C-STORE", || filter.accept(&instance));
+    if accepted == 0 && !dimse_status.is_null() {
+        let status = catch_unwind(AssertUnwindSafe(|| filter.rejection_status(&instance)))
+            .unwrap_or(DIMSE_REFUSED_NOT_AUTHORIZED);
+        unsafe { *dimse_status = status };
+    }
+    accepted
+}
+
+#[allow(clippy::too_many_arguments)]
+extern "C" fn http_request_filter(
+    method: OrthancPluginHttpMethod,
+    uri: *const c_char,
+    ip: *const c_char,
+    headers_count: u32,
+    headers_keys: *const *const c_char,
+    headers_values: *const *const c_char,
+    arguments_count: u32,
+    arguments_keys: *const *const c_char,
+    arguments_values: *const *const c_char,
+) -> i32 {
+    let (context, filter) = match HTTP_REQUEST_FILTER.get() {
+        Some(registered) => registered,
+        None => return -1,
+    };
+
+    let text = |value: *const c_char| {
+        if value.is_null() {
+            ""
+        } else {
+            unsafe { CStr::from_ptr(value) }
+                .to_str()
+                .unwrap_or_default()
+        }
+    };
+    let request = IncomingHttpRequest {
+        method: Method::from_raw(method),
+        uri: text(uri),
+        ip: text(ip),
+        headers: pairs(headers_keys, headers_values, headers_count).collect(),
+        arguments: pairs(arguments_keys, arguments_values, arguments_count).collect(),
+    };
+
+    run_filter(context, "HTTP request", || filter.allow(&request))
+}
@@ -0,0 +1,780 @@
+//! Images handled by Orthanc, and the decoders and transcoders plugins add to it.
+//!
+//! ```ignore
+//! struct Codec(Context);
+//!
+//! impl ImageDecoder for Codec {
+//!     fn decode(&self, dicom: &[u8], frame: u32) -> Result<Option<Image>, Box<dyn Error>> {
+//!         let (width, height, pixels) = match my_codec::decode(dicom, frame)? {
+//!             Some(decoded) => decoded,
+//!             None => return Ok(None),
+//!         };
+//!         Ok(Some(Image::from_pixels(self.0, PixelFormat::Grayscale8, width, height, &pixels)?))
+//!     }
+//! }
+//!
+//! image::register_decoder(context, Codec(context))?;
+//! ```
+
+use std::{
+    error::Error,
+    ffi::{c_void, CStr},
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::OnceLock,
+};
+
+use crate::{
+    _OrthancPluginService,
+    context::{check, Context, OrthancError},
+    OrthancPluginErrorCode, OrthancPluginImage, OrthancPluginImageFormat,
+    OrthancPluginMemoryBuffer, OrthancPluginPixelFormat,
+};
+
+/// Layout of the pixels of an [`Image`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Grayscale8,
+    Grayscale16,
+    SignedGrayscale16,
+    Rgb24,
+    Rgba32,
+    Rgb48,
+    Grayscale32,
+    Float32,
+    Bgra32,
+    Grayscale64,
+}
+
+impl PixelFormat {
+    pub fn from_raw(format: OrthancPluginPixelFormat) -> Option<Self> {
+        match format {
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Grayscale8 => {
+                Some(PixelFormat::Grayscale8)
+            }
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Grayscale16 => {
+                Some(PixelFormat::Grayscale16)
+            }
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_SignedGrayscale16 => {
+                Some(PixelFormat::SignedGrayscale16)
+            }
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_RGB24 => {
+                Some(PixelFormat::Rgb24)
+            }
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_RGBA32 => {
+                Some(PixelFormat::Rgba32)
+            }
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_RGB48 => {
+                Some(PixelFormat::Rgb48)
+            }
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Grayscale32 => {
+                Some(PixelFormat::Grayscale32)
+            }
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Float32 => {
+                Some(PixelFormat::Float32)
+            }
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_BGRA32 => {
+                Some(PixelFormat::Bgra32)
+            }
+            crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Grayscale64 => {
+                Some(PixelFormat::Grayscale64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_raw(&self) -> OrthancPluginPixelFormat {
+        match self {
+            PixelFormat::Grayscale8 => {
+                crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Grayscale8
+            }
+            PixelFormat::Grayscale16 => {
+                crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Grayscale16
+            }
+            PixelFormat::SignedGrayscale16 => {
+                crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_SignedGrayscale16
+            }
+            PixelFormat::Rgb24 => crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_RGB24,
+            PixelFormat::Rgba32 => crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_RGBA32,
+            PixelFormat::Rgb48 => crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_RGB48,
+            PixelFormat::Grayscale32 => {
+                crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Grayscale32
+            }
+            PixelFormat::Float32 => {
+                crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Float32
+            }
+            PixelFormat::Bgra32 => crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_BGRA32,
+            PixelFormat::Grayscale64 => {
+                crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Grayscale64
+            }
+        }
+    }
+
+    /// Size of a pixel, samples are stored in the native byte order.
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Grayscale8 => 1,
+            PixelFormat::Grayscale16 | PixelFormat::SignedGrayscale16 => 2,
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba32
+            | PixelFormat::Grayscale32
+            | PixelFormat::Float32
+            | PixelFormat::Bgra32 => 4,
+            PixelFormat::Rgb48 => 6,
+            PixelFormat::Grayscale64 => 8,
+        }
+    }
+}
+
+/// Encoding of a compressed image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    /// First frame of a DICOM file, only for decompression.
+    Dicom,
+}
+
+impl ImageFormat {
+    fn as_raw(&self) -> OrthancPluginImageFormat {
+        match self {
+            ImageFormat::Png => crate::OrthancPluginImageFormat_OrthancPluginImageFormat_Png,
+            ImageFormat::Jpeg => crate::OrthancPluginImageFormat_OrthancPluginImageFormat_Jpeg,
+            ImageFormat::Dicom => crate::OrthancPluginImageFormat_OrthancPluginImageFormat_Dicom,
+        }
+    }
+}
+
+/// `_OrthancPluginGetImageInfo`
+#[repr(C)]
+struct GetImageInfoParams {
+    image: *const OrthancPluginImage,
+    result_uint32: *mut u32,
+    result_pixel_format: *mut OrthancPluginPixelFormat,
+    result_buffer: *mut *mut c_void,
+}
+
+impl GetImageInfoParams {
+    fn new(image: *const OrthancPluginImage) -> Self {
+        Self {
+            image,
+            result_uint32: std::ptr::null_mut(),
+            result_pixel_format: std::ptr::null_mut(),
+            result_buffer: std::ptr::null_mut(),
+        }
+    }
+}
+
+#[repr(C)]
+struct FreeImageParams {
+    image: *const OrthancPluginImage,
+}
+
+#[repr(C)]
+struct CreateImageParams {
+    target: *mut *mut OrthancPluginImage,
+    format: OrthancPluginPixelFormat,
+    width: u32,
+    height: u32,
+    pitch: u32,
+    buffer: *mut c_void,
+    const_buffer: *const c_void,
+    buffer_size: u32,
+    frame_index: u32,
+}
+
+impl CreateImageParams {
+    fn new(target: *mut *mut OrthancPluginImage) -> Self {
+        Self {
+            target,
+            format: crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Unknown,
+            width: 0,
+            height: 0,
+            pitch: 0,
+            buffer: std::ptr::null_mut(),
+            const_buffer: std::ptr::null(),
+            buffer_size: 0,
+            frame_index: 0,
+        }
+    }
+}
+
+#[repr(C)]
+struct UncompressImageParams {
+    target: *mut *mut OrthancPluginImage,
+    data: *const c_void,
+    size: u32,
+    format: OrthancPluginImageFormat,
+}
+
+#[repr(C)]
+struct CompressImageParams {
+    target: *mut OrthancPluginMemoryBuffer,
+    image_format: OrthancPluginImageFormat,
+    pixel_format: OrthancPluginPixelFormat,
+    width: u32,
+    height: u32,
+    pitch: u32,
+    buffer: *const c_void,
+    quality: u8,
+}
+
+#[repr(C)]
+struct ConvertPixelFormatParams {
+    target: *mut *mut OrthancPluginImage,
+    source: *const OrthancPluginImage,
+    target_format: OrthancPluginPixelFormat,
+}
+
+#[repr(C)]
+struct CreateMemoryBufferParams {
+    target: *mut OrthancPluginMemoryBuffer,
+    size: u32,
+}
+
+/// Image allocated by Orthanc, released when dropped.
+///
+/// Rows are `pitch()` bytes apart, which may exceed `width() * bytes_per_pixel()`.
+pub struct Image {
+    context: Context,
+    image: *mut OrthancPluginImage,
+}
+
+unsafe impl Send for Image {}
+
+impl Image {
+    /// Blank image of `width` by `height` pixels.
+    pub fn new(
+        context: Context,
+        format: PixelFormat,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, OrthancError> {
+        let mut image = std::ptr::null_mut();
+        let mut params = CreateImageParams::new(&mut image);
+        params.format = format.as_raw();
+        params.width = width;
+        params.height = height;
+        Self::create(
+            context,
+            crate::_OrthancPluginService__OrthancPluginService_CreateImage,
+            &params,
+            &mut image,
+        )
+    }
+
+    /// Image of `width` by `height` pixels copied from the packed rows of `pixels`.
+    pub fn from_pixels(
+        context: Context,
+        format: PixelFormat,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+    ) -> Result<Self, OrthancError> {
+        let row = width as usize * format.bytes_per_pixel();
+        if pixels.len() != row * height as usize {
+            return Err(OrthancError(
+                crate::OrthancPluginErrorCode_OrthancPluginErrorCode_IncompatibleImageSize,
+            ));
+        }
+
+        let mut image = Self::new(context, format, width, height)?;
+        if row > 0 {
+            for (y, source) in pixels.chunks_exact(row).enumerate() {
+                image.row_mut(y as u32)?.copy_from_slice(source);
+            }
+        }
+        Ok(image)
+    }
+
+    /// Frame `frame` of the DICOM file `dicom`, decoded by Orthanc or the decoder plugins.
+    pub fn decode_dicom(context: Context, dicom: &[u8], frame: u32) -> Result<Self, OrthancError> {
+        let mut image = std::ptr::null_mut();
+        let mut params = CreateImageParams::new(&mut image);
+        params.const_buffer = dicom.as_ptr() as *const c_void;
+        params.buffer_size = dicom.len() as u32;
+        params.frame_index = frame;
+        Self::create(
+            context,
+            crate::_OrthancPluginService__OrthancPluginService_DecodeDicomImage,
+            &params,
+            &mut image,
+        )
+    }
+
+    /// Decompress the image `data` encoded as `format`.
+    pub fn uncompress(
+        context: Context,
+        data: &[u8],
+        format: ImageFormat,
+    ) -> Result<Self, OrthancError> {
+        let mut image = std::ptr::null_mut();
+        let params = UncompressImageParams {
+            target: &mut image,
+            data: data.as_ptr() as *const c_void,
+            size: data.len() as u32,
+            format: format.as_raw(),
+        };
+        Self::create(
+            context,
+            crate::_OrthancPluginService__OrthancPluginService_UncompressImage,
+            &params,
+            &mut image,
+        )
+    }
+
+    fn create<P>(
+        context: Context,
+        service: _OrthancPluginService,
+        params: &P,
+        image: *mut *mut OrthancPluginImage,
+    ) -> Result<Self, OrthancError> {
+        check(unsafe { context.invoke(service, params as *const _ as *const c_void) })?;
+
+        let image = unsafe { *image };
+        if image.is_null() {
+            return Err(OrthancError(
+                crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+            ));
+        }
+        Ok(Self { context, image })
+    }
+
+    /// # Safety
+    ///
+    /// `image` must be allocated by Orthanc and not be released by anyone else.
+    pub unsafe fn from_raw(context: Context, image: *mut OrthancPluginImage) -> Self {
+        Self { context, image }
+    }
+
+    /// Hand the image over, e.g. to Orthanc which then releases it.
+    pub fn into_raw(self) -> *mut OrthancPluginImage {
+        let image = self.image;
+        std::mem::forget(self);
+        image
+    }
+
+    pub fn as_ptr(&self) -> *const OrthancPluginImage {
+        self.image
+    }
+
+    pub fn pixel_format(&self) -> Result<PixelFormat, OrthancError> {
+        let mut format = crate::OrthancPluginPixelFormat_OrthancPluginPixelFormat_Unknown;
+        let mut params = GetImageInfoParams::new(self.image);
+        params.result_pixel_format = &mut format;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_GetImagePixelFormat,
+            &params as *const _ as *const c_void,
+        )?;
+
+        PixelFormat::from_raw(format).ok_or(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_IncompatibleImageFormat,
+        ))
+    }
+
+    pub fn width(&self) -> Result<u32, OrthancError> {
+        self.uint32(crate::_OrthancPluginService__OrthancPluginService_GetImageWidth)
+    }
+
+    pub fn height(&self) -> Result<u32, OrthancError> {
+        self.uint32(crate::_OrthancPluginService__OrthancPluginService_GetImageHeight)
+    }
+
+    /// Bytes between the starts of two consecutive rows.
+    pub fn pitch(&self) -> Result<u32, OrthancError> {
+        self.uint32(crate::_OrthancPluginService__OrthancPluginService_GetImagePitch)
+    }
+
+    /// The `pitch() * height()` bytes of the pixels.
+    pub fn buffer(&self) -> Result<&[u8], OrthancError> {
+        let (buffer, size) = self.raw_buffer()?;
+        Ok(match size {
+            0 => &[],
+            _ => unsafe { std::slice::from_raw_parts(buffer as *const u8, size) },
+        })
+    }
+
+    pub fn buffer_mut(&mut self) -> Result<&mut [u8], OrthancError> {
+        let (buffer, size) = self.raw_buffer()?;
+        Ok(match size {
+            0 => &mut [],
+            _ => unsafe { std::slice::from_raw_parts_mut(buffer as *mut u8, size) },
+        })
+    }
+
+    /// Pixels of the row `y`, without the padding up to the pitch.
+    pub fn row(&self, y: u32) -> Result<&[u8], OrthancError> {
+        let range = self.row_range(y)?;
+        Ok(&self.buffer()?[range])
+    }
+
+    pub fn row_mut(&mut self, y: u32) -> Result<&mut [u8], OrthancError> {
+        let range = self.row_range(y)?;
+        Ok(&mut self.buffer_mut()?[range])
+    }
+
+    /// Copy of the image with its pixels converted to `format`.
+    pub fn convert(&self, format: PixelFormat) -> Result<Image, OrthancError> {
+        let mut image = std::ptr::null_mut();
+        let params = ConvertPixelFormatParams {
+            target: &mut image,
+            source: self.image,
+            target_format: format.as_raw(),
+        };
+        Self::create(
+            self.context,
+            crate::_OrthancPluginService__OrthancPluginService_ConvertPixelFormat,
+            &params,
+            &mut image,
+        )
+    }
+
+    /// The image as a PNG file.
+    pub fn compress_png(&self) -> Result<Vec<u8>, OrthancError> {
+        self.compress(ImageFormat::Png, 0)
+    }
+
+    /// The image as a JPEG file of `quality`, from 1 to 100.
+    pub fn compress_jpeg(&self, quality: u8) -> Result<Vec<u8>, OrthancError> {
+        self.compress(ImageFormat::Jpeg, quality.clamp(1, 100))
+    }
+
+    fn compress(&self, format: ImageFormat, quality: u8) -> Result<Vec<u8>, OrthancError> {
+        let mut buffer = OrthancPluginMemoryBuffer {
+            data: std::ptr::null_mut(),
+            size: 0,
+        };
+        let params = CompressImageParams {
+            target: &mut buffer,
+            image_format: format.as_raw(),
+            pixel_format: self.pixel_format()?.as_raw(),
+            width: self.width()?,
+            height: self.height()?,
+            pitch: self.pitch()?,
+            buffer: self.raw_buffer()?.0,
+            quality,
+        };
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_CompressImage,
+            &params as *const _ as *const c_void,
+        )?;
+
+        if buffer.data.is_null() {
+            return Ok(Vec::new());
+        }
+        let content =
+            unsafe { std::slice::from_raw_parts(buffer.data as *const u8, buffer.size as usize) }
+                .to_vec();
+        unsafe { self.context.free(buffer.data) };
+        Ok(content)
+    }
+
+    fn raw_buffer(&self) -> Result<(*mut c_void, usize), OrthancError> {
+        let mut buffer = std::ptr::null_mut();
+        let mut params = GetImageInfoParams::new(self.image);
+        params.result_buffer = &mut buffer;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_GetImageBuffer,
+            &params as *const _ as *const c_void,
+        )?;
+
+        if buffer.is_null() {
+            return Ok((buffer, 0));
+        }
+        Ok((buffer, self.pitch()? as usize * self.height()? as usize))
+    }
+
+    fn row_range(&self, y: u32) -> Result<std::ops::Range<usize>, OrthancError> {
+        if y >= self.height()? {
+            return Err(OrthancError(
+                crate::OrthancPluginErrorCode_OrthancPluginErrorCode_ParameterOutOfRange,
+            ));
+        }
+        let start = y as usize * self.pitch()? as usize;
+        let length = self.width()? as usize * self.pixel_format()?.bytes_per_pixel();
+        Ok(start..start + length)
+    }
+
+    fn uint32(&self, service: _OrthancPluginService) -> Result<u32, OrthancError> {
+        let mut value = 0;
+        let mut params = GetImageInfoParams::new(self.image);
+        params.result_uint32 = &mut value;
+        self.invoke(service, &params as *const _ as *const c_void)?;
+        Ok(value)
+    }
+
+    fn invoke(
+        &self,
+        service: _OrthancPluginService,
+        params: *const c_void,
+    ) -> Result<(), OrthancError> {
+        check(unsafe { self.context.invoke(service, params) })
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let params = FreeImageParams { image: self.image };
+        unsafe {
+            self.context.invoke(
+                crate::_OrthancPluginService__OrthancPluginService_FreeImage,
+                &params as *const _ as *const c_void,
+            );
+        }
+    }
+}
+
+/// Decodes the frames of the DICOM files whose transfer syntax Orthanc cannot decode.
+pub trait ImageDecoder: Send + Sync + 'static {
+    /// Frame `frame` of the DICOM file `dicom`, `None` to leave it to the other decoders.
+    fn decode(&self, dicom: &[u8], frame: u32) -> Result<Option<Image>, Box<dyn Error>>;
+}
+
+/// Transcodes DICOM files to the transfer syntaxes Orthanc cannot produce.
+pub trait Transcoder: Send + Sync + 'static {
+    /// The DICOM file `dicom` transcoded to one of `allowed_syntaxes`, `None` to leave it to the
+    /// other transcoders. Lossy syntaxes changing the SOP instance UID are only allowed with
+    /// `allow_new_sop_instance_uid`.
+    fn transcode(
+        &self,
+        dicom: &[u8],
+        allowed_syntaxes: &[String],
+        allow_new_sop_instance_uid: bool,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+}
+
+static IMAGE_DECODER: OnceLock<(Context, Box<dyn ImageDecoder>)> = OnceLock::new();
+static TRANSCODER: OnceLock<(Context, Box<dyn Transcoder>)> = OnceLock::new();
+
+#[repr(C)]
+struct DecodeImageCallbackParams {
+    callback: crate::OrthancPluginDecodeImageCallback,
+}
+
+#[repr(C)]
+struct TranscoderCallbackParams {
+    callback: crate::OrthancPluginTranscoderCallback,
+}
+
+/// Decode the DICOM images with `decoder`, at most one decoder per plugin.
+///
+/// Whether the decoders of plugins are tried before the ones of Orthanc is set by the
+/// `"BuiltinDecoderTranscoderOrder"` option of Orthanc.
+pub fn register_decoder<D: ImageDecoder>(context: Context, decoder: D) -> Result<(), OrthancError> {
+    if IMAGE_DECODER.set((context, Box::new(decoder))).is_err() {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = DecodeImageCallbackParams {
+        callback: Some(decode_image),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterDecodeImageCallback,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+/// Transcode the DICOM files with `transcoder`, at most one transcoder per plugin.
+///
+/// Requires Orthanc 1.7.0.
+pub fn register_transcoder<T: Transcoder>(
+    context: Context,
+    transcoder: T,
+) -> Result<(), OrthancError> {
+    if !context.check_version(1, 7, 0) {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+        ));
+    }
+    if TRANSCODER.set((context, Box::new(transcoder))).is_err() {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = TranscoderCallbackParams {
+        callback: Some(transcode),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterTranscoderCallback,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+extern "C" fn decode_image(
+    target: *mut *mut OrthancPluginImage,
+    dicom: *const c_void,
+    size: u32,
+    frame: u32,
+) -> OrthancPluginErrorCode {
+    let (context, decoder) = match IMAGE_DECODER.get() {
+        Some(registered) => registered,
+        None => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin,
+    };
+    let dicom: &[u8] = if dicom.is_null() || size == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(dicom as *const u8, size as usize) }
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| decoder.decode(dicom, frame))) {
+        //
+        // Orthanc tries the next decoder when no image is returned
+        //
+        Ok(Ok(image)) => {
+            if !target.is_null() {
+                unsafe { *target = image.map_or(std::ptr::null_mut(), Image::into_raw) };
+            }
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+        }
+        Ok(Err(e)) => {
+            context.log_error(&format!("unable to decode frame {} - {}", frame, e));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+        Err(_) => {
+            context.log_error(&format!("decoding frame {} panicked", frame));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+    }
+}
+
+extern "C" fn transcode(
+    transcoded: *mut OrthancPluginMemoryBuffer,
+    buffer: *const c_void,
+    size: u64,
+    allowed_syntaxes: *const *const c_char,
+    count_syntaxes: u32,
+    allow_new_sop_instance_uid: u8,
+) -> OrthancPluginErrorCode {
+    let (context, transcoder) = match TRANSCODER.get() {
+        Some(registered) => registered,
+        None => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin,
+    };
+    let dicom: &[u8] = if buffer.is_null() || size == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(buffer as *const u8, size as usize) }
+    };
+    let allowed_syntaxes: Vec<String> = if allowed_syntaxes.is_null() {
+        Vec::new()
+    } else {
+        unsafe { std::slice::from_raw_parts(allowed_syntaxes, count_syntaxes as usize) }
+            .iter()
+            .filter(|syntax| !syntax.is_null())
+            .map(|syntax| {
+                unsafe { CStr::from_ptr(*syntax) }
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    };
+
+    let content = match catch_unwind(AssertUnwindSafe(|| {
+        transcoder.transcode(dicom, &allowed_syntaxes, allow_new_sop_instance_uid != 0)
+    })) {
+        Ok(Ok(Some(content))) => content,
+        //
+        // Orthanc tries the next transcoder when this one fails
+        //
+        Ok(Ok(None)) => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+        Ok(Err(e)) => {
+            context.log_error(&format!(
+                "unable to transcode to {} - {}",
+                allowed_syntaxes.join(", "),
+                e
+            ));
+            return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin;
+        }
+        Err(_) => {
+            context.log_error("transcoding panicked");
+            return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin;
+        }
+    };
+
+    //
+    // The transcoded file must be allocated by Orthanc, which releases it
+    //
+    let params = CreateMemoryBufferParams {
+        target: transcoded,
+        size: content.len() as u32,
+    };
+    let allocated = unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_CreateMemoryBuffer,
+            &params as *const _ as *const c_void,
+        )
+    };
+    if allocated != crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success {
+        return allocated;
+    }
+    let data = unsafe { (*transcoded).data as *mut u8 };
+    if data.is_null() && !content.is_empty() {
+        return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotEnoughMemory;
+    }
+    unsafe { std::ptr::copy_nonoverlapping(content.as_ptr(), data, content.len()) };
+    crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{offset_of, size_of};
+
+    use super::*;
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn params_match_the_sdk_layout() {
+        assert_eq!(offset_of!(GetImageInfoParams, image), 0);
+        assert_eq!(offset_of!(GetImageInfoParams, result_uint32), 8);
+        assert_eq!(offset_of!(GetImageInfoParams, result_pixel_format), 16);
+        assert_eq!(offset_of!(GetImageInfoParams, result_buffer), 24);
+        assert_eq!(size_of::<GetImageInfoParams>(), 32);
+
+        assert_eq!(size_of::<FreeImageParams>(), 8);
+
+        assert_eq!(offset_of!(CreateImageParams, target), 0);
+        assert_eq!(offset_of!(CreateImageParams, format), 8);
+        assert_eq!(offset_of!(CreateImageParams, width), 12);
+        assert_eq!(offset_of!(CreateImageParams, height), 16);
+        assert_eq!(offset_of!(CreateImageParams, pitch), 20);
+        assert_eq!(offset_of!(CreateImageParams, buffer), 24);
+        assert_eq!(offset_of!(CreateImageParams, const_buffer), 32);
+        assert_eq!(offset_of!(CreateImageParams, buffer_size), 40);
+        assert_eq!(offset_of!(CreateImageParams, frame_index), 44);
+        assert_eq!(size_of::<CreateImageParams>(), 48);
+
+        assert_eq!(offset_of!(UncompressImageParams, target), 0);
+        assert_eq!(offset_of!(UncompressImageParams, data), 8);
+        assert_eq!(offset_of!(UncompressImageParams, size), 16);
+        assert_eq!(offset_of!(UncompressImageParams, format), 20);
+        assert_eq!(size_of::<UncompressImageParams>(), 24);
+
+        assert_eq!(offset_of!(CompressImageParams, target), 0);
+        assert_eq!(offset_of!(CompressImageParams, image_format), 8);
+        assert_eq!(offset_of!(CompressImageParams, pixel_format), 12);
+        assert_eq!(offset_of!(CompressImageParams, width), 16);
+        assert_eq!(offset_of!(CompressImageParams, height), 20);
+        assert_eq!(offset_of!(CompressImageParams, pitch), 24);
+        assert_eq!(offset_of!(CompressImageParams, buffer), 32);
+        assert_eq!(offset_of!(CompressImageParams, quality), 40);
+        assert_eq!(size_of::<CompressImageParams>(), 48);
+
+        assert_eq!(offset_of!(ConvertPixelFormatParams, target), 0);
+        assert_eq!(offset_of!(ConvertPixelFormatParams, source), 8);
+        assert_eq!(offset_of!(ConvertPixelFormatParams, target_format), 16);
+        assert_eq!(size_of::<ConvertPixelFormatParams>(), 24);
+
+        assert_eq!(offset_of!(CreateMemoryBufferParams, target), 0);
+        assert_eq!(offset_of!(CreateMemoryBufferParams, size), 8);
+        assert_eq!(size_of::<CreateMemoryBufferParams>(), 16);
+
+        assert_eq!(size_of::<DecodeImageCallbackParams>(), 8);
+        assert_eq!(size_of::<TranscoderCallbackParams>(), 8);
+    }
+}
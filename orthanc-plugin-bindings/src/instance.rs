@@ -0,0 +1,829 @@
+//! DICOM instances received and stored by Orthanc.
+//!
+//! ```ignore
+//! instance::register_on_stored_instance(context, |instance, id| {
+//!     let tags = instance.simplified_json()?;
+//!     println!("stored {} from {:?}", id, instance.remote_aet()?);
+//!     Ok(())
+//! })?;
+//! ```
+
+use std::{
+    error::Error,
+    ffi::{c_void, CStr, CString},
+    marker::PhantomData,
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::OnceLock,
+};
+
+use crate::{
+    _OrthancPluginService,
+    context::{check, Context, OrthancError},
+    image::Image,
+    OrthancPluginCreateDicomFlags, OrthancPluginDicomInstance, OrthancPluginDicomToJsonFlags,
+    OrthancPluginDicomToJsonFormat, OrthancPluginErrorCode, OrthancPluginImage,
+    OrthancPluginInstanceOrigin, OrthancPluginMemoryBuffer,
+};
+
+/// How an instance reached Orthanc.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InstanceOrigin {
+    Unknown,
+    DicomProtocol,
+    RestApi,
+    Plugin,
+    Lua,
+    WebDav,
+}
+
+impl InstanceOrigin {
+    pub fn from_raw(origin: OrthancPluginInstanceOrigin) -> Self {
+        match origin {
+            crate::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_DicomProtocol => {
+                Self::DicomProtocol
+            }
+            crate::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_RestApi => Self::RestApi,
+            crate::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_Plugin => Self::Plugin,
+            crate::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_Lua => Self::Lua,
+            crate::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_WebDav => Self::WebDav,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Format of the tags in [`DicomInstance::advanced_json`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum JsonFormat {
+    /// Tags by `gggg,eeee`, with their name, type and value.
+    Full,
+    /// Values by `gggg,eeee`.
+    Short,
+    /// Values by tag name.
+    Human,
+}
+
+impl JsonFormat {
+    fn as_raw(&self) -> OrthancPluginDicomToJsonFormat {
+        match self {
+            Self::Full => crate::OrthancPluginDicomToJsonFormat_OrthancPluginDicomToJsonFormat_Full,
+            Self::Short => {
+                crate::OrthancPluginDicomToJsonFormat_OrthancPluginDicomToJsonFormat_Short
+            }
+            Self::Human => {
+                crate::OrthancPluginDicomToJsonFormat_OrthancPluginDicomToJsonFormat_Human
+            }
+        }
+    }
+}
+
+/// `_OrthancPluginAccessDicomInstance`
+#[repr(C)]
+struct AccessDicomInstanceParams {
+    result_string_to_free: *mut *mut c_char,
+    result_string: *mut *const c_char,
+    result_int64: *mut i64,
+    key: *const c_char,
+    instance: *const OrthancPluginDicomInstance,
+    result_origin: *mut OrthancPluginInstanceOrigin,
+}
+
+impl AccessDicomInstanceParams {
+    fn new(instance: *const OrthancPluginDicomInstance) -> Self {
+        Self {
+            result_string_to_free: std::ptr::null_mut(),
+            result_string: std::ptr::null_mut(),
+            result_int64: std::ptr::null_mut(),
+            key: std::ptr::null(),
+            instance,
+            result_origin: std::ptr::null_mut(),
+        }
+    }
+}
+
+/// `_OrthancPluginAccessDicomInstance2`
+#[repr(C)]
+struct AccessDicomInstance2Params {
+    target_uint32: *mut u32,
+    target_buffer: *mut OrthancPluginMemoryBuffer,
+    target_image: *mut *mut OrthancPluginImage,
+    target_string_to_free: *mut *mut c_char,
+    instance: *const OrthancPluginDicomInstance,
+    frame_index: u32,
+    format: OrthancPluginDicomToJsonFormat,
+    flags: OrthancPluginDicomToJsonFlags,
+    max_string_length: u32,
+    dicom_web_callback: crate::OrthancPluginDicomWebBinaryCallback2,
+    dicom_web_payload: *mut c_void,
+}
+
+impl AccessDicomInstance2Params {
+    fn new(instance: *const OrthancPluginDicomInstance) -> Self {
+        Self {
+            target_uint32: std::ptr::null_mut(),
+            target_buffer: std::ptr::null_mut(),
+            target_image: std::ptr::null_mut(),
+            target_string_to_free: std::ptr::null_mut(),
+            instance,
+            frame_index: 0,
+            format: 0,
+            flags: 0,
+            max_string_length: 0,
+            dicom_web_callback: None,
+            dicom_web_payload: std::ptr::null_mut(),
+        }
+    }
+}
+
+/// DICOM instance handed to a callback, valid for the duration of the callback.
+#[derive(Copy, Clone)]
+pub struct DicomInstance<'a> {
+    context: Context,
+    instance: *const OrthancPluginDicomInstance,
+    _callback: PhantomData<&'a OrthancPluginDicomInstance>,
+}
+
+impl<'a> DicomInstance<'a> {
+    /// # Safety
+    ///
+    /// `instance` must be the instance passed to an Orthanc callback and outlive `'a`.
+    pub unsafe fn from_raw(context: Context, instance: *const OrthancPluginDicomInstance) -> Self {
+        Self {
+            context,
+            instance,
+            _callback: PhantomData,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const OrthancPluginDicomInstance {
+        self.instance
+    }
+
+    /// AET of the modality that sent the instance, empty if it was not received by C-STORE.
+    pub fn remote_aet(&self) -> Result<&'a str, OrthancError> {
+        self.string(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceRemoteAet,
+            None,
+        )?
+        .ok_or(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+        ))
+    }
+
+    pub fn size(&self) -> Result<u64, OrthancError> {
+        self.int64(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceSize,
+            None,
+        )
+        .map(|size| size as u64)
+    }
+
+    /// Content of the DICOM file.
+    pub fn data(&self) -> Result<&'a [u8], OrthancError> {
+        let size = self.size()? as usize;
+        let mut data: *const c_char = std::ptr::null();
+        let mut params = AccessDicomInstanceParams::new(self.instance);
+        params.result_string = &mut data;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceData,
+            &params as *const _ as *const c_void,
+        )?;
+
+        if data.is_null() || size == 0 {
+            return Ok(&[]);
+        }
+        Ok(unsafe { std::slice::from_raw_parts(data as *const u8, size) })
+    }
+
+    /// Tags of the instance, as `/instances/{id}/tags`.
+    pub fn json(&self) -> Result<serde_json::Value, OrthancError> {
+        self.owned_json(crate::_OrthancPluginService__OrthancPluginService_GetInstanceJson)
+    }
+
+    /// Tags of the instance by name, as `/instances/{id}/simplified-tags`.
+    pub fn simplified_json(&self) -> Result<serde_json::Value, OrthancError> {
+        self.owned_json(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceSimplifiedJson,
+        )
+    }
+
+    /// Tags of the instance, strings longer than `max_string_length` are left out unless it is 0.
+    pub fn advanced_json(
+        &self,
+        format: JsonFormat,
+        flags: OrthancPluginDicomToJsonFlags,
+        max_string_length: u32,
+    ) -> Result<serde_json::Value, OrthancError> {
+        let mut result: *mut c_char = std::ptr::null_mut();
+        let mut params = AccessDicomInstance2Params::new(self.instance);
+        params.target_string_to_free = &mut result;
+        params.format = format.as_raw();
+        params.flags = flags;
+        params.max_string_length = max_string_length;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceAdvancedJson,
+            &params as *const _ as *const c_void,
+        )?;
+
+        self.take_json(result)
+    }
+
+    pub fn has_metadata(&self, name: &str) -> Result<bool, OrthancError> {
+        let name = key(name)?;
+        self.int64(
+            crate::_OrthancPluginService__OrthancPluginService_HasInstanceMetadata,
+            Some(&name),
+        )
+        .map(|found| found != 0)
+    }
+
+    /// Metadata of the instance, not available in filters.
+    pub fn metadata(&self, name: &str) -> Result<Option<&'a str>, OrthancError> {
+        if !self.has_metadata(name)? {
+            return Ok(None);
+        }
+        let name = key(name)?;
+        self.string(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceMetadata,
+            Some(&name),
+        )
+    }
+
+    pub fn origin(&self) -> Result<InstanceOrigin, OrthancError> {
+        let mut origin = crate::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_Unknown;
+        let mut params = AccessDicomInstanceParams::new(self.instance);
+        params.result_origin = &mut origin;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceOrigin,
+            &params as *const _ as *const c_void,
+        )?;
+
+        Ok(InstanceOrigin::from_raw(origin))
+    }
+
+    pub fn transfer_syntax_uid(&self) -> Result<String, OrthancError> {
+        let mut result: *mut c_char = std::ptr::null_mut();
+        let mut params = AccessDicomInstanceParams::new(self.instance);
+        params.result_string_to_free = &mut result;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceTransferSyntaxUid,
+            &params as *const _ as *const c_void,
+        )?;
+
+        self.take_string(result)
+    }
+
+    pub fn has_pixel_data(&self) -> Result<bool, OrthancError> {
+        self.int64(
+            crate::_OrthancPluginService__OrthancPluginService_HasInstancePixelData,
+            None,
+        )
+        .map(|found| found != 0)
+    }
+
+    pub fn frames_count(&self) -> Result<u32, OrthancError> {
+        let mut count = 0;
+        let mut params = AccessDicomInstance2Params::new(self.instance);
+        params.target_uint32 = &mut count;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceFramesCount,
+            &params as *const _ as *const c_void,
+        )?;
+
+        Ok(count)
+    }
+
+    /// Pixel data of a frame, as encoded in the transfer syntax of the instance.
+    pub fn raw_frame(&self, index: u32) -> Result<Vec<u8>, OrthancError> {
+        let mut buffer = OrthancPluginMemoryBuffer {
+            data: std::ptr::null_mut(),
+            size: 0,
+        };
+        let mut params = AccessDicomInstance2Params::new(self.instance);
+        params.target_buffer = &mut buffer;
+        params.frame_index = index;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceRawFrame,
+            &params as *const _ as *const c_void,
+        )?;
+
+        Ok(self.take_buffer(buffer))
+    }
+
+    /// Frame `index` decoded by Orthanc or the decoder plugins.
+    pub fn decoded_frame(&self, index: u32) -> Result<Image, OrthancError> {
+        let mut image = std::ptr::null_mut();
+        let mut params = AccessDicomInstance2Params::new(self.instance);
+        params.target_image = &mut image;
+        params.frame_index = index;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceDecodedFrame,
+            &params as *const _ as *const c_void,
+        )?;
+
+        if image.is_null() {
+            return Err(OrthancError(
+                crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+            ));
+        }
+        Ok(unsafe { Image::from_raw(self.context, image) })
+    }
+
+    /// DICOM file of the instance, including modifications applied by Orthanc.
+    pub fn serialize(&self) -> Result<Vec<u8>, OrthancError> {
+        let mut buffer = OrthancPluginMemoryBuffer {
+            data: std::ptr::null_mut(),
+            size: 0,
+        };
+        let mut params = AccessDicomInstance2Params::new(self.instance);
+        params.target_buffer = &mut buffer;
+        self.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_SerializeDicomInstance,
+            &params as *const _ as *const c_void,
+        )?;
+
+        Ok(self.take_buffer(buffer))
+    }
+
+    fn invoke(
+        &self,
+        service: _OrthancPluginService,
+        params: *const c_void,
+    ) -> Result<(), OrthancError> {
+        check(unsafe { self.context.invoke(service, params) })
+    }
+
+    fn string(
+        &self,
+        service: _OrthancPluginService,
+        name: Option<&CString>,
+    ) -> Result<Option<&'a str>, OrthancError> {
+        let mut result: *const c_char = std::ptr::null();
+        let mut params = AccessDicomInstanceParams::new(self.instance);
+        params.key = name.map(|name| name.as_ptr()).unwrap_or(std::ptr::null());
+        params.result_string = &mut result;
+        self.invoke(service, &params as *const _ as *const c_void)?;
+
+        if result.is_null() {
+            return Ok(None);
+        }
+        unsafe { CStr::from_ptr(result) }
+            .to_str()
+            .map(Some)
+            .map_err(|_| {
+                OrthancError(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadFileFormat)
+            })
+    }
+
+    fn int64(
+        &self,
+        service: _OrthancPluginService,
+        name: Option<&CString>,
+    ) -> Result<i64, OrthancError> {
+        let mut result = 0;
+        let mut params = AccessDicomInstanceParams::new(self.instance);
+        params.key = name.map(|name| name.as_ptr()).unwrap_or(std::ptr::null());
+        params.result_int64 = &mut result;
+        self.invoke(service, &params as *const _ as *const c_void)?;
+
+        Ok(result)
+    }
+
+    fn owned_json(
+        &self,
+        service: _OrthancPluginService,
+    ) -> Result<serde_json::Value, OrthancError> {
+        let mut result: *mut c_char = std::ptr::null_mut();
+        let mut params = AccessDicomInstanceParams::new(self.instance);
+        params.result_string_to_free = &mut result;
+        self.invoke(service, &params as *const _ as *const c_void)?;
+
+        self.take_json(result)
+    }
+
+    /// Copy a string allocated by Orthanc and release it.
+    fn take_string(&self, result: *mut c_char) -> Result<String, OrthancError> {
+        if result.is_null() {
+            return Err(OrthancError(
+                crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+            ));
+        }
+        let value = unsafe { CStr::from_ptr(result) }
+            .to_string_lossy()
+            .to_string();
+        unsafe { self.context.free(result as *mut c_void) };
+        Ok(value)
+    }
+
+    fn take_json(&self, result: *mut c_char) -> Result<serde_json::Value, OrthancError> {
+        serde_json::from_str(&self.take_string(result)?)
+            .map_err(|_| OrthancError(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadJson))
+    }
+
+    /// Copy a memory buffer allocated by Orthanc and release it.
+    fn take_buffer(&self, buffer: OrthancPluginMemoryBuffer) -> Vec<u8> {
+        if buffer.data.is_null() {
+            return Vec::new();
+        }
+        let content =
+            unsafe { std::slice::from_raw_parts(buffer.data as *const u8, buffer.size as usize) }
+                .to_vec();
+        unsafe { self.context.free(buffer.data) };
+        content
+    }
+}
+
+#[repr(C)]
+struct CreateDicomInstanceParams {
+    target: *mut *mut OrthancPluginDicomInstance,
+    buffer: *const c_void,
+    size: u32,
+}
+
+#[repr(C)]
+struct FreeDicomInstanceParams {
+    dicom: *mut OrthancPluginDicomInstance,
+}
+
+/// DICOM instance parsed by Orthanc from a DICOM file, released when dropped.
+pub struct ParsedInstance {
+    context: Context,
+    instance: *mut OrthancPluginDicomInstance,
+}
+
+impl ParsedInstance {
+    /// Parse the DICOM file `dicom`, requires Orthanc 1.7.0.
+    pub fn parse(context: Context, dicom: &[u8]) -> Result<Self, OrthancError> {
+        if !context.check_version(1, 7, 0) {
+            return Err(OrthancError(
+                crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+            ));
+        }
+
+        let mut instance = std::ptr::null_mut();
+        let params = CreateDicomInstanceParams {
+            target: &mut instance,
+            buffer: dicom.as_ptr() as *const c_void,
+            size: dicom.len() as u32,
+        };
+        check(unsafe {
+            context.invoke(
+                crate::_OrthancPluginService__OrthancPluginService_CreateDicomInstance,
+                &params as *const _ as *const c_void,
+            )
+        })?;
+
+        if instance.is_null() {
+            return Err(OrthancError(
+                crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+            ));
+        }
+        Ok(Self { context, instance })
+    }
+
+    pub fn instance(&self) -> DicomInstance<'_> {
+        unsafe { DicomInstance::from_raw(self.context, self.instance) }
+    }
+}
+
+impl Drop for ParsedInstance {
+    fn drop(&mut self) {
+        let params = FreeDicomInstanceParams {
+            dicom: self.instance,
+        };
+        unsafe {
+            self.context.invoke(
+                crate::_OrthancPluginService__OrthancPluginService_FreeDicomInstance,
+                &params as *const _ as *const c_void,
+            );
+        }
+    }
+}
+
+fn key(name: &str) -> Result<CString, OrthancError> {
+    CString::new(name).map_err(|_| {
+        OrthancError(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_ParameterOutOfRange)
+    })
+}
+
+#[repr(C)]
+struct CreateDicomParams {
+    target: *mut OrthancPluginMemoryBuffer,
+    json: *const c_char,
+    pixel_data: *const OrthancPluginImage,
+    flags: OrthancPluginCreateDicomFlags,
+}
+
+/// Create a DICOM file from tags by name, as `/tools/create-dicom`.
+pub fn create_dicom(
+    context: Context,
+    tags: &serde_json::Value,
+    flags: OrthancPluginCreateDicomFlags,
+) -> Result<Vec<u8>, OrthancError> {
+    let json = key(&tags.to_string())?;
+    let mut buffer = OrthancPluginMemoryBuffer {
+        data: std::ptr::null_mut(),
+        size: 0,
+    };
+    let params = CreateDicomParams {
+        target: &mut buffer,
+        json: json.as_ptr(),
+        pixel_data: std::ptr::null(),
+        flags,
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_CreateDicom,
+            &params as *const _ as *const c_void,
+        )
+    })?;
+
+    if buffer.data.is_null() {
+        return Ok(Vec::new());
+    }
+    let content =
+        unsafe { std::slice::from_raw_parts(buffer.data as *const u8, buffer.size as usize) }
+            .to_vec();
+    unsafe { context.free(buffer.data) };
+    Ok(content)
+}
+
+type OnStoredInstance =
+    Box<dyn Fn(&DicomInstance, &str) -> Result<(), Box<dyn Error>> + Send + Sync>;
+
+static ON_STORED_INSTANCE: OnceLock<(Context, OnStoredInstance)> = OnceLock::new();
+
+#[repr(C)]
+struct OnStoredInstanceCallbackParams {
+    callback: crate::OrthancPluginOnStoredInstanceCallback,
+}
+
+/// Call `callback` with every instance Orthanc stores, at most one callback per plugin.
+///
+/// The callback runs synchronously with the storage of the instance, errors are logged by
+/// Orthanc.
+pub fn register_on_stored_instance<F>(context: Context, callback: F) -> Result<(), OrthancError>
+where
+    F: Fn(&DicomInstance, &str) -> Result<(), Box<dyn Error>> + Send + Sync + 'static,
+{
+    if ON_STORED_INSTANCE
+        .set((context, Box::new(callback)))
+        .is_err()
+    {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = OnStoredInstanceCallbackParams {
+        callback: Some(on_stored_instance),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterOnStoredInstanceCallback,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+extern "C" fn on_stored_instance(
+    instance: *const OrthancPluginDicomInstance,
+    instance_id: *const c_char,
+) -> OrthancPluginErrorCode {
+    let (context, callback) = match ON_STORED_INSTANCE.get() {
+        Some(registered) => registered,
+        None => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin,
+    };
+    let instance = unsafe { DicomInstance::from_raw(*context, instance) };
+    let instance_id = unsafe { CStr::from_ptr(instance_id) }.to_string_lossy();
+
+    match catch_unwind(AssertUnwindSafe(|| callback(&instance, &instance_id))) {
+        Ok(Ok(())) => crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success,
+        Ok(Err(e)) => {
+            context.log_error(&format!("stored instance {} - {}", instance_id, e));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+        Err(_) => {
+            context.log_error(&format!(
+                "stored instance {} - callback panicked",
+                instance_id
+            ));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{offset_of, size_of};
+
+    use super::*;
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn params_match_the_sdk_layout() {
+        assert_eq!(
+            offset_of!(AccessDicomInstanceParams, result_string_to_free),
+            0
+        );
+        assert_eq!(offset_of!(AccessDicomInstanceParams, result_string), 8);
+        assert_eq!(offset_of!(AccessDicomInstanceParams, result_int64), 16);
+        assert_eq!(offset_of!(AccessDicomInstanceParams, key), 24);
+        assert_eq!(offset_of!(AccessDicomInstanceParams, instance), 32);
+        assert_eq!(offset_of!(AccessDicomInstanceParams, result_origin), 40);
+        assert_eq!(size_of::<AccessDicomInstanceParams>(), 48);
+
+        assert_eq!(offset_of!(AccessDicomInstance2Params, target_uint32), 0);
+        assert_eq!(offset_of!(AccessDicomInstance2Params, target_buffer), 8);
+        assert_eq!(offset_of!(AccessDicomInstance2Params, target_image), 16);
+        assert_eq!(
+            offset_of!(AccessDicomInstance2Params, target_string_to_free),
+            24
+        );
+        assert_eq!(offset_of!(AccessDicomInstance2Params, instance), 32);
+        assert_eq!(offset_of!(AccessDicomInstance2Params, frame_index), 40);
+        assert_eq!(offset_of!(AccessDicomInstance2Params, format), 44);
+        assert_eq!(offset_of!(AccessDicomInstance2Params, flags), 48);
+        assert_eq!(
+            offset_of!(AccessDicomInstance2Params, max_string_length),
+            52
+        );
+        assert_eq!(
+            offset_of!(AccessDicomInstance2Params, dicom_web_callback),
+            56
+        );
+        assert_eq!(
+            offset_of!(AccessDicomInstance2Params, dicom_web_payload),
+            64
+        );
+        assert_eq!(size_of::<AccessDicomInstance2Params>(), 72);
+
+        assert_eq!(offset_of!(CreateDicomInstanceParams, target), 0);
+        assert_eq!(offset_of!(CreateDicomInstanceParams, buffer), 8);
+        assert_eq!(offset_of!(CreateDicomInstanceParams, size), 16);
+        assert_eq!(size_of::<CreateDicomInstanceParams>(), 24);
+
+        assert_eq!(offset_of!(FreeDicomInstanceParams, dicom), 0);
+        assert_eq!(size_of::<FreeDicomInstanceParams>(), 8);
+
+        assert_eq!(offset_of!(CreateDicomParams, target), 0);
+        assert_eq!(offset_of!(CreateDicomParams, json), 8);
+        assert_eq!(offset_of!(CreateDicomParams, pixel_data), 16);
+        assert_eq!(offset_of!(CreateDicomParams, flags), 24);
+        assert_eq!(size_of::<CreateDicomParams>(), 32);
+
+        assert_eq!(offset_of!(OnStoredInstanceCallbackParams, callback), 0);
+        assert_eq!(size_of::<OnStoredInstanceCallbackParams>(), 8);
+    }
+
+    /// Instance answered by [`access`], in place of the opaque instance of Orthanc.
+    struct Received {
+        remote_aet: CString,
+        data: Vec<u8>,
+        origin: OrthancPluginInstanceOrigin,
+        metadata: Vec<(CString, CString)>,
+    }
+
+    /// Orthanc services of the stand-in context, reading the instance as a [`Received`].
+    unsafe extern "C" fn access(
+        _context: *mut crate::OrthancPluginContext,
+        service: _OrthancPluginService,
+        params: *const c_void,
+    ) -> OrthancPluginErrorCode {
+        let owned = |value: &str| CString::new(value).unwrap().into_raw();
+
+        if service == crate::_OrthancPluginService__OrthancPluginService_GetInstanceFramesCount {
+            let params = &*(params as *const AccessDicomInstance2Params);
+            let received = &*(params.instance as *const Received);
+            *params.target_uint32 = received.data.len() as u32;
+            return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success;
+        }
+
+        let params = &*(params as *const AccessDicomInstanceParams);
+        let received = &*(params.instance as *const Received);
+        let metadata = || {
+            let key = CStr::from_ptr(params.key);
+            received
+                .metadata
+                .iter()
+                .find(|(name, _)| name.as_c_str() == key)
+        };
+        match service {
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceRemoteAet => {
+                *params.result_string = received.remote_aet.as_ptr()
+            }
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceSize => {
+                *params.result_int64 = received.data.len() as i64
+            }
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceData => {
+                *params.result_string = received.data.as_ptr() as *const c_char
+            }
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceSimplifiedJson => {
+                *params.result_string_to_free = owned(r#"{"Modality":"CT"}"#)
+            }
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceTransferSyntaxUid => {
+                *params.result_string_to_free = owned("1.2.840.10008.1.2.4.80")
+            }
+            crate::_OrthancPluginService__OrthancPluginService_HasInstancePixelData => {
+                *params.result_int64 = 1
+            }
+            crate::_OrthancPluginService__OrthancPluginService_HasInstanceMetadata => {
+                *params.result_int64 = metadata().is_some() as i64
+            }
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceMetadata => {
+                match metadata() {
+                    Some((_, value)) => *params.result_string = value.as_ptr(),
+                    None => {
+                        return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InexistentItem
+                    }
+                }
+            }
+            crate::_OrthancPluginService__OrthancPluginService_GetInstanceOrigin => {
+                *params.result_origin = received.origin
+            }
+            _ => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+        }
+        crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+    }
+
+    unsafe extern "C" fn free(data: *mut c_void) {
+        drop(CString::from_raw(data as *mut c_char));
+    }
+
+    fn with_instance<T>(received: &Received, read: impl FnOnce(DicomInstance) -> T) -> T {
+        let mut raw = crate::OrthancPluginContext {
+            pluginsManager: std::ptr::null_mut(),
+            orthancVersion: std::ptr::null(),
+            Free: Some(free),
+            InvokeService: Some(access),
+        };
+        let context = unsafe { Context::from_raw(&mut raw) };
+        read(unsafe {
+            DicomInstance::from_raw(
+                context,
+                received as *const Received as *const OrthancPluginDicomInstance,
+            )
+        })
+    }
+
+    fn received() -> Received {
+        Received {
+            remote_aet: CString::new("MODALITY").unwrap(),
+            data: b"DICM0123".to_vec(),
+            origin: crate::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_DicomProtocol,
+            metadata: vec![(
+                CString::new("ReceptionDate").unwrap(),
+                CString::new("20261019T120000").unwrap(),
+            )],
+        }
+    }
+
+    #[test]
+    fn reads_the_instance_through_the_sdk_params() {
+        let received = received();
+
+        with_instance(&received, |instance| {
+            assert_eq!(instance.remote_aet().unwrap(), "MODALITY");
+            assert_eq!(instance.size().unwrap(), 8);
+            assert_eq!(instance.data().unwrap(), b"DICM0123");
+            assert_eq!(
+                instance.simplified_json().unwrap(),
+                serde_json::json!({ "Modality": "CT" })
+            );
+            assert_eq!(
+                instance.transfer_syntax_uid().unwrap(),
+                "1.2.840.10008.1.2.4.80"
+            );
+            assert!(instance.has_pixel_data().unwrap());
+            assert_eq!(instance.frames_count().unwrap(), 8);
+        });
+    }
+
+    #[test]
+    fn reads_the_origin_as_an_origin() {
+        let mut received = received();
+
+        with_instance(&received, |instance| {
+            assert_eq!(instance.origin().unwrap(), InstanceOrigin::DicomProtocol)
+        });
+
+        received.origin = crate::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_WebDav;
+        with_instance(&received, |instance| {
+            assert_eq!(instance.origin().unwrap(), InstanceOrigin::WebDav)
+        });
+    }
+
+    #[test]
+    fn reads_metadata_by_name() {
+        let received = received();
+
+        with_instance(&received, |instance| {
+            assert_eq!(
+                instance.metadata("ReceptionDate").unwrap(),
+                Some("20261019T120000")
+            );
+            assert_eq!(instance.metadata("Origin").unwrap(), None);
+            assert!(instance.metadata("Recep\0tion").is_err());
+        });
+    }
+}
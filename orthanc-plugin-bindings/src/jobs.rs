@@ -0,0 +1,580 @@
+//! Long-running tasks run by the jobs engine of Orthanc, listed under `/jobs` with their
+//! progress and controlled like the jobs of Orthanc itself (pause, resume, cancel, resubmit).
+//!
+//! ```ignore
+//! #[derive(Clone, Serialize, Deserialize)]
+//! struct Count { next: u32, to: u32 }
+//!
+//! impl Job for Count {
+//!     const TYPE: &'static str = "Count";
+//!     type State = Count;
+//!
+//!     fn state(&self) -> Option<Count> { Some(self.clone()) }
+//!     fn step(&mut self) -> Result<JobStep, Box<dyn Error>> {
+//!         self.next += 1;
+//!         Ok(if self.next < self.to { JobStep::Continue } else { JobStep::Success })
+//!     }
+//!     fn progress(&self) -> f32 { self.next as f32 / self.to as f32 }
+//!     fn reset(&mut self) -> Result<(), Box<dyn Error>> { self.next = 0; Ok(()) }
+//! }
+//!
+//! jobs::register_unserializer::<Count, _>(context, Ok)?;
+//! let id = jobs::submit(context, Count { next: 0, to: 10 }, 0)?;
+//! ```
+
+use std::{
+    error::Error,
+    ffi::{c_void, CStr, CString},
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Mutex, OnceLock},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    context::{check, Context, OrthancError},
+    OrthancPluginErrorCode, OrthancPluginJob, OrthancPluginJobStepStatus,
+    OrthancPluginJobStopReason,
+};
+
+#[repr(C)]
+struct CreateJobParams {
+    target: *mut *mut OrthancPluginJob,
+    job: *mut c_void,
+    finalize: crate::OrthancPluginJobFinalize,
+    job_type: *const c_char,
+    get_progress: crate::OrthancPluginJobGetProgress,
+    get_content: crate::OrthancPluginJobGetContent,
+    get_serialized: crate::OrthancPluginJobGetSerialized,
+    step: crate::OrthancPluginJobStep,
+    stop: crate::OrthancPluginJobStop,
+    reset: crate::OrthancPluginJobReset,
+}
+
+#[repr(C)]
+struct SubmitJobParams {
+    result_id: *mut *mut c_char,
+    job: *mut OrthancPluginJob,
+    priority: i32,
+}
+
+#[repr(C)]
+struct JobsUnserializerParams {
+    unserializer: crate::OrthancPluginJobsUnserializer,
+}
+
+/// Outcome of a [`Job::step`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum JobStep {
+    /// The job completed.
+    Success,
+    /// The job failed, it can be resubmitted after a [`Job::reset`].
+    Failure,
+    /// More steps are needed.
+    Continue,
+}
+
+impl JobStep {
+    fn as_raw(&self) -> OrthancPluginJobStepStatus {
+        match self {
+            Self::Success => crate::OrthancPluginJobStepStatus_OrthancPluginJobStepStatus_Success,
+            Self::Failure => crate::OrthancPluginJobStepStatus_OrthancPluginJobStepStatus_Failure,
+            Self::Continue => crate::OrthancPluginJobStepStatus_OrthancPluginJobStepStatus_Continue,
+        }
+    }
+}
+
+/// Why Orthanc stopped running a job.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StopReason {
+    Success,
+    /// The job is resumed later, from where it stopped.
+    Paused,
+    Failure,
+    Canceled,
+}
+
+impl StopReason {
+    fn from_raw(reason: OrthancPluginJobStopReason) -> Self {
+        match reason {
+            crate::OrthancPluginJobStopReason_OrthancPluginJobStopReason_Success => Self::Success,
+            crate::OrthancPluginJobStopReason_OrthancPluginJobStopReason_Paused => Self::Paused,
+            crate::OrthancPluginJobStopReason_OrthancPluginJobStopReason_Canceled => Self::Canceled,
+            _ => Self::Failure,
+        }
+    }
+}
+
+/// A job run by Orthanc, one step after the other.
+///
+/// Orthanc only pauses or cancels a job between two steps, so steps should be short. The
+/// progress, content and state are taken after every step, Orthanc reads them while the next
+/// step runs.
+pub trait Job: Send + 'static {
+    /// Type of the job as shown by Orthanc, also used to unserialize it after a restart.
+    const TYPE: &'static str;
+
+    /// Persisted state of the job, serialized as a JSON object, see [`register_unserializer`].
+    type State: Serialize + DeserializeOwned;
+
+    /// State saved by Orthanc to resume the job after a restart, `None` if it cannot resume.
+    fn state(&self) -> Option<Self::State>;
+
+    /// Run the next step, an error fails the job.
+    fn step(&mut self) -> Result<JobStep, Box<dyn Error>>;
+
+    /// Progress between 0 and 1.
+    fn progress(&self) -> f32;
+
+    /// Details of the job shown by Orthanc, a JSON object.
+    fn content(&self) -> serde_json::Value {
+        serde_json::Value::Object(Default::default())
+    }
+
+    /// Orthanc stopped running the job.
+    fn stop(&mut self, _reason: StopReason) {}
+
+    /// Restart the job from the beginning, before it is resubmitted.
+    fn reset(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+/// Job handed to Orthanc with the strings it borrows from the job.
+struct JobBox<J> {
+    context: Context,
+    job: Mutex<J>,
+    /// Progress, content and state of the job as of its last step, so that Orthanc reads them
+    /// without waiting for a running step.
+    snapshot: Mutex<Snapshot>,
+    /// Last content and serialized state, valid until the next call.
+    content: Mutex<CString>,
+    serialized: Mutex<Option<CString>>,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    progress: f32,
+    content: serde_json::Value,
+    state: Option<String>,
+}
+
+impl Snapshot {
+    fn of<J: Job>(job: &J) -> Self {
+        Self {
+            progress: job.progress(),
+            content: job.content(),
+            state: job
+                .state()
+                .and_then(|state| serde_json::to_string(&state).ok()),
+        }
+    }
+}
+
+impl<J: Job> JobBox<J> {
+    fn new(context: Context, job: J) -> Self {
+        let snapshot = catch_unwind(AssertUnwindSafe(|| Snapshot::of(&job))).unwrap_or_default();
+        Self {
+            context,
+            job: Mutex::new(job),
+            snapshot: Mutex::new(snapshot),
+            content: Mutex::new(CString::default()),
+            serialized: Mutex::new(None),
+        }
+    }
+
+    /// Run `f` on the job, then record its new snapshot.
+    fn update<T>(&self, f: impl FnOnce(&mut J) -> T) -> T {
+        let mut job = self.job.lock().expect("job lock poisoned");
+        let result = f(&mut job);
+        *self.snapshot.lock().expect("job lock poisoned") = Snapshot::of(&*job);
+        result
+    }
+}
+
+/// Hand a job over to Orthanc, which frees it through [`finalize`].
+fn create<J: Job>(context: Context, job: J) -> Result<*mut OrthancPluginJob, OrthancError> {
+    let job_type = CString::new(J::TYPE).map_err(|_| {
+        OrthancError(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_ParameterOutOfRange)
+    })?;
+    let job = Box::into_raw(Box::new(JobBox::new(context, job)));
+
+    let mut target: *mut OrthancPluginJob = std::ptr::null_mut();
+    let params = CreateJobParams {
+        target: &mut target,
+        job: job as *mut c_void,
+        finalize: Some(finalize::<J>),
+        job_type: job_type.as_ptr(),
+        get_progress: Some(get_progress::<J>),
+        get_content: Some(get_content::<J>),
+        get_serialized: Some(get_serialized::<J>),
+        step: Some(step::<J>),
+        stop: Some(stop::<J>),
+        reset: Some(reset::<J>),
+    };
+    let created = check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_CreateJob,
+            &params as *const _ as *const c_void,
+        )
+    });
+
+    if created.is_err() || target.is_null() {
+        drop(unsafe { Box::from_raw(job) });
+        return Err(created.err().unwrap_or(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+        )));
+    }
+    Ok(target)
+}
+
+/// Submit a job to the jobs engine, returning its ID, higher priorities run first.
+pub fn submit<J: Job>(context: Context, job: J, priority: i32) -> Result<String, OrthancError> {
+    let job = create(context, job)?;
+
+    let mut id: *mut c_char = std::ptr::null_mut();
+    let params = SubmitJobParams {
+        result_id: &mut id,
+        job,
+        priority,
+    };
+    let submitted = check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_SubmitJob,
+            &params as *const _ as *const c_void,
+        )
+    });
+
+    //
+    // Orthanc owns the job once submitted, even if the submission failed
+    //
+    submitted?;
+    if id.is_null() {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+        ));
+    }
+
+    let value = unsafe { CStr::from_ptr(id) }.to_string_lossy().to_string();
+    unsafe { context.free(id as *mut c_void) };
+    Ok(value)
+}
+
+type Restore =
+    Box<dyn Fn(Context, &str) -> Result<*mut OrthancPluginJob, Box<dyn Error>> + Send + Sync>;
+
+/// Restore function of each job type.
+type Unserializers = Mutex<Vec<(&'static str, Restore)>>;
+
+static UNSERIALIZERS: OnceLock<(Context, Unserializers)> = OnceLock::new();
+
+/// Resume the jobs of type `J` saved by Orthanc (`"SaveJobs": true`) after a restart.
+///
+/// `restore` builds the job from its saved state, e.g. to attach the services it runs with.
+pub fn register_unserializer<J, F>(context: Context, restore: F) -> Result<(), OrthancError>
+where
+    J: Job,
+    F: Fn(J::State) -> Result<J, Box<dyn Error>> + Send + Sync + 'static,
+{
+    let restore: Restore = Box::new(move |context, serialized| {
+        let state = serde_json::from_str(serialized)?;
+        Ok(create(context, restore(state)?)?)
+    });
+
+    let mut first = false;
+    let (_, unserializers) = UNSERIALIZERS.get_or_init(|| {
+        first = true;
+        (context, Mutex::new(Vec::new()))
+    });
+    {
+        let mut unserializers = unserializers.lock().expect("unserializers lock poisoned");
+        if unserializers
+            .iter()
+            .any(|(job_type, _)| *job_type == J::TYPE)
+        {
+            return Err(OrthancError(
+                crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+            ));
+        }
+        unserializers.push((J::TYPE, restore));
+    }
+
+    //
+    // A single callback dispatches the saved jobs of all the types by their type
+    //
+    if !first {
+        return Ok(());
+    }
+    let params = JobsUnserializerParams {
+        unserializer: Some(unserialize),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterJobsUnserializer,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+extern "C" fn unserialize(
+    job_type: *const c_char,
+    serialized: *const c_char,
+) -> *mut OrthancPluginJob {
+    let (context, unserializers) = match UNSERIALIZERS.get() {
+        Some(registered) => registered,
+        None => return std::ptr::null_mut(),
+    };
+    if job_type.is_null() || serialized.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    let job_type = unsafe { CStr::from_ptr(job_type) }.to_string_lossy();
+    let serialized = unsafe { CStr::from_ptr(serialized) }.to_string_lossy();
+    let unserializers = unserializers.lock().expect("unserializers lock poisoned");
+    let restore = match unserializers.iter().find(|(t, _)| *t == job_type) {
+        Some((_, restore)) => restore,
+        //
+        // Jobs of other plugins or of Orthanc itself
+        //
+        None => return std::ptr::null_mut(),
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| restore(*context, &serialized))) {
+        Ok(Ok(job)) => job,
+        Ok(Err(e)) => {
+            context.log_error(&format!("unable to restore {} job - {}", job_type, e));
+            std::ptr::null_mut()
+        }
+        Err(_) => {
+            context.log_error(&format!("restoring {} job panicked", job_type));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// The job behind a pointer handed to Orthanc by [`create`].
+fn job_box<'a, J>(job: *mut c_void) -> &'a JobBox<J> {
+    unsafe { &*(job as *const JobBox<J>) }
+}
+
+extern "C" fn finalize<J: Job>(job: *mut c_void) {
+    if !job.is_null() {
+        drop(unsafe { Box::from_raw(job as *mut JobBox<J>) });
+    }
+}
+
+extern "C" fn get_progress<J: Job>(job: *mut c_void) -> f32 {
+    let job = job_box::<J>(job);
+    job.snapshot
+        .lock()
+        .expect("job lock poisoned")
+        .progress
+        .clamp(0.0, 1.0)
+}
+
+extern "C" fn get_content<J: Job>(job: *mut c_void) -> *const c_char {
+    let job = job_box::<J>(job);
+    let content = job
+        .snapshot
+        .lock()
+        .expect("job lock poisoned")
+        .content
+        .to_string();
+
+    let mut cached = job.content.lock().expect("job lock poisoned");
+    *cached = CString::new(content).unwrap_or_default();
+    cached.as_ptr()
+}
+
+extern "C" fn get_serialized<J: Job>(job: *mut c_void) -> *const c_char {
+    let job = job_box::<J>(job);
+    let state = job
+        .snapshot
+        .lock()
+        .expect("job lock poisoned")
+        .state
+        .clone();
+
+    let mut cached = job.serialized.lock().expect("job lock poisoned");
+    *cached = state.and_then(|state| CString::new(state).ok());
+    cached
+        .as_ref()
+        .map(|state| state.as_ptr())
+        .unwrap_or(std::ptr::null())
+}
+
+extern "C" fn step<J: Job>(job: *mut c_void) -> OrthancPluginJobStepStatus {
+    let job = job_box::<J>(job);
+    let result = catch_unwind(AssertUnwindSafe(|| job.update(|job| job.step())));
+
+    match result {
+        Ok(Ok(status)) => status.as_raw(),
+        Ok(Err(e)) => {
+            job.context
+                .log_error(&format!("{} job step failed - {}", J::TYPE, e));
+            JobStep::Failure.as_raw()
+        }
+        Err(_) => {
+            job.context
+                .log_error(&format!("{} job step panicked", J::TYPE));
+            JobStep::Failure.as_raw()
+        }
+    }
+}
+
+extern "C" fn stop<J: Job>(
+    job: *mut c_void,
+    reason: OrthancPluginJobStopReason,
+) -> OrthancPluginErrorCode {
+    let job = job_box::<J>(job);
+    match catch_unwind(AssertUnwindSafe(|| {
+        job.update(|job| job.stop(StopReason::from_raw(reason)))
+    })) {
+        Ok(()) => crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success,
+        Err(_) => {
+            job.context
+                .log_error(&format!("stopping {} job panicked", J::TYPE));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+    }
+}
+
+extern "C" fn reset<J: Job>(job: *mut c_void) -> OrthancPluginErrorCode {
+    let job = job_box::<J>(job);
+    match catch_unwind(AssertUnwindSafe(|| job.update(|job| job.reset()))) {
+        Ok(Ok(())) => crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success,
+        Ok(Err(e)) => {
+            job.context
+                .log_error(&format!("unable to reset {} job - {}", J::TYPE, e));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+        Err(_) => {
+            job.context
+                .log_error(&format!("resetting {} job panicked", J::TYPE));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Count {
+        next: u32,
+    }
+
+    /// Job whose steps wait for the test to release them.
+    struct Blocking {
+        next: u32,
+        release: mpsc::Receiver<()>,
+    }
+
+    impl Job for Blocking {
+        const TYPE: &'static str = "Blocking";
+        type State = Count;
+
+        fn state(&self) -> Option<Count> {
+            Some(Count { next: self.next })
+        }
+
+        fn step(&mut self) -> Result<JobStep, Box<dyn Error>> {
+            self.release.recv()?;
+            self.next += 1;
+            Ok(if self.next < 4 {
+                JobStep::Continue
+            } else {
+                JobStep::Success
+            })
+        }
+
+        fn progress(&self) -> f32 {
+            self.next as f32 / 4.0
+        }
+
+        fn content(&self) -> serde_json::Value {
+            serde_json::json!({ "Next": self.next })
+        }
+
+        fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+            self.next = 0;
+            Ok(())
+        }
+    }
+
+    fn content(job: *mut c_void) -> String {
+        let content = get_content::<Blocking>(job);
+        unsafe { CStr::from_ptr(content) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn serialized(job: *mut c_void) -> Count {
+        let state = get_serialized::<Blocking>(job);
+        serde_json::from_str(&unsafe { CStr::from_ptr(state) }.to_string_lossy()).unwrap()
+    }
+
+    #[test]
+    fn progress_and_content_do_not_wait_for_a_running_step() {
+        let (release, receiver) = mpsc::channel();
+        let context = unsafe { Context::from_raw(std::ptr::null_mut()) };
+        let job = Box::into_raw(Box::new(JobBox::new(
+            context,
+            Blocking {
+                next: 0,
+                release: receiver,
+            },
+        )));
+        let address = job as usize;
+
+        assert_eq!(get_progress::<Blocking>(job as *mut c_void), 0.0);
+        assert_eq!(content(job as *mut c_void), r#"{"Next":0}"#);
+
+        //
+        // The step blocks until released, holding the job
+        //
+        let running = Arc::new(AtomicBool::new(false));
+        let step_running = running.clone();
+        let stepping = thread::spawn(move || {
+            step_running.store(true, Ordering::SeqCst);
+            step::<Blocking>(address as *mut c_void)
+        });
+        while !running.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(get_progress::<Blocking>(job as *mut c_void), 0.0);
+        assert_eq!(content(job as *mut c_void), r#"{"Next":0}"#);
+        assert_eq!(serialized(job as *mut c_void), Count { next: 0 });
+
+        release.send(()).unwrap();
+        assert_eq!(
+            stepping.join().unwrap(),
+            JobStep::Continue.as_raw(),
+            "the step completed"
+        );
+        assert_eq!(get_progress::<Blocking>(job as *mut c_void), 0.25);
+        assert_eq!(content(job as *mut c_void), r#"{"Next":1}"#);
+        assert_eq!(serialized(job as *mut c_void), Count { next: 1 });
+
+        assert_eq!(
+            reset::<Blocking>(job as *mut c_void),
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+        );
+        assert_eq!(get_progress::<Blocking>(job as *mut c_void), 0.0);
+
+        finalize::<Blocking>(job as *mut c_void);
+    }
+}
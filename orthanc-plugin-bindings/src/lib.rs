@@ -1,2 +1,16 @@
+pub mod api;
 mod bindgen;
 pub use self::bindgen::*;
+
+pub mod context;
+pub mod filter;
+pub mod image;
+pub mod instance;
+pub mod jobs;
+pub mod metrics;
+pub mod plugin;
+pub mod query_retrieve;
+pub mod rest;
+pub mod storage_commitment;
+pub mod webdav;
+pub mod worklist;
@@ -0,0 +1,105 @@
+//! Metrics published by Orthanc, e.g. on `/tools/metrics-prometheus`.
+//!
+//! ```ignore
+//! metrics::register_refresh(context, move || {
+//!     metrics::set_value(context, "sample_pending", pending() as f32, MetricsType::Default);
+//! })?;
+//! ```
+
+use std::{
+    ffi::{c_void, CString},
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::OnceLock,
+};
+
+use crate::context::{check, Context, OrthancError};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MetricsType {
+    /// A value, published as is.
+    Default,
+    /// A duration in milliseconds, Orthanc publishes its maximum over the last seconds.
+    Timer,
+}
+
+impl MetricsType {
+    fn as_raw(&self) -> crate::OrthancPluginMetricsType {
+        match self {
+            Self::Default => crate::OrthancPluginMetricsType_OrthancPluginMetricsType_Default,
+            Self::Timer => crate::OrthancPluginMetricsType_OrthancPluginMetricsType_Timer,
+        }
+    }
+}
+
+#[repr(C)]
+struct SetMetricsValueParams {
+    name: *const c_char,
+    value: f32,
+    metrics_type: crate::OrthancPluginMetricsType,
+}
+
+#[repr(C)]
+struct RefreshMetricsCallbackParams {
+    callback: crate::OrthancPluginRefreshMetricsCallback,
+}
+
+/// Set the value of the metric `name` (Orthanc 1.5.4 or later), ignored when metrics are
+/// disabled in Orthanc.
+pub fn set_value(context: Context, name: &str, value: f32, metrics_type: MetricsType) {
+    let name = match CString::new(name) {
+        Ok(name) => name,
+        Err(_) => return,
+    };
+    let params = SetMetricsValueParams {
+        name: name.as_ptr(),
+        value,
+        metrics_type: metrics_type.as_raw(),
+    };
+    unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_SetMetricsValue,
+            &params as *const _ as *const c_void,
+        );
+    }
+}
+
+type Refresh = Box<dyn Fn() + Send + Sync>;
+
+static REFRESH: OnceLock<(Context, Refresh)> = OnceLock::new();
+
+/// Call `refresh` whenever Orthanc is about to publish its metrics (Orthanc 1.5.4 or later), so
+/// that it sets their values. A plugin registers a single refresh callback.
+pub fn register_refresh<F>(context: Context, refresh: F) -> Result<(), OrthancError>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    if !context.check_version(1, 5, 4) {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+        ));
+    }
+    if REFRESH.set((context, Box::new(refresh))).is_err() {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = RefreshMetricsCallbackParams {
+        callback: Some(refresh_metrics),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterRefreshMetricsCallback,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+extern "C" fn refresh_metrics() {
+    if let Some((context, refresh)) = REFRESH.get() {
+        if catch_unwind(AssertUnwindSafe(refresh)).is_err() {
+            context.log_error("refreshing metrics panicked");
+        }
+    }
+}
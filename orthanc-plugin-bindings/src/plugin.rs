@@ -0,0 +1,220 @@
+//! Declaration of an Orthanc plugin in Rust.
+//!
+//! A plugin implements [`Plugin`] and is declared with [`declare_plugin!`](crate::declare_plugin),
+//! which exports the entry points Orthanc looks up in the shared library:
+//!
+//! ```ignore
+//! struct Sample;
+//!
+//! impl orthanc_plugin_bindings::plugin::Plugin for Sample {
+//!     fn initialize(_context: Context) -> Result<Self, Box<dyn std::error::Error>> {
+//!         Ok(Sample)
+//!     }
+//! }
+//!
+//! orthanc_plugin_bindings::declare_plugin!(PLUGIN: Sample);
+//! ```
+
+use std::{error::Error, sync::OnceLock};
+
+pub use crate::context::{is_compatible, Context};
+use crate::OrthancPluginContext;
+
+/// Version of the Orthanc SDK the bindings were generated from.
+pub const SDK_VERSION: (u32, u32, u32) = (1, 11, 0);
+
+/// An Orthanc plugin, see [`declare_plugin!`](crate::declare_plugin).
+pub trait Plugin: Send + Sync + Sized + 'static {
+    /// Oldest Orthanc version the plugin runs on, Orthanc refuses to load it on older versions.
+    ///
+    /// Services introduced after this version must be guarded with [`Context::check_version`].
+    const MINIMAL_ORTHANC_VERSION: (u32, u32, u32) = SDK_VERSION;
+
+    /// Build the state of the plugin, before any of its callbacks is registered.
+    fn initialize(context: Context) -> Result<Self, Box<dyn Error>>;
+
+    /// Register the callbacks of the plugin, once its state is available through the host.
+    ///
+    /// An error fails the initialization, Orthanc then refuses to start.
+    fn register(&'static self, _context: Context) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Release the resources of the plugin when Orthanc stops.
+    fn finalize(&'static self) {}
+}
+
+/// Global state of a plugin declared with [`declare_plugin!`](crate::declare_plugin).
+///
+/// The plugin is set once by `OrthancPluginInitialize` and immutable afterwards, so callbacks
+/// read it without any lock.
+pub struct PluginHost<P> {
+    plugin: OnceLock<P>,
+}
+
+impl<P: Plugin> PluginHost<P> {
+    pub const fn new() -> Self {
+        Self {
+            plugin: OnceLock::new(),
+        }
+    }
+
+    /// The plugin, `None` until it is initialized.
+    pub fn get(&self) -> Option<&P> {
+        self.plugin.get()
+    }
+
+    #[doc(hidden)]
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn initialize(
+        &'static self,
+        context: *mut OrthancPluginContext,
+        name: &str,
+        version: &str,
+    ) -> i32 {
+        let context = unsafe { Context::from_raw(context) };
+
+        let (major, minor, revision) = P::MINIMAL_ORTHANC_VERSION;
+        if !context.check_version(major, minor, revision) {
+            context.log_error(&format!(
+                "plugin {} {} requires Orthanc {}.{}.{} or later, this is Orthanc {}",
+                name,
+                version,
+                major,
+                minor,
+                revision,
+                context.orthanc_version()
+            ));
+            return -1;
+        }
+
+        let plugin = match P::initialize(context) {
+            Ok(plugin) => plugin,
+            Err(e) => {
+                context.log_error(&format!("unable to initialize plugin {} - {}", name, e));
+                return -1;
+            }
+        };
+
+        if self.plugin.set(plugin).is_err() {
+            context.log_error(&format!("plugin {} is already initialized", name));
+            return -1;
+        }
+
+        if let Err(e) = self.get().unwrap().register(context) {
+            context.log_error(&format!("unable to register plugin {} - {}", name, e));
+            return -1;
+        }
+        context.log_info(&format!("plugin {} {} initialized", name, version));
+        0
+    }
+
+    #[doc(hidden)]
+    pub fn finalize(&'static self) {
+        if let Some(plugin) = self.get() {
+            plugin.finalize();
+        }
+    }
+}
+
+impl<P: Plugin> Default for PluginHost<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Export the Orthanc entry points of a [`Plugin`] and declare its [`PluginHost`] `$host`.
+///
+/// The name and version reported to Orthanc are the package name and version from Cargo,
+/// unless a name is given, e.g. `declare_plugin!(PLUGIN: Sample, name = "sample")`.
+#[macro_export]
+macro_rules! declare_plugin {
+    ($host:ident : $plugin:ty) => {
+        $crate::declare_plugin!($host: $plugin, name = env!("CARGO_PKG_NAME"));
+    };
+    ($host:ident : $plugin:ty, name = $name:expr) => {
+        static $host: $crate::plugin::PluginHost<$plugin> = $crate::plugin::PluginHost::new();
+
+        #[no_mangle]
+        pub extern "C" fn OrthancPluginInitialize(
+            context: *mut $crate::OrthancPluginContext,
+        ) -> i32 {
+            $host.initialize(context, $name, env!("CARGO_PKG_VERSION"))
+        }
+
+        #[no_mangle]
+        pub extern "C" fn OrthancPluginFinalize() {
+            $host.finalize()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn OrthancPluginGetName() -> *const ::std::os::raw::c_char {
+            concat!($name, "\0").as_ptr() as *const ::std::os::raw::c_char
+        }
+
+        #[no_mangle]
+        pub extern "C" fn OrthancPluginGetVersion() -> *const ::std::os::raw::c_char {
+            concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const ::std::os::raw::c_char
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::c_void;
+
+    use super::*;
+
+    unsafe extern "C" fn log(
+        _context: *mut OrthancPluginContext,
+        _service: crate::_OrthancPluginService,
+        _params: *const c_void,
+    ) -> crate::OrthancPluginErrorCode {
+        crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+    }
+
+    fn initialize<P: Plugin>(host: &'static PluginHost<P>) -> i32 {
+        let mut raw = OrthancPluginContext {
+            pluginsManager: std::ptr::null_mut(),
+            orthancVersion: c"mainline".as_ptr(),
+            Free: None,
+            InvokeService: Some(log),
+        };
+        host.initialize(&mut raw, "sample", "1.0.0")
+    }
+
+    struct Registered;
+
+    impl Plugin for Registered {
+        fn initialize(_context: Context) -> Result<Self, Box<dyn Error>> {
+            Ok(Registered)
+        }
+    }
+
+    struct Unregistered;
+
+    impl Plugin for Unregistered {
+        fn initialize(_context: Context) -> Result<Self, Box<dyn Error>> {
+            Ok(Unregistered)
+        }
+
+        fn register(&'static self, _context: Context) -> Result<(), Box<dyn Error>> {
+            Err("no filter".into())
+        }
+    }
+
+    #[test]
+    fn initializes_registered_plugins() {
+        static HOST: PluginHost<Registered> = PluginHost::new();
+
+        assert_eq!(initialize(&HOST), 0);
+        assert!(HOST.get().is_some());
+    }
+
+    #[test]
+    fn fails_when_registering_fails() {
+        static HOST: PluginHost<Unregistered> = PluginHost::new();
+
+        assert_eq!(initialize(&HOST), -1);
+    }
+}
@@ -0,0 +1,543 @@
+//! Query/Retrieve SCP (C-FIND and C-MOVE) served by a plugin.
+//!
+//! ```ignore
+//! struct Archive;
+//!
+//! impl FindHandler for Archive {
+//!     fn find(&self, query: &FindQuery, answers: &FindAnswers, _issuer: &str, _called: &str)
+//!         -> Result<(), Box<dyn Error>>
+//!     {
+//!         for tag in query.tags()? {
+//!             println!("{} = {}", tag.name, tag.value);
+//!         }
+//!         answers.add(&lookup(query)?)?;
+//!         Ok(())
+//!     }
+//! }
+//!
+//! impl MoveHandler for Archive {
+//!     fn create(&self, request: &MoveRequest) -> Result<Box<dyn MoveDriver>, Box<dyn Error>> {
+//!         let files = resolve(request)?;
+//!         Ok(Box::new(MoveItems::new(files, |file| send(file))))
+//!     }
+//! }
+//!
+//! query_retrieve::register_find(context, Archive)?;
+//! query_retrieve::register_move(context, Archive)?;
+//! ```
+
+use std::{
+    error::Error,
+    ffi::{c_void, CStr},
+    marker::PhantomData,
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::OnceLock,
+};
+
+use crate::{
+    _OrthancPluginService,
+    context::{check, Context, OrthancError},
+    OrthancPluginErrorCode, OrthancPluginFindAnswers, OrthancPluginFindQuery,
+    OrthancPluginResourceType,
+};
+
+#[repr(C)]
+struct FindCallbackParams {
+    callback: crate::OrthancPluginFindCallback,
+}
+
+#[repr(C)]
+struct MoveCallbackParams {
+    callback: crate::OrthancPluginMoveCallback,
+    get_move_size: crate::OrthancPluginGetMoveSize,
+    apply_move: crate::OrthancPluginApplyMove,
+    free_move: crate::OrthancPluginFreeMove,
+}
+
+#[repr(C)]
+struct FindOperationParams {
+    answers: *mut OrthancPluginFindAnswers,
+    query: *const OrthancPluginFindQuery,
+    dicom: *const c_void,
+    size: u32,
+    index: u32,
+    result_uint32: *mut u32,
+    result_group: *mut u16,
+    result_element: *mut u16,
+    result_string: *mut *mut c_char,
+}
+
+impl FindOperationParams {
+    fn new() -> Self {
+        Self {
+            answers: std::ptr::null_mut(),
+            query: std::ptr::null(),
+            dicom: std::ptr::null(),
+            size: 0,
+            index: 0,
+            result_uint32: std::ptr::null_mut(),
+            result_group: std::ptr::null_mut(),
+            result_element: std::ptr::null_mut(),
+            result_string: std::ptr::null_mut(),
+        }
+    }
+}
+
+fn invoke(
+    context: Context,
+    service: _OrthancPluginService,
+    params: &FindOperationParams,
+) -> Result<(), OrthancError> {
+    check(unsafe { context.invoke(service, params as *const _ as *const c_void) })
+}
+
+/// Level of a resource, in the DICOM model of the real world.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ResourceType {
+    Patient,
+    Study,
+    Series,
+    Instance,
+}
+
+impl ResourceType {
+    /// `None` when the issuer did not provide the QueryRetrieveLevel.
+    pub fn from_raw(resource_type: OrthancPluginResourceType) -> Option<Self> {
+        match resource_type {
+            crate::OrthancPluginResourceType_OrthancPluginResourceType_Patient => {
+                Some(Self::Patient)
+            }
+            crate::OrthancPluginResourceType_OrthancPluginResourceType_Study => Some(Self::Study),
+            crate::OrthancPluginResourceType_OrthancPluginResourceType_Series => Some(Self::Series),
+            crate::OrthancPluginResourceType_OrthancPluginResourceType_Instance => {
+                Some(Self::Instance)
+            }
+            _ => None,
+        }
+    }
+
+    /// Value of the QueryRetrieveLevel tag.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Patient => "PATIENT",
+            Self::Study => "STUDY",
+            Self::Series => "SERIES",
+            Self::Instance => "IMAGE",
+        }
+    }
+}
+
+/// Tag of a C-FIND query.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct QueryTag {
+    pub group: u16,
+    pub element: u16,
+    /// Name of the tag in the dictionary of Orthanc, e.g. `PatientName`.
+    pub name: String,
+    /// Matching key, empty for a universal match, i.e. a tag the issuer asks to be returned.
+    pub value: String,
+}
+
+/// C-FIND query, valid for the duration of the callback.
+pub struct FindQuery<'a> {
+    context: Context,
+    query: *const OrthancPluginFindQuery,
+    _callback: PhantomData<&'a OrthancPluginFindQuery>,
+}
+
+impl<'a> FindQuery<'a> {
+    /// Number of tags in the query.
+    pub fn len(&self) -> Result<u32, OrthancError> {
+        let mut size = 0;
+        let mut params = FindOperationParams::new();
+        params.query = self.query;
+        params.result_uint32 = &mut size;
+        invoke(
+            self.context,
+            crate::_OrthancPluginService__OrthancPluginService_GetFindQuerySize,
+            &params,
+        )?;
+
+        Ok(size)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, OrthancError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Group and element of the tag `index`.
+    pub fn tag(&self, index: u32) -> Result<(u16, u16), OrthancError> {
+        let mut group = 0;
+        let mut element = 0;
+        let mut params = FindOperationParams::new();
+        params.query = self.query;
+        params.index = index;
+        params.result_group = &mut group;
+        params.result_element = &mut element;
+        invoke(
+            self.context,
+            crate::_OrthancPluginService__OrthancPluginService_GetFindQueryTag,
+            &params,
+        )?;
+
+        Ok((group, element))
+    }
+
+    /// Dictionary name of the tag `index`.
+    pub fn tag_name(&self, index: u32) -> Result<String, OrthancError> {
+        self.string(
+            crate::_OrthancPluginService__OrthancPluginService_GetFindQueryTagName,
+            index,
+        )
+    }
+
+    /// Matching key of the tag `index`.
+    pub fn value(&self, index: u32) -> Result<String, OrthancError> {
+        self.string(
+            crate::_OrthancPluginService__OrthancPluginService_GetFindQueryValue,
+            index,
+        )
+    }
+
+    /// All the tags of the query, in their order in the request.
+    pub fn tags(&self) -> Result<Vec<QueryTag>, OrthancError> {
+        (0..self.len()?)
+            .map(|index| {
+                let (group, element) = self.tag(index)?;
+                Ok(QueryTag {
+                    group,
+                    element,
+                    name: self.tag_name(index)?,
+                    value: self.value(index)?,
+                })
+            })
+            .collect()
+    }
+
+    fn string(&self, service: _OrthancPluginService, index: u32) -> Result<String, OrthancError> {
+        let mut result: *mut c_char = std::ptr::null_mut();
+        let mut params = FindOperationParams::new();
+        params.query = self.query;
+        params.index = index;
+        params.result_string = &mut result;
+        invoke(self.context, service, &params)?;
+
+        if result.is_null() {
+            return Err(OrthancError(
+                crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+            ));
+        }
+        let value = unsafe { CStr::from_ptr(result) }
+            .to_string_lossy()
+            .to_string();
+        unsafe { self.context.free(result as *mut c_void) };
+        Ok(value)
+    }
+}
+
+/// Answers of a C-FIND, valid for the duration of the callback.
+pub struct FindAnswers<'a> {
+    context: Context,
+    answers: *mut OrthancPluginFindAnswers,
+    _callback: PhantomData<&'a mut OrthancPluginFindAnswers>,
+}
+
+impl<'a> FindAnswers<'a> {
+    /// Answer a match, `dicom` is a DICOM file holding the tags sent to the issuer.
+    pub fn add(&self, dicom: &[u8]) -> Result<(), OrthancError> {
+        let mut params = FindOperationParams::new();
+        params.answers = self.answers;
+        params.dicom = dicom.as_ptr() as *const c_void;
+        params.size = dicom.len() as u32;
+        invoke(
+            self.context,
+            crate::_OrthancPluginService__OrthancPluginService_FindAddAnswer,
+            &params,
+        )
+    }
+
+    /// Tell the issuer that more resources match than were answered.
+    pub fn mark_incomplete(&self) -> Result<(), OrthancError> {
+        let mut params = FindOperationParams::new();
+        params.answers = self.answers;
+        invoke(
+            self.context,
+            crate::_OrthancPluginService__OrthancPluginService_FindMarkIncomplete,
+            &params,
+        )
+    }
+}
+
+/// Serves the C-FIND requests received by Orthanc, except the modality worklist ones.
+pub trait FindHandler: Send + Sync + 'static {
+    /// Add the resources matching `query` to `answers`, `issuer_aet` is the AET of the modality.
+    fn find(
+        &self,
+        query: &FindQuery,
+        answers: &FindAnswers,
+        issuer_aet: &str,
+        called_aet: &str,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+/// Identifiers of the resource of a C-MOVE request, `None` for the tags that were not provided.
+#[derive(Debug, Clone)]
+pub struct MoveRequest {
+    /// `None` when the issuer did not provide the QueryRetrieveLevel.
+    pub level: Option<ResourceType>,
+    pub patient_id: Option<String>,
+    pub accession_number: Option<String>,
+    pub study_instance_uid: Option<String>,
+    pub series_instance_uid: Option<String>,
+    pub sop_instance_uid: Option<String>,
+    /// AET of the modality that issued the request.
+    pub originator_aet: String,
+    /// AET expected to send the files, Orthanc itself unless the request is forwarded.
+    pub source_aet: String,
+    /// AET of the modality receiving the files, the originator for a query/retrieve.
+    pub target_aet: String,
+    /// Message ID of the request, as issued by the originator.
+    pub originator_id: u16,
+}
+
+/// State of a C-MOVE, driving its sub-operations one after the other.
+///
+/// Orthanc calls [`MoveDriver::apply`] [`MoveDriver::size`] times, from a single thread, and
+/// drops the driver once the C-MOVE completed or failed.
+pub trait MoveDriver: Send {
+    /// Number of sub-operations, i.e. of files sent to the target.
+    fn size(&self) -> u32;
+
+    /// Apply the next sub-operation.
+    fn apply(&mut self) -> Result<(), Box<dyn Error>>;
+}
+
+/// [`MoveDriver`] applying `apply` to each of `items`.
+pub struct MoveItems<T, F> {
+    items: std::vec::IntoIter<T>,
+    size: u32,
+    apply: F,
+}
+
+impl<T, F> MoveItems<T, F>
+where
+    T: Send,
+    F: FnMut(T) -> Result<(), Box<dyn Error>> + Send,
+{
+    pub fn new(items: Vec<T>, apply: F) -> Self {
+        Self {
+            size: items.len() as u32,
+            items: items.into_iter(),
+            apply,
+        }
+    }
+}
+
+impl<T, F> MoveDriver for MoveItems<T, F>
+where
+    T: Send,
+    F: FnMut(T) -> Result<(), Box<dyn Error>> + Send,
+{
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn apply(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.items.next() {
+            Some(item) => (self.apply)(item),
+            None => Err("no sub-operation left".into()),
+        }
+    }
+}
+
+/// Serves the C-MOVE requests received by Orthanc.
+pub trait MoveHandler: Send + Sync + 'static {
+    /// Create the driver of `request`, an error refuses the C-MOVE.
+    fn create(&self, request: &MoveRequest) -> Result<Box<dyn MoveDriver>, Box<dyn Error>>;
+}
+
+static FIND_HANDLER: OnceLock<(Context, Box<dyn FindHandler>)> = OnceLock::new();
+
+static MOVE_HANDLER: OnceLock<(Context, Box<dyn MoveHandler>)> = OnceLock::new();
+
+/// Serve the C-FIND requests, Orthanc accepts a single handler across all plugins.
+pub fn register_find<H: FindHandler>(context: Context, handler: H) -> Result<(), OrthancError> {
+    if FIND_HANDLER.set((context, Box::new(handler))).is_err() {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = FindCallbackParams {
+        callback: Some(find),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterFindCallback,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+/// Serve the C-MOVE requests, Orthanc accepts a single handler across all plugins.
+pub fn register_move<H: MoveHandler>(context: Context, handler: H) -> Result<(), OrthancError> {
+    if MOVE_HANDLER.set((context, Box::new(handler))).is_err() {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = MoveCallbackParams {
+        callback: Some(create_move),
+        get_move_size: Some(get_move_size),
+        apply_move: Some(apply_move),
+        free_move: Some(free_move),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterMoveCallback,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+fn text(value: *const c_char) -> Option<String> {
+    if value.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(value) }
+            .to_string_lossy()
+            .to_string(),
+    )
+}
+
+extern "C" fn find(
+    answers: *mut OrthancPluginFindAnswers,
+    query: *const OrthancPluginFindQuery,
+    issuer_aet: *const c_char,
+    called_aet: *const c_char,
+) -> OrthancPluginErrorCode {
+    let (context, handler) = match FIND_HANDLER.get() {
+        Some(registered) => registered,
+        None => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin,
+    };
+
+    let issuer_aet = text(issuer_aet).unwrap_or_default();
+    let called_aet = text(called_aet).unwrap_or_default();
+    let query = FindQuery {
+        context: *context,
+        query,
+        _callback: PhantomData,
+    };
+    let answers = FindAnswers {
+        context: *context,
+        answers,
+        _callback: PhantomData,
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| {
+        handler.find(&query, &answers, &issuer_aet, &called_aet)
+    })) {
+        Ok(Ok(())) => crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success,
+        Ok(Err(e)) => {
+            context.log_error(&format!("C-FIND from {} failed - {}", issuer_aet, e));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+        Err(_) => {
+            context.log_error(&format!("C-FIND from {} panicked", issuer_aet));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+    }
+}
+
+/// Driver handed to Orthanc, boxed twice so that it is addressed by a thin pointer.
+type RawDriver = Box<dyn MoveDriver>;
+
+#[allow(clippy::too_many_arguments)]
+extern "C" fn create_move(
+    resource_type: OrthancPluginResourceType,
+    patient_id: *const c_char,
+    accession_number: *const c_char,
+    study_instance_uid: *const c_char,
+    series_instance_uid: *const c_char,
+    sop_instance_uid: *const c_char,
+    originator_aet: *const c_char,
+    source_aet: *const c_char,
+    target_aet: *const c_char,
+    originator_id: u16,
+) -> *mut c_void {
+    let (context, handler) = match MOVE_HANDLER.get() {
+        Some(registered) => registered,
+        None => return std::ptr::null_mut(),
+    };
+
+    let request = MoveRequest {
+        level: ResourceType::from_raw(resource_type),
+        patient_id: text(patient_id),
+        accession_number: text(accession_number),
+        study_instance_uid: text(study_instance_uid),
+        series_instance_uid: text(series_instance_uid),
+        sop_instance_uid: text(sop_instance_uid),
+        originator_aet: text(originator_aet).unwrap_or_default(),
+        source_aet: text(source_aet).unwrap_or_default(),
+        target_aet: text(target_aet).unwrap_or_default(),
+        originator_id,
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| handler.create(&request))) {
+        Ok(Ok(driver)) => Box::into_raw(Box::new(driver)) as *mut c_void,
+        Ok(Err(e)) => {
+            context.log_error(&format!(
+                "C-MOVE from {} to {} refused - {}",
+                request.originator_aet, request.target_aet, e
+            ));
+            std::ptr::null_mut()
+        }
+        Err(_) => {
+            context.log_error(&format!(
+                "C-MOVE from {} to {} panicked",
+                request.originator_aet, request.target_aet
+            ));
+            std::ptr::null_mut()
+        }
+    }
+}
+
+extern "C" fn get_move_size(driver: *mut c_void) -> u32 {
+    if driver.is_null() {
+        return 0;
+    }
+    let driver = unsafe { &*(driver as *const RawDriver) };
+    catch_unwind(AssertUnwindSafe(|| driver.size())).unwrap_or(0)
+}
+
+extern "C" fn apply_move(driver: *mut c_void) -> OrthancPluginErrorCode {
+    let context = match MOVE_HANDLER.get() {
+        Some((context, _)) => context,
+        None => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin,
+    };
+    if driver.is_null() {
+        return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NullPointer;
+    }
+
+    let driver = unsafe { &mut *(driver as *mut RawDriver) };
+    match catch_unwind(AssertUnwindSafe(|| driver.apply())) {
+        Ok(Ok(())) => crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success,
+        Ok(Err(e)) => {
+            context.log_error(&format!("C-MOVE sub-operation failed - {}", e));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+        Err(_) => {
+            context.log_error("C-MOVE sub-operation panicked");
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+    }
+}
+
+extern "C" fn free_move(driver: *mut c_void) {
+    if !driver.is_null() {
+        drop(unsafe { Box::from_raw(driver as *mut RawDriver) });
+    }
+}
@@ -0,0 +1,1035 @@
+//! REST routes served by a plugin.
+//!
+//! ```ignore
+//! Router::new()
+//!     .get("/sample/items/([0-9]+)", |request| {
+//!         let id: u32 = request.param(0)?;
+//!         Response::json(&load(id)?)
+//!     })
+//!     .register(context)?;
+//! ```
+
+use std::{
+    ffi::{c_void, CStr, CString},
+    fmt,
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    context::{check, Context, OrthancError},
+    OrthancPluginErrorCode, OrthancPluginHttpMethod, OrthancPluginHttpRequest,
+    OrthancPluginRestOutput,
+};
+
+/// Number of distinct paths the routers of a plugin can register.
+pub const MAX_ROUTES: usize = 64;
+
+type Handler = Box<dyn Fn(&Request) -> Result<Response, RestError> + Send + Sync>;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+impl Method {
+    pub(crate) fn from_raw(method: OrthancPluginHttpMethod) -> Option<Self> {
+        match method {
+            crate::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Get => Some(Self::Get),
+            crate::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Post => Some(Self::Post),
+            crate::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Put => Some(Self::Put),
+            crate::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Delete => Some(Self::Delete),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+        }
+    }
+}
+
+/// Error of a route, answered with its HTTP status and message.
+#[derive(Debug, Clone)]
+pub struct RestError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl RestError {
+    pub fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(400, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(404, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(500, message)
+    }
+}
+
+impl fmt::Display for RestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for RestError {}
+
+impl From<OrthancError> for RestError {
+    fn from(e: OrthancError) -> Self {
+        Self::internal(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for RestError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::internal(format!("invalid json - {}", e))
+    }
+}
+
+/// Incoming HTTP request, valid for the duration of the route.
+pub struct Request<'a> {
+    url: &'a str,
+    raw: &'a OrthancPluginHttpRequest,
+}
+
+impl<'a> Request<'a> {
+    /// # Safety
+    ///
+    /// `url` and `request` must be the arguments of an Orthanc REST callback.
+    pub unsafe fn from_raw(url: *const c_char, request: *const OrthancPluginHttpRequest) -> Self {
+        Self {
+            url: CStr::from_ptr(url).to_str().unwrap_or_default(),
+            raw: &*request,
+        }
+    }
+
+    pub fn method(&self) -> Option<Method> {
+        Method::from_raw(self.raw.method)
+    }
+
+    pub fn url(&self) -> &'a str {
+        self.url
+    }
+
+    /// Capture group `index` of the path regular expression.
+    pub fn group(&self, index: usize) -> Option<&'a str> {
+        strings(self.raw.groups, self.raw.groupsCount)
+            .nth(index)
+            .flatten()
+    }
+
+    /// Capture group `index` parsed as `T`, a missing or invalid group is a bad request.
+    pub fn param<T>(&self, index: usize) -> Result<T, RestError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let value = self
+            .group(index)
+            .ok_or_else(|| RestError::bad_request(format!("missing path parameter {}", index)))?;
+        value.parse().map_err(|e| {
+            RestError::bad_request(format!("invalid path parameter '{}' - {}", value, e))
+        })
+    }
+
+    /// GET arguments of the request.
+    pub fn arguments(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        pairs(self.raw.getKeys, self.raw.getValues, self.raw.getCount)
+    }
+
+    pub fn argument(&self, name: &str) -> Option<&'a str> {
+        self.arguments()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// GET argument `name` parsed as `T`, an invalid argument is a bad request.
+    pub fn query<T>(&self, name: &str) -> Result<Option<T>, RestError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        self.argument(name)
+            .map(|value| {
+                value.parse().map_err(|e| {
+                    RestError::bad_request(format!("invalid argument '{}' - {}", name, e))
+                })
+            })
+            .transpose()
+    }
+
+    /// HTTP headers of the request, their names are lowercase.
+    pub fn headers(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        pairs(
+            self.raw.headersKeys,
+            self.raw.headersValues,
+            self.raw.headersCount,
+        )
+    }
+
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn body(&self) -> &'a [u8] {
+        if self.raw.body.is_null() || self.raw.bodySize == 0 {
+            return &[];
+        }
+        unsafe {
+            std::slice::from_raw_parts(self.raw.body as *const u8, self.raw.bodySize as usize)
+        }
+    }
+
+    /// Body of the request deserialized from JSON, an invalid body is a bad request.
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, RestError> {
+        serde_json::from_slice(self.body())
+            .map_err(|e| RestError::bad_request(format!("invalid request body - {}", e)))
+    }
+}
+
+fn strings<'a>(values: *const *const c_char, count: u32) -> impl Iterator<Item = Option<&'a str>> {
+    (0..count as usize).map(move |i| unsafe {
+        let value = *values.add(i);
+        (!value.is_null())
+            .then(|| CStr::from_ptr(value).to_str().ok())
+            .flatten()
+    })
+}
+
+pub(crate) fn pairs<'a>(
+    keys: *const *const c_char,
+    values: *const *const c_char,
+    count: u32,
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    strings(keys, count)
+        .zip(strings(values, count))
+        .filter_map(|(key, value)| Some((key?, value?)))
+}
+
+/// Part of a multipart answer.
+pub struct Part {
+    pub headers: Vec<(String, String)>,
+    pub data: Vec<u8>,
+}
+
+enum Body {
+    Bytes {
+        mime_type: String,
+        data: Vec<u8>,
+    },
+    Multipart {
+        sub_type: String,
+        content_type: String,
+        parts: Vec<Part>,
+    },
+}
+
+/// Answer of a route.
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Body,
+}
+
+impl Response {
+    pub fn bytes(mime_type: impl Into<String>, data: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: Body::Bytes {
+                mime_type: mime_type.into(),
+                data,
+            },
+        }
+    }
+
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::bytes("text/plain", text.into().into_bytes())
+    }
+
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Result<Self, RestError> {
+        Ok(Self::bytes("application/json", serde_json::to_vec(value)?))
+    }
+
+    /// Multipart answer, e.g. `multipart/related` with `sub_type` `related`.
+    pub fn multipart(sub_type: impl Into<String>, content_type: impl Into<String>) -> Self {
+        Self {
+            status: 200,
+            headers: Vec::new(),
+            body: Body::Multipart {
+                sub_type: sub_type.into(),
+                content_type: content_type.into(),
+                parts: Vec::new(),
+            },
+        }
+    }
+
+    /// Append a part to a multipart answer, ignored for other answers.
+    pub fn part(mut self, part: Part) -> Self {
+        if let Body::Multipart { parts, .. } = &mut self.body {
+            parts.push(part);
+        }
+        self
+    }
+
+    /// Status of the answer, statuses other than 200 keep their body and its content type.
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    fn send(
+        self,
+        context: Context,
+        output: *mut OrthancPluginRestOutput,
+    ) -> Result<(), OrthancError> {
+        for (name, value) in &self.headers {
+            set_http_header(context, output, name, value);
+        }
+
+        let header = |name: &str| {
+            self.headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        let argument = |name: &str| header(name).unwrap_or_default();
+
+        match (self.status, &self.body) {
+            (200, Body::Bytes { mime_type, data }) => {
+                answer_buffer(context, output, mime_type, data)
+            }
+            (
+                200,
+                Body::Multipart {
+                    sub_type,
+                    content_type,
+                    parts,
+                },
+            ) => answer_multipart(context, output, sub_type, content_type, parts),
+            (301, _) => {
+                output_argument(
+                    context,
+                    output,
+                    crate::_OrthancPluginService__OrthancPluginService_Redirect,
+                    argument("Location"),
+                );
+                Ok(())
+            }
+            (401, _) => {
+                output_argument(
+                    context,
+                    output,
+                    crate::_OrthancPluginService__OrthancPluginService_SendUnauthorized,
+                    argument("WWW-Authenticate"),
+                );
+                Ok(())
+            }
+            (405, _) => {
+                output_argument(
+                    context,
+                    output,
+                    crate::_OrthancPluginService__OrthancPluginService_SendMethodNotAllowed,
+                    argument("Allow"),
+                );
+                Ok(())
+            }
+            //
+            // Only `AnswerBuffer` takes a MIME type, the content type of other statuses is set
+            // as a header, e.g. for JSON error documents
+            //
+            (status, Body::Bytes { mime_type, data }) => {
+                if header("Content-Type").is_none() {
+                    set_http_header(context, output, "Content-Type", mime_type);
+                }
+                send_http_status(context, output, status, data)
+            }
+            (status, Body::Multipart { .. }) => send_http_status(context, output, status, &[]),
+        }
+    }
+}
+
+impl From<RestError> for Response {
+    fn from(e: RestError) -> Self {
+        Self::text(e.message).status(e.status)
+    }
+}
+
+#[repr(C)]
+struct RestCallbackParams {
+    path_regular_expression: *const c_char,
+    callback: crate::OrthancPluginRestCallback,
+}
+
+#[repr(C)]
+struct AnswerBufferParams {
+    output: *mut OrthancPluginRestOutput,
+    answer: *const c_void,
+    answer_size: u32,
+    mime_type: *const c_char,
+}
+
+#[repr(C)]
+struct SendHttpStatusParams {
+    output: *mut OrthancPluginRestOutput,
+    status: u16,
+    body: *const c_char,
+    body_size: u32,
+}
+
+#[repr(C)]
+struct OutputPlusArgumentParams {
+    output: *mut OrthancPluginRestOutput,
+    argument: *const c_char,
+}
+
+#[repr(C)]
+struct SetHttpHeaderParams {
+    output: *mut OrthancPluginRestOutput,
+    key: *const c_char,
+    value: *const c_char,
+}
+
+#[repr(C)]
+struct StartMultipartAnswerParams {
+    output: *mut OrthancPluginRestOutput,
+    sub_type: *const c_char,
+    content_type: *const c_char,
+}
+
+#[repr(C)]
+struct SendMultipartItem2Params {
+    output: *mut OrthancPluginRestOutput,
+    answer: *const c_char,
+    answer_size: u32,
+    headers_count: u32,
+    headers_keys: *const *const c_char,
+    headers_values: *const *const c_char,
+}
+
+/// Size of an answer as Orthanc takes it, answers of 4 GiB or more cannot be sent.
+fn answer_size(len: usize) -> Result<u32, OrthancError> {
+    u32::try_from(len).map_err(|_| {
+        OrthancError(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotEnoughMemory)
+    })
+}
+
+fn cstring(value: &str) -> CString {
+    CString::new(value.replace('\0', " ")).unwrap_or_default()
+}
+
+fn set_http_header(
+    context: Context,
+    output: *mut OrthancPluginRestOutput,
+    name: &str,
+    value: &str,
+) {
+    let name = cstring(name);
+    let value = cstring(value);
+    let params = SetHttpHeaderParams {
+        output,
+        key: name.as_ptr(),
+        value: value.as_ptr(),
+    };
+    unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_SetHttpHeader,
+            &params as *const _ as *const c_void,
+        );
+    }
+}
+
+fn answer_buffer(
+    context: Context,
+    output: *mut OrthancPluginRestOutput,
+    mime_type: &str,
+    data: &[u8],
+) -> Result<(), OrthancError> {
+    let mime_type = cstring(mime_type);
+    let params = AnswerBufferParams {
+        output,
+        answer: data.as_ptr() as *const c_void,
+        answer_size: answer_size(data.len())?,
+        mime_type: mime_type.as_ptr(),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_AnswerBuffer,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+fn answer_multipart(
+    context: Context,
+    output: *mut OrthancPluginRestOutput,
+    sub_type: &str,
+    content_type: &str,
+    parts: &[Part],
+) -> Result<(), OrthancError> {
+    //
+    // Checked before the answer starts, it cannot be aborted once a part was sent
+    //
+    for part in parts {
+        answer_size(part.data.len())?;
+    }
+
+    let sub_type = cstring(sub_type);
+    let content_type = cstring(content_type);
+    let params = StartMultipartAnswerParams {
+        output,
+        sub_type: sub_type.as_ptr(),
+        content_type: content_type.as_ptr(),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_StartMultipartAnswer,
+            &params as *const _ as *const c_void,
+        )
+    })?;
+
+    for part in parts {
+        let keys: Vec<_> = part.headers.iter().map(|(key, _)| cstring(key)).collect();
+        let values: Vec<_> = part
+            .headers
+            .iter()
+            .map(|(_, value)| cstring(value))
+            .collect();
+        let key_ptrs: Vec<_> = keys.iter().map(|key| key.as_ptr()).collect();
+        let value_ptrs: Vec<_> = values.iter().map(|value| value.as_ptr()).collect();
+
+        let params = SendMultipartItem2Params {
+            output,
+            answer: part.data.as_ptr() as *const c_char,
+            answer_size: answer_size(part.data.len())?,
+            headers_count: key_ptrs.len() as u32,
+            headers_keys: key_ptrs.as_ptr(),
+            headers_values: value_ptrs.as_ptr(),
+        };
+        check(unsafe {
+            context.invoke(
+                crate::_OrthancPluginService__OrthancPluginService_SendMultipartItem2,
+                &params as *const _ as *const c_void,
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+fn output_argument(
+    context: Context,
+    output: *mut OrthancPluginRestOutput,
+    service: crate::_OrthancPluginService,
+    argument: &str,
+) {
+    let argument = cstring(argument);
+    let params = OutputPlusArgumentParams {
+        output,
+        argument: argument.as_ptr(),
+    };
+    unsafe {
+        context.invoke(service, &params as *const _ as *const c_void);
+    }
+}
+
+fn send_http_status(
+    context: Context,
+    output: *mut OrthancPluginRestOutput,
+    status: u16,
+    body: &[u8],
+) -> Result<(), OrthancError> {
+    let params = SendHttpStatusParams {
+        output,
+        status,
+        body: body.as_ptr() as *const c_char,
+        body_size: answer_size(body.len())?,
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_SendHttpStatus,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+/// Handlers of a path, by method.
+struct Route {
+    context: Context,
+    handlers: Vec<(Method, Handler)>,
+}
+
+//
+// Orthanc callbacks carry no user data, so each registered path gets its own trampoline which
+// finds its handlers in the slot of the same index
+//
+static ROUTES: [OnceLock<Route>; MAX_ROUTES] = [const { OnceLock::new() }; MAX_ROUTES];
+static NEXT_ROUTE: AtomicUsize = AtomicUsize::new(0);
+
+type Trampoline = unsafe extern "C" fn(
+    *mut OrthancPluginRestOutput,
+    *const c_char,
+    *const OrthancPluginHttpRequest,
+) -> OrthancPluginErrorCode;
+
+macro_rules! trampolines {
+    ($($slot:literal)*) => {
+        [$(trampoline::<$slot> as Trampoline,)*]
+    };
+}
+
+static TRAMPOLINES: [Trampoline; MAX_ROUTES] = trampolines!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+    32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+);
+
+extern "C" fn trampoline<const SLOT: usize>(
+    output: *mut OrthancPluginRestOutput,
+    url: *const c_char,
+    request: *const OrthancPluginHttpRequest,
+) -> OrthancPluginErrorCode {
+    let route = match ROUTES[SLOT].get() {
+        Some(route) => route,
+        None => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin,
+    };
+    let request = unsafe { Request::from_raw(url, request) };
+
+    let handler = request.method().and_then(|method| {
+        route
+            .handlers
+            .iter()
+            .find(|(handled, _)| *handled == method)
+            .map(|(_, handler)| handler)
+    });
+
+    let response = match handler {
+        Some(handler) => match catch_unwind(AssertUnwindSafe(|| handler(&request))) {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => e.into(),
+            Err(_) => {
+                route
+                    .context
+                    .log_error(&format!("REST route {} panicked", request.url()));
+                RestError::internal("internal error").into()
+            }
+        },
+        None => {
+            let allowed: Vec<_> = route
+                .handlers
+                .iter()
+                .map(|(method, _)| method.as_str())
+                .collect();
+            Response::text("")
+                .status(405)
+                .header("Allow", allowed.join(","))
+        }
+    };
+
+    match response.send(route.context, output) {
+        Ok(()) => crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success,
+        Err(e) => {
+            route
+                .context
+                .log_error(&format!("unable to answer {} - {}", request.url(), e));
+            e.code()
+        }
+    }
+}
+
+/// REST routes of a plugin, see the [module documentation](self).
+///
+/// Routes are registered without the global REST lock of Orthanc, handlers must be thread safe.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(String, Vec<(Method, Handler)>)>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve `method` on the paths matching the regular expression `path`.
+    pub fn route<F>(mut self, method: Method, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Result<Response, RestError> + Send + Sync + 'static,
+    {
+        let handler: Handler = Box::new(handler);
+        match self
+            .routes
+            .iter_mut()
+            .find(|(existing, _)| existing == path)
+        {
+            Some((_, handlers)) => handlers.push((method, handler)),
+            None => self.routes.push((path.to_owned(), vec![(method, handler)])),
+        }
+        self
+    }
+
+    pub fn get<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Result<Response, RestError> + Send + Sync + 'static,
+    {
+        self.route(Method::Get, path, handler)
+    }
+
+    pub fn post<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Result<Response, RestError> + Send + Sync + 'static,
+    {
+        self.route(Method::Post, path, handler)
+    }
+
+    pub fn put<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Result<Response, RestError> + Send + Sync + 'static,
+    {
+        self.route(Method::Put, path, handler)
+    }
+
+    pub fn delete<F>(self, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Result<Response, RestError> + Send + Sync + 'static,
+    {
+        self.route(Method::Delete, path, handler)
+    }
+
+    /// Register the routes with Orthanc, at most [`MAX_ROUTES`] paths per plugin.
+    pub fn register(self, context: Context) -> Result<(), RestError> {
+        for (path, handlers) in self.routes {
+            let slot = NEXT_ROUTE.fetch_add(1, Ordering::SeqCst);
+            if slot >= MAX_ROUTES {
+                return Err(RestError::internal(format!(
+                    "unable to register '{}', a plugin serves at most {} paths",
+                    path, MAX_ROUTES
+                )));
+            }
+
+            if ROUTES[slot].set(Route { context, handlers }).is_err() {
+                return Err(RestError::internal(format!(
+                    "route slot {} is already in use",
+                    slot
+                )));
+            }
+
+            let path = cstring(&path);
+            let params = RestCallbackParams {
+                path_regular_expression: path.as_ptr(),
+                callback: Some(TRAMPOLINES[slot]),
+            };
+            unsafe {
+                context.invoke(
+                    crate::_OrthancPluginService__OrthancPluginService_RegisterRestCallbackNoLock,
+                    &params as *const _ as *const c_void,
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    thread_local! {
+        /// Services invoked by [`send`], with their header or status.
+        static SENT: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        /// Callbacks registered by [`Router::register`], with their path.
+        static REGISTERED: RefCell<Vec<(String, Trampoline)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    unsafe extern "C" fn record(
+        _context: *mut crate::OrthancPluginContext,
+        service: crate::_OrthancPluginService,
+        params: *const c_void,
+    ) -> OrthancPluginErrorCode {
+        let sent = match service {
+            crate::_OrthancPluginService__OrthancPluginService_SetHttpHeader => {
+                let params = &*(params as *const SetHttpHeaderParams);
+                format!(
+                    "header {}: {}",
+                    CStr::from_ptr(params.key).to_string_lossy(),
+                    CStr::from_ptr(params.value).to_string_lossy()
+                )
+            }
+            crate::_OrthancPluginService__OrthancPluginService_SendHttpStatus => {
+                let params = &*(params as *const SendHttpStatusParams);
+                let body =
+                    std::slice::from_raw_parts(params.body as *const u8, params.body_size as usize);
+                format!("status {} {}", params.status, String::from_utf8_lossy(body))
+            }
+            crate::_OrthancPluginService__OrthancPluginService_AnswerBuffer => {
+                let params = &*(params as *const AnswerBufferParams);
+                let answer = std::slice::from_raw_parts(
+                    params.answer as *const u8,
+                    params.answer_size as usize,
+                );
+                format!(
+                    "answer {} {}",
+                    CStr::from_ptr(params.mime_type).to_string_lossy(),
+                    String::from_utf8_lossy(answer)
+                )
+            }
+            crate::_OrthancPluginService__OrthancPluginService_SendMethodNotAllowed => {
+                let params = &*(params as *const OutputPlusArgumentParams);
+                format!(
+                    "method not allowed {}",
+                    CStr::from_ptr(params.argument).to_string_lossy()
+                )
+            }
+            crate::_OrthancPluginService__OrthancPluginService_LogError => {
+                format!(
+                    "error {}",
+                    CStr::from_ptr(params as *const c_char).to_string_lossy()
+                )
+            }
+            crate::_OrthancPluginService__OrthancPluginService_RegisterRestCallbackNoLock => {
+                let params = &*(params as *const RestCallbackParams);
+                let path = CStr::from_ptr(params.path_regular_expression)
+                    .to_string_lossy()
+                    .into_owned();
+                REGISTERED.with(|registered| {
+                    registered
+                        .borrow_mut()
+                        .push((path.clone(), params.callback.unwrap()))
+                });
+                format!("register {}", path)
+            }
+            service => format!("service {}", service),
+        };
+        SENT.with(|recorded| recorded.borrow_mut().push(sent));
+        crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+    }
+
+    /// Context recording the services invoked on the current thread, living as long as the
+    /// routes registered with it.
+    fn context() -> Context {
+        let raw = Box::leak(Box::new(crate::OrthancPluginContext {
+            pluginsManager: std::ptr::null_mut(),
+            orthancVersion: std::ptr::null(),
+            Free: None,
+            InvokeService: Some(record),
+        }));
+        unsafe { Context::from_raw(raw) }
+    }
+
+    fn send(response: Response) -> Vec<String> {
+        SENT.with(|recorded| recorded.borrow_mut().clear());
+        response.send(context(), std::ptr::null_mut()).unwrap();
+        SENT.with(|recorded| recorded.take())
+    }
+
+    /// Register `router` and return its callbacks by path.
+    fn register(router: Router) -> Vec<(String, Trampoline)> {
+        REGISTERED.with(|registered| registered.borrow_mut().clear());
+        router.register(context()).unwrap();
+        REGISTERED.with(|registered| registered.take())
+    }
+
+    /// Call `callback` the way Orthanc does for a request of `url`, returning its error code
+    /// and the services it invoked.
+    fn call(
+        callback: Trampoline,
+        method: OrthancPluginHttpMethod,
+        url: &str,
+        groups: &[&str],
+    ) -> (OrthancPluginErrorCode, Vec<String>) {
+        let url = CString::new(url).unwrap();
+        let groups: Vec<_> = groups
+            .iter()
+            .map(|group| CString::new(*group).unwrap())
+            .collect();
+        let group_ptrs: Vec<_> = groups.iter().map(|group| group.as_ptr()).collect();
+        let mut request: OrthancPluginHttpRequest = unsafe { std::mem::zeroed() };
+        request.method = method;
+        request.groupsCount = group_ptrs.len() as u32;
+        request.groups = group_ptrs.as_ptr();
+
+        SENT.with(|recorded| recorded.borrow_mut().clear());
+        let error = unsafe { callback(std::ptr::null_mut(), url.as_ptr(), &request) };
+        (error, SENT.with(|recorded| recorded.take()))
+    }
+
+    #[test]
+    fn json_errors_keep_their_content_type() {
+        let response = Response::json(&serde_json::json!({ "error": "busy" }))
+            .unwrap()
+            .status(409);
+        assert_eq!(
+            send(response),
+            [
+                "header Content-Type: application/json",
+                r#"status 409 {"error":"busy"}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn explicit_content_types_are_not_repeated() {
+        let response = Response::text("gone")
+            .status(410)
+            .header("content-type", "text/html");
+        assert_eq!(
+            send(response),
+            ["header content-type: text/html", "status 410 gone"]
+        );
+    }
+
+    #[test]
+    fn successful_answers_carry_their_mime_type() {
+        let response = Response::json(&[1, 2]).unwrap();
+        assert_eq!(send(response), ["answer application/json [1,2]"]);
+    }
+
+    #[test]
+    fn errors_are_answered_as_text() {
+        let response = Response::from(RestError::not_found("no such study"));
+        assert_eq!(
+            send(response),
+            [
+                "header Content-Type: text/plain",
+                "status 404 no such study"
+            ]
+        );
+    }
+
+    #[test]
+    fn routes_dispatch_on_the_method() {
+        let routes = register(
+            Router::new()
+                .get("/tests/items/([0-9]+)", |request| {
+                    Ok(Response::text(format!("item {}", request.param::<u32>(0)?)))
+                })
+                .post("/tests/items/([0-9]+)", |request| {
+                    Ok(Response::text(format!(
+                        "created {}",
+                        request.param::<u32>(0)?
+                    )))
+                })
+                .get("/tests/other", |_| Ok(Response::text("other"))),
+        );
+        let paths: Vec<_> = routes.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(paths, ["/tests/items/([0-9]+)", "/tests/other"]);
+        let (items, other) = (routes[0].1, routes[1].1);
+
+        let get = crate::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Get;
+        let post = crate::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Post;
+        assert_eq!(
+            call(items, get, "/tests/items/7", &["7"]),
+            (0, vec!["answer text/plain item 7".to_string()])
+        );
+        assert_eq!(
+            call(items, post, "/tests/items/7", &["7"]),
+            (0, vec!["answer text/plain created 7".to_string()])
+        );
+        assert_eq!(
+            call(other, get, "/tests/other", &[]),
+            (0, vec!["answer text/plain other".to_string()])
+        );
+    }
+
+    #[test]
+    fn unserved_methods_are_not_allowed() {
+        let routes = register(
+            Router::new()
+                .get("/tests/allowed", |_| Ok(Response::text("")))
+                .put("/tests/allowed", |_| Ok(Response::text(""))),
+        );
+
+        let (error, sent) = call(
+            routes[0].1,
+            crate::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Delete,
+            "/tests/allowed",
+            &[],
+        );
+        assert_eq!(error, 0);
+        assert_eq!(
+            sent,
+            ["header Allow: GET,PUT", "method not allowed GET,PUT"]
+        );
+    }
+
+    #[test]
+    fn failing_routes_answer_their_error() {
+        let routes = register(Router::new().get("/tests/failing/(.*)", |request| {
+            Ok(Response::text(request.param::<u32>(0)?.to_string()))
+        }));
+
+        let (error, sent) = call(
+            routes[0].1,
+            crate::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Get,
+            "/tests/failing/x",
+            &["x"],
+        );
+        assert_eq!(error, 0);
+        assert_eq!(sent[0], "header Content-Type: text/plain");
+        assert!(sent[1].starts_with("status 400 invalid path parameter 'x'"));
+    }
+
+    #[test]
+    fn panicking_routes_answer_an_internal_error() {
+        let routes = register(Router::new().get("/tests/panicking", |_| panic!("bug")));
+
+        let (error, sent) = call(
+            routes[0].1,
+            crate::OrthancPluginHttpMethod_OrthancPluginHttpMethod_Get,
+            "/tests/panicking",
+            &[],
+        );
+        assert_eq!(error, 0);
+        assert_eq!(
+            sent,
+            [
+                "error REST route /tests/panicking panicked",
+                "header Content-Type: text/plain",
+                "status 500 internal error"
+            ]
+        );
+    }
+
+    #[test]
+    fn oversized_answers_are_refused() {
+        assert_eq!(answer_size(10).unwrap(), 10);
+        assert_eq!(answer_size(u32::MAX as usize).unwrap(), u32::MAX);
+        assert_eq!(
+            answer_size(u32::MAX as usize + 1).unwrap_err().code(),
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotEnoughMemory
+        );
+    }
+}
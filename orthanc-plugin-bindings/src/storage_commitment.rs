@@ -0,0 +1,280 @@
+//! Storage commitment SCP served by a plugin.
+//!
+//! ```ignore
+//! struct Archive;
+//!
+//! impl StorageCommitmentHandler for Archive {
+//!     fn create(&self, request: &StorageCommitmentRequest)
+//!         -> Result<Box<dyn StorageCommitmentLookup>, Box<dyn Error>>
+//!     {
+//!         Ok(Box::new(|_sop_class_uid: &str, sop_instance_uid: &str| {
+//!             Ok(match is_archived(sop_instance_uid)? {
+//!                 true => FailureReason::Success,
+//!                 false => FailureReason::NoSuchObjectInstance,
+//!             })
+//!         }))
+//!     }
+//! }
+//!
+//! storage_commitment::register(context, Archive)?;
+//! ```
+
+use std::{
+    error::Error,
+    ffi::{c_void, CStr},
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::OnceLock,
+};
+
+use crate::{
+    context::{check, Context, OrthancError},
+    OrthancPluginErrorCode, OrthancPluginStorageCommitmentFailureReason,
+};
+
+#[repr(C)]
+struct StorageCommitmentScpCallbackParams {
+    factory: crate::OrthancPluginStorageCommitmentFactory,
+    destructor: crate::OrthancPluginStorageCommitmentDestructor,
+    lookup: crate::OrthancPluginStorageCommitmentLookup,
+}
+
+/// Status of an instance reported to the storage commitment SCU, with the Failure Reason
+/// (0008,1197) sent for the instances that are not committed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FailureReason {
+    /// The instance is committed.
+    Success,
+    /// 0110, a general failure, e.g. the instance is not safely stored yet.
+    ProcessingFailure,
+    /// 0112, the instance is unknown.
+    NoSuchObjectInstance,
+    /// 0213
+    ResourceLimitation,
+    /// 0122
+    ReferencedSopClassNotSupported,
+    /// 0119, the instance is stored with another SOP class.
+    ClassInstanceConflict,
+    /// 0131
+    DuplicateTransactionUid,
+}
+
+impl FailureReason {
+    pub fn as_raw(&self) -> OrthancPluginStorageCommitmentFailureReason {
+        match self {
+            Self::Success => crate::OrthancPluginStorageCommitmentFailureReason_OrthancPluginStorageCommitmentFailureReason_Success,
+            Self::ProcessingFailure => crate::OrthancPluginStorageCommitmentFailureReason_OrthancPluginStorageCommitmentFailureReason_ProcessingFailure,
+            Self::NoSuchObjectInstance => crate::OrthancPluginStorageCommitmentFailureReason_OrthancPluginStorageCommitmentFailureReason_NoSuchObjectInstance,
+            Self::ResourceLimitation => crate::OrthancPluginStorageCommitmentFailureReason_OrthancPluginStorageCommitmentFailureReason_ResourceLimitation,
+            Self::ReferencedSopClassNotSupported => crate::OrthancPluginStorageCommitmentFailureReason_OrthancPluginStorageCommitmentFailureReason_ReferencedSOPClassNotSupported,
+            Self::ClassInstanceConflict => crate::OrthancPluginStorageCommitmentFailureReason_OrthancPluginStorageCommitmentFailureReason_ClassInstanceConflict,
+            Self::DuplicateTransactionUid => crate::OrthancPluginStorageCommitmentFailureReason_OrthancPluginStorageCommitmentFailureReason_DuplicateTransactionUID,
+        }
+    }
+}
+
+/// Instance of a storage commitment request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReferencedInstance {
+    pub sop_class_uid: String,
+    pub sop_instance_uid: String,
+}
+
+/// Storage commitment request (N-ACTION) received from a modality.
+#[derive(Debug, Clone)]
+pub struct StorageCommitmentRequest {
+    /// ID of the Orthanc job answering the request, it looks up the instances one by one.
+    pub job_id: String,
+    /// Transaction UID (0008,1195) provided by the modality.
+    pub transaction_uid: String,
+    /// Instances the modality asks a commitment for, in their order in the request.
+    pub instances: Vec<ReferencedInstance>,
+    /// AET of the modality.
+    pub remote_aet: String,
+    /// AET of Orthanc used by the modality.
+    pub called_aet: String,
+}
+
+/// State of a storage commitment request, reporting the status of its instances.
+///
+/// Orthanc calls [`StorageCommitmentLookup::lookup`] once per instance, from the thread of the
+/// job, and drops the lookup once the N-EVENT-REPORT was sent.
+pub trait StorageCommitmentLookup: Send {
+    /// Status of the instance, an error fails the whole job.
+    fn lookup(
+        &mut self,
+        sop_class_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FailureReason, Box<dyn Error>>;
+}
+
+impl<F> StorageCommitmentLookup for F
+where
+    F: FnMut(&str, &str) -> Result<FailureReason, Box<dyn Error>> + Send,
+{
+    fn lookup(
+        &mut self,
+        sop_class_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FailureReason, Box<dyn Error>> {
+        self(sop_class_uid, sop_instance_uid)
+    }
+}
+
+/// Serves the storage commitment requests received by Orthanc.
+pub trait StorageCommitmentHandler: Send + Sync + 'static {
+    /// Create the lookup of `request`, it may start fetching the status of its instances
+    /// upfront. An error refuses the request.
+    fn create(
+        &self,
+        request: &StorageCommitmentRequest,
+    ) -> Result<Box<dyn StorageCommitmentLookup>, Box<dyn Error>>;
+}
+
+static HANDLER: OnceLock<(Context, Box<dyn StorageCommitmentHandler>)> = OnceLock::new();
+
+/// Serve the storage commitment requests (Orthanc 1.6.0 or later), Orthanc accepts a single
+/// handler across all plugins.
+pub fn register<H: StorageCommitmentHandler>(
+    context: Context,
+    handler: H,
+) -> Result<(), OrthancError> {
+    if !context.check_version(1, 6, 0) {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+        ));
+    }
+    if HANDLER.set((context, Box::new(handler))).is_err() {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = StorageCommitmentScpCallbackParams {
+        factory: Some(factory),
+        destructor: Some(destructor),
+        lookup: Some(lookup),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterStorageCommitmentScpCallback,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+fn text(value: *const c_char) -> String {
+    if value.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(value) }
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Lookup handed to Orthanc, boxed twice so that it is addressed by a thin pointer.
+type RawLookup = Box<dyn StorageCommitmentLookup>;
+
+#[allow(clippy::too_many_arguments)]
+extern "C" fn factory(
+    handler: *mut *mut c_void,
+    job_id: *const c_char,
+    transaction_uid: *const c_char,
+    sop_class_uids: *const *const c_char,
+    sop_instance_uids: *const *const c_char,
+    count_instances: u32,
+    remote_aet: *const c_char,
+    called_aet: *const c_char,
+) -> OrthancPluginErrorCode {
+    let (context, scp) = match HANDLER.get() {
+        Some(registered) => registered,
+        None => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin,
+    };
+    if handler.is_null() {
+        return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NullPointer;
+    }
+
+    let instances = (0..count_instances as usize)
+        .map(|index| unsafe {
+            ReferencedInstance {
+                sop_class_uid: text(*sop_class_uids.add(index)),
+                sop_instance_uid: text(*sop_instance_uids.add(index)),
+            }
+        })
+        .collect();
+    let request = StorageCommitmentRequest {
+        job_id: text(job_id),
+        transaction_uid: text(transaction_uid),
+        instances,
+        remote_aet: text(remote_aet),
+        called_aet: text(called_aet),
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| scp.create(&request))) {
+        Ok(Ok(lookup)) => {
+            unsafe { *handler = Box::into_raw(Box::new(lookup)) as *mut c_void };
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+        }
+        Ok(Err(e)) => {
+            context.log_error(&format!(
+                "storage commitment {} from {} refused - {}",
+                request.transaction_uid, request.remote_aet, e
+            ));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+        Err(_) => {
+            context.log_error(&format!(
+                "storage commitment {} from {} panicked",
+                request.transaction_uid, request.remote_aet
+            ));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+    }
+}
+
+extern "C" fn destructor(handler: *mut c_void) {
+    if !handler.is_null() {
+        drop(unsafe { Box::from_raw(handler as *mut RawLookup) });
+    }
+}
+
+extern "C" fn lookup(
+    target: *mut OrthancPluginStorageCommitmentFailureReason,
+    handler: *mut c_void,
+    sop_class_uid: *const c_char,
+    sop_instance_uid: *const c_char,
+) -> OrthancPluginErrorCode {
+    let context = match HANDLER.get() {
+        Some((context, _)) => context,
+        None => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin,
+    };
+    if target.is_null() || handler.is_null() {
+        return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NullPointer;
+    }
+
+    let handler = unsafe { &mut *(handler as *mut RawLookup) };
+    let sop_class_uid = text(sop_class_uid);
+    let sop_instance_uid = text(sop_instance_uid);
+    match catch_unwind(AssertUnwindSafe(|| {
+        handler.lookup(&sop_class_uid, &sop_instance_uid)
+    })) {
+        Ok(Ok(reason)) => {
+            unsafe { *target = reason.as_raw() };
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+        }
+        Ok(Err(e)) => {
+            context.log_error(&format!(
+                "storage commitment lookup of {} failed - {}",
+                sop_instance_uid, e
+            ));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+        Err(_) => {
+            context.log_error(&format!(
+                "storage commitment lookup of {} panicked",
+                sop_instance_uid
+            ));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+    }
+}
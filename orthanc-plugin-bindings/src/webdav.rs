@@ -0,0 +1,447 @@
+//! WebDAV collections served by a plugin, mounted next to the collection of Orthanc.
+//!
+//! ```ignore
+//! struct Reports;
+//!
+//! impl WebDavCollection for Reports {
+//!     fn is_existing_folder(&self, path: &[String]) -> Result<bool, Box<dyn Error>> {
+//!         Ok(path.is_empty())
+//!     }
+//!
+//!     fn list_folder(&self, path: &[String], folder: &WebDavFolder) -> Result<bool, Box<dyn Error>> {
+//!         if !path.is_empty() {
+//!             return Ok(false);
+//!         }
+//!         folder.add_file("report.txt", 5, Some("text/plain"), "20220101T000000")?;
+//!         Ok(true)
+//!     }
+//!
+//!     fn retrieve_file(&self, path: &[String]) -> Result<Option<WebDavFile>, Box<dyn Error>> {
+//!         Ok((path == ["report.txt"]).then(|| WebDavFile::new(b"hello".to_vec(), "20220101T000000")))
+//!     }
+//! }
+//!
+//! webdav::register_collection(context, "/reports", Reports)?;
+//! ```
+
+use std::{
+    error::Error,
+    ffi::{c_void, CStr, CString},
+    marker::PhantomData,
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+use crate::{
+    context::{check, Context, OrthancError},
+    OrthancPluginErrorCode, OrthancPluginWebDavAddFile, OrthancPluginWebDavAddFolder,
+    OrthancPluginWebDavCollection, OrthancPluginWebDavRetrieveFile,
+};
+
+#[repr(C)]
+struct RegisterWebDavCollectionParams {
+    uri: *const c_char,
+    is_existing_folder: crate::OrthancPluginWebDavIsExistingFolderCallback,
+    list_folder: crate::OrthancPluginWebDavListFolderCallback,
+    retrieve_file: crate::OrthancPluginWebDavRetrieveFileCallback,
+    store_file: crate::OrthancPluginWebDavStoreFileCallback,
+    create_folder: crate::OrthancPluginWebDavCreateFolderCallback,
+    delete_item: crate::OrthancPluginWebDavDeleteItemCallback,
+    payload: *mut c_void,
+}
+
+/// Outcome of a modification of a collection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebDavWrite {
+    Done,
+    /// The collection refuses the modification, the client is answered `403 Forbidden`.
+    ReadOnly,
+}
+
+/// Content of a file of a collection.
+#[derive(Debug, Clone)]
+pub struct WebDavFile {
+    pub content: Vec<u8>,
+    /// Guessed by Orthanc from the file extension when unset.
+    pub mime_type: Option<String>,
+    /// Creation time in UTC, formatted as `YYYYMMDDTHHMMSS`.
+    pub date_time: String,
+}
+
+impl WebDavFile {
+    pub fn new(content: Vec<u8>, date_time: &str) -> Self {
+        Self {
+            content,
+            mime_type: None,
+            date_time: date_time.to_owned(),
+        }
+    }
+}
+
+/// Content of a folder being listed, valid for the duration of the callback.
+pub struct WebDavFolder<'a> {
+    collection: *mut OrthancPluginWebDavCollection,
+    add_file: OrthancPluginWebDavAddFile,
+    add_folder: OrthancPluginWebDavAddFolder,
+    _callback: PhantomData<&'a mut OrthancPluginWebDavCollection>,
+}
+
+impl<'a> WebDavFolder<'a> {
+    /// Declare the file `name` of `size` bytes, `date_time` is formatted as `YYYYMMDDTHHMMSS`.
+    pub fn add_file(
+        &self,
+        name: &str,
+        size: u64,
+        mime_type: Option<&str>,
+        date_time: &str,
+    ) -> Result<(), OrthancError> {
+        let add_file = self.add_file.ok_or(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+        ))?;
+        let name = c_string(name)?;
+        let mime_type = mime_type.map(c_string).transpose()?;
+        let date_time = c_string(date_time)?;
+        check(unsafe {
+            add_file(
+                self.collection,
+                name.as_ptr(),
+                size,
+                mime_type
+                    .as_ref()
+                    .map_or(std::ptr::null(), |mime_type| mime_type.as_ptr()),
+                date_time.as_ptr(),
+            )
+        })
+    }
+
+    /// Declare the subfolder `name`, `date_time` is formatted as `YYYYMMDDTHHMMSS`.
+    pub fn add_folder(&self, name: &str, date_time: &str) -> Result<(), OrthancError> {
+        let add_folder = self.add_folder.ok_or(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError,
+        ))?;
+        let name = c_string(name)?;
+        let date_time = c_string(date_time)?;
+        check(unsafe { add_folder(self.collection, name.as_ptr(), date_time.as_ptr()) })
+    }
+}
+
+/// Tree of folders and files served over WebDAV.
+///
+/// Paths are the decoded items below the root of the collection, the root itself is the empty
+/// path. The collection is read-only unless the modifications are implemented.
+pub trait WebDavCollection: Send + Sync + 'static {
+    /// Whether `path` is an existing folder.
+    fn is_existing_folder(&self, path: &[String]) -> Result<bool, Box<dyn Error>>;
+
+    /// Declare the content of the folder `path` to `folder`, `false` when it does not exist.
+    fn list_folder(&self, path: &[String], folder: &WebDavFolder) -> Result<bool, Box<dyn Error>>;
+
+    /// Content of the file `path`, `None` when it does not exist.
+    fn retrieve_file(&self, path: &[String]) -> Result<Option<WebDavFile>, Box<dyn Error>>;
+
+    /// Store `content` as the file `path`.
+    fn store_file(&self, _path: &[String], _content: &[u8]) -> Result<WebDavWrite, Box<dyn Error>> {
+        Ok(WebDavWrite::ReadOnly)
+    }
+
+    /// Create the folder `path`.
+    fn create_folder(&self, _path: &[String]) -> Result<WebDavWrite, Box<dyn Error>> {
+        Ok(WebDavWrite::ReadOnly)
+    }
+
+    /// Remove the file or folder `path`.
+    fn delete_item(&self, _path: &[String]) -> Result<WebDavWrite, Box<dyn Error>> {
+        Ok(WebDavWrite::ReadOnly)
+    }
+}
+
+/// Registered collection, handed to the callbacks as their payload.
+struct Registered {
+    context: Context,
+    uri: String,
+    collection: Box<dyn WebDavCollection>,
+}
+
+/// Mount `collection` at `uri`, e.g. `/reports`, any number of collections can be mounted.
+///
+/// Requires Orthanc 1.10.1, the collection lives as long as the plugin.
+pub fn register_collection<C: WebDavCollection>(
+    context: Context,
+    uri: &str,
+    collection: C,
+) -> Result<(), OrthancError> {
+    if !context.check_version(1, 10, 1) {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+        ));
+    }
+
+    //
+    // Orthanc copies the URI but keeps the payload until it stops
+    //
+    let c_uri = c_string(uri)?;
+    let payload = Box::into_raw(Box::new(Registered {
+        context,
+        uri: uri.trim_end_matches('/').to_owned(),
+        collection: Box::new(collection),
+    }));
+    let params = RegisterWebDavCollectionParams {
+        uri: c_uri.as_ptr(),
+        is_existing_folder: Some(is_existing_folder),
+        list_folder: Some(list_folder),
+        retrieve_file: Some(retrieve_file),
+        store_file: Some(store_file),
+        create_folder: Some(create_folder),
+        delete_item: Some(delete_item),
+        payload: payload as *mut c_void,
+    };
+    let registered = check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterWebDavCollection,
+            &params as *const _ as *const c_void,
+        )
+    });
+    if registered.is_err() {
+        drop(unsafe { Box::from_raw(payload) });
+    }
+    registered
+}
+
+fn c_string(value: &str) -> Result<CString, OrthancError> {
+    CString::new(value).map_err(|_| {
+        OrthancError(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_ParameterOutOfRange)
+    })
+}
+
+/// Items of the path of a callback.
+fn path_items(size: u32, items: *const *const c_char) -> Vec<String> {
+    if items.is_null() {
+        return Vec::new();
+    }
+    unsafe { std::slice::from_raw_parts(items, size as usize) }
+        .iter()
+        .filter(|item| !item.is_null())
+        .map(|item| {
+            unsafe { CStr::from_ptr(*item) }
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect()
+}
+
+/// Run `callback` against the collection of `payload`, logging its failures as `operation`.
+fn dispatch<T>(
+    payload: *mut c_void,
+    size: u32,
+    items: *const *const c_char,
+    operation: &str,
+    callback: impl FnOnce(&dyn WebDavCollection, &[String]) -> Result<T, Box<dyn Error>>,
+) -> Result<T, OrthancPluginErrorCode> {
+    if payload.is_null() {
+        return Err(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_InternalError);
+    }
+    let registered = unsafe { &*(payload as *const Registered) };
+    let path = path_items(size, items);
+
+    match catch_unwind(AssertUnwindSafe(|| {
+        callback(registered.collection.as_ref(), &path)
+    })) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => {
+            registered.context.log_error(&format!(
+                "WebDAV {} of {}/{} failed - {}",
+                operation,
+                registered.uri,
+                path.join("/"),
+                e
+            ));
+            Err(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin)
+        }
+        Err(_) => {
+            registered.context.log_error(&format!(
+                "WebDAV {} of {}/{} panicked",
+                operation,
+                registered.uri,
+                path.join("/")
+            ));
+            Err(crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin)
+        }
+    }
+}
+
+fn write_outcome(
+    is_read_only: *mut u8,
+    outcome: Result<WebDavWrite, OrthancPluginErrorCode>,
+) -> OrthancPluginErrorCode {
+    match outcome {
+        Ok(outcome) => {
+            if !is_read_only.is_null() {
+                unsafe { *is_read_only = (outcome == WebDavWrite::ReadOnly) as u8 };
+            }
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+        }
+        Err(code) => code,
+    }
+}
+
+extern "C" fn is_existing_folder(
+    is_existing: *mut u8,
+    path_size: u32,
+    path_items: *const *const c_char,
+    payload: *mut c_void,
+) -> OrthancPluginErrorCode {
+    match dispatch(
+        payload,
+        path_size,
+        path_items,
+        "lookup",
+        |collection, path| collection.is_existing_folder(path),
+    ) {
+        Ok(exists) => {
+            if !is_existing.is_null() {
+                unsafe { *is_existing = exists as u8 };
+            }
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+        }
+        Err(code) => code,
+    }
+}
+
+extern "C" fn list_folder(
+    is_existing: *mut u8,
+    collection: *mut OrthancPluginWebDavCollection,
+    add_file: OrthancPluginWebDavAddFile,
+    add_folder: OrthancPluginWebDavAddFolder,
+    path_size: u32,
+    path_items: *const *const c_char,
+    payload: *mut c_void,
+) -> OrthancPluginErrorCode {
+    let folder = WebDavFolder {
+        collection,
+        add_file,
+        add_folder,
+        _callback: PhantomData,
+    };
+    match dispatch(
+        payload,
+        path_size,
+        path_items,
+        "listing",
+        |collection, path| collection.list_folder(path, &folder),
+    ) {
+        Ok(exists) => {
+            if !is_existing.is_null() {
+                unsafe { *is_existing = exists as u8 };
+            }
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+        }
+        Err(code) => code,
+    }
+}
+
+extern "C" fn retrieve_file(
+    collection: *mut OrthancPluginWebDavCollection,
+    retrieve: OrthancPluginWebDavRetrieveFile,
+    path_size: u32,
+    path_items: *const *const c_char,
+    payload: *mut c_void,
+) -> OrthancPluginErrorCode {
+    let file = match dispatch(
+        payload,
+        path_size,
+        path_items,
+        "retrieval",
+        |collection, path| collection.retrieve_file(path),
+    ) {
+        Ok(file) => file,
+        Err(code) => return code,
+    };
+
+    //
+    // Orthanc answers `404 Not Found` when the file is not handed over
+    //
+    let (file, retrieve) = match (file, retrieve) {
+        (Some(file), Some(retrieve)) => (file, retrieve),
+        _ => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success,
+    };
+    let mime_type = match file.mime_type.as_deref().map(c_string).transpose() {
+        Ok(mime_type) => mime_type,
+        Err(e) => return e.code(),
+    };
+    let date_time = match c_string(&file.date_time) {
+        Ok(date_time) => date_time,
+        Err(e) => return e.code(),
+    };
+    unsafe {
+        retrieve(
+            collection,
+            file.content.as_ptr() as *const c_void,
+            file.content.len() as u64,
+            mime_type
+                .as_ref()
+                .map_or(std::ptr::null(), |mime_type| mime_type.as_ptr()),
+            date_time.as_ptr(),
+        )
+    }
+}
+
+extern "C" fn store_file(
+    is_read_only: *mut u8,
+    path_size: u32,
+    path_items: *const *const c_char,
+    data: *const c_void,
+    size: u64,
+    payload: *mut c_void,
+) -> OrthancPluginErrorCode {
+    let content = if data.is_null() || size == 0 {
+        &[][..]
+    } else {
+        unsafe { std::slice::from_raw_parts(data as *const u8, size as usize) }
+    };
+    write_outcome(
+        is_read_only,
+        dispatch(
+            payload,
+            path_size,
+            path_items,
+            "upload",
+            |collection, path| collection.store_file(path, content),
+        ),
+    )
+}
+
+extern "C" fn create_folder(
+    is_read_only: *mut u8,
+    path_size: u32,
+    path_items: *const *const c_char,
+    payload: *mut c_void,
+) -> OrthancPluginErrorCode {
+    write_outcome(
+        is_read_only,
+        dispatch(
+            payload,
+            path_size,
+            path_items,
+            "folder creation",
+            |collection, path| collection.create_folder(path),
+        ),
+    )
+}
+
+extern "C" fn delete_item(
+    is_read_only: *mut u8,
+    path_size: u32,
+    path_items: *const *const c_char,
+    payload: *mut c_void,
+) -> OrthancPluginErrorCode {
+    write_outcome(
+        is_read_only,
+        dispatch(
+            payload,
+            path_size,
+            path_items,
+            "removal",
+            |collection, path| collection.delete_item(path),
+        ),
+    )
+}
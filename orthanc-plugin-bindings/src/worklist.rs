@@ -0,0 +1,242 @@
+//! Modality worklist C-FIND served by a plugin.
+//!
+//! ```ignore
+//! struct Items(Vec<Vec<u8>>);
+//!
+//! impl WorklistHandler for Items {
+//!     fn find(&self, query: &WorklistQuery, answers: &WorklistAnswers, _issuer: &str, _called: &str)
+//!         -> Result<(), Box<dyn Error>>
+//!     {
+//!         for item in &self.0 {
+//!             if query.is_match(item)? {
+//!                 answers.add(query, item)?;
+//!             }
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! worklist::register_worklist(context, Items(load()?))?;
+//! ```
+
+use std::{
+    error::Error,
+    ffi::{c_void, CStr},
+    marker::PhantomData,
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::OnceLock,
+};
+
+use crate::{
+    context::{check, Context, OrthancError},
+    OrthancPluginErrorCode, OrthancPluginMemoryBuffer, OrthancPluginWorklistAnswers,
+    OrthancPluginWorklistQuery,
+};
+
+#[repr(C)]
+struct WorklistCallbackParams {
+    callback: crate::OrthancPluginWorklistCallback,
+}
+
+#[repr(C)]
+struct WorklistAnswersOperationParams {
+    answers: *mut OrthancPluginWorklistAnswers,
+    query: *const OrthancPluginWorklistQuery,
+    dicom: *const c_void,
+    size: u32,
+}
+
+#[repr(C)]
+struct WorklistQueryOperationParams {
+    query: *const OrthancPluginWorklistQuery,
+    dicom: *const c_void,
+    size: u32,
+    is_match: *mut i32,
+    target: *mut OrthancPluginMemoryBuffer,
+}
+
+/// Worklist C-FIND query, valid for the duration of the callback.
+pub struct WorklistQuery<'a> {
+    context: Context,
+    query: *const OrthancPluginWorklistQuery,
+    _callback: PhantomData<&'a OrthancPluginWorklistQuery>,
+}
+
+impl<'a> WorklistQuery<'a> {
+    /// Whether the worklist item `dicom`, a DICOM file, matches the query.
+    pub fn is_match(&self, dicom: &[u8]) -> Result<bool, OrthancError> {
+        let mut is_match = 0;
+        let params = WorklistQueryOperationParams {
+            query: self.query,
+            dicom: dicom.as_ptr() as *const c_void,
+            size: dicom.len() as u32,
+            is_match: &mut is_match,
+            target: std::ptr::null_mut(),
+        };
+        check(unsafe {
+            self.context.invoke(
+                crate::_OrthancPluginService__OrthancPluginService_WorklistIsMatch,
+                &params as *const _ as *const c_void,
+            )
+        })?;
+
+        Ok(is_match != 0)
+    }
+
+    /// The query as a DICOM file.
+    pub fn dicom(&self) -> Result<Vec<u8>, OrthancError> {
+        let mut buffer = OrthancPluginMemoryBuffer {
+            data: std::ptr::null_mut(),
+            size: 0,
+        };
+        let params = WorklistQueryOperationParams {
+            query: self.query,
+            dicom: std::ptr::null(),
+            size: 0,
+            is_match: std::ptr::null_mut(),
+            target: &mut buffer,
+        };
+        check(unsafe {
+            self.context.invoke(
+                crate::_OrthancPluginService__OrthancPluginService_WorklistGetDicomQuery,
+                &params as *const _ as *const c_void,
+            )
+        })?;
+
+        if buffer.data.is_null() {
+            return Ok(Vec::new());
+        }
+        let content =
+            unsafe { std::slice::from_raw_parts(buffer.data as *const u8, buffer.size as usize) }
+                .to_vec();
+        unsafe { self.context.free(buffer.data) };
+        Ok(content)
+    }
+}
+
+/// Answers of a worklist C-FIND, valid for the duration of the callback.
+pub struct WorklistAnswers<'a> {
+    context: Context,
+    answers: *mut OrthancPluginWorklistAnswers,
+    _callback: PhantomData<&'a mut OrthancPluginWorklistAnswers>,
+}
+
+impl<'a> WorklistAnswers<'a> {
+    /// Answer the worklist item `dicom`, only the tags requested by `query` are sent.
+    pub fn add(&self, query: &WorklistQuery, dicom: &[u8]) -> Result<(), OrthancError> {
+        let params = WorklistAnswersOperationParams {
+            answers: self.answers,
+            query: query.query,
+            dicom: dicom.as_ptr() as *const c_void,
+            size: dicom.len() as u32,
+        };
+        check(unsafe {
+            self.context.invoke(
+                crate::_OrthancPluginService__OrthancPluginService_WorklistAddAnswer,
+                &params as *const _ as *const c_void,
+            )
+        })
+    }
+
+    /// Tell the modality that more items match than were answered.
+    pub fn mark_incomplete(&self) -> Result<(), OrthancError> {
+        let params = WorklistAnswersOperationParams {
+            answers: self.answers,
+            query: std::ptr::null(),
+            dicom: std::ptr::null(),
+            size: 0,
+        };
+        check(unsafe {
+            self.context.invoke(
+                crate::_OrthancPluginService__OrthancPluginService_WorklistMarkIncomplete,
+                &params as *const _ as *const c_void,
+            )
+        })
+    }
+}
+
+/// Serves the modality worklist C-FIND requests received by Orthanc.
+pub trait WorklistHandler: Send + Sync + 'static {
+    /// Add the items matching `query` to `answers`, `issuer_aet` is the AET of the modality.
+    fn find(
+        &self,
+        query: &WorklistQuery,
+        answers: &WorklistAnswers,
+        issuer_aet: &str,
+        called_aet: &str,
+    ) -> Result<(), Box<dyn Error>>;
+}
+
+static WORKLIST_HANDLER: OnceLock<(Context, Box<dyn WorklistHandler>)> = OnceLock::new();
+
+/// Serve the modality worklists, Orthanc accepts a single handler across all plugins.
+pub fn register_worklist<H: WorklistHandler>(
+    context: Context,
+    handler: H,
+) -> Result<(), OrthancError> {
+    if WORKLIST_HANDLER.set((context, Box::new(handler))).is_err() {
+        return Err(OrthancError(
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_BadSequenceOfCalls,
+        ));
+    }
+
+    let params = WorklistCallbackParams {
+        callback: Some(worklist),
+    };
+    check(unsafe {
+        context.invoke(
+            crate::_OrthancPluginService__OrthancPluginService_RegisterWorklistCallback,
+            &params as *const _ as *const c_void,
+        )
+    })
+}
+
+extern "C" fn worklist(
+    answers: *mut OrthancPluginWorklistAnswers,
+    query: *const OrthancPluginWorklistQuery,
+    issuer_aet: *const c_char,
+    called_aet: *const c_char,
+) -> OrthancPluginErrorCode {
+    let (context, handler) = match WORKLIST_HANDLER.get() {
+        Some(registered) => registered,
+        None => return crate::OrthancPluginErrorCode_OrthancPluginErrorCode_NoWorklistHandler,
+    };
+
+    let text = |value: *const c_char| {
+        if value.is_null() {
+            Default::default()
+        } else {
+            unsafe { CStr::from_ptr(value) }.to_string_lossy()
+        }
+    };
+    let issuer_aet = text(issuer_aet);
+    let called_aet = text(called_aet);
+    let query = WorklistQuery {
+        context: *context,
+        query,
+        _callback: PhantomData,
+    };
+    let answers = WorklistAnswers {
+        context: *context,
+        answers,
+        _callback: PhantomData,
+    };
+
+    match catch_unwind(AssertUnwindSafe(|| {
+        handler.find(&query, &answers, &issuer_aet, &called_aet)
+    })) {
+        Ok(Ok(())) => crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Success,
+        Ok(Err(e)) => {
+            context.log_error(&format!(
+                "worklist query from {} failed - {}",
+                issuer_aet, e
+            ));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+        Err(_) => {
+            context.log_error(&format!("worklist query from {} panicked", issuer_aet));
+            crate::OrthancPluginErrorCode_OrthancPluginErrorCode_Plugin
+        }
+    }
+}
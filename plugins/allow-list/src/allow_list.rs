@@ -0,0 +1,386 @@
+use std::{collections::HashSet, error::Error, net::IpAddr, str::FromStr};
+
+use orthanc_plugin_bindings::{
+    filter::{DicomInstanceFilter, HttpRequestFilter, IncomingHttpRequest},
+    instance::{DicomInstance, InstanceOrigin},
+};
+use tracing::warn;
+
+use crate::config::Config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AllowListError {
+    #[error("invalid network '{0}'")]
+    Network(String),
+}
+
+/// IP address or network in CIDR notation, e.g. `192.168.0.0/16`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V4(network), IpAddr::V6(ip)) => ip
+                .to_ipv4_mapped()
+                .map(|ip| prefix_matches(&network.octets(), &ip.octets(), self.prefix))
+                .unwrap_or(false),
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full = (prefix / 8) as usize;
+    if network[..full] != ip[..full] {
+        return false;
+    }
+
+    let rest = prefix % 8;
+    rest == 0 || {
+        let mask = !(0xffu8 >> rest);
+        network[full] & mask == ip[full] & mask
+    }
+}
+
+impl FromStr for Network {
+    type Err = AllowListError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || AllowListError::Network(value.to_owned());
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+
+        let address: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        Ok(Self { address, prefix })
+    }
+}
+
+/// AETs allowed to store instances and clients allowed to call the REST API.
+///
+/// A list left unset allows everything.
+#[derive(Debug, Clone, Default)]
+pub struct AllowList {
+    aets: Option<HashSet<String>>,
+    networks: Option<Vec<Network>>,
+}
+
+impl AllowList {
+    pub fn new(aets: Option<HashSet<String>>, networks: Option<Vec<Network>>) -> Self {
+        Self { aets, networks }
+    }
+
+    pub fn allows_aet(&self, aet: &str) -> bool {
+        self.aets
+            .as_ref()
+            .map(|aets| aets.contains(aet.trim()))
+            .unwrap_or(true)
+    }
+
+    /// Whether the client `ip` is allowed, an address that cannot be parsed never is.
+    pub fn allows_ip(&self, ip: &str) -> bool {
+        let networks = match &self.networks {
+            Some(networks) => networks,
+            None => return true,
+        };
+
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => networks.iter().any(|network| network.contains(ip)),
+            Err(_) => false,
+        }
+    }
+
+    pub fn filters_aets(&self) -> bool {
+        self.aets.is_some()
+    }
+
+    pub fn filters_ips(&self) -> bool {
+        self.networks.is_some()
+    }
+}
+
+impl TryFrom<&Config> for AllowList {
+    type Error = AllowListError;
+
+    fn try_from(config: &Config) -> Result<Self, Self::Error> {
+        let aets = config
+            .allow_list_aets
+            .as_deref()
+            .map(|aets| entries(aets).map(str::to_owned).collect::<HashSet<_>>());
+        let networks = config
+            .allow_list_ips
+            .as_deref()
+            .map(|ips| entries(ips).map(str::parse).collect::<Result<Vec<_>, _>>())
+            .transpose()?;
+
+        Ok(Self { aets, networks })
+    }
+}
+
+fn entries(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+impl DicomInstanceFilter for AllowList {
+    fn accept(&self, instance: &DicomInstance) -> Result<bool, Box<dyn Error>> {
+        //
+        // Instances uploaded through the REST API are covered by the IP allow-list
+        //
+        if instance.origin()? != InstanceOrigin::DicomProtocol {
+            return Ok(true);
+        }
+
+        let aet = instance.remote_aet()?;
+        let allowed = self.allows_aet(aet);
+        if !allowed {
+            warn!("rejecting instance sent by unknown AET '{}'", aet);
+        }
+        Ok(allowed)
+    }
+}
+
+impl HttpRequestFilter for AllowList {
+    fn allow(&self, request: &IncomingHttpRequest) -> Result<bool, Box<dyn Error>> {
+        let allowed = self.allows_ip(request.ip());
+        if !allowed {
+            warn!(
+                "rejecting request {} from unknown client {}",
+                request.uri(),
+                request.ip()
+            );
+        }
+        Ok(allowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{c_void, CString},
+        os::raw::c_char,
+    };
+
+    use orthanc_plugin_bindings::{
+        _OrthancPluginService, plugin::Context, OrthancPluginContext, OrthancPluginDicomInstance,
+        OrthancPluginErrorCode, OrthancPluginInstanceOrigin,
+    };
+
+    use super::*;
+
+    /// `_OrthancPluginAccessDicomInstance`
+    #[repr(C)]
+    struct AccessDicomInstanceParams {
+        result_string_to_free: *mut *mut c_char,
+        result_string: *mut *const c_char,
+        result_int64: *mut i64,
+        key: *const c_char,
+        instance: *const OrthancPluginDicomInstance,
+        result_origin: *mut OrthancPluginInstanceOrigin,
+    }
+
+    /// Instance received by Orthanc, in place of its opaque instance.
+    struct Received {
+        remote_aet: CString,
+        origin: OrthancPluginInstanceOrigin,
+    }
+
+    unsafe extern "C" fn access(
+        _context: *mut OrthancPluginContext,
+        service: _OrthancPluginService,
+        params: *const c_void,
+    ) -> OrthancPluginErrorCode {
+        let params = &*(params as *const AccessDicomInstanceParams);
+        let received = &*(params.instance as *const Received);
+        match service {
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetInstanceRemoteAet => {
+                *params.result_string = received.remote_aet.as_ptr()
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetInstanceOrigin => {
+                *params.result_origin = received.origin
+            }
+            _ => {
+                return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented
+            }
+        }
+        orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+    }
+
+    fn accepts(
+        allow_list: &AllowList,
+        remote_aet: &str,
+        origin: OrthancPluginInstanceOrigin,
+    ) -> bool {
+        let mut raw = OrthancPluginContext {
+            pluginsManager: std::ptr::null_mut(),
+            orthancVersion: std::ptr::null(),
+            Free: None,
+            InvokeService: Some(access),
+        };
+        let received = Received {
+            remote_aet: CString::new(remote_aet).unwrap(),
+            origin,
+        };
+        let instance = unsafe {
+            DicomInstance::from_raw(
+                Context::from_raw(&mut raw),
+                &received as *const Received as *const OrthancPluginDicomInstance,
+            )
+        };
+        allow_list.accept(&instance).unwrap()
+    }
+
+    fn network(value: &str) -> Network {
+        value.parse().unwrap()
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks() {
+        assert_eq!(
+            network("192.168.0.0/16"),
+            Network {
+                address: ip("192.168.0.0"),
+                prefix: 16
+            }
+        );
+        assert_eq!(network(" 10.0.0.1 ").prefix, 32);
+        assert_eq!(network("::1").prefix, 128);
+        assert_eq!(network("0.0.0.0/0").prefix, 0);
+        assert_eq!(network("fe80::/10").prefix, 10);
+    }
+
+    #[test]
+    fn rejects_malformed_networks() {
+        for value in [
+            "",
+            "host",
+            "10.0.0/8",
+            "10.0.0.0/",
+            "10.0.0.0/x",
+            "10.0.0.0/-1",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/8/8",
+        ] {
+            assert!(value.parse::<Network>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn matches_prefixes() {
+        assert!(prefix_matches(&[10, 1, 2, 3], &[10, 200, 0, 0], 8));
+        assert!(!prefix_matches(&[10, 1, 2, 3], &[11, 1, 2, 3], 8));
+        assert!(prefix_matches(&[192, 168, 0, 0], &[192, 168, 15, 1], 20));
+        assert!(!prefix_matches(&[192, 168, 0, 0], &[192, 168, 16, 1], 20));
+        assert!(prefix_matches(&[1, 2, 3, 4], &[4, 3, 2, 1], 0));
+        assert!(prefix_matches(&[1, 2, 3, 4], &[1, 2, 3, 4], 32));
+        assert!(!prefix_matches(&[1, 2, 3, 4], &[1, 2, 3, 5], 32));
+    }
+
+    #[test]
+    fn contains_ipv4() {
+        assert!(network("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(network("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!network("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert!(network("172.16.0.0/12").contains(ip("172.31.255.255")));
+        assert!(!network("172.16.0.0/12").contains(ip("172.32.0.0")));
+    }
+
+    #[test]
+    fn contains_ipv6() {
+        assert!(network("::/0").contains(ip("2001:db8::1")));
+        assert!(network("::1/128").contains(ip("::1")));
+        assert!(!network("::1/128").contains(ip("::2")));
+        assert!(network("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!network("2001:db8::/32").contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn contains_ipv4_mapped_peers() {
+        assert!(network("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!network("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+        assert!(!network("10.0.0.0/8").contains(ip("2001:db8::1")));
+        assert!(!network("::/0").contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn allows_ips() {
+        let allow_list = AllowList::new(None, Some(vec![network("192.168.1.0/24")]));
+        assert!(allow_list.allows_ip("192.168.1.20"));
+        assert!(!allow_list.allows_ip("192.168.2.20"));
+        assert!(!allow_list.allows_ip("not an ip"));
+        assert!(AllowList::default().allows_ip("not an ip"));
+    }
+
+    #[test]
+    fn allows_aets() {
+        let aets = ["MODALITY".to_owned()].into_iter().collect();
+        let allow_list = AllowList::new(Some(aets), None);
+        assert!(allow_list.allows_aet("MODALITY"));
+        assert!(allow_list.allows_aet(" MODALITY "));
+        assert!(!allow_list.allows_aet("modality"));
+        assert!(!allow_list.allows_aet("OTHER"));
+        assert!(AllowList::default().allows_aet("OTHER"));
+    }
+
+    #[test]
+    fn lists_entries() {
+        assert_eq!(
+            entries(" A, ,B ,,C").collect::<Vec<_>>(),
+            vec!["A", "B", "C"]
+        );
+    }
+
+    #[test]
+    fn accepts_instances_of_allowed_aets() {
+        let aets = ["MODALITY".to_owned()].into_iter().collect();
+        let allow_list = AllowList::new(Some(aets), None);
+        let dicom =
+            orthanc_plugin_bindings::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_DicomProtocol;
+
+        assert!(accepts(&allow_list, "MODALITY", dicom));
+        assert!(!accepts(&allow_list, "OTHER", dicom));
+        assert!(!accepts(&allow_list, "", dicom));
+    }
+
+    #[test]
+    fn accepts_instances_not_received_by_dicom() {
+        let allow_list = AllowList::new(Some(HashSet::new()), None);
+
+        assert!(accepts(
+            &allow_list,
+            "",
+            orthanc_plugin_bindings::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_RestApi
+        ));
+        assert!(accepts(
+            &allow_list,
+            "",
+            orthanc_plugin_bindings::OrthancPluginInstanceOrigin_OrthancPluginInstanceOrigin_Plugin
+        ));
+    }
+}
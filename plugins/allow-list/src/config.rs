@@ -0,0 +1,10 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Config {
+    /// Comma separated AETs allowed to store instances by C-STORE, any AET when unset.
+    pub allow_list_aets: Option<String>,
+    /// Comma separated IP addresses and networks (`10.0.0.0/8`) allowed to call the REST API
+    /// of Orthanc, any client when unset.
+    pub allow_list_ips: Option<String>,
+}
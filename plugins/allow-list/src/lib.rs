@@ -0,0 +1,3 @@
+pub mod allow_list;
+pub mod config;
+pub mod plugin;
@@ -0,0 +1,62 @@
+use std::error::Error;
+
+use orthanc_plugin_bindings::{
+    declare_plugin, filter,
+    plugin::{Context, Plugin},
+};
+use tracing::info;
+
+use crate::{allow_list::AllowList, config::Config};
+
+declare_plugin!(STATE: AllowListPlugin);
+
+pub struct AllowListPlugin {
+    allow_list: AllowList,
+}
+
+impl Plugin for AllowListPlugin {
+    /// Filter of incoming DICOM instances.
+    const MINIMAL_ORTHANC_VERSION: (u32, u32, u32) = (1, 6, 1);
+
+    fn initialize(_orthanc: Context) -> Result<Self, Box<dyn Error>> {
+        dotenv::dotenv().ok();
+        if std::env::var("RUST_LOG").is_err() {
+            std::env::set_var("RUST_LOG", "allow_list=info")
+        }
+        tracing_subscriber::fmt::try_init().ok();
+
+        let config: Config = envy::from_env()?;
+        info!("config - {:#?}", &config);
+
+        Ok(AllowListPlugin {
+            allow_list: AllowList::try_from(&config)?,
+        })
+    }
+
+    fn register(&'static self, orthanc: Context) -> Result<(), Box<dyn Error>> {
+        //
+        // Orthanc would accept everything without the filters, so failing to register them
+        // fails the initialization
+        //
+        if self.allow_list.filters_aets() {
+            //
+            // The C-STORE filter answers the rejection to the modality, older versions of Orthanc
+            // only support the filter of all incoming instances
+            //
+            if orthanc.check_version(1, 10, 0) {
+                filter::register_cstore_instance_filter(orthanc, self.allow_list.clone())
+            } else {
+                filter::register_dicom_instance_filter(orthanc, self.allow_list.clone())
+            }
+            .map_err(|e| format!("unable to register the AET allow-list - {}", e))?;
+            info!("successfully registered the AET allow-list");
+        }
+
+        if self.allow_list.filters_ips() {
+            filter::register_http_request_filter(orthanc, self.allow_list.clone())
+                .map_err(|e| format!("unable to register the IP allow-list - {}", e))?;
+            info!("successfully registered the IP allow-list");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1078 @@
+//! Decoder of JPEG-LS streams (ITU-T T.87), lossless and near-lossless.
+//!
+//! Mapping tables and restart intervals are not supported, they are rarely used by modalities.
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("truncated JPEG-LS stream")]
+    Truncated,
+    #[error("not a JPEG-LS stream - {0}")]
+    NotJpegLs(String),
+    #[error("unsupported JPEG-LS stream - {0}")]
+    Unsupported(String),
+    #[error("corrupt JPEG-LS stream - {0}")]
+    Corrupt(String),
+}
+
+const SOI: u8 = 0xD8;
+const EOI: u8 = 0xD9;
+const SOS: u8 = 0xDA;
+const DRI: u8 = 0xDD;
+const SOF55: u8 = 0xF7;
+const LSE: u8 = 0xF8;
+
+/// Run lengths of the run mode, by run index (A.7.1.1).
+const J: [u32; 32] = [
+    0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 9, 10, 11, 12, 13,
+    14, 15,
+];
+
+/// Regular contexts, a context for each sign-normalized triplet of quantized gradients.
+const CONTEXTS: usize = 365;
+
+/// Decoded frame, samples are interleaved by pixel.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub components: u8,
+    /// Bits per sample, from 2 to 16.
+    pub precision: u8,
+    pub samples: Vec<u16>,
+}
+
+/// Coding parameters of LSE marker segments, defaults of C.2.4.1.1 when unset.
+#[derive(Debug, Clone, Copy, Default)]
+struct Preset {
+    max_value: Option<i32>,
+    t1: Option<i32>,
+    t2: Option<i32>,
+    t3: Option<i32>,
+    reset: Option<i32>,
+}
+
+#[derive(Debug, Clone)]
+struct Header {
+    width: usize,
+    height: usize,
+    precision: u8,
+    component_ids: Vec<u8>,
+}
+
+/// Decode a JPEG-LS stream, such as a frame of a DICOM file encoded as
+/// `1.2.840.10008.1.2.4.80` or `1.2.840.10008.1.2.4.81`.
+pub fn decode(data: &[u8]) -> Result<Frame, CodecError> {
+    if data.len() < 2 || data[0] != 0xFF || data[1] != SOI {
+        return Err(CodecError::NotJpegLs("missing SOI marker".into()));
+    }
+
+    let mut position = 2;
+    let mut header: Option<Header> = None;
+    let mut preset = Preset::default();
+    let mut samples = Vec::new();
+    let mut decoded = Vec::new();
+
+    loop {
+        let marker = next_marker(data, &mut position)?;
+        match marker {
+            EOI => break,
+            SOF55 => {
+                let segment = segment(data, &mut position)?;
+                let frame = parse_frame(segment)?;
+                samples = vec![0; frame.width * frame.height * frame.component_ids.len()];
+                decoded = vec![false; frame.component_ids.len()];
+                header = Some(frame);
+            }
+            LSE => parse_preset(segment(data, &mut position)?, &mut preset)?,
+            DRI => {
+                let segment = segment(data, &mut position)?;
+                if segment.len() >= 2 && u16::from_be_bytes([segment[0], segment[1]]) != 0 {
+                    return Err(CodecError::Unsupported("restart intervals".into()));
+                }
+            }
+            SOS => {
+                let frame = header
+                    .as_ref()
+                    .ok_or_else(|| CodecError::Corrupt("scan before the frame header".into()))?;
+                let scan = parse_scan(segment(data, &mut position)?, frame)?;
+                let end = scan_end(data, position);
+                let parameters = Parameters::new(frame.precision, scan.near, &preset)?;
+                let mut reader = BitReader::new(&data[position..end]);
+                decode_scan(frame, &scan, &parameters, &mut reader, &mut samples)?;
+                for component in &scan.components {
+                    decoded[*component] = true;
+                }
+                position = end;
+            }
+            0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                return Err(CodecError::NotJpegLs(format!(
+                    "JPEG frame marker {:02X}",
+                    marker
+                )));
+            }
+            _ => {
+                segment(data, &mut position)?;
+            }
+        }
+    }
+
+    let header = header.ok_or_else(|| CodecError::Corrupt("missing frame header".into()))?;
+    if decoded.iter().any(|decoded| !decoded) {
+        return Err(CodecError::Corrupt("missing scans".into()));
+    }
+    Ok(Frame {
+        width: header.width as u32,
+        height: header.height as u32,
+        components: header.component_ids.len() as u8,
+        precision: header.precision,
+        samples,
+    })
+}
+
+/// Marker at `position`, skipping fill bytes.
+fn next_marker(data: &[u8], position: &mut usize) -> Result<u8, CodecError> {
+    if data.get(*position) != Some(&0xFF) {
+        return Err(if *position >= data.len() {
+            CodecError::Truncated
+        } else {
+            CodecError::Corrupt(format!("expected a marker at offset {}", position))
+        });
+    }
+    while data.get(*position) == Some(&0xFF) {
+        *position += 1;
+    }
+    let marker = *data.get(*position).ok_or(CodecError::Truncated)?;
+    *position += 1;
+    Ok(marker)
+}
+
+/// Content of the marker segment at `position`, without its length.
+fn segment<'a>(data: &'a [u8], position: &mut usize) -> Result<&'a [u8], CodecError> {
+    let length = data
+        .get(*position..*position + 2)
+        .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+        .ok_or(CodecError::Truncated)?;
+    if length < 2 {
+        return Err(CodecError::Corrupt("marker segment length".into()));
+    }
+    let segment = data
+        .get(*position + 2..*position + length)
+        .ok_or(CodecError::Truncated)?;
+    *position += length;
+    Ok(segment)
+}
+
+/// Offset of the marker ending the scan data starting at `position`, bytes following a `0xFF`
+/// with their high bit clear are stuffed data.
+fn scan_end(data: &[u8], position: usize) -> usize {
+    let mut end = position;
+    while end + 1 < data.len() {
+        if data[end] == 0xFF && data[end + 1] >= 0x80 {
+            return end;
+        }
+        end += 1;
+    }
+    data.len()
+}
+
+fn parse_frame(segment: &[u8]) -> Result<Header, CodecError> {
+    if segment.len() < 6 {
+        return Err(CodecError::Truncated);
+    }
+    let precision = segment[0];
+    let height = u16::from_be_bytes([segment[1], segment[2]]) as usize;
+    let width = u16::from_be_bytes([segment[3], segment[4]]) as usize;
+    let count = segment[5] as usize;
+    if segment.len() < 6 + 3 * count {
+        return Err(CodecError::Truncated);
+    }
+    if !(2..=16).contains(&precision) {
+        return Err(CodecError::Unsupported(format!(
+            "{} bits samples",
+            precision
+        )));
+    }
+    if width == 0 || height == 0 {
+        return Err(CodecError::Unsupported(
+            "frame dimensions defined by LSE".into(),
+        ));
+    }
+    if count == 0 {
+        return Err(CodecError::Corrupt("frame without components".into()));
+    }
+
+    Ok(Header {
+        width,
+        height,
+        precision,
+        component_ids: (0..count).map(|i| segment[6 + 3 * i]).collect(),
+    })
+}
+
+fn parse_preset(segment: &[u8], preset: &mut Preset) -> Result<(), CodecError> {
+    match segment.first() {
+        Some(1) if segment.len() >= 11 => {
+            let value = |offset: usize| {
+                let value = u16::from_be_bytes([segment[offset], segment[offset + 1]]) as i32;
+                (value != 0).then_some(value)
+            };
+            *preset = Preset {
+                max_value: value(1),
+                t1: value(3),
+                t2: value(5),
+                t3: value(7),
+                reset: value(9),
+            };
+            Ok(())
+        }
+        Some(1) => Err(CodecError::Truncated),
+        Some(id) => Err(CodecError::Unsupported(format!(
+            "LSE marker segment {}",
+            id
+        ))),
+        None => Err(CodecError::Truncated),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Interleave {
+    None,
+    Line,
+    Sample,
+}
+
+#[derive(Debug, Clone)]
+struct Scan {
+    /// Indices of the components of the scan in the frame.
+    components: Vec<usize>,
+    near: i32,
+    interleave: Interleave,
+}
+
+fn parse_scan(segment: &[u8], header: &Header) -> Result<Scan, CodecError> {
+    let count = *segment.first().ok_or(CodecError::Truncated)? as usize;
+    if segment.len() < 1 + 2 * count + 3 || count == 0 {
+        return Err(CodecError::Truncated);
+    }
+
+    let mut components = Vec::with_capacity(count);
+    for i in 0..count {
+        let id = segment[1 + 2 * i];
+        let mapping_table = segment[2 + 2 * i];
+        if mapping_table != 0 {
+            return Err(CodecError::Unsupported("mapping tables".into()));
+        }
+        let index = header
+            .component_ids
+            .iter()
+            .position(|component| *component == id)
+            .ok_or_else(|| CodecError::Corrupt(format!("unknown component {}", id)))?;
+        components.push(index);
+    }
+
+    let near = segment[1 + 2 * count] as i32;
+    let interleave = match (segment[2 + 2 * count], count) {
+        (_, 1) => Interleave::None,
+        (1, _) => Interleave::Line,
+        (2, _) => Interleave::Sample,
+        (mode, _) => {
+            return Err(CodecError::Corrupt(format!(
+                "interleave mode {} with {} components",
+                mode, count
+            )))
+        }
+    };
+    if segment[3 + 2 * count] & 0x0F != 0 {
+        return Err(CodecError::Unsupported("point transform".into()));
+    }
+
+    Ok(Scan {
+        components,
+        near,
+        interleave,
+    })
+}
+
+/// Coding parameters of a scan (C.2.4.1).
+#[derive(Debug, Clone)]
+struct Parameters {
+    max_value: i32,
+    near: i32,
+    t1: i32,
+    t2: i32,
+    t3: i32,
+    reset: i32,
+    range: i32,
+    qbpp: u32,
+    limit: u32,
+}
+
+impl Parameters {
+    fn new(precision: u8, near: i32, preset: &Preset) -> Result<Self, CodecError> {
+        let max_value = preset.max_value.unwrap_or((1 << precision) - 1);
+        if near > (max_value / 2).min(255) {
+            return Err(CodecError::Corrupt(format!("NEAR {}", near)));
+        }
+
+        let clamp = |value: i32, low: i32| {
+            if value > max_value || value < low {
+                low
+            } else {
+                value
+            }
+        };
+        let (t1, t2, t3) = if max_value >= 128 {
+            let factor = (max_value.min(4095) + 128) / 256;
+            let t1 = clamp(factor + 2 + 3 * near, near + 1);
+            let t2 = clamp(factor * 4 + 3 + 5 * near, t1);
+            let t3 = clamp(factor * 17 + 4 + 7 * near, t2);
+            (t1, t2, t3)
+        } else {
+            let factor = 256 / (max_value + 1);
+            let t1 = clamp((3 / factor + 3 * near).max(2), near + 1);
+            let t2 = clamp((7 / factor + 5 * near).max(3), t1);
+            let t3 = clamp((21 / factor + 7 * near).max(4), t2);
+            (t1, t2, t3)
+        };
+
+        let range = (max_value + 2 * near) / (2 * near + 1) + 1;
+        let qbpp = ceil_log2(range);
+        let bpp = ceil_log2(max_value + 1).max(2);
+        Ok(Self {
+            max_value,
+            near,
+            t1: preset.t1.unwrap_or(t1),
+            t2: preset.t2.unwrap_or(t2),
+            t3: preset.t3.unwrap_or(t3),
+            reset: preset.reset.unwrap_or(64),
+            range,
+            qbpp,
+            limit: 2 * (bpp + bpp.max(8)),
+        })
+    }
+
+    fn quantize(&self, gradient: i32) -> i32 {
+        match gradient {
+            d if d <= -self.t3 => -4,
+            d if d <= -self.t2 => -3,
+            d if d <= -self.t1 => -2,
+            d if d < -self.near => -1,
+            d if d <= self.near => 0,
+            d if d < self.t1 => 1,
+            d if d < self.t2 => 2,
+            d if d < self.t3 => 3,
+            _ => 4,
+        }
+    }
+
+    /// Context of the gradients around a sample, 0 selects the run mode.
+    fn context(&self, ra: i32, rb: i32, rc: i32, rd: i32) -> i32 {
+        (self.quantize(rd - rb) * 9 + self.quantize(rb - rc)) * 9 + self.quantize(rc - ra)
+    }
+
+    /// Sample reconstructed from its prediction and error, modulo the range (A.4.2).
+    fn reconstruct(&self, predicted: i32, error: i32) -> i32 {
+        let mut value = predicted + error * (2 * self.near + 1);
+        if value < -self.near {
+            value += self.range * (2 * self.near + 1);
+        } else if value > self.max_value + self.near {
+            value -= self.range * (2 * self.near + 1);
+        }
+        value.clamp(0, self.max_value)
+    }
+}
+
+fn ceil_log2(value: i32) -> u32 {
+    let mut bits = 0;
+    while (1i64 << bits) < value as i64 {
+        bits += 1;
+    }
+    bits
+}
+
+/// Reads the bits of scan data, dropping the bit stuffed after each `0xFF` byte.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    cache: u64,
+    bits: u32,
+    stuffed: bool,
+    /// Zero bits supplied past the end of the data.
+    overrun: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+            cache: 0,
+            bits: 0,
+            stuffed: false,
+            overrun: 0,
+        }
+    }
+
+    fn fill(&mut self) -> Result<(), CodecError> {
+        while self.bits <= 56 {
+            let (value, width) = match self.data.get(self.position) {
+                Some(byte) => {
+                    self.position += 1;
+                    let width = if self.stuffed { 7 } else { 8 };
+                    self.stuffed = *byte == 0xFF;
+                    (*byte as u64 & ((1 << width) - 1), width)
+                }
+                None => {
+                    //
+                    // The encoder pads the last byte, a few spare bits are read ahead
+                    //
+                    self.overrun += 8;
+                    if self.overrun > 64 {
+                        return Err(CodecError::Truncated);
+                    }
+                    (0, 8)
+                }
+            };
+            self.cache |= value << (64 - self.bits - width);
+            self.bits += width;
+        }
+        Ok(())
+    }
+
+    fn read_bit(&mut self) -> Result<bool, CodecError> {
+        if self.bits == 0 {
+            self.fill()?;
+        }
+        let bit = self.cache >> 63 == 1;
+        self.cache <<= 1;
+        self.bits -= 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<i32, CodecError> {
+        if count == 0 {
+            return Ok(0);
+        }
+        if self.bits < count {
+            self.fill()?;
+        }
+        let value = (self.cache >> (64 - count)) as i32;
+        self.cache <<= count;
+        self.bits -= count;
+        Ok(value)
+    }
+
+    /// Golomb code of parameter `k` limited to `limit` bits (A.5.3).
+    fn read_golomb(&mut self, k: u32, limit: u32, qbpp: u32) -> Result<i32, CodecError> {
+        let escape = limit - qbpp - 1;
+        let mut zeros = 0;
+        while !self.read_bit()? {
+            zeros += 1;
+            if zeros > escape {
+                return Err(CodecError::Corrupt("Golomb code exceeds its limit".into()));
+            }
+        }
+        if zeros == escape {
+            return Ok(self.read_bits(qbpp)? + 1);
+        }
+        Ok(((zeros as i32) << k) + self.read_bits(k)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Context {
+    a: i32,
+    b: i32,
+    c: i32,
+    n: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RunContext {
+    a: i32,
+    n: i32,
+    nn: i32,
+}
+
+/// Context statistics of a scan, shared by its components.
+struct State<'p> {
+    parameters: &'p Parameters,
+    contexts: Vec<Context>,
+    /// Run interruption contexts, of the samples whose neighbours differ and do not.
+    run_contexts: [RunContext; 2],
+}
+
+impl<'p> State<'p> {
+    fn new(parameters: &'p Parameters) -> Self {
+        let a = ((parameters.range + 32) / 64).max(2);
+        Self {
+            parameters,
+            contexts: vec![
+                Context {
+                    a,
+                    b: 0,
+                    c: 0,
+                    n: 1
+                };
+                CONTEXTS
+            ],
+            run_contexts: [RunContext { a, n: 1, nn: 0 }; 2],
+        }
+    }
+
+    /// Sample of regular mode with its neighbours and context `q` (A.3 to A.6).
+    fn regular(
+        &mut self,
+        reader: &mut BitReader,
+        q: i32,
+        ra: i32,
+        rb: i32,
+        rc: i32,
+    ) -> Result<i32, CodecError> {
+        let p = self.parameters;
+        let sign = if q < 0 { -1 } else { 1 };
+        let context = &mut self.contexts[(q * sign) as usize];
+
+        let predicted = if rc >= ra.max(rb) {
+            ra.min(rb)
+        } else if rc <= ra.min(rb) {
+            ra.max(rb)
+        } else {
+            ra + rb - rc
+        };
+        let predicted = (predicted + sign * context.c).clamp(0, p.max_value);
+
+        let mut k = 0;
+        while (context.n << k) < context.a {
+            k += 1;
+        }
+        let mapped = reader.read_golomb(k, p.limit, p.qbpp)?;
+        let mut error = if mapped & 1 == 0 {
+            mapped >> 1
+        } else {
+            -((mapped + 1) >> 1)
+        };
+        if p.near == 0 && k == 0 && 2 * context.b + context.n - 1 < 0 {
+            error = -error - 1;
+        }
+
+        context.a += error.abs();
+        context.b += error * (2 * p.near + 1);
+        if context.n == p.reset {
+            context.a >>= 1;
+            context.b >>= 1;
+            context.n >>= 1;
+        }
+        context.n += 1;
+        if context.b + context.n <= 0 {
+            context.b += context.n;
+            if context.b <= -context.n {
+                context.b = -context.n + 1;
+            }
+            if context.c > -128 {
+                context.c -= 1;
+            }
+        } else if context.b > 0 {
+            context.b -= context.n;
+            if context.b > 0 {
+                context.b = 0;
+            }
+            if context.c < 127 {
+                context.c += 1;
+            }
+        }
+
+        Ok(p.reconstruct(predicted, error * sign))
+    }
+
+    /// Length of the run starting a line with `remaining` samples left (A.7.1).
+    fn run_length(
+        &self,
+        reader: &mut BitReader,
+        run_index: &mut usize,
+        remaining: usize,
+    ) -> Result<usize, CodecError> {
+        let mut length = 0;
+        while reader.read_bit()? {
+            let block = 1usize << J[*run_index];
+            let count = block.min(remaining - length);
+            length += count;
+            if count == block && *run_index < 31 {
+                *run_index += 1;
+            }
+            if length == remaining {
+                return Ok(length);
+            }
+        }
+
+        length += reader.read_bits(J[*run_index])? as usize;
+        if length >= remaining {
+            return Err(CodecError::Corrupt("run past the end of the line".into()));
+        }
+        Ok(length)
+    }
+
+    /// Error of a run interruption sample of type `kind` (A.7.2).
+    fn interruption_error(
+        &mut self,
+        reader: &mut BitReader,
+        run_index: usize,
+        kind: usize,
+    ) -> Result<i32, CodecError> {
+        let p = self.parameters;
+        let context = &mut self.run_contexts[kind];
+        let temp = if kind == 0 {
+            context.a
+        } else {
+            context.a + (context.n >> 1)
+        };
+        let mut k = 0;
+        while (context.n << k) < temp {
+            k += 1;
+        }
+
+        let mapped = reader.read_golomb(k, p.limit - J[run_index] - 1, p.qbpp)?;
+        let temp = mapped + kind as i32;
+        let map = temp & 1 == 1;
+        let magnitude = (temp + (temp & 1)) / 2;
+        let error = if (k != 0 || 2 * context.nn >= context.n) == map {
+            -magnitude
+        } else {
+            magnitude
+        };
+
+        if error < 0 {
+            context.nn += 1;
+        }
+        context.a += (mapped + 1 - kind as i32) >> 1;
+        if context.n == p.reset {
+            context.a >>= 1;
+            context.n >>= 1;
+            context.nn >>= 1;
+        }
+        context.n += 1;
+        Ok(error)
+    }
+}
+
+/// Reconstructed lines of a component, with a sample of margin on each side (A.2.1).
+struct Lines {
+    previous: Vec<i32>,
+    current: Vec<i32>,
+    /// Components interleaved in the lines.
+    stride: usize,
+}
+
+impl Lines {
+    fn new(width: usize, stride: usize) -> Self {
+        Self {
+            previous: vec![0; (width + 2) * stride],
+            current: vec![0; (width + 2) * stride],
+            stride,
+        }
+    }
+
+    /// Set the margins of the current line from the previous one.
+    fn start(&mut self, width: usize) {
+        let s = self.stride;
+        for c in 0..s {
+            self.previous[(width + 1) * s + c] = self.previous[width * s + c];
+            self.current[c] = self.previous[s + c];
+        }
+    }
+
+    fn next(&mut self) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+    }
+}
+
+fn decode_scan(
+    header: &Header,
+    scan: &Scan,
+    parameters: &Parameters,
+    reader: &mut BitReader,
+    samples: &mut [u16],
+) -> Result<(), CodecError> {
+    let width = header.width;
+    let components = header.component_ids.len();
+    let mut state = State::new(parameters);
+
+    let store =
+        |samples: &mut [u16], line: &[i32], y: usize, component: usize, stride: usize, c| {
+            for x in 0..width {
+                samples[(y * width + x) * components + component] =
+                    line[(x + 1) * stride + c] as u16;
+            }
+        };
+
+    match scan.interleave {
+        Interleave::None | Interleave::Line => {
+            let mut lines: Vec<Lines> = scan
+                .components
+                .iter()
+                .map(|_| Lines::new(width, 1))
+                .collect();
+            let mut run_indices = vec![0; scan.components.len()];
+            for y in 0..header.height {
+                for (i, component) in scan.components.iter().enumerate() {
+                    lines[i].start(width);
+                    decode_line(
+                        &mut state,
+                        reader,
+                        &mut lines[i],
+                        width,
+                        &mut run_indices[i],
+                    )?;
+                    store(samples, &lines[i].current, y, *component, 1, 0);
+                    lines[i].next();
+                }
+            }
+        }
+        Interleave::Sample => {
+            let stride = scan.components.len();
+            let mut lines = Lines::new(width, stride);
+            let mut run_index = 0;
+            for y in 0..header.height {
+                lines.start(width);
+                decode_sample_line(&mut state, reader, &mut lines, width, &mut run_index)?;
+                for (c, component) in scan.components.iter().enumerate() {
+                    store(samples, &lines.current, y, *component, stride, c);
+                }
+                lines.next();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Line of a single component.
+fn decode_line(
+    state: &mut State,
+    reader: &mut BitReader,
+    lines: &mut Lines,
+    width: usize,
+    run_index: &mut usize,
+) -> Result<(), CodecError> {
+    let p = state.parameters;
+    let mut x = 1;
+    while x <= width {
+        let ra = lines.current[x - 1];
+        let rb = lines.previous[x];
+        let rc = lines.previous[x - 1];
+        let rd = lines.previous[x + 1];
+
+        let q = p.context(ra, rb, rc, rd);
+        if q != 0 {
+            lines.current[x] = state.regular(reader, q, ra, rb, rc)?;
+            x += 1;
+            continue;
+        }
+
+        let remaining = width + 1 - x;
+        let length = state.run_length(reader, run_index, remaining)?;
+        lines.current[x..x + length].fill(ra);
+        x += length;
+        if length == remaining {
+            break;
+        }
+
+        //
+        // Run interruption sample
+        //
+        let rb = lines.previous[x];
+        let kind = ((ra - rb).abs() <= p.near) as usize;
+        let error = state.interruption_error(reader, *run_index, kind)?;
+        lines.current[x] = if kind == 1 {
+            p.reconstruct(ra, error)
+        } else {
+            p.reconstruct(rb, if rb < ra { -error } else { error })
+        };
+        *run_index = run_index.saturating_sub(1);
+        x += 1;
+    }
+    Ok(())
+}
+
+/// Line of sample-interleaved components, runs span all the components of a pixel.
+fn decode_sample_line(
+    state: &mut State,
+    reader: &mut BitReader,
+    lines: &mut Lines,
+    width: usize,
+    run_index: &mut usize,
+) -> Result<(), CodecError> {
+    let p = state.parameters;
+    let s = lines.stride;
+    let mut q = vec![0; s];
+    let mut x = 1;
+    while x <= width {
+        for (c, q) in q.iter_mut().enumerate() {
+            *q = p.context(
+                lines.current[(x - 1) * s + c],
+                lines.previous[x * s + c],
+                lines.previous[(x - 1) * s + c],
+                lines.previous[(x + 1) * s + c],
+            );
+        }
+
+        if q.iter().any(|q| *q != 0) {
+            for (c, q) in q.iter().enumerate() {
+                lines.current[x * s + c] = state.regular(
+                    reader,
+                    *q,
+                    lines.current[(x - 1) * s + c],
+                    lines.previous[x * s + c],
+                    lines.previous[(x - 1) * s + c],
+                )?;
+            }
+            x += 1;
+            continue;
+        }
+
+        let remaining = width + 1 - x;
+        let length = state.run_length(reader, run_index, remaining)?;
+        for i in x..x + length {
+            for c in 0..s {
+                lines.current[i * s + c] = lines.current[(x - 1) * s + c];
+            }
+        }
+        x += length;
+        if length == remaining {
+            break;
+        }
+
+        //
+        // Each component of the interruption pixel is predicted from the sample above
+        //
+        for c in 0..s {
+            let ra = lines.current[(x - 1) * s + c];
+            let rb = lines.previous[x * s + c];
+            let error = state.interruption_error(reader, *run_index, 0)?;
+            lines.current[x * s + c] = p.reconstruct(rb, if rb < ra { -error } else { error });
+        }
+        *run_index = run_index.saturating_sub(1);
+        x += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    //
+    // Streams produced by an encoder written independently from this decoder, from the images
+    // computed below
+    //
+    const LOSSLESS_8: &[u8] = &[
+        0xFF, 0xD8, 0xFF, 0xF7, 0x00, 0x0B, 0x08, 0x00, 0x04, 0x00, 0x08, 0x01, 0x01, 0x11, 0x00,
+        0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xC6, 0x94,
+        0x00, 0x00, 0x02, 0xBC, 0x17, 0x0A, 0x91, 0xCE, 0x00, 0x00, 0x02, 0xD2, 0x00, 0x00, 0x60,
+        0x00, 0x02, 0x80, 0x00, 0x00, 0xB4, 0x70, 0x54, 0x4E, 0x2E, 0x2C, 0xC6, 0xA6, 0xC2, 0x82,
+        0xA0, 0xFF, 0xD9,
+    ];
+    pub(crate) const LOSSLESS_16: &[u8] = &[
+        0xFF, 0xD8, 0xFF, 0xF7, 0x00, 0x0B, 0x10, 0x00, 0x04, 0x00, 0x06, 0x01, 0x01, 0x11, 0x00,
+        0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x80, 0x00, 0x01, 0x7B, 0xC0,
+        0x00, 0x01, 0x7B, 0xCD, 0x7B, 0x4D, 0x7A, 0xD5, 0x7A, 0x40, 0x01, 0xAC, 0x00, 0x00, 0x22,
+        0xA1, 0x4F, 0xB3, 0x6A, 0xC2, 0x04, 0xE0, 0x00, 0x02, 0xFC, 0x36, 0xA8, 0x20, 0x4A, 0x5A,
+        0x00, 0xDA, 0x88, 0x81, 0x11, 0x68, 0x53, 0x69, 0xE4, 0x6A, 0x8B, 0x3D, 0x9B, 0x4C, 0x00,
+        0x76, 0x72, 0xD1, 0x80, 0xFF, 0xD9,
+    ];
+    pub(crate) const NEAR_LOSSLESS_8: &[u8] = &[
+        0xFF, 0xD8, 0xFF, 0xF7, 0x00, 0x0B, 0x08, 0x00, 0x04, 0x00, 0x08, 0x01, 0x01, 0x11, 0x00,
+        0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x02, 0x00, 0x00, 0x81, 0x83, 0x0C, 0xE9, 0x5A,
+        0x44, 0x26, 0x61, 0x33, 0x08, 0x26, 0x66, 0x64, 0x46, 0x44, 0xC8, 0x88, 0x81, 0x00, 0xFF,
+        0xD9,
+    ];
+    const RGB_8_ILV0: &[u8] = &[
+        0xFF, 0xD8, 0xFF, 0xF7, 0x00, 0x11, 0x08, 0x00, 0x03, 0x00, 0x04, 0x03, 0x01, 0x11, 0x00,
+        0x02, 0x11, 0x00, 0x03, 0x11, 0x00, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x01, 0x62, 0x80, 0x00, 0x00, 0x31, 0x43, 0x59, 0x00, 0x00, 0x02, 0x00,
+        0x00, 0x00, 0xA7, 0xE0, 0x00, 0x00, 0x81, 0xE0, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x02, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xDA, 0x80, 0x00, 0x00, 0x37, 0x8E, 0xB2, 0x00, 0x00,
+        0x0F, 0x30, 0x00, 0x00, 0x40, 0x00, 0x00, 0x17, 0x60, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x03,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xA9, 0x80, 0x00, 0x00, 0x3D, 0xCE, 0xB2, 0x00,
+        0x00, 0x04, 0x00, 0x00, 0x01, 0x4F, 0xC0, 0x00, 0x03, 0x87, 0x80, 0xFF, 0xD9,
+    ];
+    const RGB_8_ILV1: &[u8] = &[
+        0xFF, 0xD8, 0xFF, 0xF7, 0x00, 0x11, 0x08, 0x00, 0x03, 0x00, 0x04, 0x03, 0x01, 0x11, 0x00,
+        0x02, 0x11, 0x00, 0x03, 0x11, 0x00, 0xFF, 0xDA, 0x00, 0x0C, 0x03, 0x01, 0x00, 0x02, 0x00,
+        0x03, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x62, 0x80, 0x00, 0x00, 0x31, 0x43, 0x50,
+        0x1D, 0xC4, 0x07, 0x2E, 0x23, 0x54, 0x81, 0x9D, 0xB6, 0x40, 0x00, 0x00, 0x80, 0x00, 0x00,
+        0x29, 0xF1, 0x80, 0x00, 0x07, 0x91, 0x8C, 0x83, 0xD8, 0x00, 0x00, 0x20, 0x73, 0x18, 0x0F,
+        0x71, 0x79, 0x60, 0xFF, 0xD9,
+    ];
+    const RGB_8_ILV2: &[u8] = &[
+        0xFF, 0xD8, 0xFF, 0xF7, 0x00, 0x11, 0x08, 0x00, 0x03, 0x00, 0x04, 0x03, 0x01, 0x11, 0x00,
+        0x02, 0x11, 0x00, 0x03, 0x11, 0x00, 0xFF, 0xDA, 0x00, 0x0C, 0x03, 0x01, 0x00, 0x02, 0x00,
+        0x03, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x63, 0x03, 0xC3, 0x5C, 0xA0, 0x00, 0x00,
+        0x18, 0xA0, 0x01, 0xB0, 0x15, 0xB8, 0xB7, 0xB6, 0xCA, 0x94, 0x00, 0x00, 0x10, 0x00, 0x00,
+        0x73, 0x20, 0x00, 0x00, 0x14, 0xF8, 0x1E, 0xCA, 0x0A, 0x09, 0xE1, 0xC1, 0xE9, 0x60, 0xFF,
+        0xD9,
+    ];
+
+    /// Pixels of `NEAR_LOSSLESS_8` as reconstructed by the encoder.
+    pub(crate) const NEAR_LOSSLESS_8_PIXELS: [u16; 32] = [
+        0, 30, 60, 85, 116, 143, 176, 205, 15, 45, 76, 102, 131, 161, 192, 220, 35, 62, 94, 121,
+        151, 177, 206, 236, 52, 80, 108, 136, 167, 194, 226, 255,
+    ];
+
+    fn image(
+        width: u16,
+        height: u16,
+        components: u16,
+        f: impl Fn(u16, u16, u16) -> u32,
+    ) -> Vec<u16> {
+        (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| (0..components).map(move |c| (x, y, c))))
+            .map(|(x, y, c)| f(x, y, c) as u16)
+            .collect()
+    }
+
+    fn gray_8() -> Vec<u16> {
+        image(8, 4, 1, |x, y, _| {
+            if x < 4 {
+                100
+            } else {
+                (x as u32 * 37 + y as u32 * 53) % 256
+            }
+        })
+    }
+
+    fn rgb_8() -> Vec<u16> {
+        image(4, 3, 3, |x, y, c| {
+            let (x, y, c) = (x as u32, y as u32, c as u32);
+            if x < 2 {
+                50 + c * 60
+            } else {
+                (x * 60 + y * 40 + c * 85) % 256
+            }
+        })
+    }
+
+    #[test]
+    fn decodes_lossless_8_bits() {
+        let frame = decode(LOSSLESS_8).unwrap();
+        assert_eq!((frame.width, frame.height), (8, 4));
+        assert_eq!(frame.components, 1);
+        assert_eq!(frame.precision, 8);
+        assert_eq!(frame.samples, gray_8());
+    }
+
+    #[test]
+    fn decodes_lossless_16_bits() {
+        let frame = decode(LOSSLESS_16).unwrap();
+        assert_eq!((frame.width, frame.height), (6, 4));
+        assert_eq!(frame.precision, 16);
+        assert_eq!(
+            frame.samples,
+            image(6, 4, 1, |x, y, _| {
+                let (x, y) = (x as u32, y as u32);
+                (x * 11000 + y * 7000 + (x * y) % 3 * 1234) % 65536
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_near_lossless() {
+        let frame = decode(NEAR_LOSSLESS_8).unwrap();
+        assert_eq!(frame.samples, NEAR_LOSSLESS_8_PIXELS);
+
+        let original = image(8, 4, 1, |x, y, _| (x as u32 * 29 + y as u32 * 17) % 256);
+        assert!(frame
+            .samples
+            .iter()
+            .zip(&original)
+            .all(|(decoded, original)| decoded.abs_diff(*original) <= 2));
+    }
+
+    #[test]
+    fn decodes_three_components_whatever_the_interleave() {
+        for stream in [RGB_8_ILV0, RGB_8_ILV1, RGB_8_ILV2] {
+            let frame = decode(stream).unwrap();
+            assert_eq!((frame.width, frame.height), (4, 3));
+            assert_eq!(frame.components, 3);
+            assert_eq!(frame.samples, rgb_8());
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_streams() {
+        for length in 0..LOSSLESS_8.len() {
+            match decode(&LOSSLESS_8[..length]) {
+                Err(CodecError::NotJpegLs(_)) => assert!(length < 2),
+                Err(CodecError::Truncated | CodecError::Corrupt(_)) => {}
+                other => panic!("{} bytes decoded as {:?}", length, other),
+            }
+        }
+    }
+
+    #[test]
+    fn survives_corrupt_scan_data() {
+        //
+        // The scan data follows the SOS marker segment, up to the EOI marker
+        //
+        for stream in [LOSSLESS_8, LOSSLESS_16, NEAR_LOSSLESS_8, RGB_8_ILV2] {
+            let start = stream.windows(2).position(|w| w == [0xFF, SOS]).unwrap() + 2;
+            let start = start + u16::from_be_bytes([stream[start], stream[start + 1]]) as usize;
+            for position in start..stream.len() - 2 {
+                for mask in [0x01, 0x55, 0xFF] {
+                    let mut corrupt = stream.to_vec();
+                    corrupt[position] ^= mask;
+                    if let Ok(frame) = decode(&corrupt) {
+                        assert_eq!(
+                            frame.samples.len(),
+                            (frame.width * frame.height) as usize * frame.components as usize
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_missing_scans() {
+        let second_scan = RGB_8_ILV0
+            .windows(2)
+            .enumerate()
+            .filter(|(_, w)| *w == [0xFF, SOS])
+            .nth(1)
+            .map(|(position, _)| position)
+            .unwrap();
+        let mut stream = RGB_8_ILV0[..second_scan].to_vec();
+        stream.extend_from_slice(&[0xFF, EOI]);
+
+        assert!(matches!(decode(&stream), Err(CodecError::Corrupt(_))));
+    }
+
+    #[test]
+    fn rejects_other_streams() {
+        assert!(matches!(
+            decode(&[0x89, b'P', b'N', b'G']),
+            Err(CodecError::NotJpegLs(_))
+        ));
+        assert!(matches!(
+            decode(&[0xFF, SOI, 0xFF, 0xC0, 0x00, 0x0B, 8, 0, 1, 0, 1, 1, 1, 0x11, 0]),
+            Err(CodecError::NotJpegLs(_))
+        ));
+    }
+
+    #[test]
+    fn reports_unsupported_features() {
+        //
+        // Mapping table of the first component of the scan
+        //
+        let mut stream = LOSSLESS_8.to_vec();
+        let sos = stream.windows(2).position(|w| w == [0xFF, SOS]).unwrap();
+        stream[sos + 6] = 1;
+        assert!(matches!(decode(&stream), Err(CodecError::Unsupported(_))));
+
+        let mut stream = LOSSLESS_8[..sos].to_vec();
+        stream.extend_from_slice(&[0xFF, DRI, 0x00, 0x04, 0x00, 0x02]);
+        stream.extend_from_slice(&LOSSLESS_8[sos..]);
+        assert!(matches!(decode(&stream), Err(CodecError::Unsupported(_))));
+    }
+}
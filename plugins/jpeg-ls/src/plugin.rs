@@ -0,0 +1,552 @@
+use std::error::Error;
+
+use orthanc_plugin_bindings::{
+    declare_plugin,
+    image::{self, Image, ImageDecoder, PixelFormat},
+    instance::ParsedInstance,
+    plugin::{Context, Plugin},
+};
+use tracing::{debug, info, warn};
+
+use crate::codec::{self, CodecError, Frame};
+
+declare_plugin!(STATE: JpegLsPlugin);
+
+/// Transfer syntaxes of JPEG-LS, lossless and near-lossless.
+const TRANSFER_SYNTAXES: [&str; 2] = ["1.2.840.10008.1.2.4.80", "1.2.840.10008.1.2.4.81"];
+
+pub struct JpegLsPlugin;
+
+/// Decodes the frames of the JPEG-LS instances, the others are left to Orthanc.
+struct JpegLsDecoder {
+    context: Context,
+}
+
+impl ImageDecoder for JpegLsDecoder {
+    fn decode(&self, dicom: &[u8], frame: u32) -> Result<Option<Image>, Box<dyn Error>> {
+        let parsed = ParsedInstance::parse(self.context, dicom)?;
+        let instance = parsed.instance();
+        let transfer_syntax = instance.transfer_syntax_uid()?;
+        if !TRANSFER_SYNTAXES.contains(&transfer_syntax.as_str()) {
+            return Ok(None);
+        }
+
+        let decoded = match codec::decode(&instance.raw_frame(frame)?) {
+            Ok(decoded) => decoded,
+            Err(CodecError::Unsupported(feature)) => {
+                debug!("leaving frame {} using {} to Orthanc", frame, feature);
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let signed = instance
+            .simplified_json()?
+            .get("PixelRepresentation")
+            .and_then(|value| value.as_str())
+            == Some("1");
+
+        let image = image(self.context, &decoded, signed)?;
+        match image.as_ref() {
+            Some(_) => debug!(
+                "decoded frame {} of {}x{} pixels",
+                frame, decoded.width, decoded.height
+            ),
+            None => debug!(
+                "leaving frame {} of {} components to Orthanc",
+                frame, decoded.components
+            ),
+        }
+        Ok(image)
+    }
+}
+
+/// Image of the decoded `frame`, `None` when Orthanc has no matching pixel format.
+fn image(context: Context, frame: &Frame, signed: bool) -> Result<Option<Image>, Box<dyn Error>> {
+    let format = match (frame.components, frame.precision, signed) {
+        (1, 2..=8, false) => PixelFormat::Grayscale8,
+        (1, _, false) => PixelFormat::Grayscale16,
+        (1, _, true) => PixelFormat::SignedGrayscale16,
+        (3, 2..=8, _) => PixelFormat::Rgb24,
+        (3, _, _) => PixelFormat::Rgb48,
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Image::from_pixels(
+        context,
+        format,
+        frame.width,
+        frame.height,
+        &pixels(frame, format),
+    )?))
+}
+
+/// Samples of `frame` as the pixels of an image of `format`, in native byte order.
+fn pixels(frame: &Frame, format: PixelFormat) -> Vec<u8> {
+    match format {
+        PixelFormat::Grayscale8 | PixelFormat::Rgb24 => {
+            frame.samples.iter().map(|sample| *sample as u8).collect()
+        }
+        //
+        // Signed samples are two's complement values of `precision` bits
+        //
+        PixelFormat::SignedGrayscale16 => frame
+            .samples
+            .iter()
+            .flat_map(|sample| {
+                let shift = 16 - frame.precision as u32;
+                (((*sample << shift) as i16) >> shift).to_ne_bytes()
+            })
+            .collect(),
+        _ => frame
+            .samples
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes())
+            .collect(),
+    }
+}
+
+impl Plugin for JpegLsPlugin {
+    /// Instances are parsed with `OrthancPluginCreateDicomInstance`.
+    const MINIMAL_ORTHANC_VERSION: (u32, u32, u32) = (1, 7, 0);
+
+    fn initialize(_orthanc: Context) -> Result<Self, Box<dyn Error>> {
+        dotenv::dotenv().ok();
+        if std::env::var("RUST_LOG").is_err() {
+            std::env::set_var("RUST_LOG", "jpeg_ls=info")
+        }
+        tracing_subscriber::fmt::try_init().ok();
+
+        Ok(JpegLsPlugin)
+    }
+
+    fn register(&'static self, orthanc: Context) -> Result<(), Box<dyn Error>> {
+        match image::register_decoder(orthanc, JpegLsDecoder { context: orthanc }) {
+            Ok(()) => info!("successfully registered the JPEG-LS decoder"),
+            Err(e) => warn!("unable to register the JPEG-LS decoder - {}", e),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        ffi::{c_void, CString},
+        os::raw::c_char,
+    };
+
+    use orthanc_plugin_bindings::{
+        _OrthancPluginService, OrthancPluginContext, OrthancPluginDicomInstance,
+        OrthancPluginDicomToJsonFlags, OrthancPluginDicomToJsonFormat, OrthancPluginErrorCode,
+        OrthancPluginImage, OrthancPluginInstanceOrigin, OrthancPluginMemoryBuffer,
+        OrthancPluginPixelFormat,
+    };
+
+    use super::*;
+    use crate::codec::tests::{LOSSLESS_16, NEAR_LOSSLESS_8, NEAR_LOSSLESS_8_PIXELS};
+
+    /// `_OrthancPluginCreateDicomInstance`
+    #[repr(C)]
+    struct CreateDicomInstanceParams {
+        target: *mut *mut OrthancPluginDicomInstance,
+        buffer: *const c_void,
+        size: u32,
+    }
+
+    /// `_OrthancPluginFreeDicomInstance`
+    #[repr(C)]
+    struct FreeDicomInstanceParams {
+        dicom: *mut OrthancPluginDicomInstance,
+    }
+
+    /// `_OrthancPluginAccessDicomInstance`
+    #[repr(C)]
+    struct AccessDicomInstanceParams {
+        result_string_to_free: *mut *mut c_char,
+        result_string: *mut *const c_char,
+        result_int64: *mut i64,
+        key: *const c_char,
+        instance: *const OrthancPluginDicomInstance,
+        result_origin: *mut OrthancPluginInstanceOrigin,
+    }
+
+    /// `_OrthancPluginAccessDicomInstance2`
+    #[repr(C)]
+    struct AccessDicomInstance2Params {
+        target_uint32: *mut u32,
+        target_buffer: *mut OrthancPluginMemoryBuffer,
+        target_image: *mut *mut OrthancPluginImage,
+        target_string_to_free: *mut *mut c_char,
+        instance: *const OrthancPluginDicomInstance,
+        frame_index: u32,
+        format: OrthancPluginDicomToJsonFormat,
+        flags: OrthancPluginDicomToJsonFlags,
+        max_string_length: u32,
+        dicom_web_callback: orthanc_plugin_bindings::OrthancPluginDicomWebBinaryCallback2,
+        dicom_web_payload: *mut c_void,
+    }
+
+    /// `_OrthancPluginCreateImage`
+    #[repr(C)]
+    struct CreateImageParams {
+        target: *mut *mut OrthancPluginImage,
+        format: OrthancPluginPixelFormat,
+        width: u32,
+        height: u32,
+        pitch: u32,
+        buffer: *mut c_void,
+        const_buffer: *const c_void,
+        buffer_size: u32,
+        frame_index: u32,
+    }
+
+    /// `_OrthancPluginGetImageInfo`
+    #[repr(C)]
+    struct GetImageInfoParams {
+        image: *const OrthancPluginImage,
+        result_uint32: *mut u32,
+        result_pixel_format: *mut OrthancPluginPixelFormat,
+        result_buffer: *mut *mut c_void,
+    }
+
+    /// `_OrthancPluginFreeImage`
+    #[repr(C)]
+    struct FreeImageParams {
+        image: *const OrthancPluginImage,
+    }
+
+    /// DICOM file parsed by the stand-in Orthanc.
+    #[derive(Default)]
+    struct Parsed {
+        transfer_syntax: String,
+        pixel_representation: u16,
+        fragments: Vec<Vec<u8>>,
+    }
+
+    /// Image created by the stand-in Orthanc, with packed rows.
+    struct Created {
+        format: OrthancPluginPixelFormat,
+        width: u32,
+        height: u32,
+        pitch: u32,
+        buffer: Vec<u8>,
+    }
+
+    thread_local! {
+        /// Memory handed over by the stand-in Orthanc and not released yet, by address.
+        static ALLOCATED: RefCell<HashMap<usize, Vec<u8>>> = RefCell::new(HashMap::new());
+    }
+
+    fn allocate(content: Vec<u8>) -> *mut c_void {
+        let data = content.as_ptr() as *mut c_void;
+        ALLOCATED.with(|allocated| allocated.borrow_mut().insert(data as usize, content));
+        data
+    }
+
+    fn allocate_string(value: &str) -> *mut c_char {
+        allocate(CString::new(value).unwrap().into_bytes_with_nul()) as *mut c_char
+    }
+
+    unsafe extern "C" fn free(data: *mut c_void) {
+        ALLOCATED.with(|allocated| allocated.borrow_mut().remove(&(data as usize)));
+    }
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Elements of an explicit VR little endian file, enough for the files of [`dicom`].
+    fn parse(dicom: &[u8]) -> Parsed {
+        let mut parsed = Parsed::default();
+        let mut offset = 132;
+        while offset < dicom.len() {
+            let tag = (read_u16(dicom, offset), read_u16(dicom, offset + 2));
+            let (length, start) = match &dicom[offset + 4..offset + 6] {
+                b"OB" | b"OW" | b"SQ" | b"UN" => (read_u32(dicom, offset + 8), offset + 12),
+                _ => (read_u16(dicom, offset + 6) as u32, offset + 8),
+            };
+
+            //
+            // Encapsulated pixel data, the first item is the basic offset table
+            //
+            if length == u32::MAX {
+                offset = start;
+                loop {
+                    let item = (read_u16(dicom, offset), read_u16(dicom, offset + 2));
+                    let length = read_u32(dicom, offset + 4) as usize;
+                    offset += 8;
+                    if item == (0xFFFE, 0xE0DD) {
+                        break;
+                    }
+                    parsed
+                        .fragments
+                        .push(dicom[offset..offset + length].to_vec());
+                    offset += length;
+                }
+                parsed.fragments.remove(0);
+                continue;
+            }
+
+            let value = &dicom[start..start + length as usize];
+            match tag {
+                (0x0002, 0x0010) => {
+                    parsed.transfer_syntax = String::from_utf8_lossy(value)
+                        .trim_end_matches('\0')
+                        .to_owned()
+                }
+                (0x0028, 0x0103) => parsed.pixel_representation = read_u16(value, 0),
+                _ => {}
+            }
+            offset = start + length as usize;
+        }
+        parsed
+    }
+
+    /// Orthanc services used by the decoder, on the files of [`dicom`].
+    unsafe extern "C" fn invoke_service(
+        _context: *mut OrthancPluginContext,
+        service: _OrthancPluginService,
+        params: *const c_void,
+    ) -> OrthancPluginErrorCode {
+        match service {
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_CreateDicomInstance => {
+                let params = &*(params as *const CreateDicomInstanceParams);
+                let dicom =
+                    std::slice::from_raw_parts(params.buffer as *const u8, params.size as usize);
+                *params.target = Box::into_raw(Box::new(parse(dicom))) as *mut _;
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_FreeDicomInstance => {
+                let params = &*(params as *const FreeDicomInstanceParams);
+                drop(Box::from_raw(params.dicom as *mut Parsed));
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetInstanceTransferSyntaxUid => {
+                let params = &*(params as *const AccessDicomInstanceParams);
+                let parsed = &*(params.instance as *const Parsed);
+                *params.result_string_to_free = allocate_string(&parsed.transfer_syntax);
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetInstanceSimplifiedJson => {
+                let params = &*(params as *const AccessDicomInstanceParams);
+                let parsed = &*(params.instance as *const Parsed);
+                let tags = serde_json::json!({
+                    "PixelRepresentation": parsed.pixel_representation.to_string()
+                });
+                *params.result_string_to_free = allocate_string(&tags.to_string());
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetInstanceRawFrame => {
+                let params = &*(params as *const AccessDicomInstance2Params);
+                let parsed = &*(params.instance as *const Parsed);
+                let fragment = match parsed.fragments.get(params.frame_index as usize) {
+                    Some(fragment) => fragment.clone(),
+                    None => {
+                        return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_ParameterOutOfRange
+                    }
+                };
+                (*params.target_buffer).size = fragment.len() as u32;
+                (*params.target_buffer).data = allocate(fragment);
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_CreateImage => {
+                let params = &*(params as *const CreateImageParams);
+                let format = PixelFormat::from_raw(params.format).unwrap();
+                let pitch = params.width * format.bytes_per_pixel() as u32;
+                *params.target = Box::into_raw(Box::new(Created {
+                    format: params.format,
+                    width: params.width,
+                    height: params.height,
+                    pitch,
+                    buffer: vec![0; (pitch * params.height) as usize],
+                })) as *mut _;
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetImagePixelFormat => {
+                let params = &*(params as *const GetImageInfoParams);
+                *params.result_pixel_format = (*(params.image as *const Created)).format;
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetImageWidth => {
+                let params = &*(params as *const GetImageInfoParams);
+                *params.result_uint32 = (*(params.image as *const Created)).width;
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetImageHeight => {
+                let params = &*(params as *const GetImageInfoParams);
+                *params.result_uint32 = (*(params.image as *const Created)).height;
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetImagePitch => {
+                let params = &*(params as *const GetImageInfoParams);
+                *params.result_uint32 = (*(params.image as *const Created)).pitch;
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_GetImageBuffer => {
+                let params = &*(params as *const GetImageInfoParams);
+                let created = &mut *(params.image as *mut Created);
+                *params.result_buffer = created.buffer.as_mut_ptr() as *mut c_void;
+            }
+            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_FreeImage => {
+                let params = &*(params as *const FreeImageParams);
+                drop(Box::from_raw(params.image as *mut Created));
+            }
+            _ => {
+                return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented
+            }
+        }
+        orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+    }
+
+    /// DICOM file of a single frame encapsulated in `transfer_syntax`.
+    fn dicom(transfer_syntax: &str, pixel_representation: u16, frame: &[u8]) -> Vec<u8> {
+        fn element(dicom: &mut Vec<u8>, tag: (u16, u16), vr: &[u8; 2], value: &[u8]) {
+            dicom.extend(tag.0.to_le_bytes());
+            dicom.extend(tag.1.to_le_bytes());
+            dicom.extend(vr);
+            dicom.extend((value.len() as u16).to_le_bytes());
+            dicom.extend(value);
+        }
+        fn item(dicom: &mut Vec<u8>, tag: (u16, u16), value: &[u8]) {
+            dicom.extend(tag.0.to_le_bytes());
+            dicom.extend(tag.1.to_le_bytes());
+            dicom.extend((value.len() as u32).to_le_bytes());
+            dicom.extend(value);
+        }
+
+        let mut dicom = vec![0; 128];
+        dicom.extend(b"DICM");
+        let mut uid = transfer_syntax.as_bytes().to_vec();
+        if uid.len() % 2 == 1 {
+            uid.push(0);
+        }
+        element(&mut dicom, (0x0002, 0x0010), b"UI", &uid);
+        element(
+            &mut dicom,
+            (0x0028, 0x0103),
+            b"US",
+            &pixel_representation.to_le_bytes(),
+        );
+
+        dicom.extend([0xE0, 0x7F, 0x10, 0x00]);
+        dicom.extend(b"OB\0\0");
+        dicom.extend(u32::MAX.to_le_bytes());
+        item(&mut dicom, (0xFFFE, 0xE000), &[]);
+        let mut fragment = frame.to_vec();
+        if fragment.len() % 2 == 1 {
+            fragment.push(0);
+        }
+        item(&mut dicom, (0xFFFE, 0xE000), &fragment);
+        item(&mut dicom, (0xFFFE, 0xE0DD), &[]);
+        dicom
+    }
+
+    /// Format, size and pixels of frame `index` of `dicom` decoded by the plugin.
+    fn decode(dicom: &[u8], index: u32) -> Option<(PixelFormat, u32, u32, Vec<u8>)> {
+        let mut raw = OrthancPluginContext {
+            pluginsManager: std::ptr::null_mut(),
+            orthancVersion: c"mainline".as_ptr(),
+            Free: Some(free),
+            InvokeService: Some(invoke_service),
+        };
+        let decoder = JpegLsDecoder {
+            context: unsafe { Context::from_raw(&mut raw) },
+        };
+
+        let decoded = decoder.decode(dicom, index).unwrap().map(|image| {
+            (
+                image.pixel_format().unwrap(),
+                image.width().unwrap(),
+                image.height().unwrap(),
+                image.buffer().unwrap().to_vec(),
+            )
+        });
+        assert!(
+            ALLOCATED.with(|allocated| allocated.borrow().is_empty()),
+            "the buffers of Orthanc are released"
+        );
+        decoded
+    }
+
+    fn frame(precision: u8, samples: Vec<u16>) -> Frame {
+        Frame {
+            width: samples.len() as u32,
+            height: 1,
+            components: 1,
+            precision,
+            samples,
+        }
+    }
+
+    fn signed(frame: &Frame) -> Vec<i16> {
+        pixels(frame, PixelFormat::SignedGrayscale16)
+            .chunks(2)
+            .map(|pixel| i16::from_ne_bytes([pixel[0], pixel[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn extends_the_sign_of_signed_samples() {
+        assert_eq!(
+            signed(&frame(12, vec![0, 1, 0x7FF, 0x800, 0xFFF, 0xC18])),
+            vec![0, 1, 2047, -2048, -1, -1000]
+        );
+        assert_eq!(
+            signed(&frame(16, vec![0x7FFF, 0x8000, 0xFFFF])),
+            vec![32767, -32768, -1]
+        );
+        assert_eq!(signed(&frame(2, vec![0, 1, 2, 3])), vec![0, 1, -2, -1]);
+    }
+
+    #[test]
+    fn keeps_unsigned_samples() {
+        assert_eq!(
+            pixels(&frame(12, vec![0, 0x800, 0xFFF]), PixelFormat::Grayscale16),
+            [0u16, 0x800, 0xFFF]
+                .iter()
+                .flat_map(|sample| sample.to_ne_bytes())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            pixels(&frame(8, vec![0, 127, 255]), PixelFormat::Grayscale8),
+            vec![0, 127, 255]
+        );
+    }
+
+    #[test]
+    fn decodes_encapsulated_frames() {
+        let dicom = dicom(TRANSFER_SYNTAXES[1], 0, NEAR_LOSSLESS_8);
+
+        let (format, width, height, pixels) = decode(&dicom, 0).unwrap();
+
+        assert_eq!(format, PixelFormat::Grayscale8);
+        assert_eq!((width, height), (8, 4));
+        assert_eq!(
+            pixels,
+            NEAR_LOSSLESS_8_PIXELS
+                .iter()
+                .map(|sample| *sample as u8)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn decodes_signed_frames() {
+        let dicom = dicom(TRANSFER_SYNTAXES[0], 1, LOSSLESS_16);
+
+        let (format, width, height, pixels) = decode(&dicom, 0).unwrap();
+
+        assert_eq!(format, PixelFormat::SignedGrayscale16);
+        assert_eq!((width, height), (6, 4));
+        assert_eq!(
+            pixels,
+            super::pixels(
+                &codec::decode(LOSSLESS_16).unwrap(),
+                PixelFormat::SignedGrayscale16
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_other_transfer_syntaxes_to_orthanc() {
+        let dicom = dicom("1.2.840.10008.1.2.4.50", 0, NEAR_LOSSLESS_8);
+
+        assert_eq!(decode(&dicom, 0), None);
+    }
+}
@@ -0,0 +1,652 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use orthanc_plugin_bindings::{
+    api::{AttachmentInfo, OrthancApi},
+    plugin::Context,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    client::{Object, S3Client},
+    layout::{object_key, uuid_from_key},
+    metrics::{self, Operation},
+    staging::Staging,
+};
+
+/// Resource levels that can own attachments in Orthanc.
+const RESOURCE_LEVELS: [&str; 4] = ["patients", "studies", "series", "instances"];
+
+#[derive(Debug, Clone)]
+pub struct AuditOptions {
+    pub bucket: String,
+    /// Delete orphaned objects found by scheduled audits.
+    pub delete_orphans: bool,
+    /// Orphans that were modified more recently than this are never deleted, they may belong
+    /// to an attachment that is still being written.
+    pub grace_period: Duration,
+}
+
+/// Child resource listed by Orthanc, e.g. by `/studies/{id}/series`.
+#[derive(Deserialize)]
+struct Resource {
+    #[serde(rename = "ID")]
+    id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct KnownAttachment {
+    pub level: String,
+    pub resource: String,
+    pub attachment: String,
+    pub uuid: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanObject {
+    pub key: String,
+    pub size: i64,
+    pub last_modified: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditReport {
+    pub started: String,
+    pub duration_ms: u128,
+    pub bucket_objects: usize,
+    pub orthanc_attachments: usize,
+    pub missing: Vec<KnownAttachment>,
+    pub orphans: Vec<OrphanObject>,
+    pub deleted: Vec<String>,
+    pub delete_failures: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuditError {
+    #[error("an audit is already running")]
+    AlreadyRunning,
+    #[error("unable to list bucket - {0}")]
+    Storage(String),
+    #[error("unable to enumerate Orthanc attachments - {0}")]
+    Orthanc(String),
+}
+
+/// Compares the content of the bucket with the attachments known to Orthanc.
+pub struct Auditor {
+    context: Context,
+    s3: S3Client,
+    /// Attachments staged but not uploaded yet are not missing.
+    staging: Option<Arc<Staging>>,
+    options: AuditOptions,
+    running: AtomicBool,
+    last_report: Mutex<Option<AuditReport>>,
+}
+
+impl Auditor {
+    pub fn new(
+        context: Context,
+        s3: S3Client,
+        staging: Option<Arc<Staging>>,
+        options: AuditOptions,
+    ) -> Self {
+        Self {
+            context,
+            s3,
+            staging,
+            options,
+            running: AtomicBool::new(false),
+            last_report: Mutex::new(None),
+        }
+    }
+
+    pub fn options(&self) -> &AuditOptions {
+        &self.options
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn last_report(&self) -> Option<AuditReport> {
+        self.last_report
+            .lock()
+            .expect("audit report lock poisoned")
+            .clone()
+    }
+
+    /// Run an audit, deleting orphans older than the grace period if `delete_orphans` is set.
+    pub async fn run(self: &Arc<Self>, delete_orphans: bool) -> Result<AuditReport, AuditError> {
+        let mut run = self.start(delete_orphans)?;
+        loop {
+            if let Some(report) = run.step().await? {
+                return Ok(report);
+            }
+        }
+    }
+
+    /// Start an audit advanced step by step with [`AuditRun::step`], e.g. by an Orthanc job.
+    pub fn start(self: &Arc<Self>, delete_orphans: bool) -> Result<AuditRun, AuditError> {
+        if self.running.swap(true, Ordering::SeqCst) {
+            return Err(AuditError::AlreadyRunning);
+        }
+
+        info!("starting storage audit of bucket '{}'", self.options.bucket);
+        Ok(AuditRun {
+            auditor: self.clone(),
+            delete_orphans,
+            started: SystemTime::now(),
+            timer: Instant::now(),
+            phase: AuditPhase::ListBucket,
+            objects: Vec::new(),
+            known: HashMap::new(),
+            missing: Vec::new(),
+            orphans: Vec::new(),
+            deletable: Vec::new(),
+            deleted: Vec::new(),
+            delete_failures: Vec::new(),
+        })
+    }
+}
+
+/// Orphans deleted by a single [`AuditRun::step`].
+const DELETE_BATCH: usize = 100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum AuditPhase {
+    ListBucket,
+    /// Enumerating the attachments of the resources of `RESOURCE_LEVELS[i]`.
+    ListAttachments(usize),
+    Compare,
+    DeleteOrphans,
+    Done,
+}
+
+/// Counters of an audit in progress.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuditProgress {
+    pub phase: AuditPhase,
+    pub bucket_objects: usize,
+    pub orthanc_attachments: usize,
+    pub missing: usize,
+    pub orphans: usize,
+    pub deleted: usize,
+    pub delete_failures: usize,
+}
+
+/// Audit in progress, the auditor is released once the run is dropped.
+pub struct AuditRun {
+    auditor: Arc<Auditor>,
+    delete_orphans: bool,
+    started: SystemTime,
+    timer: Instant,
+    phase: AuditPhase,
+    objects: Vec<Object>,
+    known: HashMap<String, KnownAttachment>,
+    missing: Vec<KnownAttachment>,
+    orphans: Vec<OrphanObject>,
+    /// Orphans old enough to be deleted, that were not deleted yet.
+    deletable: Vec<String>,
+    deleted: Vec<String>,
+    delete_failures: Vec<String>,
+}
+
+impl Drop for AuditRun {
+    fn drop(&mut self) {
+        self.auditor.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl AuditRun {
+    pub fn progress(&self) -> f32 {
+        match self.phase {
+            AuditPhase::ListBucket => 0.0,
+            AuditPhase::ListAttachments(level) => {
+                0.1 + 0.4 * level as f32 / RESOURCE_LEVELS.len() as f32
+            }
+            AuditPhase::Compare => 0.5,
+            AuditPhase::DeleteOrphans => {
+                let done = self.deleted.len() + self.delete_failures.len();
+                let total = done + self.deletable.len();
+                0.5 + 0.5 * done as f32 / total.max(1) as f32
+            }
+            AuditPhase::Done => 1.0,
+        }
+    }
+
+    pub fn status(&self) -> AuditProgress {
+        AuditProgress {
+            phase: self.phase,
+            bucket_objects: self.objects.len(),
+            orthanc_attachments: self.known.len(),
+            missing: self.missing.len(),
+            orphans: self.orphans.len(),
+            deleted: self.deleted.len(),
+            delete_failures: self.delete_failures.len(),
+        }
+    }
+
+    /// Run the next step of the audit, returning the report once the audit completed.
+    pub async fn step(&mut self) -> Result<Option<AuditReport>, AuditError> {
+        let auditor = self.auditor.clone();
+
+        match self.phase {
+            //
+            // The bucket is listed before Orthanc is queried, so that attachments created in
+            // between are known to Orthanc and are not reported as orphans
+            //
+            AuditPhase::ListBucket => {
+                self.objects = list_objects(&auditor.s3, &auditor.options.bucket, None).await?;
+                self.phase = AuditPhase::ListAttachments(0);
+            }
+            AuditPhase::ListAttachments(level) => {
+                let context = auditor.context;
+                let attachments = tokio::task::spawn_blocking(move || {
+                    level_attachments(context, RESOURCE_LEVELS[level])
+                })
+                .await
+                .map_err(|e| AuditError::Orthanc(format!("{}", e)))??;
+
+                self.known.extend(
+                    attachments
+                        .into_iter()
+                        .map(|attachment| (attachment.uuid.to_owned(), attachment)),
+                );
+                self.phase = if level + 1 < RESOURCE_LEVELS.len() {
+                    AuditPhase::ListAttachments(level + 1)
+                } else {
+                    AuditPhase::Compare
+                };
+            }
+            AuditPhase::Compare => self.compare(),
+            AuditPhase::DeleteOrphans => {
+                let batch = self
+                    .deletable
+                    .split_off(self.deletable.len().saturating_sub(DELETE_BATCH));
+                for key in batch {
+                    match metrics::timed(
+                        Operation::DeleteObject,
+                        auditor.s3.delete_object(&auditor.options.bucket, &key),
+                    )
+                    .await
+                    {
+                        Ok(_) => self.deleted.push(key),
+                        Err(e) => {
+                            warn!("could not delete orphan '{}' - {}", key, e);
+                            self.delete_failures.push(key);
+                        }
+                    }
+                }
+                if self.deletable.is_empty() {
+                    self.phase = AuditPhase::Done;
+                }
+            }
+            AuditPhase::Done => {}
+        }
+
+        if self.phase != AuditPhase::Done {
+            return Ok(None);
+        }
+
+        let report = self.report();
+        info!(
+            "storage audit complete - {} missing, {} orphans, {} deleted",
+            report.missing.len(),
+            report.orphans.len(),
+            report.deleted.len()
+        );
+        *auditor
+            .last_report
+            .lock()
+            .expect("audit report lock poisoned") = Some(report.clone());
+        Ok(Some(report))
+    }
+
+    fn compare(&mut self) {
+        let stored: HashSet<&str> = self.objects.iter().map(|o| o.key.as_str()).collect();
+        let staging = self.auditor.staging.as_deref();
+
+        self.missing = self
+            .known
+            .values()
+            .filter(|attachment| !stored.contains(object_key(&attachment.uuid).as_str()))
+            .filter(|attachment| !staging.is_some_and(|s| s.is_staged(&attachment.uuid)))
+            .cloned()
+            .collect();
+        self.missing.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+
+        self.orphans = self
+            .objects
+            .iter()
+            .filter_map(|o| {
+                let uuid = uuid_from_key(&o.key)?;
+                if self.known.contains_key(uuid) {
+                    None
+                } else {
+                    Some(OrphanObject {
+                        key: o.key.to_owned(),
+                        size: o.size,
+                        last_modified: o.last_modified.clone(),
+                    })
+                }
+            })
+            .collect();
+
+        if self.delete_orphans {
+            let cutoff = DateTime::<Utc>::from(self.started)
+                - chrono::Duration::from_std(self.auditor.options.grace_period)
+                    .unwrap_or_else(|_| chrono::Duration::max_value());
+
+            self.deletable = self
+                .orphans
+                .iter()
+                .filter(|o| is_older_than(o, cutoff))
+                .map(|o| o.key.to_owned())
+                .collect();
+        }
+
+        self.phase = if self.deletable.is_empty() {
+            AuditPhase::Done
+        } else {
+            AuditPhase::DeleteOrphans
+        };
+    }
+
+    fn report(&self) -> AuditReport {
+        AuditReport {
+            started: DateTime::<Utc>::from(self.started).to_rfc3339(),
+            duration_ms: self.timer.elapsed().as_millis(),
+            bucket_objects: self.objects.len(),
+            orthanc_attachments: self.known.len(),
+            missing: self.missing.clone(),
+            orphans: self.orphans.clone(),
+            deleted: self.deleted.clone(),
+            delete_failures: self.delete_failures.clone(),
+        }
+    }
+}
+
+fn is_older_than(orphan: &OrphanObject, cutoff: DateTime<Utc>) -> bool {
+    orphan
+        .last_modified
+        .as_deref()
+        .and_then(|l| DateTime::parse_from_rfc3339(l).ok())
+        .map(|l| l.with_timezone(&Utc) < cutoff)
+        .unwrap_or(false)
+}
+
+/// List every object of the bucket whose key starts with `prefix`.
+pub async fn list_objects(
+    s3: &S3Client,
+    bucket: &str,
+    prefix: Option<&str>,
+) -> Result<Vec<Object>, AuditError> {
+    let mut objects = Vec::new();
+    let mut continuation_token = None;
+
+    loop {
+        let page = metrics::timed(
+            Operation::ListObjects,
+            s3.list_objects(bucket, prefix, continuation_token),
+        )
+        .await
+        .map_err(|e| AuditError::Storage(format!("{}", e)))?;
+
+        objects.extend(page.objects);
+
+        match page.next_continuation_token {
+            Some(token) => continuation_token = Some(token),
+            None => break,
+        }
+    }
+
+    Ok(objects)
+}
+
+/// Attachments of the resources of `level`, e.g. `studies`.
+pub fn level_attachments(
+    context: Context,
+    level: &str,
+) -> Result<Vec<KnownAttachment>, AuditError> {
+    let mut attachments = Vec::new();
+    let resources: Vec<String> = get_json(context, &format!("/{}", level))?.unwrap_or_default();
+
+    for resource in resources {
+        //
+        // Resources deleted while the audit runs vanish from the REST API, their
+        // attachments are being removed by Orthanc anyway
+        //
+        attachments.extend(resource_attachments(context, level, &resource)?.unwrap_or_default());
+    }
+
+    Ok(attachments)
+}
+
+/// Attachments of a single resource, `None` if the resource does not exist.
+pub fn resource_attachments(
+    context: Context,
+    level: &str,
+    resource: &str,
+) -> Result<Option<Vec<KnownAttachment>>, AuditError> {
+    let names: Vec<String> =
+        match get_json(context, &format!("/{}/{}/attachments", level, resource))? {
+            Some(names) => names,
+            None => return Ok(None),
+        };
+
+    let mut attachments = Vec::with_capacity(names.len());
+    for name in names {
+        let uri = format!("/{}/{}/attachments/{}/info", level, resource, name);
+        if let Some(info) = get_json::<AttachmentInfo>(context, &uri)? {
+            attachments.push(KnownAttachment {
+                level: level.to_string(),
+                resource: resource.to_owned(),
+                attachment: name,
+                uuid: info.uuid,
+            });
+        }
+    }
+
+    Ok(Some(attachments))
+}
+
+/// Attachment uuids of the study `study`, its series and its instances, `None` if the study
+/// does not exist.
+pub fn study_attachments(
+    context: Context,
+    study: &str,
+) -> Result<Option<BTreeSet<String>>, AuditError> {
+    let mut uuids: BTreeSet<String> = match resource_attachments(context, "studies", study)? {
+        Some(attachments) => attachments.into_iter().map(|a| a.uuid).collect(),
+        None => return Ok(None),
+    };
+
+    for level in ["series", "instances"] {
+        let children: Vec<Resource> =
+            get_json(context, &format!("/studies/{}/{}", study, level))?.unwrap_or_default();
+
+        for child in children {
+            uuids.extend(
+                resource_attachments(context, level, &child.id)?
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|a| a.uuid),
+            );
+        }
+    }
+
+    Ok(Some(uuids))
+}
+
+/// GET a JSON document from Orthanc, `None` if the resource does not exist (anymore).
+pub(crate) fn get_json<T: serde::de::DeserializeOwned>(
+    context: Context,
+    uri: &str,
+) -> Result<Option<T>, AuditError> {
+    OrthancApi::new(context)
+        .find_json(uri)
+        .map_err(|e| AuditError::Orthanc(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        staging::StagingOptions,
+        stub::{Bucket, Orthanc, Response, StoredObject, Stub},
+    };
+
+    const STORED: &str = "0a1b2c3d-0000-4000-8000-000000000001";
+    const MISSING: &str = "0a1b2c3d-0000-4000-8000-000000000002";
+    const STAGED: &str = "0a1b2c3d-0000-4000-8000-000000000003";
+    const OLD_ORPHAN: &str = "0a1b2c3d-0000-4000-8000-000000000004";
+    const NEW_ORPHAN: &str = "0a1b2c3d-0000-4000-8000-000000000005";
+
+    const GRACE_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn store(bucket: &Bucket, key: &str, age: chrono::Duration) {
+        bucket.lock().unwrap().insert(
+            key.to_string(),
+            StoredObject {
+                body: b"DICM".to_vec(),
+                headers: Default::default(),
+                last_modified: Utc::now() - age,
+            },
+        );
+    }
+
+    fn auditor(
+        stub: &Stub,
+        orthanc: &'static Orthanc,
+        staging: Option<Arc<Staging>>,
+    ) -> Arc<Auditor> {
+        Arc::new(Auditor::new(
+            orthanc.context(),
+            stub.s3_client(),
+            staging,
+            AuditOptions {
+                bucket: "bucket".to_string(),
+                delete_orphans: false,
+                grace_period: GRACE_PERIOD,
+            },
+        ))
+    }
+
+    /// Orthanc knowing the instances of a study, one of them only staged, and a bucket with
+    /// an orphan older than the grace period, a recent orphan and an object that is not an
+    /// attachment.
+    async fn setup(directory: &std::path::Path) -> (Stub, Bucket, Arc<Auditor>) {
+        let (stub, bucket) = Stub::bucket().await;
+        store(&bucket, &object_key(STORED), chrono::Duration::days(3));
+        store(&bucket, &object_key(OLD_ORPHAN), chrono::Duration::days(2));
+        store(
+            &bucket,
+            &object_key(NEW_ORPHAN),
+            chrono::Duration::minutes(5),
+        );
+        store(&bucket, "README.txt", chrono::Duration::days(2));
+
+        let orthanc = Orthanc::start();
+        orthanc
+            .study(
+                "study",
+                &[("stored", STORED), ("missing", MISSING), ("staged", STAGED)],
+            )
+            .serve("/studies", json!(["study"]))
+            .serve("/instances", json!(["stored", "missing", "staged"]));
+
+        let (staging, _uploads) = Staging::new(
+            stub.s3_client(),
+            StagingOptions {
+                bucket: "bucket".to_string(),
+                directory: directory.to_path_buf(),
+                workers: 1,
+            },
+        )
+        .unwrap();
+        staging.stage(STAGED, b"DICM").unwrap();
+
+        let auditor = auditor(&stub, orthanc, Some(Arc::new(staging)));
+        (stub, bucket, auditor)
+    }
+
+    fn orphans(report: &AuditReport) -> Vec<&str> {
+        report.orphans.iter().map(|o| o.key.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn reports_missing_objects_and_orphans() {
+        let directory = tempfile::tempdir().unwrap();
+        let (_stub, bucket, auditor) = setup(directory.path()).await;
+
+        let report = auditor.run(false).await.unwrap();
+        assert_eq!(report.bucket_objects, 4);
+        assert_eq!(report.orthanc_attachments, 3);
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].level, "instances");
+        assert_eq!(report.missing[0].resource, "missing");
+        assert_eq!(report.missing[0].attachment, "dicom");
+        assert_eq!(report.missing[0].uuid, MISSING);
+        assert_eq!(orphans(&report), [OLD_ORPHAN, NEW_ORPHAN]);
+        assert!(report.deleted.is_empty());
+        assert_eq!(bucket.lock().unwrap().len(), 4);
+
+        assert!(!auditor.is_running());
+        assert_eq!(auditor.last_report().unwrap().orphans.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn deletes_orphans_older_than_the_grace_period() {
+        let directory = tempfile::tempdir().unwrap();
+        let (_stub, bucket, auditor) = setup(directory.path()).await;
+
+        let report = auditor.run(true).await.unwrap();
+        assert_eq!(orphans(&report), [OLD_ORPHAN, NEW_ORPHAN]);
+        assert_eq!(report.deleted, [OLD_ORPHAN]);
+        assert!(report.delete_failures.is_empty());
+
+        let keys: Vec<String> = bucket.lock().unwrap().keys().cloned().collect();
+        assert_eq!(keys, [STORED, NEW_ORPHAN, "README.txt"]);
+    }
+
+    #[tokio::test]
+    async fn runs_one_audit_at_a_time() {
+        let directory = tempfile::tempdir().unwrap();
+        let (_stub, _bucket, auditor) = setup(directory.path()).await;
+
+        let mut run = auditor.start(false).unwrap();
+        assert!(matches!(
+            auditor.start(false),
+            Err(AuditError::AlreadyRunning)
+        ));
+        while run.step().await.unwrap().is_none() {
+            assert!(run.progress() < 1.0);
+        }
+        assert_eq!(run.progress(), 1.0);
+        drop(run);
+        assert!(auditor.start(false).is_ok());
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_bucket_cannot_be_listed() {
+        let stub = Stub::start(|_| Response::status(403)).await;
+        let auditor = auditor(&stub, Orthanc::start(), None);
+
+        assert!(matches!(
+            auditor.run(false).await,
+            Err(AuditError::Storage(_))
+        ));
+        assert!(!auditor.is_running());
+        assert!(auditor.last_report().is_none());
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use s3::{
+    client::S3Client,
+    config::Config,
+    migrate::{self, MigrationOptions},
+};
+
+/// Copy an Orthanc filesystem storage area into the bucket used by the s3 plugin.
+///
+/// The S3 connection is configured exactly like the plugin (".env" file or environment).
+#[derive(Parser, Debug)]
+#[command(name = "s3-migrate", version)]
+struct Args {
+    /// Root of the Orthanc storage area (`StorageDirectory` in the Orthanc configuration)
+    storage_directory: PathBuf,
+
+    /// File recording migrated attachments, used to resume an interrupted migration
+    #[arg(long, default_value = "s3-migrate.progress")]
+    progress_file: PathBuf,
+
+    /// Only report what would be uploaded
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Number of concurrent uploads
+    #[arg(long, default_value_t = 8)]
+    parallelism: usize,
+
+    /// Maximum size of the attachments read in memory at once, in MiB
+    #[arg(long, default_value_t = 512)]
+    max_buffered_mib: u64,
+
+    /// Also write the final report as JSON to this file
+    #[arg(long)]
+    report: Option<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "s3=info")
+    }
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+    let config: Config = envy::from_env()?;
+    let s3 = S3Client::try_from(&config).map_err(|e| anyhow::anyhow!("{}", e))?;
+
+    let options = MigrationOptions {
+        storage_directory: args.storage_directory,
+        progress_file: args.progress_file,
+        bucket: config.s3_bucket.to_owned(),
+        dry_run: args.dry_run,
+        parallelism: args.parallelism,
+        max_buffered_bytes: args.max_buffered_mib * 1024 * 1024,
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let report = runtime.block_on(migrate::run(&s3, &options))?;
+
+    let json = serde_json::to_string_pretty(&report)?;
+    if let Some(path) = args.report {
+        std::fs::write(path, &json)?;
+    }
+    println!("{}", json);
+
+    if !report.failed.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1025 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use reqwest::{header::HeaderMap, Method, Response, Url};
+use tracing::debug;
+use xml::reader::{EventReader, XmlEvent};
+
+use crate::{
+    credentials::{CredentialsError, CredentialsProvider, ProvideCredentials},
+    metrics::METRICS,
+    signature::{self, EMPTY_PAYLOAD_SHA256},
+};
+
+const METADATA_PREFIX: &str = "x-amz-meta-";
+
+/// Attempts of a request failing with a transient error, e.g. throttling.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry of a request, doubled for every following retry.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, thiserror::Error)]
+pub enum S3Error {
+    #[error("request failed - {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("no credentials - {0}")]
+    Credentials(#[from] CredentialsError),
+    #[error("request timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("{status} {code} - {message}")]
+    Service {
+        status: u16,
+        code: String,
+        message: String,
+    },
+    #[error("invalid response - {0}")]
+    InvalidResponse(String),
+    #[error("invalid endpoint '{0}'")]
+    InvalidEndpoint(String),
+}
+
+impl S3Error {
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, S3Error::Service { status: 404, .. })
+    }
+
+    /// Whether the request may succeed when sent again: throttling, server errors, timeouts
+    /// and connection failures.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            S3Error::Service { status, .. } => *status == 429 || *status >= 500,
+            S3Error::Timeout(_) => true,
+            S3Error::Request(e) => e.is_connect() || e.is_timeout(),
+            _ => false,
+        }
+    }
+}
+
+/// Object returned by a bucket listing.
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub key: String,
+    pub size: i64,
+    /// RFC 3339 timestamp, e.g. `2009-10-12T17:50:30.000Z`.
+    pub last_modified: Option<String>,
+    pub e_tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ObjectList {
+    pub objects: Vec<Object>,
+    pub next_continuation_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ObjectHead {
+    pub content_length: Option<i64>,
+    /// HTTP date, e.g. `Wed, 12 Oct 2009 17:50:00 GMT`.
+    pub last_modified: Option<String>,
+    pub e_tag: Option<String>,
+    pub content_type: Option<String>,
+    pub storage_class: Option<String>,
+    /// `x-amz-server-side-encryption`, e.g. `AES256` or `aws:kms`.
+    pub server_side_encryption: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+impl ObjectHead {
+    /// MD5 of the content of the object, `None` when the ETag is not one, see [`payload_md5`].
+    pub fn md5(&self) -> Option<String> {
+        payload_md5(
+            self.e_tag.as_deref(),
+            self.server_side_encryption.as_deref(),
+        )
+    }
+}
+
+/// Body of an object being downloaded, consumed chunk by chunk as it arrives.
+pub struct ObjectStream {
+    resp: Response,
+    timeout: Option<Duration>,
+}
+
+impl ObjectStream {
+    /// Size announced by the `Content-Length` header, if any.
+    pub fn content_length(&self) -> Option<u64> {
+        self.resp.content_length()
+    }
+
+    /// Copy the whole body into `target` and return the number of bytes read.
+    ///
+    /// Fails without writing past the end of `target` when the body is larger than `target`.
+    pub async fn read_into(mut self, target: &mut [u8]) -> Result<usize, S3Error> {
+        let mut read = 0;
+        while let Some(chunk) = within(self.timeout, self.resp.chunk()).await? {
+            let end = read + chunk.len();
+            if end > target.len() {
+                return Err(S3Error::InvalidResponse(format!(
+                    "body exceeds the expected {} bytes",
+                    target.len()
+                )));
+            }
+            target[read..end].copy_from_slice(&chunk);
+            read = end;
+        }
+        Ok(read)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PutOptions {
+    pub content_type: Option<String>,
+    /// Base64 encoded MD5 of the body, verified by the object store.
+    pub content_md5: Option<String>,
+}
+
+/// Answer of a successful upload.
+#[derive(Debug, Clone, Default)]
+pub struct PutResult {
+    pub e_tag: Option<String>,
+    /// `x-amz-server-side-encryption`, e.g. `AES256` or `aws:kms`.
+    pub server_side_encryption: Option<String>,
+}
+
+impl PutResult {
+    /// MD5 of the uploaded payload, `None` when the ETag is not one, see [`payload_md5`].
+    pub fn md5(&self) -> Option<String> {
+        payload_md5(
+            self.e_tag.as_deref(),
+            self.server_side_encryption.as_deref(),
+        )
+    }
+}
+
+/// MD5 of the content of an object, read from its ETag.
+///
+/// The ETag of an object encrypted with KMS (`aws:kms`, `aws:kms:dsse`) looks like an MD5 but is
+/// not one, and the ETag of a multipart upload (`<md5>-<parts>`) is the MD5 of its parts.
+pub fn payload_md5(e_tag: Option<&str>, server_side_encryption: Option<&str>) -> Option<String> {
+    if server_side_encryption.is_some_and(|sse| sse.starts_with("aws:kms")) {
+        return None;
+    }
+
+    let e_tag = e_tag?.trim_matches('"');
+    (e_tag.len() == 32 && e_tag.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| e_tag.to_ascii_lowercase())
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub endpoint: String,
+    pub region: String,
+    /// Address buckets as `<endpoint>/<bucket>` rather than `<bucket>.<endpoint>`.
+    pub path_style: bool,
+    /// Maximum time to wait for the response headers of a request, then for each chunk of its
+    /// body, so that a stalled transfer fails rather than hanging.
+    pub request_timeout: Option<Duration>,
+}
+
+/// Minimal S3 client signing its requests with SigV4, for AWS and compatible object stores.
+///
+/// Cloning the client is cheap, clones share their connection pool and credentials cache.
+#[derive(Clone)]
+pub struct S3Client {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    http: reqwest::Client,
+    credentials: CredentialsProvider,
+    endpoint: Url,
+    region: String,
+    path_style: bool,
+    request_timeout: Option<Duration>,
+}
+
+impl S3Client {
+    pub fn new(
+        http: reqwest::Client,
+        credentials: CredentialsProvider,
+        options: ClientOptions,
+    ) -> Result<Self, S3Error> {
+        let endpoint = Url::parse(&options.endpoint)
+            .ok()
+            .filter(|e| e.host_str().is_some())
+            .ok_or_else(|| S3Error::InvalidEndpoint(options.endpoint.to_owned()))?;
+
+        Ok(Self {
+            inner: Arc::new(Inner {
+                http,
+                credentials,
+                endpoint,
+                region: options.region,
+                path_style: options.path_style,
+                request_timeout: options.request_timeout,
+            }),
+        })
+    }
+
+    pub async fn list_buckets(&self) -> Result<Vec<String>, S3Error> {
+        let resp = self
+            .send(Method::GET, None, None, &[], BTreeMap::new(), None)
+            .await?;
+        let body = read_body(resp, self.inner.request_timeout).await?;
+
+        Ok(xml_records(&body, "Bucket")?
+            .into_iter()
+            .filter_map(|mut bucket| bucket.remove("Name"))
+            .collect())
+    }
+
+    pub async fn head_bucket(&self, bucket: &str) -> Result<(), S3Error> {
+        self.send(Method::HEAD, Some(bucket), None, &[], BTreeMap::new(), None)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn put_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+        options: PutOptions,
+    ) -> Result<PutResult, S3Error> {
+        let mut headers = BTreeMap::new();
+        if let Some(content_type) = options.content_type {
+            headers.insert("content-type".to_string(), content_type);
+        }
+        if let Some(content_md5) = options.content_md5 {
+            headers.insert("content-md5".to_string(), content_md5);
+        }
+
+        let resp = self
+            .send(
+                Method::PUT,
+                Some(bucket),
+                Some(key),
+                &[],
+                headers,
+                Some(body),
+            )
+            .await?;
+        Ok(PutResult {
+            e_tag: header(resp.headers(), "etag"),
+            server_side_encryption: header(resp.headers(), "x-amz-server-side-encryption"),
+        })
+    }
+
+    /// Download an object, or only the bytes of `range` when set.
+    pub async fn get_object(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<Range<u64>>,
+    ) -> Result<Vec<u8>, S3Error> {
+        let mut headers = BTreeMap::new();
+        if let Some(range) = &range {
+            if range.is_empty() {
+                return Ok(Vec::new());
+            }
+            headers.insert(
+                "range".to_string(),
+                format!("bytes={}-{}", range.start, range.end - 1),
+            );
+        }
+
+        let resp = self
+            .send(Method::GET, Some(bucket), Some(key), &[], headers, None)
+            .await?;
+        read_body(resp, self.inner.request_timeout).await
+    }
+
+    /// Start downloading an object without buffering its body, see [`ObjectStream`].
+    pub async fn get_object_stream(
+        &self,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ObjectStream, S3Error> {
+        let resp = self
+            .send(
+                Method::GET,
+                Some(bucket),
+                Some(key),
+                &[],
+                BTreeMap::new(),
+                None,
+            )
+            .await?;
+        Ok(ObjectStream {
+            resp,
+            timeout: self.inner.request_timeout,
+        })
+    }
+
+    pub async fn head_object(&self, bucket: &str, key: &str) -> Result<ObjectHead, S3Error> {
+        let resp = self
+            .send(
+                Method::HEAD,
+                Some(bucket),
+                Some(key),
+                &[],
+                BTreeMap::new(),
+                None,
+            )
+            .await?;
+        let headers = resp.headers();
+
+        Ok(ObjectHead {
+            content_length: header(headers, "content-length").and_then(|l| l.parse().ok()),
+            last_modified: header(headers, "last-modified"),
+            e_tag: header(headers, "etag"),
+            content_type: header(headers, "content-type"),
+            storage_class: header(headers, "x-amz-storage-class"),
+            server_side_encryption: header(headers, "x-amz-server-side-encryption"),
+            metadata: headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    let name = name.as_str().strip_prefix(METADATA_PREFIX)?;
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+        })
+    }
+
+    pub async fn delete_object(&self, bucket: &str, key: &str) -> Result<(), S3Error> {
+        self.send(
+            Method::DELETE,
+            Some(bucket),
+            Some(key),
+            &[],
+            BTreeMap::new(),
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Copy `source_key` to `key` within `bucket`, replacing the metadata of the copy.
+    pub async fn copy_object(
+        &self,
+        bucket: &str,
+        source_key: &str,
+        key: &str,
+        metadata: &HashMap<String, String>,
+    ) -> Result<(), S3Error> {
+        let mut headers: BTreeMap<_, _> = metadata
+            .iter()
+            .map(|(name, value)| {
+                (
+                    format!("{}{}", METADATA_PREFIX, name.to_lowercase()),
+                    value.to_owned(),
+                )
+            })
+            .collect();
+        headers.insert(
+            "x-amz-metadata-directive".to_string(),
+            "REPLACE".to_string(),
+        );
+
+        self.copy(bucket, source_key, key, headers).await
+    }
+
+    /// Move the object `key` to `storage_class`, e.g. `STANDARD_IA`, by copying it onto itself
+    /// with its content and metadata.
+    pub async fn set_storage_class(
+        &self,
+        bucket: &str,
+        key: &str,
+        storage_class: &str,
+    ) -> Result<(), S3Error> {
+        let headers = BTreeMap::from([
+            ("x-amz-metadata-directive".to_string(), "COPY".to_string()),
+            ("x-amz-storage-class".to_string(), storage_class.to_string()),
+        ]);
+
+        self.copy(bucket, key, key, headers).await
+    }
+
+    async fn copy(
+        &self,
+        bucket: &str,
+        source_key: &str,
+        key: &str,
+        mut headers: BTreeMap<String, String>,
+    ) -> Result<(), S3Error> {
+        headers.insert(
+            "x-amz-copy-source".to_string(),
+            format!("/{}/{}", bucket, signature::uri_encode(source_key, false)),
+        );
+
+        let resp = self
+            .send(Method::PUT, Some(bucket), Some(key), &[], headers, None)
+            .await?;
+
+        //
+        // A copy can fail after the response status was sent, the error is then in the body
+        //
+        let body = read_body(resp, self.inner.request_timeout).await?;
+        match xml_records(&body, "Error")?.pop() {
+            Some(error) => Err(service_error(500, error)),
+            None => Ok(()),
+        }
+    }
+
+    /// List one page of the objects whose key starts with `prefix`.
+    pub async fn list_objects(
+        &self,
+        bucket: &str,
+        prefix: Option<&str>,
+        continuation_token: Option<String>,
+    ) -> Result<ObjectList, S3Error> {
+        let mut query = vec![("list-type", "2".to_string())];
+        if let Some(prefix) = prefix {
+            query.push(("prefix", prefix.to_string()));
+        }
+        if let Some(token) = continuation_token {
+            query.push(("continuation-token", token));
+        }
+
+        let resp = self
+            .send(
+                Method::GET,
+                Some(bucket),
+                None,
+                &query,
+                BTreeMap::new(),
+                None,
+            )
+            .await?;
+        let body = read_body(resp, self.inner.request_timeout).await?;
+
+        let objects = xml_records(&body, "Contents")?
+            .into_iter()
+            .filter_map(|mut o| {
+                Some(Object {
+                    key: o.remove("Key")?,
+                    size: o
+                        .get("Size")
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or_default(),
+                    last_modified: o.remove("LastModified"),
+                    e_tag: o.remove("ETag"),
+                })
+            })
+            .collect();
+
+        let result = xml_records(&body, "ListBucketResult")?
+            .pop()
+            .unwrap_or_default();
+        let truncated = result.get("IsTruncated").map(|t| t == "true");
+
+        Ok(ObjectList {
+            objects,
+            next_continuation_token: result
+                .get("NextContinuationToken")
+                .filter(|_| truncated.unwrap_or(false))
+                .cloned(),
+        })
+    }
+
+    /// Send a signed request, retrying transient failures up to [`MAX_ATTEMPTS`] times.
+    async fn send(
+        &self,
+        method: Method,
+        bucket: Option<&str>,
+        key: Option<&str>,
+        query: &[(&str, String)],
+        headers: BTreeMap<String, String>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response, S3Error> {
+        let body = body.map(Bytes::from);
+        let mut attempt = 1;
+
+        loop {
+            let result = self
+                .send_once(
+                    method.clone(),
+                    bucket,
+                    key,
+                    query,
+                    headers.clone(),
+                    body.clone(),
+                )
+                .await;
+            match result {
+                Err(e) if e.is_transient() && attempt < MAX_ATTEMPTS => {
+                    let delay = RETRY_DELAY * 2u32.pow(attempt - 1);
+                    debug!("retrying {} request in {:?} - {}", method, delay, e);
+                    METRICS.add_retry();
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sign and send a request once, it is signed again for every attempt.
+    async fn send_once(
+        &self,
+        method: Method,
+        bucket: Option<&str>,
+        key: Option<&str>,
+        query: &[(&str, String)],
+        mut headers: BTreeMap<String, String>,
+        body: Option<Bytes>,
+    ) -> Result<Response, S3Error> {
+        let inner = &self.inner;
+        let endpoint = &inner.endpoint;
+        let endpoint_host = endpoint.host_str().unwrap_or_default();
+
+        let key = key
+            .map(|k| format!("/{}", signature::uri_encode(k, false)))
+            .unwrap_or_default();
+        let (host, path) = match bucket {
+            Some(bucket) if inner.path_style => {
+                (endpoint_host.to_string(), format!("/{}{}", bucket, key))
+            }
+            Some(bucket) => (format!("{}.{}", bucket, endpoint_host), key),
+            None => (endpoint_host.to_string(), key),
+        };
+        let path = if path.is_empty() {
+            "/".to_string()
+        } else {
+            path
+        };
+        let host = match endpoint.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host,
+        };
+
+        let query = signature::canonical_query(query);
+        let payload_sha256 = body
+            .as_deref()
+            .map(signature::sha256_hex)
+            .unwrap_or_else(|| EMPTY_PAYLOAD_SHA256.to_string());
+
+        let credentials = inner.credentials.credentials().await?;
+        headers.insert("host".to_string(), host.clone());
+        signature::sign(
+            &credentials,
+            &inner.region,
+            "s3",
+            DateTime::<Utc>::from(SystemTime::now()),
+            method.as_str(),
+            &path,
+            &query,
+            &payload_sha256,
+            &mut headers,
+        );
+
+        let mut url = format!("{}://{}{}", endpoint.scheme(), host, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query);
+        }
+
+        let mut request = inner.http.request(method, &url);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+
+        let resp = within(inner.request_timeout, request.send()).await?;
+
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp);
+        }
+
+        let body = read_body(resp, inner.request_timeout)
+            .await
+            .unwrap_or_default();
+        let error = xml_records(&body, "Error")
+            .ok()
+            .and_then(|mut e| e.pop())
+            .unwrap_or_default();
+        Err(service_error(status.as_u16(), error))
+    }
+}
+
+/// Wait for `future` at most `timeout`.
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, reqwest::Error>>,
+) -> Result<T, S3Error> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| S3Error::Timeout(timeout))?
+            .map_err(S3Error::from),
+        None => Ok(future.await?),
+    }
+}
+
+/// Whole body of `resp`, waiting at most `timeout` for each of its chunks.
+async fn read_body(mut resp: Response, timeout: Option<Duration>) -> Result<Vec<u8>, S3Error> {
+    let mut body = Vec::with_capacity(resp.content_length().unwrap_or(0) as usize);
+    while let Some(chunk) = within(timeout, resp.chunk()).await? {
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+fn service_error(status: u16, mut error: HashMap<String, String>) -> S3Error {
+    S3Error::Service {
+        status,
+        code: error.remove("Code").unwrap_or_else(|| status.to_string()),
+        message: error.remove("Message").unwrap_or_default(),
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+/// Text of the direct children of every `record` element of an XML document.
+fn xml_records(xml: &[u8], record: &str) -> Result<Vec<HashMap<String, String>>, S3Error> {
+    let mut records = Vec::new();
+    let mut current: Option<(usize, HashMap<String, String>)> = None;
+    let mut child: Option<String> = None;
+    let mut depth = 0;
+
+    if xml.is_empty() {
+        return Ok(records);
+    }
+
+    for event in EventReader::new(xml) {
+        match event.map_err(|e| S3Error::InvalidResponse(format!("{}", e)))? {
+            XmlEvent::StartElement { name, .. } => {
+                depth += 1;
+                match &current {
+                    None if name.local_name == record => current = Some((depth, HashMap::new())),
+                    Some((record_depth, _)) if depth == record_depth + 1 => {
+                        child = Some(name.local_name)
+                    }
+                    _ => {}
+                }
+            }
+            XmlEvent::Characters(text) | XmlEvent::CData(text) => {
+                if let (Some((_, fields)), Some(child)) = (&mut current, &child) {
+                    fields.entry(child.clone()).or_default().push_str(&text);
+                }
+            }
+            XmlEvent::EndElement { .. } => {
+                match &current {
+                    Some((record_depth, _)) if depth == *record_depth => {
+                        records.extend(current.take().map(|(_, fields)| fields))
+                    }
+                    Some((record_depth, _)) if depth == record_depth + 1 => child = None,
+                    _ => {}
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::stub::{Response, StoredObject, Stub};
+
+    const MD5: &str = "781e5e245d69b566979b86e28d23f2c7";
+
+    #[test]
+    fn payload_md5_reads_plain_etags() {
+        let quoted = format!("\"{}\"", MD5);
+        assert_eq!(payload_md5(Some(&quoted), None).as_deref(), Some(MD5));
+        assert_eq!(
+            payload_md5(Some(&MD5.to_ascii_uppercase()), Some("AES256")).as_deref(),
+            Some(MD5)
+        );
+    }
+
+    #[test]
+    fn payload_md5_ignores_other_etags() {
+        let quoted = format!("\"{}\"", MD5);
+        assert_eq!(payload_md5(Some(&quoted), Some("aws:kms")), None);
+        assert_eq!(payload_md5(Some(&quoted), Some("aws:kms:dsse")), None);
+        assert_eq!(payload_md5(Some(&format!("\"{}-3\"", MD5)), None), None);
+        assert_eq!(payload_md5(Some("\"not-an-md5\""), None), None);
+        assert_eq!(payload_md5(Some(&"z".repeat(32)), None), None);
+        assert_eq!(payload_md5(None, None), None);
+    }
+
+    #[tokio::test]
+    async fn head_object_reads_the_encryption() {
+        let stub = Stub::start(|_| {
+            Response::ok()
+                .header("content-length", "10")
+                .header("etag", &format!("\"{}\"", MD5))
+                .header("x-amz-server-side-encryption", "aws:kms")
+                .header("x-amz-meta-orthanc-type", "dicom")
+        })
+        .await;
+
+        let head = stub.s3_client().head_object("bucket", "key").await.unwrap();
+
+        assert_eq!(head.content_length, Some(10));
+        assert_eq!(head.server_side_encryption.as_deref(), Some("aws:kms"));
+        assert_eq!(head.md5(), None);
+        assert_eq!(head.metadata["orthanc-type"], "dicom");
+        assert_eq!(stub.requests()[0].method, "HEAD");
+    }
+
+    #[tokio::test]
+    async fn stalled_bodies_time_out() {
+        //
+        // Announces a body of 10 bytes and stalls after the first 5
+        //
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhello")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+
+        let client = S3Client::new(
+            reqwest::Client::new(),
+            CredentialsProvider::from_keys("AKIDEXAMPLE", "secret"),
+            ClientOptions {
+                endpoint: format!("http://{}", address),
+                region: "us-east-1".to_string(),
+                path_style: true,
+                request_timeout: Some(Duration::from_millis(200)),
+            },
+        )
+        .unwrap();
+
+        let started = std::time::Instant::now();
+        let result = client.get_object("bucket", "key", None).await;
+
+        assert!(matches!(result, Err(S3Error::Timeout(_))), "{:?}", result);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let retries = || {
+            METRICS
+                .snapshot()
+                .into_iter()
+                .find(|(name, _)| name == "s3_retries_total")
+                .map(|(_, value)| value)
+                .unwrap()
+        };
+
+        //
+        // Throttled twice, then accepted
+        //
+        let counter = std::sync::atomic::AtomicUsize::new(0);
+        let throttling =
+            Stub::start(
+                move |_| match counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                    0 | 1 => Response::status(503)
+                        .body("<Error><Code>SlowDown</Code><Message>slow down</Message></Error>"),
+                    _ => Response::ok().header("etag", &format!("\"{}\"", MD5)),
+                },
+            )
+            .await;
+        let before = retries();
+        throttling
+            .s3_client()
+            .put_object(
+                "bucket",
+                "key",
+                b"0123456789".to_vec(),
+                PutOptions::default(),
+            )
+            .await
+            .unwrap();
+        let requests = throttling.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests.iter().all(|r| r.body == b"0123456789"));
+        assert!(retries() >= before + 2.0);
+
+        //
+        // Client errors are final
+        //
+        let stub = Stub::start(|_| Response::status(403)).await;
+        let result = stub.s3_client().delete_object("bucket", "key").await;
+        assert!(matches!(result, Err(S3Error::Service { status: 403, .. })));
+        assert_eq!(stub.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_give_up_after_the_last_attempt() {
+        let stub = Stub::start(|_| Response::status(500)).await;
+
+        let result = stub.s3_client().head_object("bucket", "key").await;
+
+        assert!(matches!(result, Err(S3Error::Service { status: 500, .. })));
+        assert_eq!(stub.requests().len(), MAX_ATTEMPTS as usize);
+    }
+
+    #[tokio::test]
+    async fn list_objects_pages_through_the_bucket() {
+        let stub = Stub::start(|request| {
+            if request.target.contains("continuation-token=page%202") {
+                Response::ok().body(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                     <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                     <Name>bucket</Name><Prefix>dicom/</Prefix><KeyCount>1</KeyCount>\
+                     <IsTruncated>false</IsTruncated>\
+                     <NextContinuationToken>ignored</NextContinuationToken>\
+                     <Contents><Key>dicom/c</Key><Size>3</Size></Contents>\
+                     </ListBucketResult>",
+                )
+            } else {
+                Response::ok().body(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                     <ListBucketResult xmlns=\"http://s3.amazonaws.com/doc/2006-03-01/\">\
+                     <Name>bucket</Name><Prefix>dicom/</Prefix><KeyCount>2</KeyCount>\
+                     <IsTruncated>true</IsTruncated>\
+                     <NextContinuationToken>page 2</NextContinuationToken>\
+                     <Contents><Key>dicom/a</Key><LastModified>2022-01-01T00:00:00.000Z</LastModified>\
+                     <ETag>&quot;781e5e245d69b566979b86e28d23f2c7&quot;</ETag><Size>10</Size>\
+                     <StorageClass>STANDARD</StorageClass></Contents>\
+                     <Contents><Key>dicom/b &amp; c</Key><Size>0</Size></Contents>\
+                     </ListBucketResult>",
+                )
+            }
+        })
+        .await;
+        let client = stub.s3_client();
+
+        let first = client
+            .list_objects("bucket", Some("dicom/"), None)
+            .await
+            .unwrap();
+        assert_eq!(first.next_continuation_token.as_deref(), Some("page 2"));
+        assert_eq!(first.objects.len(), 2);
+        assert_eq!(first.objects[0].key, "dicom/a");
+        assert_eq!(first.objects[0].size, 10);
+        assert_eq!(
+            first.objects[0].last_modified.as_deref(),
+            Some("2022-01-01T00:00:00.000Z")
+        );
+        assert_eq!(
+            first.objects[0].e_tag.as_deref(),
+            Some(format!("\"{}\"", MD5).as_str())
+        );
+        assert_eq!(first.objects[1].key, "dicom/b & c");
+        assert_eq!(first.objects[1].last_modified, None);
+
+        let second = client
+            .list_objects("bucket", Some("dicom/"), first.next_continuation_token)
+            .await
+            .unwrap();
+        assert_eq!(second.next_continuation_token, None);
+        assert_eq!(second.objects.len(), 1);
+        assert_eq!(second.objects[0].key, "dicom/c");
+
+        let requests = stub.requests();
+        assert_eq!(requests[0].path(), "/bucket");
+        assert!(requests[0].target.contains("list-type=2"));
+        assert!(requests[0].target.contains("prefix=dicom%2F"));
+        assert!(!requests[0].target.contains("continuation-token"));
+        assert!(requests[1].target.contains("continuation-token=page%202"));
+    }
+
+    #[tokio::test]
+    async fn list_objects_rejects_malformed_listings() {
+        let stub = Stub::start(|_| Response::ok().body("<ListBucketResult><Contents>")).await;
+
+        let result = stub.s3_client().list_objects("bucket", None, None).await;
+
+        assert!(matches!(result, Err(S3Error::InvalidResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn list_buckets_reads_their_names() {
+        let stub = Stub::start(|_| {
+            Response::ok().body(
+                "<ListAllMyBucketsResult><Owner><ID>owner</ID></Owner><Buckets>\
+                 <Bucket><Name>orthanc</Name><CreationDate>2022-01-01T00:00:00.000Z</CreationDate></Bucket>\
+                 <Bucket><Name>backups</Name></Bucket>\
+                 </Buckets></ListAllMyBucketsResult>",
+            )
+        })
+        .await;
+
+        let buckets = stub.s3_client().list_buckets().await.unwrap();
+
+        assert_eq!(buckets, ["orthanc", "backups"]);
+        assert_eq!(stub.requests()[0].method, "GET");
+        assert_eq!(stub.requests()[0].path(), "/");
+    }
+
+    #[tokio::test]
+    async fn copy_object_replaces_the_metadata() {
+        let (stub, bucket) = Stub::bucket().await;
+        bucket.lock().unwrap().insert(
+            "source key".to_string(),
+            StoredObject {
+                body: b"DICM".to_vec(),
+                headers: BTreeMap::from([(
+                    "x-amz-meta-orthanc-type".to_string(),
+                    "dicom".to_string(),
+                )]),
+                last_modified: chrono::Utc::now(),
+            },
+        );
+
+        stub.s3_client()
+            .copy_object(
+                "bucket",
+                "source key",
+                "copy",
+                &HashMap::from([("Deleted-At".to_string(), "2022-01-01".to_string())]),
+            )
+            .await
+            .unwrap();
+
+        let request = &stub.requests()[0];
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path(), "/bucket/copy");
+        assert_eq!(request.headers["x-amz-copy-source"], "/bucket/source%20key");
+        assert_eq!(request.headers["x-amz-metadata-directive"], "REPLACE");
+        let objects = bucket.lock().unwrap();
+        assert_eq!(objects["copy"].body, b"DICM");
+        assert_eq!(
+            objects["copy"].headers,
+            BTreeMap::from([(
+                "x-amz-meta-deleted-at".to_string(),
+                "2022-01-01".to_string()
+            )])
+        );
+        assert!(objects.contains_key("source key"));
+    }
+
+    #[tokio::test]
+    async fn copy_object_reads_errors_sent_after_the_status() {
+        let stub = Stub::start(|_| {
+            Response::ok().body(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                 <Error><Code>InternalError</Code><Message>copy failed</Message></Error>",
+            )
+        })
+        .await;
+
+        let result = stub
+            .s3_client()
+            .copy_object("bucket", "source", "copy", &HashMap::new())
+            .await;
+
+        match result {
+            Err(S3Error::Service {
+                status,
+                code,
+                message,
+            }) => {
+                assert_eq!(status, 500);
+                assert_eq!(code, "InternalError");
+                assert_eq!(message, "copy failed");
+            }
+            result => panic!("unexpected copy result {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_object_removes_the_object() {
+        let (stub, bucket) = Stub::bucket().await;
+        let client = stub.s3_client();
+        client
+            .put_object("bucket", "key", b"DICM".to_vec(), PutOptions::default())
+            .await
+            .unwrap();
+
+        client.delete_object("bucket", "key").await.unwrap();
+
+        assert!(bucket.lock().unwrap().is_empty());
+        let request = &stub.requests()[1];
+        assert_eq!(request.method, "DELETE");
+        assert_eq!(request.path(), "/bucket/key");
+        assert!(request.headers["authorization"].starts_with("AWS4-HMAC-SHA256 "));
+    }
+}
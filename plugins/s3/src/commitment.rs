@@ -0,0 +1,365 @@
+use std::error::Error;
+
+use md5::{Digest, Md5};
+use orthanc_plugin_bindings::{
+    api::OrthancApi,
+    storage_commitment::{
+        FailureReason, StorageCommitmentHandler, StorageCommitmentLookup, StorageCommitmentRequest,
+    },
+};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    client::{S3Client, S3Error},
+    layout::object_key,
+    limits::Lane,
+    metrics::{self, Operation, METRICS},
+    reader::InstanceReader,
+};
+
+/// `/tools/lookup`
+#[derive(Deserialize)]
+struct LookupResult {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(rename = "Type")]
+    resource_type: String,
+}
+
+/// Storage commitment SCP committing an instance only once its DICOM file is verifiably in the
+/// bucket: its object exists with the size and MD5 recorded by Orthanc.
+///
+/// Instances that are not uploaded yet, or whose object does not match, are reported as a
+/// processing failure so that the modality keeps its copy and asks again later.
+#[derive(Clone)]
+pub struct StorageCommitment {
+    pub reader: InstanceReader,
+}
+
+impl StorageCommitment {
+    /// Status of the instance `sop_instance_uid`, stored with the SOP class `sop_class_uid`.
+    pub fn verify(
+        &self,
+        sop_class_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FailureReason, Box<dyn Error>> {
+        let api = OrthancApi::new(self.reader.context);
+        let matches: Vec<LookupResult> =
+            serde_json::from_slice(&api.post("/tools/lookup", sop_instance_uid.as_bytes())?)?;
+        let id = match matches
+            .into_iter()
+            .find(|result| result.resource_type == "Instance")
+        {
+            Some(result) => result.id,
+            None => return Ok(FailureReason::NoSuchObjectInstance),
+        };
+
+        //
+        // Instances stored before Orthanc recorded their SOP class are not checked
+        //
+        match api.get(&format!("/instances/{}/metadata/SopClassUid", id)) {
+            Ok(stored) if String::from_utf8_lossy(&stored).trim() != sop_class_uid => {
+                return Ok(FailureReason::ClassInstanceConflict)
+            }
+            Ok(_) => {}
+            Err(e) if e.is_not_found() => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let info = api.attachment_info("instances", &id, "dicom")?;
+        let expected_md5 = match info.compressed_md5 {
+            Some(md5) => md5,
+            None => {
+                warn!(
+                    "Orthanc has no MD5 of {}, enable \"StoreMD5ForAttachments\" to commit it",
+                    sop_instance_uid
+                );
+                return Ok(FailureReason::ProcessingFailure);
+            }
+        };
+
+        if self
+            .reader
+            .staging
+            .as_ref()
+            .is_some_and(|staging| staging.open(&info.uuid).is_some())
+        {
+            debug!("{} is not uploaded yet", sop_instance_uid);
+            return Ok(FailureReason::ProcessingFailure);
+        }
+
+        let _permit = self
+            .reader
+            .runtime
+            .block_on(self.reader.limits.acquire(Lane::Read))?;
+        let key = object_key(&info.uuid);
+        let check = self.reader.runtime.block_on(check_object(
+            &self.reader.s3,
+            &self.reader.bucket,
+            &key,
+            info.compressed_size,
+            &expected_md5,
+        ))?;
+
+        match check {
+            ObjectCheck::Verified => Ok(FailureReason::Success),
+            ObjectCheck::Missing => {
+                warn!("object {} of {} is missing", key, sop_instance_uid);
+                Ok(FailureReason::ProcessingFailure)
+            }
+            ObjectCheck::Size(size) => {
+                warn!(
+                    "object {} of {} holds {:?} bytes, Orthanc stored {}",
+                    key, sop_instance_uid, size, info.compressed_size
+                );
+                Ok(FailureReason::ProcessingFailure)
+            }
+            ObjectCheck::Md5(actual_md5) => {
+                warn!(
+                    "object {} of {} has the MD5 {}, Orthanc stored {}",
+                    key, sop_instance_uid, actual_md5, expected_md5
+                );
+                Ok(FailureReason::ProcessingFailure)
+            }
+        }
+    }
+}
+
+/// Outcome of the comparison of an object with the attachment recorded by Orthanc.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ObjectCheck {
+    Verified,
+    Missing,
+    /// The object holds a different number of bytes, if known.
+    Size(Option<i64>),
+    /// The content of the object has a different MD5.
+    Md5(String),
+}
+
+/// Compare the object `key` with the `size` bytes of MD5 `md5` stored by Orthanc, i.e. its
+/// compressed size and MD5 when Orthanc compresses attachments.
+///
+/// The ETag is the MD5 of the content, except for multipart uploads and objects encrypted with
+/// KMS, which are downloaded to compute it. A mismatching ETag is confirmed the same way, as some
+/// stores do not report how objects are encrypted.
+async fn check_object(
+    s3: &S3Client,
+    bucket: &str,
+    key: &str,
+    size: u64,
+    md5: &str,
+) -> Result<ObjectCheck, S3Error> {
+    let head = match metrics::timed(Operation::HeadObject, s3.head_object(bucket, key)).await {
+        Ok(head) => head,
+        Err(e) if e.is_not_found() => return Ok(ObjectCheck::Missing),
+        Err(e) => return Err(e),
+    };
+
+    if head.content_length != Some(size as i64) {
+        return Ok(ObjectCheck::Size(head.content_length));
+    }
+    if head
+        .md5()
+        .is_some_and(|e_tag| e_tag.eq_ignore_ascii_case(md5))
+    {
+        return Ok(ObjectCheck::Verified);
+    }
+
+    let content = metrics::timed(Operation::GetObject, s3.get_object(bucket, key, None)).await?;
+    METRICS.add_downloaded_bytes(content.len() as u64);
+    let actual = hex::encode(Md5::digest(&content));
+    if actual.eq_ignore_ascii_case(md5) {
+        Ok(ObjectCheck::Verified)
+    } else {
+        Ok(ObjectCheck::Md5(actual))
+    }
+}
+
+/// Instances of a storage commitment request, verified one by one.
+struct Transaction {
+    commitment: StorageCommitment,
+    uid: String,
+    remote_aet: String,
+    committed: usize,
+    failed: usize,
+}
+
+impl StorageCommitmentLookup for Transaction {
+    fn lookup(
+        &mut self,
+        sop_class_uid: &str,
+        sop_instance_uid: &str,
+    ) -> Result<FailureReason, Box<dyn Error>> {
+        //
+        // A failed verification is reported to the modality rather than failing the whole job
+        //
+        let reason = match self.commitment.verify(sop_class_uid, sop_instance_uid) {
+            Ok(reason) => reason,
+            Err(e) => {
+                warn!("unable to verify {} - {}", sop_instance_uid, e);
+                FailureReason::ProcessingFailure
+            }
+        };
+
+        match reason {
+            FailureReason::Success => self.committed += 1,
+            _ => {
+                debug!("not committing {} - {:?}", sop_instance_uid, reason);
+                self.failed += 1
+            }
+        }
+        Ok(reason)
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        info!(
+            "storage commitment {} from {}: {} committed, {} failed",
+            self.uid, self.remote_aet, self.committed, self.failed
+        );
+    }
+}
+
+impl StorageCommitmentHandler for StorageCommitment {
+    fn create(
+        &self,
+        request: &StorageCommitmentRequest,
+    ) -> Result<Box<dyn StorageCommitmentLookup>, Box<dyn Error>> {
+        debug!(
+            "storage commitment {} from {} for {} instances, job {}",
+            request.transaction_uid,
+            request.remote_aet,
+            request.instances.len(),
+            request.job_id
+        );
+        Ok(Box::new(Transaction {
+            commitment: self.clone(),
+            uid: request.transaction_uid.clone(),
+            remote_aet: request.remote_aet.clone(),
+            committed: 0,
+            failed: 0,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{Response, Stub};
+
+    /// Content of the object, e.g. an attachment compressed by Orthanc.
+    const CONTENT: &[u8] = b"compressed attachment";
+
+    /// An MD5 that is not the one of `CONTENT`.
+    const OTHER_MD5: &str = "00000000000000000000000000000000";
+
+    fn content_md5() -> String {
+        hex::encode(Md5::digest(CONTENT))
+    }
+
+    /// Bucket holding `CONTENT`, answering HEAD requests with `headers`.
+    async fn bucket(headers: Vec<(&'static str, String)>) -> Stub {
+        Stub::start(move |request| match request.method.as_str() {
+            "HEAD" => headers.iter().fold(
+                Response::ok().header("content-length", &CONTENT.len().to_string()),
+                |response, (name, value)| response.header(name, value),
+            ),
+            "GET" => Response::ok().body(CONTENT),
+            _ => Response::status(405),
+        })
+        .await
+    }
+
+    async fn check(stub: &Stub, expected_md5: &str) -> ObjectCheck {
+        check_object(
+            &stub.s3_client(),
+            "bucket",
+            "key",
+            CONTENT.len() as u64,
+            expected_md5,
+        )
+        .await
+        .unwrap()
+    }
+
+    fn methods(stub: &Stub) -> Vec<String> {
+        stub.requests()
+            .into_iter()
+            .map(|request| request.method)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn verifies_the_etag_without_downloading() {
+        let stub = bucket(vec![("etag", format!("\"{}\"", content_md5()))]).await;
+
+        assert_eq!(check(&stub, &content_md5()).await, ObjectCheck::Verified);
+        assert_eq!(methods(&stub), vec!["HEAD"]);
+    }
+
+    #[tokio::test]
+    async fn downloads_kms_encrypted_objects() {
+        //
+        // The ETag of an SSE-KMS object has the length of an MD5 without being one
+        //
+        let stub = bucket(vec![
+            ("etag", format!("\"{}\"", OTHER_MD5)),
+            ("x-amz-server-side-encryption", "aws:kms".to_owned()),
+        ])
+        .await;
+
+        assert_eq!(check(&stub, &content_md5()).await, ObjectCheck::Verified);
+        assert_eq!(methods(&stub), vec!["HEAD", "GET"]);
+    }
+
+    #[tokio::test]
+    async fn downloads_multipart_objects() {
+        let stub = bucket(vec![("etag", format!("\"{}-2\"", OTHER_MD5))]).await;
+
+        assert_eq!(check(&stub, &content_md5()).await, ObjectCheck::Verified);
+        assert_eq!(methods(&stub), vec!["HEAD", "GET"]);
+    }
+
+    #[tokio::test]
+    async fn confirms_a_mismatching_etag_with_the_content() {
+        let stub = bucket(vec![("etag", format!("\"{}\"", OTHER_MD5))]).await;
+
+        assert_eq!(check(&stub, &content_md5()).await, ObjectCheck::Verified);
+        assert_eq!(methods(&stub), vec!["HEAD", "GET"]);
+    }
+
+    #[tokio::test]
+    async fn reports_content_of_another_md5() {
+        let stub = bucket(vec![
+            ("etag", format!("\"{}\"", content_md5())),
+            ("x-amz-server-side-encryption", "aws:kms".to_owned()),
+        ])
+        .await;
+
+        assert_eq!(
+            check(&stub, OTHER_MD5).await,
+            ObjectCheck::Md5(content_md5())
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_objects_of_another_size() {
+        let stub = bucket(vec![("etag", format!("\"{}\"", content_md5()))]).await;
+
+        let check = check_object(&stub.s3_client(), "bucket", "key", 3, &content_md5())
+            .await
+            .unwrap();
+
+        assert_eq!(check, ObjectCheck::Size(Some(CONTENT.len() as i64)));
+        assert_eq!(methods(&stub), vec!["HEAD"]);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_objects() {
+        let stub = Stub::start(|_| Response::status(404)).await;
+
+        assert_eq!(check(&stub, &content_md5()).await, ObjectCheck::Missing);
+    }
+}
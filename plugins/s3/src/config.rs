@@ -1,11 +1,190 @@
-use serde::Deserialize;
+use std::path::PathBuf;
 
-#[derive(Deserialize, Debug, Clone)]
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::credentials::CredentialSource;
+
+/// Configuration value that must never show up in logs or diagnostics.
+#[derive(Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("\"<redacted>\"")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+/// Proxy url, `http://[user:password@]host:port`, whose credentials never show up in logs or
+/// diagnostics.
+#[derive(Deserialize, Clone)]
+#[serde(transparent)]
+pub struct ProxyUrl(String);
+
+impl ProxyUrl {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// The url with its user and password replaced by `<redacted>`.
+    pub fn redacted(&self) -> String {
+        let authority = self.0.find("://").map(|i| i + 3).unwrap_or(0);
+        let end = self.0[authority..]
+            .find('/')
+            .map(|i| authority + i)
+            .unwrap_or(self.0.len());
+        match self.0[authority..end].rfind('@') {
+            Some(at) => format!(
+                "{}<redacted>{}",
+                &self.0[..authority],
+                &self.0[authority + at..]
+            ),
+            None => self.0.to_owned(),
+        }
+    }
+}
+
+impl std::fmt::Debug for ProxyUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.redacted())
+    }
+}
+
+impl Serialize for ProxyUrl {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.redacted())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
     pub s3_endpoint: String,
+    /// Address buckets as `<endpoint>/<bucket>` rather than `<bucket>.<endpoint>`.
+    #[serde(default = "default_force_path_style")]
+    pub s3_force_path_style: bool,
+    /// PEM bundle of additional certificate authorities, e.g. an internal CA.
+    pub s3_ca_bundle: Option<PathBuf>,
+    /// PEM client certificate and key for endpoints requiring mutual TLS.
+    pub s3_client_certificate: Option<PathBuf>,
+    pub s3_client_key: Option<PathBuf>,
+    pub s3_connect_timeout_secs: Option<u64>,
+    /// Maximum time to wait for the response of a request, then for each chunk of its body.
+    pub s3_request_timeout_secs: Option<u64>,
+    pub s3_pool_idle_timeout_secs: Option<u64>,
+    /// HTTP proxy, `http://[user:password@]host:port`.
+    pub s3_proxy: Option<ProxyUrl>,
+    #[serde(default)]
+    pub s3_credentials: CredentialSource,
+    /// Only used with static credentials.
+    #[serde(default)]
     pub s3_access_key: String,
-    pub s3_secret_key: String,
+    #[serde(default)]
+    pub s3_secret_key: Secret,
+    /// Profile of the AWS credentials file, for the `profile` and `chain` credentials.
+    pub s3_profile: Option<String>,
+    /// Directory holding the key files, for the `file` credentials.
+    pub s3_credentials_directory: Option<PathBuf>,
+    /// STS endpoint used to exchange web identity tokens.
+    pub s3_sts_endpoint: Option<String>,
+    /// Instance metadata service as `<ip>:<port>`, e.g. a local stand-in.
+    pub s3_metadata_endpoint: Option<String>,
     pub s3_bucket: String,
     pub s3_region: String,
+    /// Interval between scheduled storage audits, disabled when unset.
+    pub s3_audit_interval_secs: Option<u64>,
+    /// Delete orphaned objects during scheduled audits.
+    #[serde(default)]
+    pub s3_audit_delete_orphans: bool,
+    /// Minimum age of an orphaned object before an audit deletes it.
+    #[serde(default = "default_audit_grace_period_secs")]
+    pub s3_audit_grace_period_secs: u64,
+    /// Keep removed attachments in the trash for this many days, removal is permanent when unset.
+    pub s3_trash_retention_days: Option<u64>,
+    /// Interval between sweeps purging expired objects from the trash.
+    #[serde(default = "default_trash_sweep_interval_secs")]
+    pub s3_trash_sweep_interval_secs: u64,
+    /// Maximum number of concurrent reads from the bucket, unbounded when unset.
+    pub s3_max_concurrent_reads: Option<usize>,
+    /// Maximum number of concurrent uploads to the bucket, unbounded when unset.
+    pub s3_max_concurrent_writes: Option<usize>,
+    /// Maximum number of concurrent removals from the bucket, unbounded when unset.
+    pub s3_max_concurrent_removes: Option<usize>,
+    /// Fail a storage request that waited this long for a free slot, waits forever when unset.
+    pub s3_queue_timeout_secs: Option<u64>,
+    /// Stage new attachments in this directory and upload them in the background.
+    pub s3_staging_directory: Option<PathBuf>,
+    /// Number of concurrent background uploads of staged attachments.
+    #[serde(default = "default_staging_workers")]
+    pub s3_staging_workers: usize,
+    /// Maximum time the plugin waits for pending uploads when Orthanc stops.
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub s3_shutdown_timeout_secs: u64,
+    /// SQLite index answering C-FIND and C-MOVE from the bucket, Orthanc answers them when unset.
+    pub s3_query_retrieve_index: Option<PathBuf>,
+    /// Maximum number of answers to a C-FIND, the answer is marked incomplete beyond.
+    pub s3_query_retrieve_max_answers: Option<usize>,
+    /// Mount the bucket as a WebDAV collection at this URI, e.g. `/s3-webdav`, disabled when unset.
+    pub s3_webdav_uri: Option<String>,
+    /// Commit instances to storage commitment SCUs only once they are verified in the bucket.
+    #[serde(default)]
+    pub s3_storage_commitment: bool,
+}
+
+fn default_force_path_style() -> bool {
+    true
+}
+
+fn default_audit_grace_period_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_trash_sweep_interval_secs() -> u64 {
+    60 * 60
 }
 
+fn default_staging_workers() -> usize {
+    4
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_credentials_are_redacted() {
+        let proxy = ProxyUrl("http://user:p@ss@proxy:3128/path@x".to_string());
+        assert_eq!(proxy.redacted(), "http://<redacted>@proxy:3128/path@x");
+        assert_eq!(
+            format!("{:?}", proxy),
+            "\"http://<redacted>@proxy:3128/path@x\""
+        );
+        assert_eq!(
+            serde_json::to_string(&proxy).unwrap(),
+            "\"http://<redacted>@proxy:3128/path@x\""
+        );
+        assert_eq!(proxy.expose(), "http://user:p@ss@proxy:3128/path@x");
+    }
+
+    #[test]
+    fn proxies_without_credentials_are_kept() {
+        let proxy = ProxyUrl("http://proxy:3128".to_string());
+        assert_eq!(proxy.redacted(), "http://proxy:3128");
+        assert_eq!(ProxyUrl("proxy:3128".to_string()).redacted(), "proxy:3128");
+    }
+}
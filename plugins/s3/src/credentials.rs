@@ -0,0 +1,1150 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::{config::Config, transport};
+
+const DEFAULT_STS_ENDPOINT: &str = "https://sts.amazonaws.com";
+
+/// Instance metadata service of EC2.
+const DEFAULT_METADATA_ENDPOINT: &str = "169.254.169.254:80";
+
+/// Container credentials endpoint of ECS, for `AWS_CONTAINER_CREDENTIALS_RELATIVE_URI`.
+const CONTAINER_ENDPOINT: &str = "http://169.254.170.2";
+
+/// Lifetime requested for the session tokens of the instance metadata service (IMDSv2).
+const METADATA_TOKEN_TTL_SECS: u32 = 21600;
+
+/// Credentials are renewed when they expire within this margin.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// Credentials without expiry, e.g. of the environment or a profile found by the chain, are
+/// resolved again after this interval so that rotated keys are picked up.
+const RERESOLVE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Timeout of the requests to the credential endpoints and to STS.
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{0}")]
+pub struct CredentialsError(String);
+
+impl CredentialsError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+}
+
+/// AWS access key, with its session token when it is temporary.
+#[derive(Clone)]
+pub struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Credentials {
+    pub fn new(
+        access_key_id: impl Into<String>,
+        secret_access_key: impl Into<String>,
+        token: Option<String>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            access_key_id: access_key_id.into(),
+            secret_access_key: secret_access_key.into(),
+            token,
+            expires_at,
+        }
+    }
+
+    pub fn access_key_id(&self) -> &str {
+        &self.access_key_id
+    }
+
+    pub fn secret_access_key(&self) -> &str {
+        &self.secret_access_key
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.expires_at
+    }
+
+    /// Whether the credentials expire within `margin`, credentials without expiry never do:
+    /// [`AutoRefreshingProvider`] resolves those again after [`RERESOLVE_INTERVAL`].
+    fn expires_within(&self, margin: Duration) -> bool {
+        let deadline = DateTime::<Utc>::from(SystemTime::now() + margin);
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= deadline)
+    }
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("expires_at", &self.expires_at)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+pub trait ProvideCredentials: Send + Sync {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError>;
+}
+
+/// Where the plugin obtains its S3 credentials from (`S3_CREDENTIALS`).
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CredentialSource {
+    /// `S3_ACCESS_KEY` and `S3_SECRET_KEY`.
+    #[default]
+    Static,
+    /// Environment, profile files, container and instance metadata, in that order.
+    Chain,
+    /// A profile of `~/.aws/credentials`, `S3_PROFILE` or `default`.
+    Profile,
+    /// `AssumeRoleWithWebIdentity` with a projected service account token (e.g. IRSA).
+    WebIdentity,
+    /// The ECS/EKS container credentials endpoint (`AWS_CONTAINER_CREDENTIALS_*_URI`).
+    Container,
+    /// The EC2 instance metadata service.
+    InstanceMetadata,
+    /// Key files in `S3_CREDENTIALS_DIRECTORY`, reloaded whenever they are rotated.
+    File,
+}
+
+/// Credentials provider selected by the configuration.
+///
+/// Providers whose credentials expire are wrapped in an [`AutoRefreshingProvider`], so the
+/// credentials are cached and renewed shortly before they expire. The client owning this
+/// provider must therefore be shared rather than created for every request.
+pub enum CredentialsProvider {
+    Static(Credentials),
+    Chain(Box<AutoRefreshingProvider<ChainProvider>>),
+    Profile(ProfileProvider),
+    WebIdentity(AutoRefreshingProvider<WebIdentityProvider>),
+    Container(AutoRefreshingProvider<ContainerProvider>),
+    InstanceMetadata(AutoRefreshingProvider<InstanceMetadataProvider>),
+    File(FileProvider),
+}
+
+impl CredentialsProvider {
+    pub fn from_config(config: &Config) -> Result<Self, CredentialsError> {
+        info!("using {:?} credentials", config.s3_credentials);
+
+        let provider = match config.s3_credentials {
+            CredentialSource::Static => {
+                if config.s3_access_key.is_empty() || config.s3_secret_key.expose().is_empty() {
+                    return Err(CredentialsError::new(
+                        "S3_ACCESS_KEY and S3_SECRET_KEY are required for static credentials",
+                    ));
+                }
+
+                Self::from_keys(&config.s3_access_key, config.s3_secret_key.expose())
+            }
+            CredentialSource::Chain => {
+                Self::Chain(Box::new(AutoRefreshingProvider::new(ChainProvider::new(
+                    config.s3_profile.as_deref(),
+                    config.s3_metadata_endpoint.as_deref(),
+                )?)))
+            }
+            //
+            // Profile files are read on every request so that rotated keys are picked up
+            //
+            CredentialSource::Profile => Self::Profile(ProfileProvider::new(
+                config.s3_profile.as_deref().unwrap_or("default"),
+            )?),
+            CredentialSource::WebIdentity => Self::WebIdentity(AutoRefreshingProvider::new(
+                WebIdentityProvider::from_env(config)?,
+            )),
+            CredentialSource::Container => Self::Container(AutoRefreshingProvider::new(
+                ContainerProvider::from_env()?.ok_or_else(|| {
+                    CredentialsError::new(
+                        "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI or \
+                         AWS_CONTAINER_CREDENTIALS_FULL_URI is required for container credentials",
+                    )
+                })?,
+            )),
+            CredentialSource::InstanceMetadata => {
+                Self::InstanceMetadata(AutoRefreshingProvider::new(InstanceMetadataProvider::new(
+                    config.s3_metadata_endpoint.as_deref(),
+                )?))
+            }
+            CredentialSource::File => Self::File(FileProvider::new(
+                config.s3_credentials_directory.clone().ok_or_else(|| {
+                    CredentialsError::new(
+                        "S3_CREDENTIALS_DIRECTORY is required for file credentials",
+                    )
+                })?,
+            )),
+        };
+
+        Ok(provider)
+    }
+
+    /// Static credentials, without a session token.
+    pub fn from_keys(access_key: &str, secret_key: &str) -> Self {
+        Self::Static(Credentials::new(access_key, secret_key, None, None))
+    }
+}
+
+#[async_trait]
+impl ProvideCredentials for CredentialsProvider {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        match self {
+            Self::Static(credentials) => Ok(credentials.clone()),
+            Self::Chain(provider) => provider.credentials().await,
+            Self::Profile(provider) => provider.credentials().await,
+            Self::WebIdentity(provider) => provider.credentials().await,
+            Self::Container(provider) => provider.credentials().await,
+            Self::InstanceMetadata(provider) => provider.credentials().await,
+            Self::File(provider) => provider.credentials().await,
+        }
+    }
+}
+
+/// Caches the credentials of a provider and renews them shortly before they expire, or after
+/// [`RERESOLVE_INTERVAL`] when they do not expire.
+pub struct AutoRefreshingProvider<P> {
+    provider: P,
+    cached: tokio::sync::Mutex<Option<(Instant, Credentials)>>,
+    reresolve_interval: Duration,
+}
+
+impl<P: ProvideCredentials> AutoRefreshingProvider<P> {
+    pub fn new(provider: P) -> Self {
+        Self {
+            provider,
+            cached: tokio::sync::Mutex::new(None),
+            reresolve_interval: RERESOLVE_INTERVAL,
+        }
+    }
+
+    fn is_fresh(&self, resolved: Instant, credentials: &Credentials) -> bool {
+        match credentials.expires_at() {
+            Some(_) => !credentials.expires_within(REFRESH_MARGIN),
+            None => resolved.elapsed() < self.reresolve_interval,
+        }
+    }
+}
+
+#[async_trait]
+impl<P: ProvideCredentials> ProvideCredentials for AutoRefreshingProvider<P> {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        //
+        // Held while refreshing, so that concurrent requests wait for a single renewal
+        //
+        let mut cached = self.cached.lock().await;
+        match cached.as_ref() {
+            Some((resolved, credentials)) if self.is_fresh(*resolved, credentials) => {
+                Ok(credentials.clone())
+            }
+            _ => {
+                let credentials = self.provider.credentials().await?;
+                debug!(
+                    "renewed credentials, expiring at {:?}",
+                    credentials.expires_at()
+                );
+                *cached = Some((Instant::now(), credentials.clone()));
+                Ok(credentials)
+            }
+        }
+    }
+}
+
+/// `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and optionally `AWS_SESSION_TOKEN`.
+pub struct EnvironmentProvider;
+
+#[async_trait]
+impl ProvideCredentials for EnvironmentProvider {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key), Some(secret_key)) => Ok(Credentials::new(
+                access_key,
+                secret_key,
+                var("AWS_SESSION_TOKEN"),
+                None,
+            )),
+            _ => Err(CredentialsError::new(
+                "AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY are not set",
+            )),
+        }
+    }
+}
+
+/// A profile of the shared credentials file, `AWS_SHARED_CREDENTIALS_FILE` or
+/// `~/.aws/credentials`.
+pub struct ProfileProvider {
+    file: PathBuf,
+    profile: String,
+}
+
+impl ProfileProvider {
+    pub fn new(profile: &str) -> Result<Self, CredentialsError> {
+        let file = match std::env::var_os("AWS_SHARED_CREDENTIALS_FILE") {
+            Some(file) => PathBuf::from(file),
+            None => std::env::var_os("HOME")
+                .map(|home| Path::new(&home).join(".aws").join("credentials"))
+                .ok_or_else(|| {
+                    CredentialsError::new("HOME is not set, unable to locate ~/.aws/credentials")
+                })?,
+        };
+        Ok(Self::with_file(file, profile))
+    }
+
+    pub fn with_file(file: PathBuf, profile: &str) -> Self {
+        Self {
+            file,
+            profile: profile.to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl ProvideCredentials for ProfileProvider {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let content = tokio::fs::read_to_string(&self.file).await.map_err(|e| {
+            CredentialsError::new(format!("unable to read '{}' - {}", self.file.display(), e))
+        })?;
+        parse_profile(&content, &self.profile).ok_or_else(|| {
+            CredentialsError::new(format!(
+                "no credentials for profile '{}' in '{}'",
+                self.profile,
+                self.file.display()
+            ))
+        })
+    }
+}
+
+/// Credentials of `profile` in the INI content of a shared credentials file.
+fn parse_profile(content: &str, profile: &str) -> Option<Credentials> {
+    let mut section = None;
+    let mut access_key = None;
+    let mut secret_key = None;
+    let mut token = None;
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = Some(name.trim());
+            continue;
+        }
+        if section != Some(profile) {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let value = Some(value.trim().to_string());
+            match key.trim().to_ascii_lowercase().as_str() {
+                "aws_access_key_id" => access_key = value,
+                "aws_secret_access_key" => secret_key = value,
+                "aws_session_token" => token = value,
+                _ => {}
+            }
+        }
+    }
+
+    Some(Credentials::new(access_key?, secret_key?, token, None))
+}
+
+/// Temporary credentials answered by the container and instance metadata endpoints.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TemporaryCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    expiration: Option<String>,
+}
+
+impl TryFrom<TemporaryCredentials> for Credentials {
+    type Error = CredentialsError;
+
+    fn try_from(temporary: TemporaryCredentials) -> Result<Self, Self::Error> {
+        let expires_at = temporary
+            .expiration
+            .map(|expiration| {
+                DateTime::parse_from_rfc3339(&expiration)
+                    .map(|e| e.with_timezone(&Utc))
+                    .map_err(|e| CredentialsError::new(format!("invalid expiration - {}", e)))
+            })
+            .transpose()?;
+        Ok(Credentials::new(
+            temporary.access_key_id,
+            temporary.secret_access_key,
+            temporary.token,
+            expires_at,
+        ))
+    }
+}
+
+/// Client of the local credential endpoints, which are never reached through a proxy.
+fn endpoint_client() -> Result<reqwest::Client, CredentialsError> {
+    reqwest::Client::builder()
+        .no_proxy()
+        .timeout(ENDPOINT_TIMEOUT)
+        .build()
+        .map_err(|e| CredentialsError::new(format!("unable to build http client - {}", e)))
+}
+
+/// Body of a successful answer of a credential endpoint.
+async fn endpoint_text(
+    request: reqwest::RequestBuilder,
+    endpoint: &str,
+) -> Result<String, CredentialsError> {
+    let resp = request
+        .send()
+        .await
+        .map_err(|e| CredentialsError::new(format!("{} request failed - {}", endpoint, e)))?;
+    let status = resp.status();
+    let body = resp
+        .text()
+        .await
+        .map_err(|e| CredentialsError::new(format!("{} request failed - {}", endpoint, e)))?;
+    if !status.is_success() {
+        return Err(CredentialsError::new(format!(
+            "{} answered {} {}",
+            endpoint, status, body
+        )));
+    }
+    Ok(body)
+}
+
+/// The ECS/EKS container credentials endpoint.
+pub struct ContainerProvider {
+    client: reqwest::Client,
+    uri: String,
+    /// `AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE`, read again for every request.
+    token_file: Option<PathBuf>,
+    /// `AWS_CONTAINER_AUTHORIZATION_TOKEN`.
+    token: Option<String>,
+}
+
+impl ContainerProvider {
+    /// Provider configured by the environment of the container, `None` outside a container.
+    pub fn from_env() -> Result<Option<Self>, CredentialsError> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let uri = match (
+            var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+            var("AWS_CONTAINER_CREDENTIALS_FULL_URI"),
+        ) {
+            (Some(relative), _) => format!("{}{}", CONTAINER_ENDPOINT, relative),
+            (None, Some(full)) => full,
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            client: endpoint_client()?,
+            uri,
+            token_file: var("AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE").map(PathBuf::from),
+            token: var("AWS_CONTAINER_AUTHORIZATION_TOKEN"),
+        }))
+    }
+}
+
+#[async_trait]
+impl ProvideCredentials for ContainerProvider {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let token = match &self.token_file {
+            Some(file) => Some(
+                tokio::fs::read_to_string(file)
+                    .await
+                    .map_err(|e| {
+                        CredentialsError::new(format!(
+                            "unable to read '{}' - {}",
+                            file.display(),
+                            e
+                        ))
+                    })?
+                    .trim()
+                    .to_string(),
+            ),
+            None => self.token.clone(),
+        };
+
+        let mut request = self.client.get(&self.uri);
+        if let Some(token) = token {
+            request = request.header("authorization", token);
+        }
+        let body = endpoint_text(request, "container credentials").await?;
+        serde_json::from_str::<TemporaryCredentials>(&body)
+            .map_err(|e| CredentialsError::new(format!("invalid container credentials - {}", e)))?
+            .try_into()
+    }
+}
+
+/// The instance metadata service of EC2, with a session token (IMDSv2) when the service
+/// provides one.
+pub struct InstanceMetadataProvider {
+    client: reqwest::Client,
+    endpoint: String,
+}
+
+impl InstanceMetadataProvider {
+    /// `endpoint` is `<ip>:<port>`, e.g. a local stand-in, the service of EC2 when unset.
+    pub fn new(endpoint: Option<&str>) -> Result<Self, CredentialsError> {
+        let endpoint = endpoint.unwrap_or(DEFAULT_METADATA_ENDPOINT);
+        if endpoint.rsplit_once(':').is_none() {
+            return Err(CredentialsError::new(
+                "S3_METADATA_ENDPOINT must be <ip>:<port>",
+            ));
+        }
+
+        Ok(Self {
+            client: endpoint_client()?,
+            endpoint: format!("http://{}", endpoint),
+        })
+    }
+
+    /// Session token of IMDSv2, `None` when the service only supports IMDSv1.
+    async fn session_token(&self) -> Option<String> {
+        let request = self
+            .client
+            .put(format!("{}/latest/api/token", self.endpoint))
+            .header(
+                "x-aws-ec2-metadata-token-ttl-seconds",
+                METADATA_TOKEN_TTL_SECS.to_string(),
+            );
+        match endpoint_text(request, "instance metadata").await {
+            Ok(token) => Some(token.trim().to_string()),
+            Err(e) => {
+                debug!("falling back to IMDSv1 - {}", e);
+                None
+            }
+        }
+    }
+
+    async fn get(&self, path: &str, token: Option<&str>) -> Result<String, CredentialsError> {
+        let mut request = self.client.get(format!("{}{}", self.endpoint, path));
+        if let Some(token) = token {
+            request = request.header("x-aws-ec2-metadata-token", token);
+        }
+        endpoint_text(request, "instance metadata").await
+    }
+}
+
+#[async_trait]
+impl ProvideCredentials for InstanceMetadataProvider {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        const CREDENTIALS_PATH: &str = "/latest/meta-data/iam/security-credentials/";
+
+        let token = self.session_token().await;
+        let roles = self.get(CREDENTIALS_PATH, token.as_deref()).await?;
+        let role = roles
+            .lines()
+            .map(str::trim)
+            .find(|role| !role.is_empty())
+            .ok_or_else(|| CredentialsError::new("no IAM role attached to the instance"))?;
+
+        let body = self
+            .get(&format!("{}{}", CREDENTIALS_PATH, role), token.as_deref())
+            .await?;
+        serde_json::from_str::<TemporaryCredentials>(&body)
+            .map_err(|e| CredentialsError::new(format!("invalid instance credentials - {}", e)))?
+            .try_into()
+    }
+}
+
+/// Environment, profile file, container and instance metadata, the first one that provides
+/// credentials wins.
+pub struct ChainProvider {
+    profile: Option<ProfileProvider>,
+    container: Option<ContainerProvider>,
+    instance_metadata: InstanceMetadataProvider,
+}
+
+impl ChainProvider {
+    pub fn new(
+        profile: Option<&str>,
+        metadata_endpoint: Option<&str>,
+    ) -> Result<Self, CredentialsError> {
+        Ok(Self {
+            profile: ProfileProvider::new(profile.unwrap_or("default")).ok(),
+            container: ContainerProvider::from_env()?,
+            instance_metadata: InstanceMetadataProvider::new(metadata_endpoint)?,
+        })
+    }
+}
+
+#[async_trait]
+impl ProvideCredentials for ChainProvider {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let mut errors = Vec::new();
+
+        match EnvironmentProvider.credentials().await {
+            Ok(credentials) => return Ok(credentials),
+            Err(e) => errors.push(e.to_string()),
+        }
+        if let Some(profile) = &self.profile {
+            match profile.credentials().await {
+                Ok(credentials) => return Ok(credentials),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        if let Some(container) = &self.container {
+            match container.credentials().await {
+                Ok(credentials) => return Ok(credentials),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        match self.instance_metadata.credentials().await {
+            Ok(credentials) => Ok(credentials),
+            Err(e) => {
+                errors.push(e.to_string());
+                Err(CredentialsError::new(format!(
+                    "no provider in the chain has credentials - {}",
+                    errors.join(", ")
+                )))
+            }
+        }
+    }
+}
+
+/// Exchanges a web identity token for temporary credentials with STS.
+///
+/// Configured through the variables Kubernetes injects for IAM roles for service accounts:
+/// `AWS_ROLE_ARN`, `AWS_WEB_IDENTITY_TOKEN_FILE` and optionally `AWS_ROLE_SESSION_NAME`.
+/// The token file is read again for every exchange since it is rotated by the kubelet.
+pub struct WebIdentityProvider {
+    client: reqwest::Client,
+    endpoint: String,
+    role_arn: String,
+    token_file: PathBuf,
+    session_name: String,
+}
+
+impl WebIdentityProvider {
+    /// STS is reached with the TLS and proxy settings of the S3 client, `S3_STS_ENDPOINT`
+    /// overrides the global endpoint.
+    pub fn from_env(config: &Config) -> Result<Self, CredentialsError> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| CredentialsError::new(format!("{} is not set", name)))
+        };
+        let client = transport::client_builder(config)
+            .map_err(|e| CredentialsError::new(e.to_string()))?
+            .timeout(ENDPOINT_TIMEOUT)
+            .build()
+            .map_err(|e| CredentialsError::new(format!("unable to build http client - {}", e)))?;
+
+        Ok(Self::new(
+            client,
+            config
+                .s3_sts_endpoint
+                .as_deref()
+                .unwrap_or(DEFAULT_STS_ENDPOINT),
+            var("AWS_ROLE_ARN")?,
+            PathBuf::from(var("AWS_WEB_IDENTITY_TOKEN_FILE")?),
+            var("AWS_ROLE_SESSION_NAME").unwrap_or_else(|_| "orthanc-s3-plugin".to_string()),
+        ))
+    }
+
+    pub fn new(
+        client: reqwest::Client,
+        endpoint: &str,
+        role_arn: String,
+        token_file: PathBuf,
+        session_name: String,
+    ) -> Self {
+        Self {
+            client,
+            endpoint: endpoint.to_owned(),
+            role_arn,
+            token_file,
+            session_name,
+        }
+    }
+}
+
+#[async_trait]
+impl ProvideCredentials for WebIdentityProvider {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let token = tokio::fs::read_to_string(&self.token_file)
+            .await
+            .map_err(|e| {
+                CredentialsError::new(format!(
+                    "unable to read web identity token '{}' - {}",
+                    self.token_file.display(),
+                    e
+                ))
+            })?;
+
+        //
+        // The token is sent in the body, it is too large for a query string and must not end up
+        // in access logs
+        //
+        let resp = self
+            .client
+            .post(&self.endpoint)
+            .form(&[
+                ("Action", "AssumeRoleWithWebIdentity"),
+                ("Version", "2011-06-15"),
+                ("RoleArn", self.role_arn.as_str()),
+                ("RoleSessionName", self.session_name.as_str()),
+                ("WebIdentityToken", token.trim()),
+            ])
+            .send()
+            .await
+            .map_err(|e| CredentialsError::new(format!("STS request failed - {}", e)))?;
+
+        let status = resp.status();
+        let body = resp
+            .text()
+            .await
+            .map_err(|e| CredentialsError::new(format!("STS request failed - {}", e)))?;
+        if !status.is_success() {
+            return Err(CredentialsError::new(format!(
+                "STS rejected web identity - {} {}",
+                status, body
+            )));
+        }
+
+        let element = |name: &str| {
+            xml_element(&body, name)
+                .ok_or_else(|| CredentialsError::new(format!("STS response without '{}'", name)))
+        };
+
+        let expiration = DateTime::parse_from_rfc3339(element("Expiration")?)
+            .map_err(|e| CredentialsError::new(format!("invalid STS expiration - {}", e)))?;
+
+        Ok(Credentials::new(
+            element("AccessKeyId")?,
+            element("SecretAccessKey")?,
+            Some(element("SessionToken")?.to_string()),
+            Some(expiration.with_timezone(&Utc)),
+        ))
+    }
+}
+
+fn xml_element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].trim())
+}
+
+/// Reads `access_key`, `secret_key` and optionally `session_token` from a directory, e.g. a
+/// mounted Kubernetes secret.
+///
+/// The files are checked for modifications on every request and reloaded after a rotation,
+/// so new credentials are used without restarting Orthanc.
+pub struct FileProvider {
+    directory: PathBuf,
+    cached: Mutex<Option<(SystemTime, Credentials)>>,
+}
+
+impl FileProvider {
+    pub fn new(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            cached: Mutex::new(None),
+        }
+    }
+
+    fn modified(&self) -> Result<SystemTime, CredentialsError> {
+        ["access_key", "secret_key", "session_token"]
+            .iter()
+            .filter_map(|name| std::fs::metadata(self.directory.join(name)).ok())
+            .filter_map(|metadata| metadata.modified().ok())
+            .max()
+            .ok_or_else(|| {
+                CredentialsError::new(format!(
+                    "no credentials found in '{}'",
+                    self.directory.display()
+                ))
+            })
+    }
+
+    fn load(&self) -> Result<Credentials, CredentialsError> {
+        let read = |path: &Path| {
+            std::fs::read_to_string(path)
+                .map(|value| value.trim().to_string())
+                .map_err(|e| {
+                    CredentialsError::new(format!("unable to read '{}' - {}", path.display(), e))
+                })
+        };
+
+        let session_token = self.directory.join("session_token");
+        Ok(Credentials::new(
+            read(&self.directory.join("access_key"))?,
+            read(&self.directory.join("secret_key"))?,
+            session_token
+                .exists()
+                .then(|| read(&session_token))
+                .transpose()?,
+            None,
+        ))
+    }
+}
+
+#[async_trait]
+impl ProvideCredentials for FileProvider {
+    async fn credentials(&self) -> Result<Credentials, CredentialsError> {
+        let modified = self.modified()?;
+        let mut cached = self.cached.lock().expect("credentials lock poisoned");
+
+        match cached.as_ref() {
+            Some((loaded, credentials)) if *loaded == modified => Ok(credentials.clone()),
+            _ => {
+                let credentials = self.load()?;
+                if cached.is_some() {
+                    info!(
+                        "reloaded rotated credentials from '{}'",
+                        self.directory.display()
+                    );
+                }
+                *cached = Some((modified, credentials.clone()));
+                Ok(credentials)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::stub::{Response, Stub};
+
+    #[test]
+    fn profiles_are_read_from_the_shared_credentials_file() {
+        let content = "\
+# comment
+[default]
+aws_access_key_id = AKIDDEFAULT
+aws_secret_access_key = default-secret
+
+[archive]
+aws_access_key_id=AKIDARCHIVE
+aws_secret_access_key=archive-secret
+aws_session_token = archive-token
+";
+
+        let default = parse_profile(content, "default").unwrap();
+        assert_eq!(default.access_key_id(), "AKIDDEFAULT");
+        assert_eq!(default.secret_access_key(), "default-secret");
+        assert_eq!(default.token(), None);
+
+        let archive = parse_profile(content, "archive").unwrap();
+        assert_eq!(archive.access_key_id(), "AKIDARCHIVE");
+        assert_eq!(archive.token(), Some("archive-token"));
+
+        assert!(parse_profile(content, "missing").is_none());
+    }
+
+    #[test]
+    fn secrets_are_not_debugged() {
+        let credentials = Credentials::new("AKID", "secret", Some("token".to_string()), None);
+        let debugged = format!("{:?}", credentials);
+        assert!(debugged.contains("AKID"));
+        assert!(!debugged.contains("secret"));
+        assert!(!debugged.contains("token"));
+    }
+
+    fn temporary_credentials(access_key_id: &str, valid_for: Duration) -> String {
+        let expiration = DateTime::<Utc>::from(SystemTime::now() + valid_for);
+        serde_json::json!({
+            "Code": "Success",
+            "AccessKeyId": access_key_id,
+            "SecretAccessKey": "secret",
+            "Token": "session",
+            "Expiration": expiration.to_rfc3339(),
+        })
+        .to_string()
+    }
+
+    fn instance_metadata(stub: &Stub) -> InstanceMetadataProvider {
+        InstanceMetadataProvider::new(Some(&stub.address.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn instance_metadata_requests_a_session_token() {
+        let stub = Stub::start(|request| {
+            match (request.method.as_str(), request.path()) {
+                ("PUT", "/latest/api/token") => {
+                    assert_eq!(
+                        request.headers["x-aws-ec2-metadata-token-ttl-seconds"],
+                        "21600"
+                    );
+                    return Response::ok().body("session-token");
+                }
+                _ if request
+                    .headers
+                    .get("x-aws-ec2-metadata-token")
+                    .map(String::as_str)
+                    != Some("session-token") =>
+                {
+                    return Response::status(401)
+                }
+                _ => {}
+            }
+            match request.path() {
+                "/latest/meta-data/iam/security-credentials/" => Response::ok().body("orthanc\n"),
+                "/latest/meta-data/iam/security-credentials/orthanc" => Response::ok().body(
+                    temporary_credentials("AKIDINSTANCE", Duration::from_secs(6 * 3600)),
+                ),
+                _ => Response::status(404),
+            }
+        })
+        .await;
+
+        let credentials = instance_metadata(&stub).credentials().await.unwrap();
+        assert_eq!(credentials.access_key_id(), "AKIDINSTANCE");
+        assert_eq!(credentials.secret_access_key(), "secret");
+        assert_eq!(credentials.token(), Some("session"));
+        assert!(!credentials.expires_within(REFRESH_MARGIN));
+
+        let methods: Vec<_> = stub
+            .requests()
+            .iter()
+            .map(|request| request.method.clone())
+            .collect();
+        assert_eq!(methods, ["PUT", "GET", "GET"]);
+    }
+
+    #[tokio::test]
+    async fn instance_metadata_falls_back_to_imds_v1() {
+        let stub = Stub::start(|request| {
+            if request.method == "PUT" {
+                return Response::status(403);
+            }
+            assert!(!request.headers.contains_key("x-aws-ec2-metadata-token"));
+            match request.path() {
+                "/latest/meta-data/iam/security-credentials/" => Response::ok().body("orthanc"),
+                "/latest/meta-data/iam/security-credentials/orthanc" => {
+                    Response::ok().body(temporary_credentials("AKIDV1", Duration::from_secs(3600)))
+                }
+                _ => Response::status(404),
+            }
+        })
+        .await;
+
+        let credentials = instance_metadata(&stub).credentials().await.unwrap();
+        assert_eq!(credentials.access_key_id(), "AKIDV1");
+    }
+
+    #[tokio::test]
+    async fn credentials_are_refreshed_before_they_expire() {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let counter = issued.clone();
+        let stub = Stub::start(move |request| match request.path() {
+            "/latest/api/token" => Response::ok().body("session-token"),
+            "/latest/meta-data/iam/security-credentials/" => Response::ok().body("orthanc"),
+            "/latest/meta-data/iam/security-credentials/orthanc" => {
+                //
+                // The first credentials expire within the refresh margin, the next ones do not
+                //
+                let issued = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let valid_for = match issued {
+                    1 => Duration::from_secs(60),
+                    _ => Duration::from_secs(6 * 3600),
+                };
+                Response::ok().body(temporary_credentials(&format!("AKID{}", issued), valid_for))
+            }
+            _ => Response::status(404),
+        })
+        .await;
+
+        let provider = AutoRefreshingProvider::new(instance_metadata(&stub));
+        assert_eq!(
+            provider.credentials().await.unwrap().access_key_id(),
+            "AKID1"
+        );
+        assert_eq!(
+            provider.credentials().await.unwrap().access_key_id(),
+            "AKID2"
+        );
+        assert_eq!(
+            provider.credentials().await.unwrap().access_key_id(),
+            "AKID2"
+        );
+        assert_eq!(issued.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn web_identity_is_exchanged_with_a_form_post() {
+        let stub = Stub::start(|_| {
+            Response::ok().body(
+                "<AssumeRoleWithWebIdentityResponse><AssumeRoleWithWebIdentityResult>\
+                 <Credentials>\
+                 <AccessKeyId>AKIDSTS</AccessKeyId>\
+                 <SecretAccessKey>sts-secret</SecretAccessKey>\
+                 <SessionToken>sts-session</SessionToken>\
+                 <Expiration>2030-01-01T00:00:00Z</Expiration>\
+                 </Credentials>\
+                 </AssumeRoleWithWebIdentityResult></AssumeRoleWithWebIdentityResponse>",
+            )
+        })
+        .await;
+        let directory = tempfile::tempdir().unwrap();
+        let token_file = directory.path().join("token");
+        std::fs::write(&token_file, "web-identity-token\n").unwrap();
+
+        let provider = WebIdentityProvider::new(
+            endpoint_client().unwrap(),
+            &stub.url(),
+            "arn:aws:iam::123456789012:role/orthanc".to_string(),
+            token_file,
+            "orthanc".to_string(),
+        );
+        let credentials = provider.credentials().await.unwrap();
+        assert_eq!(credentials.access_key_id(), "AKIDSTS");
+        assert_eq!(credentials.token(), Some("sts-session"));
+
+        let requests = stub.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].target, "/");
+        assert_eq!(
+            requests[0].headers["content-type"],
+            "application/x-www-form-urlencoded"
+        );
+        let form = String::from_utf8(requests[0].body.clone()).unwrap();
+        let fields: Vec<_> = form.split('&').collect();
+        assert!(fields.contains(&"Action=AssumeRoleWithWebIdentity"));
+        assert!(fields.contains(&"RoleArn=arn%3Aaws%3Aiam%3A%3A123456789012%3Arole%2Forthanc"));
+        assert!(fields.contains(&"WebIdentityToken=web-identity-token"));
+    }
+
+    #[tokio::test]
+    async fn web_identity_token_file_is_read_for_every_exchange() {
+        let stub = Stub::start(|request| {
+            let form = String::from_utf8(request.body.clone()).unwrap();
+            let token = form
+                .split('&')
+                .find_map(|field| field.strip_prefix("WebIdentityToken="))
+                .unwrap()
+                .to_string();
+            Response::ok().body(format!(
+                "<Credentials>\
+                 <AccessKeyId>AKID-{}</AccessKeyId>\
+                 <SecretAccessKey>sts-secret</SecretAccessKey>\
+                 <SessionToken>sts-session</SessionToken>\
+                 <Expiration>2030-01-01T00:00:00Z</Expiration>\
+                 </Credentials>",
+                token
+            ))
+        })
+        .await;
+        let directory = tempfile::tempdir().unwrap();
+        let token_file = directory.path().join("token");
+        std::fs::write(&token_file, "first").unwrap();
+
+        let provider = WebIdentityProvider::new(
+            endpoint_client().unwrap(),
+            &stub.url(),
+            "arn:aws:iam::123456789012:role/orthanc".to_string(),
+            token_file.clone(),
+            "orthanc".to_string(),
+        );
+        assert_eq!(
+            provider.credentials().await.unwrap().access_key_id(),
+            "AKID-first"
+        );
+
+        //
+        // The kubelet rotates the projected token in place
+        //
+        std::fs::write(&token_file, "second").unwrap();
+        assert_eq!(
+            provider.credentials().await.unwrap().access_key_id(),
+            "AKID-second"
+        );
+        assert_eq!(stub.requests().len(), 2);
+    }
+
+    fn write_key(directory: &Path, name: &str, value: &str, modified: SystemTime) {
+        let path = directory.join(name);
+        std::fs::write(&path, value).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_credentials_are_reloaded_after_a_rotation() {
+        let directory = tempfile::tempdir().unwrap();
+        let mounted = SystemTime::now() - Duration::from_secs(3600);
+        write_key(directory.path(), "access_key", "AKIDFIRST\n", mounted);
+        write_key(directory.path(), "secret_key", "first-secret\n", mounted);
+
+        let provider = FileProvider::new(directory.path().to_owned());
+        let credentials = provider.credentials().await.unwrap();
+        assert_eq!(credentials.access_key_id(), "AKIDFIRST");
+        assert_eq!(credentials.secret_access_key(), "first-secret");
+        assert_eq!(credentials.token(), None);
+
+        //
+        // Files are only read again when they are modified
+        //
+        write_key(directory.path(), "access_key", "AKIDIGNORED", mounted);
+        assert_eq!(
+            provider.credentials().await.unwrap().access_key_id(),
+            "AKIDFIRST"
+        );
+
+        let rotated = SystemTime::now();
+        write_key(directory.path(), "access_key", "AKIDSECOND", rotated);
+        write_key(directory.path(), "secret_key", "second-secret", rotated);
+        write_key(directory.path(), "session_token", "second-token", rotated);
+        let credentials = provider.credentials().await.unwrap();
+        assert_eq!(credentials.access_key_id(), "AKIDSECOND");
+        assert_eq!(credentials.secret_access_key(), "second-secret");
+        assert_eq!(credentials.token(), Some("second-token"));
+    }
+
+    #[tokio::test]
+    async fn credentials_without_expiry_are_resolved_again() {
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("credentials");
+        std::fs::write(
+            &file,
+            "[default]\naws_access_key_id = AKIDFIRST\naws_secret_access_key = secret\n",
+        )
+        .unwrap();
+
+        let mut provider =
+            AutoRefreshingProvider::new(ProfileProvider::with_file(file.clone(), "default"));
+        assert_eq!(
+            provider.credentials().await.unwrap().access_key_id(),
+            "AKIDFIRST"
+        );
+
+        std::fs::write(
+            &file,
+            "[default]\naws_access_key_id = AKIDSECOND\naws_secret_access_key = secret\n",
+        )
+        .unwrap();
+        assert_eq!(
+            provider.credentials().await.unwrap().access_key_id(),
+            "AKIDFIRST"
+        );
+
+        provider.reresolve_interval = Duration::ZERO;
+        assert_eq!(
+            provider.credentials().await.unwrap().access_key_id(),
+            "AKIDSECOND"
+        );
+    }
+}
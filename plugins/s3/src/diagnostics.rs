@@ -0,0 +1,145 @@
+use std::{collections::HashMap, time::Instant};
+
+use serde::Serialize;
+
+use crate::{
+    client::S3Client,
+    layout::object_key,
+    metrics::{self, Operation},
+};
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub bucket: String,
+    pub latency_ms: u128,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObjectInfo {
+    pub uuid: String,
+    pub key: String,
+    pub size: Option<i64>,
+    pub last_modified: Option<String>,
+    pub e_tag: Option<String>,
+    pub content_type: Option<String>,
+    pub storage_class: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+/// Check that the bucket is reachable with the configured credentials.
+pub async fn health(s3: &S3Client, bucket: &str) -> Health {
+    let timer = Instant::now();
+    let result = metrics::timed(Operation::HeadBucket, s3.head_bucket(bucket)).await;
+
+    Health {
+        healthy: result.is_ok(),
+        bucket: bucket.to_owned(),
+        latency_ms: timer.elapsed().as_millis(),
+        error: result.err().map(|e| format!("{}", e)),
+    }
+}
+
+/// Look up the object storing the attachment `uuid`, `None` if it does not exist.
+pub async fn object_info(
+    s3: &S3Client,
+    bucket: &str,
+    uuid: &str,
+) -> Result<Option<ObjectInfo>, String> {
+    let key = object_key(uuid);
+    match metrics::timed(Operation::HeadObject, s3.head_object(bucket, &key)).await {
+        Ok(head) => Ok(Some(ObjectInfo {
+            uuid: uuid.to_owned(),
+            key,
+            size: head.content_length,
+            last_modified: head.last_modified,
+            e_tag: head.e_tag,
+            content_type: head.content_type,
+            //
+            // S3 omits the storage class of objects in the STANDARD class
+            //
+            storage_class: Some(head.storage_class.unwrap_or_else(|| "STANDARD".to_string())),
+            metadata: head.metadata,
+        })),
+        Err(e) if e.is_not_found() => Ok(None),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::stub::{Response, StoredObject, Stub};
+
+    const UUID: &str = "0a1b2c3d-0000-4000-8000-000000000001";
+
+    #[tokio::test]
+    async fn reachable_buckets_are_healthy() {
+        let (stub, _) = Stub::bucket().await;
+
+        let health = health(&stub.s3_client(), "bucket").await;
+        assert!(health.healthy);
+        assert_eq!(health.bucket, "bucket");
+        assert!(health.error.is_none());
+        assert_eq!(stub.requests()[0].method, "HEAD");
+        assert_eq!(stub.requests()[0].path(), "/bucket");
+    }
+
+    #[tokio::test]
+    async fn unreachable_buckets_are_unhealthy() {
+        let stub = Stub::start(|_| {
+            Response::status(403)
+                .body("<Error><Code>AccessDenied</Code><Message>Access Denied</Message></Error>")
+        })
+        .await;
+
+        let health = health(&stub.s3_client(), "bucket").await;
+        assert!(!health.healthy);
+        assert!(health.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn describes_stored_objects() {
+        let (stub, bucket) = Stub::bucket().await;
+        bucket.lock().unwrap().insert(
+            object_key(UUID),
+            StoredObject {
+                body: b"DICM".to_vec(),
+                headers: [
+                    ("content-type", "application/dicom"),
+                    ("x-amz-meta-orthanc-content-type", "1"),
+                ]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+                last_modified: Utc::now(),
+            },
+        );
+        let s3 = stub.s3_client();
+
+        let info = object_info(&s3, "bucket", UUID).await.unwrap().unwrap();
+        assert_eq!(info.uuid, UUID);
+        assert_eq!(info.key, object_key(UUID));
+        assert_eq!(info.size, Some(4));
+        assert!(info.last_modified.is_some());
+        assert!(info.e_tag.is_some());
+        assert_eq!(info.content_type.as_deref(), Some("application/dicom"));
+        assert_eq!(info.storage_class.as_deref(), Some("STANDARD"));
+        assert_eq!(info.metadata["orthanc-content-type"], "1");
+
+        let missing = "0a1b2c3d-0000-4000-8000-000000000002";
+        assert!(object_info(&s3, "bucket", missing).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_failed_lookups() {
+        let stub = Stub::start(|_| Response::status(403)).await;
+
+        assert!(object_info(&stub.s3_client(), "bucket", UUID)
+            .await
+            .is_err());
+    }
+}
@@ -1,4 +1,14 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
 use serde::Serialize;
+use tokio::sync::{mpsc, Notify};
+use tracing::warn;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, PartialOrd, Ord, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -37,6 +47,7 @@ pub enum ChangeType {
     StableSeries,
     StableStudy,
     StablePatient,
+    OrthancStarted,
     Other,
 }
 
@@ -62,7 +73,150 @@ impl From<std::os::raw::c_uint> for ChangeType {
             orthanc_plugin_bindings::OrthancPluginChangeType_OrthancPluginChangeType_StablePatient => {
                 Self::StablePatient
             }
+            orthanc_plugin_bindings::OrthancPluginChangeType_OrthancPluginChangeType_OrthancStarted => {
+                Self::OrthancStarted
+            }
             _ => Self::Other,
         }
     }
 }
+
+/// Change notified by Orthanc.
+#[derive(Debug)]
+pub struct Event {
+    pub change_type: ChangeType,
+    pub resource_type: orthanc_plugin_bindings::OrthancPluginResourceType,
+    pub resource_id: Option<String>,
+    received: Instant,
+}
+
+impl Event {
+    pub fn new(
+        change_type: ChangeType,
+        resource_type: orthanc_plugin_bindings::OrthancPluginResourceType,
+        resource_id: Option<String>,
+    ) -> Self {
+        Self {
+            change_type,
+            resource_type,
+            resource_id,
+            received: Instant::now(),
+        }
+    }
+}
+
+type Handler = dyn Fn(Event) + Send + Sync;
+
+/// Queue of the changes notified by Orthanc, handled in order by a background task so that
+/// Orthanc never waits for the plugin, e.g. for the query/retrieve index.
+pub struct EventSink {
+    sender: mpsc::UnboundedSender<Event>,
+    depth: Arc<AtomicUsize>,
+    lag_ms: Arc<AtomicU64>,
+    drained: Arc<Notify>,
+}
+
+impl EventSink {
+    /// Handle the events on `runtime` with `handler`, which may block.
+    pub fn start<F>(runtime: &tokio::runtime::Handle, handler: F) -> Self
+    where
+        F: Fn(Event) + Send + Sync + 'static,
+    {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Event>();
+        let depth = Arc::new(AtomicUsize::new(0));
+        let lag_ms = Arc::new(AtomicU64::new(0));
+        let drained = Arc::new(Notify::new());
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let (handled, lag, notify) = (depth.clone(), lag_ms.clone(), drained.clone());
+        runtime.spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let received = event.received;
+                let handler = handler.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || handler(event)).await {
+                    warn!("unable to handle a change - {}", e);
+                }
+
+                lag.store(received.elapsed().as_millis() as u64, Ordering::Relaxed);
+                if handled.fetch_sub(1, Ordering::AcqRel) == 1 {
+                    notify.notify_waiters();
+                }
+            }
+        });
+
+        Self {
+            sender,
+            depth,
+            lag_ms,
+            drained,
+        }
+    }
+
+    pub fn push(&self, event: Event) {
+        self.depth.fetch_add(1, Ordering::AcqRel);
+        if let Err(e) = self.sender.send(event) {
+            self.depth.fetch_sub(1, Ordering::AcqRel);
+            warn!("dropping {:?}, the event sink is stopped", e.0.change_type);
+        }
+    }
+
+    /// Events notified and not handled yet.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Acquire)
+    }
+
+    /// Delay between the notification of the last handled event and the end of its handling.
+    pub fn lag(&self) -> Duration {
+        Duration::from_millis(self.lag_ms.load(Ordering::Relaxed))
+    }
+
+    /// Wait up to `deadline` for the queued events to be handled, returns how many are left.
+    pub async fn drain(&self, deadline: Duration) -> usize {
+        let drained = async {
+            loop {
+                let notified = self.drained.notified();
+                if self.depth() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(deadline, drained).await;
+
+        self.depth()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn events_are_handled_in_order() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let (gate, opened) = (Arc::new(tokio::sync::Mutex::new(())), handled.clone());
+        let closed = gate.clone().lock_owned().await;
+        let sink = EventSink::start(&tokio::runtime::Handle::current(), move |event: Event| {
+            let _open = gate.blocking_lock();
+            opened.lock().unwrap().push(event.resource_id.unwrap());
+        });
+
+        for id in ["first", "second", "third"] {
+            sink.push(Event::new(
+                ChangeType::Deleted,
+                orthanc_plugin_bindings::OrthancPluginResourceType_OrthancPluginResourceType_Study,
+                Some(id.to_string()),
+            ));
+        }
+        assert_eq!(sink.depth(), 3);
+        assert_eq!(sink.drain(Duration::from_millis(50)).await, 3);
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(closed);
+        assert_eq!(sink.drain(Duration::from_secs(5)).await, 0);
+        assert_eq!(*handled.lock().unwrap(), ["first", "second", "third"]);
+        assert!(sink.lag() >= Duration::from_millis(20));
+    }
+}
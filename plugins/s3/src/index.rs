@@ -0,0 +1,927 @@
+use std::{collections::BTreeMap, path::Path, sync::Mutex};
+
+use orthanc_plugin_bindings::query_retrieve::{MoveRequest, QueryTag, ResourceType};
+use rusqlite::{params, params_from_iter, types::Value, Connection};
+
+/// Tags of an indexed resource by name, as answered to a C-FIND.
+pub type Tags = BTreeMap<String, String>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IndexError {
+    #[error("index error - {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("C-FIND without QueryRetrieveLevel")]
+    MissingLevel,
+    #[error("unsupported QueryRetrieveLevel '{0}'")]
+    UnsupportedLevel(String),
+    #[error("C-MOVE without any identifier")]
+    MissingIdentifier,
+}
+
+/// How the values of a column are matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Matching {
+    /// Case-sensitive.
+    Text,
+    /// Person names, case-insensitive.
+    PersonName,
+    /// Dates and times, also matched against ranges.
+    Range,
+}
+
+/// Tag stored in a column of the index.
+struct Column {
+    tag: &'static str,
+    name: &'static str,
+    level: ResourceType,
+    matching: Matching,
+}
+
+const COLUMNS: &[Column] = &[
+    Column {
+        tag: "PatientID",
+        name: "patient_id",
+        level: ResourceType::Patient,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "PatientName",
+        name: "patient_name",
+        level: ResourceType::Patient,
+        matching: Matching::PersonName,
+    },
+    Column {
+        tag: "PatientBirthDate",
+        name: "patient_birth_date",
+        level: ResourceType::Patient,
+        matching: Matching::Range,
+    },
+    Column {
+        tag: "PatientSex",
+        name: "patient_sex",
+        level: ResourceType::Patient,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "StudyInstanceUID",
+        name: "study_instance_uid",
+        level: ResourceType::Study,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "StudyDate",
+        name: "study_date",
+        level: ResourceType::Study,
+        matching: Matching::Range,
+    },
+    Column {
+        tag: "StudyTime",
+        name: "study_time",
+        level: ResourceType::Study,
+        matching: Matching::Range,
+    },
+    Column {
+        tag: "StudyID",
+        name: "study_id",
+        level: ResourceType::Study,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "StudyDescription",
+        name: "study_description",
+        level: ResourceType::Study,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "AccessionNumber",
+        name: "accession_number",
+        level: ResourceType::Study,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "ReferringPhysicianName",
+        name: "referring_physician_name",
+        level: ResourceType::Study,
+        matching: Matching::PersonName,
+    },
+    Column {
+        tag: "SeriesInstanceUID",
+        name: "series_instance_uid",
+        level: ResourceType::Series,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "Modality",
+        name: "modality",
+        level: ResourceType::Series,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "SeriesNumber",
+        name: "series_number",
+        level: ResourceType::Series,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "SeriesDescription",
+        name: "series_description",
+        level: ResourceType::Series,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "SOPInstanceUID",
+        name: "sop_instance_uid",
+        level: ResourceType::Instance,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "SOPClassUID",
+        name: "sop_class_uid",
+        level: ResourceType::Instance,
+        matching: Matching::Text,
+    },
+    Column {
+        tag: "InstanceNumber",
+        name: "instance_number",
+        level: ResourceType::Instance,
+        matching: Matching::Text,
+    },
+];
+
+/// Tags computed over the instances of the answered resource, only returned.
+const COMPUTED: &[(&str, &str, ResourceType)] = &[
+    (
+        "NumberOfPatientRelatedStudies",
+        "COUNT(DISTINCT study_instance_uid)",
+        ResourceType::Patient,
+    ),
+    (
+        "NumberOfPatientRelatedSeries",
+        "COUNT(DISTINCT series_instance_uid)",
+        ResourceType::Patient,
+    ),
+    (
+        "NumberOfPatientRelatedInstances",
+        "COUNT(*)",
+        ResourceType::Patient,
+    ),
+    (
+        "NumberOfStudyRelatedSeries",
+        "COUNT(DISTINCT series_instance_uid)",
+        ResourceType::Study,
+    ),
+    (
+        "NumberOfStudyRelatedInstances",
+        "COUNT(*)",
+        ResourceType::Study,
+    ),
+    (
+        "ModalitiesInStudy",
+        "REPLACE(GROUP_CONCAT(DISTINCT modality), ',', '\\')",
+        ResourceType::Study,
+    ),
+    (
+        "NumberOfSeriesRelatedInstances",
+        "COUNT(*)",
+        ResourceType::Series,
+    ),
+];
+
+fn depth(level: ResourceType) -> u8 {
+    match level {
+        ResourceType::Patient => 0,
+        ResourceType::Study => 1,
+        ResourceType::Series => 2,
+        ResourceType::Instance => 3,
+    }
+}
+
+/// Column identifying the resources of `level`.
+fn key(level: ResourceType) -> &'static str {
+    match level {
+        ResourceType::Patient => "patient_id",
+        ResourceType::Study => "study_instance_uid",
+        ResourceType::Series => "series_instance_uid",
+        ResourceType::Instance => "sop_instance_uid",
+    }
+}
+
+/// Column holding the Orthanc identifier of the resources of `level`.
+fn orthanc_column(level: ResourceType) -> &'static str {
+    match level {
+        ResourceType::Patient => "orthanc_patient",
+        ResourceType::Study => "orthanc_study",
+        ResourceType::Series => "orthanc_series",
+        ResourceType::Instance => "orthanc_instance",
+    }
+}
+
+pub fn parse_level(value: &str) -> Result<ResourceType, IndexError> {
+    match value.trim() {
+        "PATIENT" => Ok(ResourceType::Patient),
+        "STUDY" => Ok(ResourceType::Study),
+        "SERIES" => Ok(ResourceType::Series),
+        "IMAGE" | "INSTANCE" => Ok(ResourceType::Instance),
+        "" => Err(IndexError::MissingLevel),
+        other => Err(IndexError::UnsupportedLevel(other.to_owned())),
+    }
+}
+
+/// Instance as recorded in the index.
+#[derive(Debug, Clone)]
+pub struct IndexedInstance {
+    pub orthanc_patient: String,
+    pub orthanc_study: String,
+    pub orthanc_series: String,
+    pub orthanc_instance: String,
+    /// Uuid of the DICOM attachment, i.e. of its object in the bucket.
+    pub attachment: String,
+    /// Main DICOM tags, only those of [`COLUMNS`] are recorded.
+    pub tags: Tags,
+}
+
+/// Instance retrieved by a C-MOVE.
+#[derive(Debug, Clone)]
+pub struct MoveItem {
+    pub orthanc_instance: String,
+    pub attachment: String,
+    pub sop_instance_uid: String,
+}
+
+/// Local SQLite index of the instances stored in the bucket, answering C-FIND and C-MOVE.
+pub struct Index {
+    connection: Mutex<Connection>,
+}
+
+impl Index {
+    pub fn open(path: &Path) -> Result<Self, IndexError> {
+        let connection = Connection::open(path)?;
+        let columns: String = COLUMNS
+            .iter()
+            .map(|column| format!("{} TEXT,\n", column.name))
+            .collect();
+        connection.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS instances (
+                orthanc_instance TEXT PRIMARY KEY,
+                orthanc_series TEXT NOT NULL,
+                orthanc_study TEXT NOT NULL,
+                orthanc_patient TEXT NOT NULL,
+                attachment TEXT NOT NULL,
+                {}
+                indexed INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))
+            );
+            CREATE INDEX IF NOT EXISTS instances_patient ON instances (patient_id);
+            CREATE INDEX IF NOT EXISTS instances_study ON instances (study_instance_uid);
+            CREATE INDEX IF NOT EXISTS instances_series ON instances (series_instance_uid);
+            CREATE INDEX IF NOT EXISTS instances_sop ON instances (sop_instance_uid);",
+            columns
+        ))?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Record an instance, replacing a previous record of the same instance.
+    pub fn insert(&self, instance: &IndexedInstance) -> Result<(), IndexError> {
+        let names: Vec<_> = COLUMNS.iter().map(|column| column.name).collect();
+        let placeholders = vec!["?"; 5 + COLUMNS.len()].join(", ");
+        let sql = format!(
+            "INSERT OR REPLACE INTO instances (orthanc_instance, orthanc_series, orthanc_study, \
+             orthanc_patient, attachment, {}) VALUES ({})",
+            names.join(", "),
+            placeholders
+        );
+
+        let values = [
+            Some(instance.orthanc_instance.as_str()),
+            Some(instance.orthanc_series.as_str()),
+            Some(instance.orthanc_study.as_str()),
+            Some(instance.orthanc_patient.as_str()),
+            Some(instance.attachment.as_str()),
+        ]
+        .into_iter()
+        .chain(
+            COLUMNS
+                .iter()
+                .map(|column| instance.tags.get(column.tag).map(String::as_str)),
+        );
+
+        let connection = self.connection.lock().expect("index lock poisoned");
+        connection.execute(&sql, params_from_iter(values))?;
+        Ok(())
+    }
+
+    /// Forget the instances of the Orthanc resource `id` of `level`, returning their number.
+    pub fn remove(&self, level: ResourceType, id: &str) -> Result<usize, IndexError> {
+        let connection = self.connection.lock().expect("index lock poisoned");
+        let removed = connection.execute(
+            &format!("DELETE FROM instances WHERE {} = ?1", orthanc_column(level)),
+            params![id],
+        )?;
+        Ok(removed)
+    }
+
+    /// Orthanc identifiers of the indexed instances.
+    pub fn instance_ids(&self) -> Result<Vec<String>, IndexError> {
+        let connection = self.connection.lock().expect("index lock poisoned");
+        let mut statement = connection.prepare("SELECT orthanc_instance FROM instances")?;
+        let ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, IndexError> {
+        let connection = self.connection.lock().expect("index lock poisoned");
+        let empty =
+            connection.query_row("SELECT NOT EXISTS (SELECT 1 FROM instances)", [], |row| {
+                row.get(0)
+            })?;
+        Ok(empty)
+    }
+
+    /// Resources of `level` matching `query`, with the tags it requests.
+    ///
+    /// Tags missing from the index are neither matched nor returned. `limit` caps the number of
+    /// answers.
+    pub fn find(
+        &self,
+        level: ResourceType,
+        query: &[QueryTag],
+        limit: Option<usize>,
+    ) -> Result<Vec<Tags>, IndexError> {
+        let mut selected = vec![(key_tag(level), key(level).to_owned())];
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+
+        for tag in query {
+            if let Some(column) = COLUMNS.iter().find(|column| column.tag == tag.name) {
+                if depth(column.level) <= depth(level) && column.name != key(level) {
+                    selected.push((column.tag, column.name.to_owned()));
+                }
+                if !tag.value.is_empty() {
+                    conditions.push(matches(
+                        column.name,
+                        column.matching,
+                        &tag.value,
+                        &mut values,
+                    ));
+                }
+            } else if let Some((name, expression, _)) = COMPUTED
+                .iter()
+                .find(|(name, _, computed)| *name == tag.name && *computed == level)
+            {
+                selected.push((name, expression.to_string()));
+                if *name == "ModalitiesInStudy" && !tag.value.is_empty() {
+                    conditions.push(format!(
+                        "study_instance_uid IN (SELECT study_instance_uid FROM instances WHERE {})",
+                        matches("modality", Matching::Text, &tag.value, &mut values)
+                    ));
+                }
+            }
+        }
+
+        let mut sql = format!(
+            "SELECT {} FROM instances",
+            selected
+                .iter()
+                .map(|(_, expression)| expression.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        if !conditions.is_empty() {
+            sql += &format!(" WHERE {}", conditions.join(" AND "));
+        }
+        sql += &format!(" GROUP BY {0} ORDER BY {0}", key(level));
+        if let Some(limit) = limit {
+            sql += &format!(" LIMIT {}", limit);
+        }
+
+        let connection = self.connection.lock().expect("index lock poisoned");
+        let mut statement = connection.prepare(&sql)?;
+        let rows = statement.query_map(params_from_iter(values), |row| {
+            let mut tags = Tags::new();
+            for (i, (tag, _)) in selected.iter().enumerate() {
+                let value = match row.get::<_, Value>(i)? {
+                    Value::Text(text) => text,
+                    Value::Integer(integer) => integer.to_string(),
+                    _ => String::new(),
+                };
+                tags.insert(tag.to_string(), value);
+            }
+            Ok(tags)
+        })?;
+
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    /// Instances retrieved by a C-MOVE, selected by all the identifiers of the request.
+    pub fn instances(&self, request: &MoveRequest) -> Result<Vec<MoveItem>, IndexError> {
+        let identifiers = [
+            ("patient_id", &request.patient_id),
+            ("accession_number", &request.accession_number),
+            ("study_instance_uid", &request.study_instance_uid),
+            ("series_instance_uid", &request.series_instance_uid),
+            ("sop_instance_uid", &request.sop_instance_uid),
+        ];
+
+        let mut conditions = Vec::new();
+        let mut values = Vec::new();
+        for (column, identifier) in identifiers {
+            if let Some(identifier) = identifier.as_deref().filter(|i| !i.trim().is_empty()) {
+                conditions.push(matches(
+                    column,
+                    Matching::Text,
+                    identifier.trim(),
+                    &mut values,
+                ));
+            }
+        }
+        if conditions.is_empty() {
+            return Err(IndexError::MissingIdentifier);
+        }
+
+        let connection = self.connection.lock().expect("index lock poisoned");
+        let mut statement = connection.prepare(&format!(
+            "SELECT orthanc_instance, attachment, sop_instance_uid FROM instances WHERE {} \
+             ORDER BY series_instance_uid, CAST(instance_number AS INTEGER)",
+            conditions.join(" AND ")
+        ))?;
+        let items = statement
+            .query_map(params_from_iter(values), |row| {
+                Ok(MoveItem {
+                    orthanc_instance: row.get(0)?,
+                    attachment: row.get(1)?,
+                    sop_instance_uid: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(items)
+    }
+}
+
+/// Query tag holding the identifier of the resources of `level`.
+fn key_tag(level: ResourceType) -> &'static str {
+    COLUMNS
+        .iter()
+        .find(|column| column.name == key(level))
+        .map(|column| column.tag)
+        .unwrap_or_default()
+}
+
+/// SQL condition of the DICOM matching key `value` on `column`.
+///
+/// Supports lists of values separated by `\`, `*` and `?` wildcards, and `<from>-<to>` ranges
+/// with either bound optional for [`Matching::Range`]. Only person names are matched
+/// regardless of their case.
+fn matches(column: &str, matching: Matching, value: &str, values: &mut Vec<String>) -> String {
+    let alternatives: Vec<_> = value
+        .split('\\')
+        .map(|value| match value.split_once('-') {
+            Some((from, to)) if matching == Matching::Range => {
+                let mut bounds = Vec::new();
+                if !from.is_empty() {
+                    values.push(from.to_owned());
+                    bounds.push(format!("{} >= ?", column));
+                }
+                if !to.is_empty() {
+                    values.push(to.to_owned());
+                    bounds.push(format!("{} <= ?", column));
+                }
+                if bounds.is_empty() {
+                    "1".to_owned()
+                } else {
+                    bounds.join(" AND ")
+                }
+            }
+            //
+            // LIKE ignores the case of ASCII letters, GLOB does not
+            //
+            _ if value.contains(['*', '?']) && matching == Matching::PersonName => {
+                let pattern: String = value
+                    .chars()
+                    .flat_map(|c| match c {
+                        '*' => vec!['%'],
+                        '?' => vec!['_'],
+                        '%' | '_' | '!' => vec!['!', c],
+                        c => vec![c],
+                    })
+                    .collect();
+                values.push(pattern);
+                format!("{} LIKE ? ESCAPE '!'", column)
+            }
+            _ if value.contains(['*', '?']) => {
+                values.push(value.replace('[', "[[]"));
+                format!("{} GLOB ?", column)
+            }
+            _ if matching == Matching::PersonName => {
+                values.push(value.to_owned());
+                format!("{} = ? COLLATE NOCASE", column)
+            }
+            _ => {
+                values.push(value.to_owned());
+                format!("{} = ?", column)
+            }
+        })
+        .collect();
+
+    format!("({})", alternatives.join(" OR "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(matching: Matching, value: &str) -> (String, Vec<String>) {
+        let mut values = Vec::new();
+        let condition = matches("column", matching, value, &mut values);
+        (condition, values)
+    }
+
+    #[test]
+    fn matches_single_values() {
+        assert_eq!(
+            condition(Matching::Text, "CT"),
+            ("(column = ?)".to_owned(), vec!["CT".to_owned()])
+        );
+        assert_eq!(
+            condition(Matching::PersonName, "DOE^JOHN"),
+            (
+                "(column = ? COLLATE NOCASE)".to_owned(),
+                vec!["DOE^JOHN".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn matches_lists() {
+        assert_eq!(
+            condition(Matching::Text, "1.2.3\\1.2.4"),
+            (
+                "(column = ? OR column = ?)".to_owned(),
+                vec!["1.2.3".to_owned(), "1.2.4".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert_eq!(
+            condition(Matching::Text, "A*[1]?"),
+            ("(column GLOB ?)".to_owned(), vec!["A*[[]1]?".to_owned()])
+        );
+        assert_eq!(
+            condition(Matching::PersonName, "DOE_%!*?"),
+            (
+                "(column LIKE ? ESCAPE '!')".to_owned(),
+                vec!["DOE!_!%!!%_".to_owned()]
+            )
+        );
+    }
+
+    #[test]
+    fn matches_ranges() {
+        assert_eq!(
+            condition(Matching::Range, "20200101-20201231"),
+            (
+                "(column >= ? AND column <= ?)".to_owned(),
+                vec!["20200101".to_owned(), "20201231".to_owned()]
+            )
+        );
+        assert_eq!(
+            condition(Matching::Range, "20200101-"),
+            ("(column >= ?)".to_owned(), vec!["20200101".to_owned()])
+        );
+        assert_eq!(
+            condition(Matching::Range, "-20201231"),
+            ("(column <= ?)".to_owned(), vec!["20201231".to_owned()])
+        );
+        assert_eq!(condition(Matching::Range, "-"), ("(1)".to_owned(), vec![]));
+
+        //
+        // Only dates and times are ranges
+        //
+        assert_eq!(
+            condition(Matching::Text, "1-2"),
+            ("(column = ?)".to_owned(), vec!["1-2".to_owned()])
+        );
+    }
+
+    fn instance(
+        patient: &str,
+        study: &str,
+        series: &str,
+        sop: &str,
+        tags: &[(&str, &str)],
+    ) -> IndexedInstance {
+        let mut all: Tags = [
+            ("PatientID", patient),
+            ("StudyInstanceUID", study),
+            ("SeriesInstanceUID", series),
+            ("SOPInstanceUID", sop),
+        ]
+        .into_iter()
+        .chain(tags.iter().copied())
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+        all.entry("InstanceNumber".to_owned())
+            .or_insert_with(|| sop.rsplit('.').next().unwrap_or_default().to_owned());
+
+        IndexedInstance {
+            orthanc_patient: format!("orthanc-{}", patient),
+            orthanc_study: format!("orthanc-{}", study),
+            orthanc_series: format!("orthanc-{}", series),
+            orthanc_instance: format!("orthanc-{}", sop),
+            attachment: format!("attachment-{}", sop),
+            tags: all,
+        }
+    }
+
+    /// Two patients, the first with two studies, one of them with a CT and an MR series.
+    fn index() -> Index {
+        let index = Index::open(Path::new(":memory:")).unwrap();
+        let doe = [("PatientName", "Doe^John"), ("StudyDate", "20200115")];
+        for instance in [
+            instance(
+                "P1",
+                "1.1",
+                "1.1.1",
+                "1.1.1.1",
+                &[doe[0], doe[1], ("Modality", "CT")],
+            ),
+            instance(
+                "P1",
+                "1.1",
+                "1.1.1",
+                "1.1.1.2",
+                &[doe[0], doe[1], ("Modality", "CT")],
+            ),
+            instance(
+                "P1",
+                "1.1",
+                "1.1.2",
+                "1.1.2.1",
+                &[doe[0], doe[1], ("Modality", "MR")],
+            ),
+            instance(
+                "P1",
+                "1.2",
+                "1.2.1",
+                "1.2.1.1",
+                &[doe[0], ("StudyDate", "20210601"), ("Modality", "CT")],
+            ),
+            instance(
+                "P2",
+                "2.1",
+                "2.1.1",
+                "2.1.1.1",
+                &[
+                    ("PatientName", "ROE^JANE"),
+                    ("StudyDate", "20220101"),
+                    ("Modality", "US"),
+                ],
+            ),
+        ] {
+            index.insert(&instance).unwrap();
+        }
+        index
+    }
+
+    fn query(tags: &[(&str, &str)]) -> Vec<QueryTag> {
+        tags.iter()
+            .map(|(name, value)| QueryTag {
+                group: 0,
+                element: 0,
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    fn keys(answers: &[Tags], tag: &str) -> Vec<String> {
+        answers.iter().map(|tags| tags[tag].clone()).collect()
+    }
+
+    #[test]
+    fn finds_one_answer_per_resource_of_the_level() {
+        let index = index();
+
+        let patients = index
+            .find(
+                ResourceType::Patient,
+                &query(&[
+                    ("PatientName", ""),
+                    ("NumberOfPatientRelatedStudies", ""),
+                    ("NumberOfPatientRelatedInstances", ""),
+                ]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(keys(&patients, "PatientID"), vec!["P1", "P2"]);
+        assert_eq!(patients[0]["PatientName"], "Doe^John");
+        assert_eq!(patients[0]["NumberOfPatientRelatedStudies"], "2");
+        assert_eq!(patients[0]["NumberOfPatientRelatedInstances"], "4");
+
+        let studies = index
+            .find(
+                ResourceType::Study,
+                &query(&[
+                    ("PatientID", "P1"),
+                    ("NumberOfStudyRelatedSeries", ""),
+                    ("ModalitiesInStudy", ""),
+                ]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(keys(&studies, "StudyInstanceUID"), vec!["1.1", "1.2"]);
+        assert_eq!(studies[0]["NumberOfStudyRelatedSeries"], "2");
+        assert_eq!(studies[0]["PatientID"], "P1");
+        let mut modalities: Vec<_> = studies[0]["ModalitiesInStudy"].split('\\').collect();
+        modalities.sort();
+        assert_eq!(modalities, vec!["CT", "MR"]);
+
+        let series = index
+            .find(
+                ResourceType::Series,
+                &query(&[
+                    ("StudyInstanceUID", "1.1"),
+                    ("NumberOfSeriesRelatedInstances", ""),
+                ]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(keys(&series, "SeriesInstanceUID"), vec!["1.1.1", "1.1.2"]);
+        assert_eq!(
+            keys(&series, "NumberOfSeriesRelatedInstances"),
+            vec!["2", "1"]
+        );
+
+        let instances = index
+            .find(
+                ResourceType::Instance,
+                &query(&[("SeriesInstanceUID", "1.1.1")]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            keys(&instances, "SOPInstanceUID"),
+            vec!["1.1.1.1", "1.1.1.2"]
+        );
+    }
+
+    #[test]
+    fn finds_with_matching_keys() {
+        let index = index();
+        let studies = |tags: &[(&str, &str)]| {
+            keys(
+                &index.find(ResourceType::Study, &query(tags), None).unwrap(),
+                "StudyInstanceUID",
+            )
+        };
+
+        assert_eq!(
+            studies(&[("StudyDate", "20200101-20211231")]),
+            vec!["1.1", "1.2"]
+        );
+        assert_eq!(studies(&[("StudyDate", "20210101-")]), vec!["1.2", "2.1"]);
+        assert_eq!(
+            studies(&[("StudyInstanceUID", "1.2\\2.1")]),
+            vec!["1.2", "2.1"]
+        );
+        assert_eq!(studies(&[("ModalitiesInStudy", "MR")]), vec!["1.1"]);
+        assert_eq!(
+            studies(&[("ModalitiesInStudy", "CT\\US")]),
+            vec!["1.1", "1.2", "2.1"]
+        );
+        assert_eq!(
+            studies(&[("PatientID", "P1"), ("StudyDate", "2021*")]),
+            vec!["1.2"]
+        );
+    }
+
+    #[test]
+    fn matches_person_names_regardless_of_case_only() {
+        let index = index();
+        let patients = |tags: &[(&str, &str)]| {
+            keys(
+                &index
+                    .find(ResourceType::Patient, &query(tags), None)
+                    .unwrap(),
+                "PatientID",
+            )
+        };
+
+        assert_eq!(patients(&[("PatientName", "doe^john")]), vec!["P1"]);
+        assert_eq!(patients(&[("PatientName", "DOE*")]), vec!["P1"]);
+        assert_eq!(patients(&[("PatientName", "?oe^*")]), vec!["P1", "P2"]);
+        assert_eq!(patients(&[("PatientID", "p1")]), Vec::<String>::new());
+        assert_eq!(patients(&[("PatientID", "p*")]), Vec::<String>::new());
+        assert_eq!(patients(&[("PatientID", "P*")]), vec!["P1", "P2"]);
+    }
+
+    #[test]
+    fn escapes_wildcard_patterns() {
+        let index = index();
+        index
+            .insert(&instance(
+                "P_%[1]",
+                "3.1",
+                "3.1.1",
+                "3.1.1.1",
+                &[("PatientName", "A%B")],
+            ))
+            .unwrap();
+        let patients = |tags: &[(&str, &str)]| {
+            keys(
+                &index
+                    .find(ResourceType::Patient, &query(tags), None)
+                    .unwrap(),
+                "PatientID",
+            )
+        };
+
+        assert_eq!(patients(&[("PatientID", "P_%[1]*")]), vec!["P_%[1]"]);
+        assert_eq!(patients(&[("PatientID", "P_*")]), vec!["P_%[1]"]);
+        assert_eq!(patients(&[("PatientName", "a%*")]), vec!["P_%[1]"]);
+        assert_eq!(patients(&[("PatientName", "%*")]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn limits_answers() {
+        let answers = index().find(ResourceType::Instance, &[], Some(2)).unwrap();
+        assert_eq!(keys(&answers, "SOPInstanceUID"), vec!["1.1.1.1", "1.1.1.2"]);
+    }
+
+    #[test]
+    fn selects_moved_instances() {
+        let index = index();
+        let request = MoveRequest {
+            level: Some(ResourceType::Study),
+            patient_id: None,
+            accession_number: None,
+            study_instance_uid: Some(" 1.1 ".to_owned()),
+            series_instance_uid: None,
+            sop_instance_uid: None,
+            originator_aet: "SCU".to_owned(),
+            source_aet: "ORTHANC".to_owned(),
+            target_aet: "SCU".to_owned(),
+            originator_id: 1,
+        };
+
+        let items = index.instances(&request).unwrap();
+        assert_eq!(
+            items
+                .iter()
+                .map(|item| item.attachment.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "attachment-1.1.1.1",
+                "attachment-1.1.1.2",
+                "attachment-1.1.2.1"
+            ]
+        );
+
+        let request = MoveRequest {
+            study_instance_uid: Some(" ".to_owned()),
+            ..request
+        };
+        assert!(matches!(
+            index.instances(&request),
+            Err(IndexError::MissingIdentifier)
+        ));
+    }
+
+    #[test]
+    fn removes_and_lists_instances() {
+        let index = index();
+        assert!(!index.is_empty().unwrap());
+        assert_eq!(index.instance_ids().unwrap().len(), 5);
+
+        assert_eq!(index.remove(ResourceType::Study, "orthanc-1.1").unwrap(), 3);
+        assert_eq!(
+            index
+                .remove(ResourceType::Instance, "orthanc-2.1.1.1")
+                .unwrap(),
+            1
+        );
+        assert_eq!(index.instance_ids().unwrap(), vec!["orthanc-1.2.1.1"]);
+        assert_eq!(
+            index.remove(ResourceType::Patient, "orthanc-P1").unwrap(),
+            1
+        );
+        assert!(index.is_empty().unwrap());
+    }
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(parse_level(" STUDY ").unwrap(), ResourceType::Study);
+        assert_eq!(parse_level("IMAGE").unwrap(), ResourceType::Instance);
+        assert!(matches!(parse_level(""), Err(IndexError::MissingLevel)));
+        assert!(matches!(
+            parse_level("study"),
+            Err(IndexError::UnsupportedLevel(_))
+        ));
+    }
+}
@@ -0,0 +1,922 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    error::Error,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use orthanc_plugin_bindings::{
+    api::OrthancApi,
+    jobs::{Job, JobStep, StopReason},
+    plugin::Context,
+    query_retrieve::ResourceType,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::runtime::Handle;
+use tracing::{info, warn};
+
+use crate::{
+    audit::{self, AuditReport, AuditRun, Auditor},
+    client::{S3Client, S3Error},
+    layout::object_key,
+    metrics::{self, Operation},
+    migrate::{Migration, MigrationOptions, MigrationReport},
+    query_retrieve::QueryRetrieve,
+    trash::{SweepReport, Trash, TrashedObject},
+};
+
+/// Objects purged by a single step of a [`SweepJob`].
+const PURGE_BATCH: usize = 100;
+
+/// Instances indexed by a single step of an [`IndexRebuildJob`].
+const INDEX_BATCH: usize = 100;
+
+/// Attachments uploaded by a single step of a [`MigrationJob`].
+const MIGRATION_BATCH: usize = 100;
+
+/// Objects moved by a single step of a [`StorageClassJob`].
+const STORAGE_CLASS_BATCH: usize = 100;
+
+/// Storage class of the objects that do not report one.
+const DEFAULT_STORAGE_CLASS: &str = "STANDARD";
+
+/// Saved state of an [`AuditJob`], a resumed audit starts over.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct AuditJobState {
+    pub delete_orphans: bool,
+}
+
+/// Storage audit run by the Orthanc jobs engine.
+pub struct AuditJob {
+    auditor: Arc<Auditor>,
+    runtime: Handle,
+    delete_orphans: bool,
+    run: Option<AuditRun>,
+    report: Option<AuditReport>,
+}
+
+impl AuditJob {
+    pub fn new(auditor: Arc<Auditor>, runtime: Handle, delete_orphans: bool) -> Self {
+        Self {
+            auditor,
+            runtime,
+            delete_orphans,
+            run: None,
+            report: None,
+        }
+    }
+}
+
+impl Job for AuditJob {
+    const TYPE: &'static str = "S3Audit";
+    type State = AuditJobState;
+
+    fn state(&self) -> Option<AuditJobState> {
+        Some(AuditJobState {
+            delete_orphans: self.delete_orphans,
+        })
+    }
+
+    fn step(&mut self) -> Result<JobStep, Box<dyn Error>> {
+        if self.report.is_some() {
+            return Ok(JobStep::Success);
+        }
+
+        let run = match self.run.as_mut() {
+            Some(run) => run,
+            None => self.run.insert(self.auditor.start(self.delete_orphans)?),
+        };
+
+        match self.runtime.block_on(run.step())? {
+            Some(report) => {
+                self.report = Some(report);
+                self.run = None;
+                Ok(JobStep::Success)
+            }
+            None => Ok(JobStep::Continue),
+        }
+    }
+
+    fn progress(&self) -> f32 {
+        match (&self.run, &self.report) {
+            (_, Some(_)) => 1.0,
+            (Some(run), None) => run.progress(),
+            (None, None) => 0.0,
+        }
+    }
+
+    fn content(&self) -> serde_json::Value {
+        match (&self.run, &self.report) {
+            (_, Some(report)) => json!({
+                "DeleteOrphans": self.delete_orphans,
+                "BucketObjects": report.bucket_objects,
+                "OrthancAttachments": report.orthanc_attachments,
+                "Missing": report.missing.len(),
+                "Orphans": report.orphans.len(),
+                "Deleted": report.deleted.len(),
+                "DeleteFailures": report.delete_failures.len(),
+                "Report": "/s3/audit",
+            }),
+            (Some(run), None) => json!({
+                "DeleteOrphans": self.delete_orphans,
+                "Status": run.status(),
+            }),
+            (None, None) => json!({ "DeleteOrphans": self.delete_orphans }),
+        }
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        //
+        // A paused audit resumes where it stopped, otherwise the auditor is released
+        //
+        if reason != StopReason::Paused {
+            self.run = None;
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.run = None;
+        self.report = None;
+        Ok(())
+    }
+}
+
+/// Saved state of a [`SweepJob`], a resumed sweep starts over.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SweepJobState {}
+
+/// Trash sweep run by the Orthanc jobs engine.
+pub struct SweepJob {
+    trash: Arc<Trash>,
+    runtime: Handle,
+    /// Expired objects that were not purged yet, `None` until they are listed.
+    expired: Option<Vec<TrashedObject>>,
+    total: usize,
+    report: SweepReport,
+}
+
+impl SweepJob {
+    pub fn new(trash: Arc<Trash>, runtime: Handle) -> Self {
+        Self {
+            trash,
+            runtime,
+            expired: None,
+            total: 0,
+            report: SweepReport::default(),
+        }
+    }
+}
+
+impl Job for SweepJob {
+    const TYPE: &'static str = "S3TrashSweep";
+    type State = SweepJobState;
+
+    fn state(&self) -> Option<SweepJobState> {
+        Some(SweepJobState {})
+    }
+
+    fn step(&mut self) -> Result<JobStep, Box<dyn Error>> {
+        let expired = match self.expired.as_mut() {
+            Some(expired) => expired,
+            None => {
+                let expired = self.runtime.block_on(self.trash.expired())?;
+                self.total = expired.len();
+                self.expired.insert(expired)
+            }
+        };
+
+        let batch = expired.split_off(expired.len().saturating_sub(PURGE_BATCH));
+        self.runtime
+            .block_on(self.trash.purge(batch, &mut self.report));
+        if !expired.is_empty() {
+            return Ok(JobStep::Continue);
+        }
+
+        info!(
+            "trash sweep complete - {} purged, {} held, {} failures",
+            self.report.purged.len(),
+            self.report.held.len(),
+            self.report.failures.len()
+        );
+        Ok(JobStep::Success)
+    }
+
+    fn progress(&self) -> f32 {
+        match &self.expired {
+            Some(expired) => 1.0 - expired.len() as f32 / self.total.max(1) as f32,
+            None => 0.0,
+        }
+    }
+
+    fn content(&self) -> serde_json::Value {
+        json!({
+            "Expired": self.total,
+            "Purged": self.report.purged.len(),
+            "Held": self.report.held.len(),
+            "Failures": self.report.failures.len(),
+        })
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.expired = None;
+        self.total = 0;
+        self.report = SweepReport::default();
+        Ok(())
+    }
+}
+
+/// Saved state of an [`IndexRebuildJob`], a resumed rebuild starts over.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexRebuildJobState {}
+
+/// Rebuild of the Query/Retrieve index from the instances stored by Orthanc, run by the Orthanc
+/// jobs engine.
+///
+/// Every instance is indexed again and the instances Orthanc no longer stores are forgotten.
+pub struct IndexRebuildJob {
+    query_retrieve: QueryRetrieve,
+    /// Instances that were not indexed yet, `None` until they are listed.
+    pending: Option<Vec<String>>,
+    total: usize,
+    indexed: usize,
+    removed: usize,
+    failures: usize,
+}
+
+impl IndexRebuildJob {
+    pub fn new(query_retrieve: QueryRetrieve) -> Self {
+        Self {
+            query_retrieve,
+            pending: None,
+            total: 0,
+            indexed: 0,
+            removed: 0,
+            failures: 0,
+        }
+    }
+
+    /// List the instances of Orthanc, forgetting those of the index it no longer stores.
+    fn list(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let instances: Vec<String> =
+            OrthancApi::new(self.query_retrieve.context).get_json("/instances")?;
+
+        let stored: HashSet<_> = instances.iter().map(String::as_str).collect();
+        for id in self.query_retrieve.index.instance_ids()? {
+            if !stored.contains(id.as_str()) {
+                self.removed += self
+                    .query_retrieve
+                    .index
+                    .remove(ResourceType::Instance, &id)?;
+            }
+        }
+
+        self.total = instances.len();
+        Ok(instances)
+    }
+}
+
+impl Job for IndexRebuildJob {
+    const TYPE: &'static str = "S3IndexRebuild";
+    type State = IndexRebuildJobState;
+
+    fn state(&self) -> Option<IndexRebuildJobState> {
+        Some(IndexRebuildJobState {})
+    }
+
+    fn step(&mut self) -> Result<JobStep, Box<dyn Error>> {
+        if self.pending.is_none() {
+            let instances = self.list()?;
+            self.pending = Some(instances);
+        }
+        let pending = self.pending.as_mut().expect("instances listed");
+
+        //
+        // An instance deleted since the listing only fails its own indexing
+        //
+        for id in pending.split_off(pending.len().saturating_sub(INDEX_BATCH)) {
+            match self.query_retrieve.index_instance(&id) {
+                Ok(()) => self.indexed += 1,
+                Err(e) => {
+                    warn!("unable to index instance {} - {}", id, e);
+                    self.failures += 1;
+                }
+            }
+        }
+        if !pending.is_empty() {
+            return Ok(JobStep::Continue);
+        }
+
+        info!(
+            "index rebuild complete - {} indexed, {} removed, {} failures",
+            self.indexed, self.removed, self.failures
+        );
+        Ok(JobStep::Success)
+    }
+
+    fn progress(&self) -> f32 {
+        match &self.pending {
+            Some(pending) => 1.0 - pending.len() as f32 / self.total.max(1) as f32,
+            None => 0.0,
+        }
+    }
+
+    fn content(&self) -> serde_json::Value {
+        json!({
+            "Instances": self.total,
+            "Indexed": self.indexed,
+            "Removed": self.removed,
+            "Failures": self.failures,
+        })
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.pending = None;
+        self.total = 0;
+        self.indexed = 0;
+        self.removed = 0;
+        self.failures = 0;
+        Ok(())
+    }
+}
+
+/// Migration requested through `/s3/migrate`, also the saved state of a [`MigrationJob`]: a
+/// resumed migration skips the attachments recorded in its progress file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct MigrationJobState {
+    pub storage_directory: PathBuf,
+    /// `s3-migrate.progress` in the storage area by default.
+    #[serde(default)]
+    pub progress_file: Option<PathBuf>,
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default = "default_parallelism")]
+    pub parallelism: usize,
+    #[serde(default = "default_max_buffered_mib", rename = "MaxBufferedMiB")]
+    pub max_buffered_mib: u64,
+}
+
+fn default_parallelism() -> usize {
+    8
+}
+
+fn default_max_buffered_mib() -> u64 {
+    512
+}
+
+/// Migration of a filesystem storage area into the bucket run by the Orthanc jobs engine, like
+/// the `s3-migrate` binary.
+pub struct MigrationJob {
+    s3: S3Client,
+    runtime: Handle,
+    state: MigrationJobState,
+    options: MigrationOptions,
+    migration: Option<Migration>,
+    report: Option<MigrationReport>,
+}
+
+impl MigrationJob {
+    pub fn new(s3: S3Client, runtime: Handle, bucket: &str, state: MigrationJobState) -> Self {
+        let options = MigrationOptions {
+            progress_file: state
+                .progress_file
+                .clone()
+                .unwrap_or_else(|| state.storage_directory.join("s3-migrate.progress")),
+            storage_directory: state.storage_directory.clone(),
+            bucket: bucket.to_owned(),
+            dry_run: state.dry_run,
+            parallelism: state.parallelism,
+            max_buffered_bytes: state.max_buffered_mib.saturating_mul(1024 * 1024),
+        };
+        Self {
+            s3,
+            runtime,
+            state,
+            options,
+            migration: None,
+            report: None,
+        }
+    }
+}
+
+impl Job for MigrationJob {
+    const TYPE: &'static str = "S3Migration";
+    type State = MigrationJobState;
+
+    fn state(&self) -> Option<MigrationJobState> {
+        Some(self.state.clone())
+    }
+
+    fn step(&mut self) -> Result<JobStep, Box<dyn Error>> {
+        if self.report.is_some() {
+            return Ok(JobStep::Success);
+        }
+
+        let migration = match self.migration.as_mut() {
+            Some(migration) => migration,
+            None => self
+                .migration
+                .insert(Migration::start(self.options.clone())?),
+        };
+
+        self.runtime
+            .block_on(migration.upload(&self.s3, MIGRATION_BATCH))?;
+        if !migration.is_complete() {
+            return Ok(JobStep::Continue);
+        }
+
+        //
+        // A failed migration can be resubmitted, it only retries the failed attachments
+        //
+        let report = self.migration.take().expect("migration started").finish();
+        let failed = !report.failed.is_empty();
+        self.report = Some(report);
+        Ok(if failed {
+            JobStep::Failure
+        } else {
+            JobStep::Success
+        })
+    }
+
+    fn progress(&self) -> f32 {
+        let report = match (&self.migration, &self.report) {
+            (_, Some(_)) => return 1.0,
+            (Some(migration), None) => migration.report(),
+            (None, None) => return 0.0,
+        };
+        (report.uploaded + report.failed.len()) as f32 / report.pending.max(1) as f32
+    }
+
+    fn content(&self) -> serde_json::Value {
+        let mut content = json!({
+            "StorageDirectory": self.options.storage_directory,
+            "ProgressFile": self.options.progress_file,
+            "DryRun": self.options.dry_run,
+        });
+        let report = self
+            .report
+            .as_ref()
+            .or_else(|| self.migration.as_ref().map(Migration::report));
+        if let Some(report) = report {
+            content["Discovered"] = report.discovered.into();
+            content["AlreadyMigrated"] = report.already_migrated.into();
+            content["Pending"] = report.pending.into();
+            content["PendingBytes"] = report.pending_bytes.into();
+            content["Uploaded"] = report.uploaded.into();
+            content["UploadedBytes"] = report.uploaded_bytes.into();
+            content["Ignored"] = report.ignored.len().into();
+            content["Failed"] = json!(report.failed);
+        }
+        content
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        //
+        // A paused migration resumes where it stopped, otherwise it starts over from its
+        // progress file
+        //
+        if reason != StopReason::Paused {
+            self.migration = None;
+        }
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.migration = None;
+        self.report = None;
+        Ok(())
+    }
+}
+
+/// Change of storage class requested through `/s3/storage-class`, also the saved state of a
+/// [`StorageClassJob`]: a resumed change starts over and skips the objects already moved.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StorageClassJobState {
+    /// e.g. `STANDARD_IA` or `GLACIER_IR`.
+    pub storage_class: String,
+    /// Orthanc studies whose attachments are moved.
+    pub studies: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StorageClassReport {
+    pub changed: usize,
+    /// Objects already in the storage class.
+    pub unchanged: usize,
+    /// Attachments without an object, e.g. still in the staging directory.
+    pub missing: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+/// Outcome of moving a single object.
+enum StorageClassChange {
+    Changed,
+    Unchanged,
+    Missing,
+}
+
+/// Bulk change of the storage class of the attachments of studies, run by the Orthanc jobs
+/// engine.
+pub struct StorageClassJob {
+    context: Context,
+    s3: S3Client,
+    runtime: Handle,
+    bucket: String,
+    state: StorageClassJobState,
+    /// Attachments of the studies, listed by the first step.
+    attachments: Option<Vec<String>>,
+    next: usize,
+    report: StorageClassReport,
+}
+
+impl StorageClassJob {
+    pub fn new(
+        context: Context,
+        s3: S3Client,
+        runtime: Handle,
+        bucket: &str,
+        state: StorageClassJobState,
+    ) -> Self {
+        Self {
+            context,
+            s3,
+            runtime,
+            bucket: bucket.to_owned(),
+            state,
+            attachments: None,
+            next: 0,
+            report: StorageClassReport::default(),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut attachments = BTreeSet::new();
+        for study in &self.state.studies {
+            match audit::study_attachments(self.context, study)? {
+                Some(uuids) => attachments.extend(uuids),
+                None => return Err(format!("unknown study {}", study).into()),
+            }
+        }
+        Ok(attachments.into_iter().collect())
+    }
+
+    async fn change(&self, uuid: &str) -> Result<StorageClassChange, S3Error> {
+        let key = object_key(uuid);
+        let head = match metrics::timed(
+            Operation::HeadObject,
+            self.s3.head_object(&self.bucket, &key),
+        )
+        .await
+        {
+            Ok(head) => head,
+            Err(e) if e.is_not_found() => return Ok(StorageClassChange::Missing),
+            Err(e) => return Err(e),
+        };
+        if head
+            .storage_class
+            .as_deref()
+            .unwrap_or(DEFAULT_STORAGE_CLASS)
+            == self.state.storage_class
+        {
+            return Ok(StorageClassChange::Unchanged);
+        }
+
+        metrics::timed(
+            Operation::CopyObject,
+            self.s3
+                .set_storage_class(&self.bucket, &key, &self.state.storage_class),
+        )
+        .await?;
+        Ok(StorageClassChange::Changed)
+    }
+}
+
+impl Job for StorageClassJob {
+    const TYPE: &'static str = "S3StorageClass";
+    type State = StorageClassJobState;
+
+    fn state(&self) -> Option<StorageClassJobState> {
+        Some(self.state.clone())
+    }
+
+    fn step(&mut self) -> Result<JobStep, Box<dyn Error>> {
+        if self.attachments.is_none() {
+            self.attachments = Some(self.list()?);
+        }
+        let attachments = self.attachments.as_deref().unwrap_or_default();
+        let batch: Vec<String> = attachments
+            .iter()
+            .skip(self.next)
+            .take(STORAGE_CLASS_BATCH)
+            .cloned()
+            .collect();
+        let remaining = attachments.len() - self.next - batch.len();
+
+        for uuid in batch {
+            match self.runtime.block_on(self.change(&uuid)) {
+                Ok(StorageClassChange::Changed) => self.report.changed += 1,
+                Ok(StorageClassChange::Unchanged) => self.report.unchanged += 1,
+                Ok(StorageClassChange::Missing) => self.report.missing.push(uuid),
+                Err(e) => {
+                    warn!("unable to change the storage class of {} - {}", uuid, e);
+                    self.report.failed.push(uuid);
+                }
+            }
+            self.next += 1;
+        }
+
+        Ok(if remaining > 0 {
+            JobStep::Continue
+        } else if !self.report.failed.is_empty() {
+            JobStep::Failure
+        } else {
+            info!(
+                "moved {} objects to {}",
+                self.report.changed, self.state.storage_class
+            );
+            JobStep::Success
+        })
+    }
+
+    fn progress(&self) -> f32 {
+        match &self.attachments {
+            Some(attachments) if !attachments.is_empty() => {
+                self.next as f32 / attachments.len() as f32
+            }
+            Some(_) => 1.0,
+            None => 0.0,
+        }
+    }
+
+    fn content(&self) -> serde_json::Value {
+        let mut content = json!({
+            "StorageClass": self.state.storage_class,
+            "Studies": self.state.studies.len(),
+        });
+        if let Some(attachments) = &self.attachments {
+            content["Attachments"] = attachments.len().into();
+            content["Changed"] = self.report.changed.into();
+            content["Unchanged"] = self.report.unchanged.into();
+            content["Missing"] = json!(self.report.missing);
+            content["Failed"] = json!(self.report.failed);
+        }
+        content
+    }
+
+    fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.attachments = None;
+        self.next = 0;
+        self.report = StorageClassReport::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use md5::{Digest, Md5};
+
+    use super::*;
+    use crate::stub::{Response, Stub};
+
+    fn store(root: &std::path::Path, uuid: &str, content: &[u8]) {
+        let directory = root.join(&uuid[0..2]).join(&uuid[2..4]);
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join(uuid), content).unwrap();
+    }
+
+    #[test]
+    fn migration_state_has_defaults() {
+        let state: MigrationJobState =
+            serde_json::from_str(r#"{"StorageDirectory": "/var/lib/orthanc/db"}"#).unwrap();
+        assert_eq!(
+            state.storage_directory,
+            PathBuf::from("/var/lib/orthanc/db")
+        );
+        assert_eq!(state.progress_file, None);
+        assert!(!state.dry_run);
+        assert_eq!(state.parallelism, 8);
+        assert_eq!(state.max_buffered_mib, 512);
+    }
+
+    #[test]
+    fn migration_job_uploads_the_storage_area() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stub = runtime.block_on(Stub::start(|request| {
+            Response::ok().header(
+                "etag",
+                &format!("\"{}\"", hex::encode(Md5::digest(&request.body))),
+            )
+        }));
+
+        let root = tempfile::tempdir().unwrap();
+        store(
+            root.path(),
+            "0a1b2c3d-0000-4000-8000-000000000001",
+            b"first",
+        );
+        store(
+            root.path(),
+            "0a1b9999-0000-4000-8000-000000000002",
+            b"second",
+        );
+        let state = MigrationJobState {
+            storage_directory: root.path().to_owned(),
+            progress_file: None,
+            dry_run: false,
+            parallelism: 2,
+            max_buffered_mib: 1,
+        };
+        let mut job =
+            MigrationJob::new(stub.s3_client(), runtime.handle().clone(), "bucket", state);
+        assert_eq!(job.progress(), 0.0);
+
+        assert_eq!(job.step().unwrap(), JobStep::Success);
+        assert_eq!(job.progress(), 1.0);
+        let content = job.content();
+        assert_eq!(content["Uploaded"], 2);
+        assert_eq!(content["UploadedBytes"], 11);
+        assert_eq!(stub.requests().len(), 2);
+        let progress = std::fs::read_to_string(root.path().join("s3-migrate.progress")).unwrap();
+        assert_eq!(progress.lines().count(), 2);
+
+        //
+        // A resubmitted migration skips the migrated attachments
+        //
+        job.reset().unwrap();
+        assert_eq!(job.step().unwrap(), JobStep::Success);
+        assert_eq!(job.content()["AlreadyMigrated"], 2);
+        assert_eq!(stub.requests().len(), 2);
+    }
+
+    #[test]
+    fn migration_job_fails_when_an_upload_fails() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stub = runtime.block_on(Stub::start(|_| Response::status(403)));
+
+        let root = tempfile::tempdir().unwrap();
+        store(
+            root.path(),
+            "0a1b2c3d-0000-4000-8000-000000000001",
+            b"first",
+        );
+        let mut job = MigrationJob::new(
+            stub.s3_client(),
+            runtime.handle().clone(),
+            "bucket",
+            MigrationJobState {
+                storage_directory: root.path().to_owned(),
+                progress_file: Some(root.path().join("progress")),
+                dry_run: false,
+                parallelism: 1,
+                max_buffered_mib: 1,
+            },
+        );
+
+        assert_eq!(job.step().unwrap(), JobStep::Failure);
+        assert_eq!(job.content()["Failed"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            job.state().unwrap().progress_file,
+            Some(root.path().join("progress"))
+        );
+    }
+
+    #[test]
+    fn migration_job_resumes_from_its_saved_state() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stub = runtime.block_on(Stub::start(|request| {
+            Response::ok().header(
+                "etag",
+                &format!("\"{}\"", hex::encode(Md5::digest(&request.body))),
+            )
+        }));
+
+        let root = tempfile::tempdir().unwrap();
+        store(
+            root.path(),
+            "0a1b2c3d-0000-4000-8000-000000000001",
+            b"first",
+        );
+        let mut job = MigrationJob::new(
+            stub.s3_client(),
+            runtime.handle().clone(),
+            "bucket",
+            MigrationJobState {
+                storage_directory: root.path().to_owned(),
+                progress_file: Some(root.path().join("progress")),
+                dry_run: false,
+                parallelism: 3,
+                max_buffered_mib: 16,
+            },
+        );
+        assert_eq!(job.step().unwrap(), JobStep::Success);
+        assert_eq!(stub.requests().len(), 1);
+
+        //
+        // Orthanc saves the state as JSON and gives it back to the unserializer on restart
+        //
+        let saved = serde_json::to_string(&job.state().unwrap()).unwrap();
+        let state: MigrationJobState = serde_json::from_str(&saved).unwrap();
+        assert_eq!(state.storage_directory, root.path());
+        assert_eq!(state.progress_file, Some(root.path().join("progress")));
+        assert_eq!(state.parallelism, 3);
+        assert_eq!(state.max_buffered_mib, 16);
+
+        let mut resumed =
+            MigrationJob::new(stub.s3_client(), runtime.handle().clone(), "bucket", state);
+        assert_eq!(resumed.step().unwrap(), JobStep::Success);
+        assert_eq!(resumed.content()["AlreadyMigrated"], 1);
+        assert_eq!(stub.requests().len(), 1);
+    }
+
+    unsafe extern "C" fn unavailable(
+        _context: *mut orthanc_plugin_bindings::OrthancPluginContext,
+        _service: orthanc_plugin_bindings::_OrthancPluginService,
+        _params: *const std::ffi::c_void,
+    ) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
+        orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented
+    }
+
+    /// Job whose attachments are already listed: the studies are not looked up in Orthanc.
+    fn storage_class_job(
+        stub: &Stub,
+        runtime: &tokio::runtime::Runtime,
+        attachments: &[&str],
+    ) -> StorageClassJob {
+        let raw = Box::leak(Box::new(orthanc_plugin_bindings::OrthancPluginContext {
+            pluginsManager: std::ptr::null_mut(),
+            orthancVersion: c"mainline".as_ptr(),
+            Free: None,
+            InvokeService: Some(unavailable),
+        }));
+        let mut job = StorageClassJob::new(
+            unsafe { Context::from_raw(raw) },
+            stub.s3_client(),
+            runtime.handle().clone(),
+            "bucket",
+            StorageClassJobState {
+                storage_class: "GLACIER_IR".to_string(),
+                studies: vec!["study".to_string()],
+            },
+        );
+        job.attachments = Some(attachments.iter().map(|a| a.to_string()).collect());
+        job
+    }
+
+    #[test]
+    fn storage_class_job_moves_the_objects() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stub = runtime.block_on(Stub::start(|request| {
+            match (request.method.as_str(), request.path()) {
+                ("HEAD", path) if path.ends_with("/archived") => {
+                    Response::ok().header("x-amz-storage-class", "GLACIER_IR")
+                }
+                ("HEAD", path) if path.ends_with("/staged") => Response::status(404),
+                ("HEAD", _) => Response::ok(),
+                _ => Response::ok().body(
+                    "<CopyObjectResult><ETag>\"781e5e245d69b566979b86e28d23f2c7\"</ETag>\
+                     </CopyObjectResult>",
+                ),
+            }
+        }));
+        let mut job = storage_class_job(&stub, &runtime, &["archived", "current", "staged"]);
+        assert_eq!(job.progress(), 0.0);
+
+        assert_eq!(job.step().unwrap(), JobStep::Success);
+        assert_eq!(job.progress(), 1.0);
+        let content = job.content();
+        assert_eq!(content["Changed"], 1);
+        assert_eq!(content["Unchanged"], 1);
+        assert_eq!(content["Missing"], json!(["staged"]));
+
+        let copies: Vec<_> = stub
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == "PUT")
+            .collect();
+        assert_eq!(copies.len(), 1);
+        assert!(copies[0].path().ends_with("/current"));
+        assert_eq!(copies[0].headers["x-amz-storage-class"], "GLACIER_IR");
+        assert_eq!(copies[0].headers["x-amz-metadata-directive"], "COPY");
+        assert!(copies[0].headers["x-amz-copy-source"].ends_with("/current"));
+    }
+
+    #[test]
+    fn storage_class_job_fails_when_a_copy_fails() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stub = runtime.block_on(Stub::start(|request| match request.method.as_str() {
+            "HEAD" => Response::ok(),
+            _ => Response::status(403),
+        }));
+        let mut job = storage_class_job(&stub, &runtime, &["current"]);
+
+        assert_eq!(job.step().unwrap(), JobStep::Failure);
+        assert_eq!(job.content()["Failed"], json!(["current"]));
+
+        job.reset().unwrap();
+        assert_eq!(job.progress(), 0.0);
+        assert_eq!(job.state().unwrap().storage_class, "GLACIER_IR");
+    }
+}
@@ -0,0 +1,34 @@
+/// Object key used to store the attachment identified by `uuid` in the bucket.
+///
+/// Every component that reads or writes attachments (the storage callbacks as well as
+/// offline tooling such as the migration binary) must go through this function, so that
+/// all of them agree on where an attachment lives.
+pub fn object_key(uuid: &str) -> String {
+    uuid.to_owned()
+}
+
+/// Attachment uuid stored under `key`, or `None` if the key was not produced by [`object_key`].
+pub fn uuid_from_key(key: &str) -> Option<&str> {
+    uuid::Uuid::parse_str(key).ok().map(|_| key)
+}
+
+/// Prefix under which removed attachments are kept while retention is enabled.
+pub const TRASH_PREFIX: &str = "trash/";
+
+/// Prefix under which legal holds are persisted.
+pub const LEGAL_HOLD_PREFIX: &str = "legal-holds/";
+
+/// Object key of the attachment `uuid` once it has been moved to the trash.
+pub fn trash_key(uuid: &str) -> String {
+    format!("{}{}", TRASH_PREFIX, object_key(uuid))
+}
+
+/// Attachment uuid of a trashed object.
+pub fn uuid_from_trash_key(key: &str) -> Option<&str> {
+    key.strip_prefix(TRASH_PREFIX).and_then(uuid_from_key)
+}
+
+/// Object key of the legal hold placed on the Orthanc study `study`.
+pub fn legal_hold_key(study: &str) -> String {
+    format!("{}{}", LEGAL_HOLD_PREFIX, study)
+}
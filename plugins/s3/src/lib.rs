@@ -1,6 +1,26 @@
 #[macro_use]
 extern crate lazy_static;
 
+pub mod audit;
+pub mod client;
+pub mod commitment;
 pub mod config;
+pub mod credentials;
+pub mod diagnostics;
 pub mod events;
+pub mod index;
+pub mod jobs;
+pub mod layout;
+pub mod limits;
+pub mod metrics;
+pub mod migrate;
 pub mod plugin;
+pub mod query_retrieve;
+pub mod reader;
+pub mod signature;
+pub mod staging;
+#[cfg(test)]
+mod stub;
+pub mod transport;
+pub mod trash;
+pub mod webdav;
@@ -0,0 +1,301 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::config::Config;
+
+/// Storage callbacks sharing a concurrency limit.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Lane {
+    Read,
+    Write,
+    Remove,
+}
+
+impl Lane {
+    const ALL: [Lane; 3] = [Lane::Read, Lane::Write, Lane::Remove];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lane::Read => "read",
+            Lane::Write => "write",
+            Lane::Remove => "remove",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LimitError {
+    #[error("no {0} slot became available within {1:?}")]
+    QueueTimeout(&'static str, Duration),
+}
+
+struct LaneState {
+    /// Unbounded when unset.
+    semaphore: Option<Semaphore>,
+    limit: Option<usize>,
+    waiting: AtomicU64,
+    in_flight: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+impl LaneState {
+    fn new(limit: Option<usize>) -> Self {
+        Self {
+            semaphore: limit.map(|limit| Semaphore::new(limit.max(1))),
+            limit,
+            waiting: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Queue of a lane, as reported by `/s3/stats`.
+#[derive(Serialize, Debug)]
+pub struct QueueStats {
+    pub limit: Option<usize>,
+    pub in_flight: u64,
+    pub waiting: u64,
+    pub timeouts: u64,
+}
+
+/// Bounds the number of concurrent S3 requests issued by the storage callbacks.
+///
+/// Orthanc calls the storage area from as many worker threads as it has, e.g. during a C-STORE
+/// burst. Callbacks wait for a free slot of their lane and give up after the queue timeout, so a
+/// slow object store results in failed requests rather than an ever growing number of sockets.
+pub struct Limits {
+    lanes: [LaneState; Lane::ALL.len()],
+    queue_timeout: Option<Duration>,
+}
+
+/// Slot of a lane, released when dropped.
+pub struct Permit<'a> {
+    lane: &'a LaneState,
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.lane.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            lanes: [
+                LaneState::new(config.s3_max_concurrent_reads),
+                LaneState::new(config.s3_max_concurrent_writes),
+                LaneState::new(config.s3_max_concurrent_removes),
+            ],
+            queue_timeout: config.s3_queue_timeout_secs.map(Duration::from_secs),
+        }
+    }
+
+    /// Wait for a free slot of `lane`.
+    pub async fn acquire(&self, lane: Lane) -> Result<Permit<'_>, LimitError> {
+        let state = &self.lanes[lane as usize];
+
+        let permit = match &state.semaphore {
+            Some(semaphore) => {
+                state.waiting.fetch_add(1, Ordering::Relaxed);
+                let acquired = match self.queue_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, semaphore.acquire())
+                        .await
+                        .map_err(|_| LimitError::QueueTimeout(lane.name(), timeout)),
+                    None => Ok(semaphore.acquire().await),
+                };
+                state.waiting.fetch_sub(1, Ordering::Relaxed);
+
+                if acquired.is_err() {
+                    state.timeouts.fetch_add(1, Ordering::Relaxed);
+                }
+                Some(acquired?.expect("storage semaphores are never closed"))
+            }
+            None => None,
+        };
+
+        state.in_flight.fetch_add(1, Ordering::Relaxed);
+        Ok(Permit {
+            lane: state,
+            _permit: permit,
+        })
+    }
+
+    pub fn stats(&self) -> Vec<(&'static str, QueueStats)> {
+        Lane::ALL
+            .iter()
+            .map(|lane| {
+                let state = &self.lanes[*lane as usize];
+                (
+                    lane.name(),
+                    QueueStats {
+                        limit: state.limit,
+                        in_flight: state.in_flight.load(Ordering::Relaxed),
+                        waiting: state.waiting.load(Ordering::Relaxed),
+                        timeouts: state.timeouts.load(Ordering::Relaxed),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// Queue metrics, named the way they are published to Orthanc.
+    pub fn snapshot(&self) -> Vec<(String, f32)> {
+        self.stats()
+            .into_iter()
+            .flat_map(|(name, stats)| {
+                [
+                    (format!("s3_{}_in_flight", name), stats.in_flight as f32),
+                    (format!("s3_{}_queue_depth", name), stats.waiting as f32),
+                    (
+                        format!("s3_{}_queue_timeouts_total", name),
+                        stats.timeouts as f32,
+                    ),
+                ]
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn limits(reads: Option<usize>, queue_timeout: Option<Duration>) -> Limits {
+        Limits {
+            lanes: [
+                LaneState::new(reads),
+                LaneState::new(None),
+                LaneState::new(Some(2)),
+            ],
+            queue_timeout,
+        }
+    }
+
+    fn metric(limits: &Limits, name: &str) -> f32 {
+        limits
+            .snapshot()
+            .into_iter()
+            .find(|(metric, _)| metric == name)
+            .unwrap()
+            .1
+    }
+
+    #[tokio::test]
+    async fn lanes_are_limited_separately() {
+        let limits = limits(Some(1), Some(Duration::from_millis(50)));
+
+        let read = limits.acquire(Lane::Read).await.unwrap();
+        let writes = [
+            limits.acquire(Lane::Write).await.unwrap(),
+            limits.acquire(Lane::Write).await.unwrap(),
+            limits.acquire(Lane::Write).await.unwrap(),
+        ];
+        let removes = [
+            limits.acquire(Lane::Remove).await.unwrap(),
+            limits.acquire(Lane::Remove).await.unwrap(),
+        ];
+        assert!(limits.acquire(Lane::Read).await.is_err());
+        assert!(limits.acquire(Lane::Remove).await.is_err());
+
+        assert_eq!(metric(&limits, "s3_read_in_flight"), 1.0);
+        assert_eq!(metric(&limits, "s3_write_in_flight"), 3.0);
+        assert_eq!(metric(&limits, "s3_remove_in_flight"), 2.0);
+
+        drop((read, writes, removes));
+        assert_eq!(metric(&limits, "s3_read_in_flight"), 0.0);
+        assert_eq!(metric(&limits, "s3_write_in_flight"), 0.0);
+        assert_eq!(metric(&limits, "s3_remove_in_flight"), 0.0);
+        assert!(limits.acquire(Lane::Read).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn callers_give_up_after_the_queue_timeout() {
+        let limits = limits(Some(1), Some(Duration::from_millis(50)));
+        let _read = limits.acquire(Lane::Read).await.unwrap();
+
+        let started = std::time::Instant::now();
+        match limits.acquire(Lane::Read).await {
+            Err(LimitError::QueueTimeout(lane, timeout)) => {
+                assert_eq!(lane, "read");
+                assert_eq!(timeout, Duration::from_millis(50));
+            }
+            Ok(_) => panic!("acquired a slot of a full lane"),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(limits.acquire(Lane::Read).await.is_err());
+
+        assert_eq!(metric(&limits, "s3_read_queue_timeouts_total"), 2.0);
+        assert_eq!(metric(&limits, "s3_read_queue_depth"), 0.0);
+        assert_eq!(metric(&limits, "s3_read_in_flight"), 1.0);
+        assert_eq!(metric(&limits, "s3_write_queue_timeouts_total"), 0.0);
+    }
+
+    #[tokio::test]
+    async fn waiting_callers_are_counted_in_the_queue_depth() {
+        let limits = Arc::new(limits(Some(1), None));
+        let read = limits.acquire(Lane::Read).await.unwrap();
+
+        let waiting: Vec<_> = (0..3)
+            .map(|_| {
+                let limits = limits.clone();
+                tokio::spawn(async move {
+                    let _read = limits.acquire(Lane::Read).await.unwrap();
+                })
+            })
+            .collect();
+        while metric(&limits, "s3_read_queue_depth") < 3.0 {
+            tokio::task::yield_now().await;
+        }
+        let stats = limits.stats();
+        assert_eq!(stats[0].0, "read");
+        assert_eq!(stats[0].1.limit, Some(1));
+        assert_eq!(stats[0].1.waiting, 3);
+        assert_eq!(stats[0].1.in_flight, 1);
+
+        drop(read);
+        for caller in waiting {
+            caller.await.unwrap();
+        }
+        assert_eq!(metric(&limits, "s3_read_queue_depth"), 0.0);
+        assert_eq!(metric(&limits, "s3_read_in_flight"), 0.0);
+        assert_eq!(metric(&limits, "s3_read_queue_timeouts_total"), 0.0);
+    }
+
+    #[test]
+    fn limits_are_configured_per_lane() {
+        let config: Config = envy::from_iter([
+            (
+                "S3_ENDPOINT".to_string(),
+                "http://localhost:9000".to_string(),
+            ),
+            ("S3_BUCKET".to_string(), "bucket".to_string()),
+            ("S3_REGION".to_string(), "us-east-1".to_string()),
+            ("S3_MAX_CONCURRENT_READS".to_string(), "8".to_string()),
+            ("S3_MAX_CONCURRENT_REMOVES".to_string(), "2".to_string()),
+            ("S3_QUEUE_TIMEOUT_SECS".to_string(), "30".to_string()),
+        ])
+        .unwrap();
+        let limits = Limits::from_config(&config);
+
+        let configured: Vec<_> = limits
+            .stats()
+            .into_iter()
+            .map(|(lane, stats)| (lane, stats.limit))
+            .collect();
+        assert_eq!(
+            configured,
+            [("read", Some(8)), ("write", None), ("remove", Some(2))]
+        );
+        assert_eq!(limits.queue_timeout, Some(Duration::from_secs(30)));
+    }
+}
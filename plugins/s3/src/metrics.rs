@@ -0,0 +1,220 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Upper bounds (in milliseconds) of the buckets of the latency histograms.
+const LATENCY_BUCKETS_MS: [u64; 12] =
+    [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+/// S3 operations issued by the plugin.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operation {
+    PutObject,
+    GetObject,
+    GetObjectRange,
+    HeadObject,
+    DeleteObject,
+    CopyObject,
+    ListObjects,
+    ListBuckets,
+    HeadBucket,
+}
+
+impl Operation {
+    const ALL: [Operation; 9] = [
+        Operation::PutObject,
+        Operation::GetObject,
+        Operation::GetObjectRange,
+        Operation::HeadObject,
+        Operation::DeleteObject,
+        Operation::CopyObject,
+        Operation::ListObjects,
+        Operation::ListBuckets,
+        Operation::HeadBucket,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Operation::PutObject => "put_object",
+            Operation::GetObject => "get_object",
+            Operation::GetObjectRange => "get_object_range",
+            Operation::HeadObject => "head_object",
+            Operation::DeleteObject => "delete_object",
+            Operation::CopyObject => "copy_object",
+            Operation::ListObjects => "list_objects",
+            Operation::ListBuckets => "list_buckets",
+            Operation::HeadBucket => "head_bucket",
+        }
+    }
+}
+
+#[derive(Default)]
+struct OperationMetrics {
+    success: AtomicU64,
+    failure: AtomicU64,
+    latency_sum_ms: AtomicU64,
+    latency_max_ms: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+impl OperationMetrics {
+    fn record(&self, success: bool, elapsed: Duration) {
+        if success {
+            self.success.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failure.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let elapsed_ms = elapsed.as_millis() as u64;
+        self.latency_sum_ms.fetch_add(elapsed_ms, Ordering::Relaxed);
+        self.latency_max_ms.fetch_max(elapsed_ms, Ordering::Relaxed);
+
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| elapsed_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Estimate a latency quantile, as the upper bound of the bucket it falls in.
+    fn quantile(&self, counts: &[u64], total: u64, quantile: f64) -> u64 {
+        let rank = (total as f64 * quantile).ceil() as u64;
+        let mut seen = 0;
+        for (i, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return LATENCY_BUCKETS_MS
+                    .get(i)
+                    .copied()
+                    .unwrap_or_else(|| self.latency_max_ms.load(Ordering::Relaxed));
+            }
+        }
+
+        self.latency_max_ms.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters published to Orthanc's metrics (`/tools/metrics-prometheus`).
+#[derive(Default)]
+pub struct Metrics {
+    operations: [OperationMetrics; Operation::ALL.len()],
+    uploaded_bytes: AtomicU64,
+    downloaded_bytes: AtomicU64,
+    retries: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+}
+
+impl Metrics {
+    pub fn record(&self, operation: Operation, success: bool, elapsed: Duration) {
+        self.operations[operation as usize].record(success, elapsed);
+    }
+
+    pub fn add_uploaded_bytes(&self, bytes: u64) {
+        self.uploaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded_bytes(&self, bytes: u64) {
+        self.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a read served from the local copy of an attachment (`hit`) or from the bucket.
+    pub fn add_cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.cache_hits
+        } else {
+            &self.cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current value of every metric, named the way they are published to Orthanc.
+    pub fn snapshot(&self) -> Vec<(String, f32)> {
+        let mut values = vec![
+            (
+                "s3_uploaded_bytes_total".to_string(),
+                self.uploaded_bytes.load(Ordering::Relaxed) as f32,
+            ),
+            (
+                "s3_downloaded_bytes_total".to_string(),
+                self.downloaded_bytes.load(Ordering::Relaxed) as f32,
+            ),
+            (
+                "s3_retries_total".to_string(),
+                self.retries.load(Ordering::Relaxed) as f32,
+            ),
+        ];
+
+        let hits = self.cache_hits.load(Ordering::Relaxed);
+        let misses = self.cache_misses.load(Ordering::Relaxed);
+        values.push(("s3_cache_hits_total".to_string(), hits as f32));
+        values.push(("s3_cache_misses_total".to_string(), misses as f32));
+        values.push((
+            "s3_cache_hit_ratio".to_string(),
+            match hits + misses {
+                0 => 0.0,
+                lookups => hits as f32 / lookups as f32,
+            },
+        ));
+
+        for operation in Operation::ALL {
+            let metrics = &self.operations[operation as usize];
+            let name = operation.name();
+            let success = metrics.success.load(Ordering::Relaxed);
+            let failure = metrics.failure.load(Ordering::Relaxed);
+            let total = success + failure;
+
+            values.push((format!("s3_{}_success_total", name), success as f32));
+            values.push((format!("s3_{}_failure_total", name), failure as f32));
+
+            if total == 0 {
+                continue;
+            }
+
+            let counts: Vec<u64> = metrics
+                .latency_buckets
+                .iter()
+                .map(|b| b.load(Ordering::Relaxed))
+                .collect();
+            let total = counts.iter().sum::<u64>().max(1);
+
+            values.push((
+                format!("s3_{}_latency_avg_ms", name),
+                metrics.latency_sum_ms.load(Ordering::Relaxed) as f32 / total as f32,
+            ));
+            values.push((
+                format!("s3_{}_latency_max_ms", name),
+                metrics.latency_max_ms.load(Ordering::Relaxed) as f32,
+            ));
+            for (label, quantile) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+                values.push((
+                    format!("s3_{}_latency_{}_ms", name, label),
+                    metrics.quantile(&counts, total, quantile) as f32,
+                ));
+            }
+        }
+
+        values
+    }
+}
+
+/// Await an S3 request, recording its outcome and latency.
+pub async fn timed<T, E, F>(operation: Operation, request: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let timer = Instant::now();
+    let result = request.await;
+    METRICS.record(operation, result.is_ok(), timer.elapsed());
+    result
+}
@@ -0,0 +1,557 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use futures::StreamExt;
+use md5::{Digest, Md5};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+use crate::{
+    client::{PutOptions, S3Client},
+    layout::object_key,
+};
+
+/// Options controlling a migration of an Orthanc filesystem storage area into S3.
+#[derive(Debug, Clone)]
+pub struct MigrationOptions {
+    /// Root of the Orthanc storage area (`StorageDirectory` in the Orthanc configuration).
+    pub storage_directory: PathBuf,
+    /// File recording the attachments that were already uploaded, one uuid per line.
+    pub progress_file: PathBuf,
+    pub bucket: String,
+    pub dry_run: bool,
+    pub parallelism: usize,
+    /// Maximum size of the attachments read in memory at once, an attachment larger than this
+    /// is uploaded alone.
+    pub max_buffered_bytes: u64,
+}
+
+/// Attachment discovered in the Orthanc filesystem storage area (`ab/cd/abcd...`).
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub uuid: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationFailure {
+    pub uuid: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    pub discovered: usize,
+    pub already_migrated: usize,
+    pub pending: usize,
+    pub pending_bytes: u64,
+    pub uploaded: usize,
+    pub uploaded_bytes: u64,
+    pub ignored: Vec<PathBuf>,
+    pub failed: Vec<MigrationFailure>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationError {
+    #[error("unable to read '{0}' - {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("upload rejected by storage - {0}")]
+    Upload(String),
+    #[error("checksum mismatch - expected {expected}, storage reported {actual}")]
+    Checksum { expected: String, actual: String },
+}
+
+/// Walk an Orthanc filesystem storage area and collect its attachments.
+///
+/// Orthanc stores every attachment as `<root>/<uuid[0..2]>/<uuid[2..4]>/<uuid>`. Anything that
+/// does not follow this layout (e.g. the SQLite index) is returned as ignored.
+pub fn discover(root: &Path) -> std::io::Result<(Vec<Attachment>, Vec<PathBuf>)> {
+    let mut attachments = Vec::new();
+    let mut ignored = Vec::new();
+
+    for first in std::fs::read_dir(root)? {
+        let first = first?;
+        if !is_prefix_directory(&first)? {
+            ignored.push(first.path());
+            continue;
+        }
+
+        for second in std::fs::read_dir(first.path())? {
+            let second = second?;
+            if !is_prefix_directory(&second)? {
+                ignored.push(second.path());
+                continue;
+            }
+
+            let prefix = format!(
+                "{}{}",
+                first.file_name().to_string_lossy(),
+                second.file_name().to_string_lossy()
+            );
+
+            for entry in std::fs::read_dir(second.path())? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                let metadata = entry.metadata()?;
+
+                if metadata.is_file()
+                    && name.starts_with(&prefix)
+                    && uuid::Uuid::parse_str(&name).is_ok()
+                {
+                    attachments.push(Attachment {
+                        uuid: name,
+                        path: entry.path(),
+                        size: metadata.len(),
+                    });
+                } else {
+                    ignored.push(entry.path());
+                }
+            }
+        }
+    }
+
+    Ok((attachments, ignored))
+}
+
+fn is_prefix_directory(entry: &std::fs::DirEntry) -> std::io::Result<bool> {
+    let name = entry.file_name();
+    let name = name.to_string_lossy();
+    Ok(entry.file_type()?.is_dir()
+        && name.len() == 2
+        && name.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Resumable record of the attachments that were successfully uploaded.
+struct Progress {
+    completed: HashSet<String>,
+    file: Option<Mutex<File>>,
+}
+
+impl Progress {
+    fn open(path: &Path, read_only: bool) -> std::io::Result<Self> {
+        let completed = match File::open(path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .map(|line| line.map(|l| l.trim().to_string()))
+                .filter(|line| !matches!(line, Ok(l) if l.is_empty()))
+                .collect::<std::io::Result<HashSet<_>>>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+
+        let file = if read_only {
+            None
+        } else {
+            Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            ))
+        };
+
+        Ok(Self { completed, file })
+    }
+
+    fn record(&self, uuid: &str) -> std::io::Result<()> {
+        if let Some(file) = &self.file {
+            let mut file = file.lock().expect("progress file lock poisoned");
+            writeln!(file, "{}", uuid)?;
+            file.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Upload a single attachment, letting the object store verify the payload with `Content-MD5`
+/// and comparing the returned ETag with the local digest.
+pub async fn upload(
+    s3: &S3Client,
+    bucket: &str,
+    attachment: &Attachment,
+) -> Result<u64, MigrationError> {
+    let content = tokio::fs::read(&attachment.path)
+        .await
+        .map_err(|e| MigrationError::Io(attachment.path.clone(), e))?;
+
+    let digest = Md5::digest(&content);
+    let expected = hex::encode(digest);
+    let size = content.len() as u64;
+
+    let options = PutOptions {
+        content_md5: Some(base64::encode(digest)),
+        ..Default::default()
+    };
+
+    let put = s3
+        .put_object(bucket, &object_key(&attachment.uuid), content, options)
+        .await
+        .map_err(|e| MigrationError::Upload(format!("{}", e)))?;
+
+    //
+    // The store accepted the upload only if it matched the Content-MD5 header, objects whose ETag
+    // is not an MD5 (e.g. SSE-KMS encryption) are therefore verified too
+    //
+    match put.md5() {
+        Some(actual) if actual != expected => Err(MigrationError::Checksum { expected, actual }),
+        _ => Ok(size),
+    }
+}
+
+/// Uploads hold permits of the memory budget in KiB, so that the budget of a semaphore is
+/// enough for attachments of several GiB.
+const BUDGET_UNIT: u64 = 1024;
+
+/// Permits of the memory budget taken by the upload of `size` bytes.
+fn budget_permits(size: u64, budget: u32) -> u32 {
+    size.div_ceil(BUDGET_UNIT).clamp(1, budget as u64) as u32
+}
+
+/// Migration of the attachments of a filesystem storage area that are not yet recorded in the
+/// progress file, uploaded batch after batch.
+pub struct Migration {
+    options: MigrationOptions,
+    progress: Progress,
+    /// Attachments that were not uploaded yet, the next ones last.
+    pending: Vec<Attachment>,
+    report: MigrationReport,
+}
+
+impl Migration {
+    /// Discover the attachments of the storage area and those that were already migrated.
+    pub fn start(options: MigrationOptions) -> anyhow::Result<Self> {
+        let (attachments, ignored) = discover(&options.storage_directory)?;
+        let progress = Progress::open(&options.progress_file, options.dry_run)?;
+
+        let discovered = attachments.len();
+        let mut pending: Vec<_> = attachments
+            .into_iter()
+            .filter(|a| !progress.completed.contains(&a.uuid))
+            .collect();
+        pending.reverse();
+
+        let report = MigrationReport {
+            dry_run: options.dry_run,
+            discovered,
+            already_migrated: discovered - pending.len(),
+            pending: pending.len(),
+            pending_bytes: pending.iter().map(|a| a.size).sum(),
+            ignored,
+            ..Default::default()
+        };
+
+        info!(
+            "discovered {} attachments, {} already migrated, {} pending ({} bytes)",
+            report.discovered, report.already_migrated, report.pending, report.pending_bytes
+        );
+
+        Ok(Self {
+            options,
+            progress,
+            pending,
+            report,
+        })
+    }
+
+    /// Whether every pending attachment was uploaded or failed, always true for a dry run.
+    pub fn is_complete(&self) -> bool {
+        self.options.dry_run || self.pending.is_empty()
+    }
+
+    pub fn report(&self) -> &MigrationReport {
+        &self.report
+    }
+
+    /// Upload up to `count` pending attachments, nothing for a dry run.
+    pub async fn upload(&mut self, s3: &S3Client, count: usize) -> anyhow::Result<()> {
+        if self.options.dry_run {
+            return Ok(());
+        }
+
+        let batch = self
+            .pending
+            .split_off(self.pending.len().saturating_sub(count));
+
+        let budget = self
+            .options
+            .max_buffered_bytes
+            .div_ceil(BUDGET_UNIT)
+            .clamp(1, u32::MAX as u64) as u32;
+        let memory = Semaphore::new(budget as usize);
+        let bucket = &self.options.bucket;
+
+        let mut uploads = futures::stream::iter(batch.iter().rev())
+            .map(|attachment| {
+                let memory = &memory;
+                async move {
+                    let _permit = memory
+                        .acquire_many(budget_permits(attachment.size, budget))
+                        .await
+                        .expect("memory budget is never closed");
+                    (attachment, upload(s3, bucket, attachment).await)
+                }
+            })
+            .buffer_unordered(self.options.parallelism.max(1));
+
+        while let Some((attachment, result)) = uploads.next().await {
+            let report = &mut self.report;
+            match result {
+                Ok(size) => {
+                    self.progress.record(&attachment.uuid)?;
+                    report.uploaded += 1;
+                    report.uploaded_bytes += size;
+                    debug!(
+                        "migrated {} ({}/{})",
+                        attachment.uuid, report.uploaded, report.pending
+                    );
+                }
+                Err(e) => {
+                    warn!("could not migrate '{}' - {}", attachment.uuid, e);
+                    report.failed.push(MigrationFailure {
+                        uuid: attachment.uuid.clone(),
+                        reason: format!("{}", e),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> MigrationReport {
+        if !self.options.dry_run {
+            info!(
+                "migration finished - {} uploaded ({} bytes), {} failed",
+                self.report.uploaded,
+                self.report.uploaded_bytes,
+                self.report.failed.len()
+            );
+        }
+        self.report
+    }
+}
+
+/// Copy every attachment of the filesystem storage area that is not yet recorded in the
+/// progress file into the bucket.
+pub async fn run(s3: &S3Client, options: &MigrationOptions) -> anyhow::Result<MigrationReport> {
+    let mut migration = Migration::start(options.clone())?;
+    migration.upload(s3, usize::MAX).await?;
+    Ok(migration.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::stub::{Response, Stub};
+
+    const FIRST: &str = "0a1b2c3d-0000-4000-8000-000000000001";
+    const SECOND: &str = "0a1b9999-0000-4000-8000-000000000002";
+
+    fn store(root: &Path, uuid: &str, content: &[u8]) -> PathBuf {
+        let directory = root.join(&uuid[0..2]).join(&uuid[2..4]);
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(uuid);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn options(root: &Path, progress_file: PathBuf, dry_run: bool) -> MigrationOptions {
+        MigrationOptions {
+            storage_directory: root.to_path_buf(),
+            progress_file,
+            bucket: "bucket".to_string(),
+            dry_run,
+            parallelism: 4,
+            max_buffered_bytes: 1024 * 1024,
+        }
+    }
+
+    /// S3 answering uploads with the MD5 of their body as ETag.
+    async fn bucket() -> Stub {
+        Stub::start(|request| {
+            Response::ok().header(
+                "etag",
+                &format!("\"{}\"", hex::encode(Md5::digest(&request.body))),
+            )
+        })
+        .await
+    }
+
+    #[test]
+    fn discover_follows_the_layout_of_orthanc() {
+        let root = tempfile::tempdir().unwrap();
+        let first = store(root.path(), FIRST, b"first");
+        std::fs::write(root.path().join("index"), b"sqlite").unwrap();
+        std::fs::create_dir_all(root.path().join("zz")).unwrap();
+        std::fs::write(root.path().join("0a").join("1b").join("not-a-uuid"), b"").unwrap();
+        let misplaced = root
+            .path()
+            .join("0a")
+            .join("1b")
+            .join("ffff0000-0000-4000-8000-000000000003");
+        std::fs::write(&misplaced, b"misplaced").unwrap();
+
+        let (attachments, ignored) = discover(root.path()).unwrap();
+
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].uuid, FIRST);
+        assert_eq!(attachments[0].path, first);
+        assert_eq!(attachments[0].size, 5);
+        assert_eq!(ignored.len(), 4);
+        assert!(ignored.contains(&root.path().join("index")));
+        assert!(ignored.contains(&root.path().join("zz")));
+        assert!(ignored.contains(&misplaced));
+    }
+
+    #[tokio::test]
+    async fn run_resumes_from_the_progress_file() {
+        let root = tempfile::tempdir().unwrap();
+        store(root.path(), FIRST, b"first");
+        store(root.path(), SECOND, b"second");
+        let progress_file = root.path().join("progress");
+        std::fs::write(&progress_file, format!("{}\n", FIRST)).unwrap();
+        let stub = bucket().await;
+
+        let report = run(
+            &stub.s3_client(),
+            &options(root.path(), progress_file.clone(), false),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.discovered, 2);
+        assert_eq!(report.already_migrated, 1);
+        assert_eq!(report.uploaded, 1);
+        assert_eq!(report.uploaded_bytes, 6);
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "PUT");
+        assert_eq!(requests[0].path(), format!("/bucket/{}", SECOND));
+        assert_eq!(requests[0].body, b"second");
+        assert_eq!(
+            requests[0].headers.get("content-md5").unwrap(),
+            &base64::encode(Md5::digest(b"second"))
+        );
+
+        let recorded = std::fs::read_to_string(&progress_file).unwrap();
+        assert_eq!(
+            recorded.lines().collect::<Vec<_>>(),
+            vec![FIRST, SECOND],
+            "the upload is recorded after the resumed one"
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_run_uploads_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        store(root.path(), FIRST, b"first");
+        let progress_file = root.path().join("progress");
+        let stub = bucket().await;
+
+        let report = run(
+            &stub.s3_client(),
+            &options(root.path(), progress_file.clone(), true),
+        )
+        .await
+        .unwrap();
+
+        assert!(report.dry_run);
+        assert_eq!(report.pending, 1);
+        assert_eq!(report.pending_bytes, 5);
+        assert_eq!(report.uploaded, 0);
+        assert!(stub.requests().is_empty());
+        assert!(!progress_file.exists());
+    }
+
+    #[tokio::test]
+    async fn checksum_mismatch_fails_the_attachment() {
+        let root = tempfile::tempdir().unwrap();
+        store(root.path(), FIRST, b"first");
+        let progress_file = root.path().join("progress");
+        let stub =
+            Stub::start(|_| Response::ok().header("etag", "\"00000000000000000000000000000000\""))
+                .await;
+
+        let report = run(
+            &stub.s3_client(),
+            &options(root.path(), progress_file.clone(), false),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.uploaded, 0);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].uuid, FIRST);
+        assert!(report.failed[0].reason.contains("checksum mismatch"));
+        assert_eq!(std::fs::read_to_string(&progress_file).unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn kms_encrypted_objects_are_accepted() {
+        let root = tempfile::tempdir().unwrap();
+        store(root.path(), FIRST, b"first");
+        let stub = Stub::start(|_| {
+            Response::ok()
+                .header("etag", "\"4f1c0d9b8e1a4c6f8d2e7a9b0c1d2e3f\"")
+                .header("x-amz-server-side-encryption", "aws:kms")
+        })
+        .await;
+
+        let report = run(
+            &stub.s3_client(),
+            &options(root.path(), root.path().join("progress"), false),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.uploaded, 1);
+        assert!(report.failed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn migration_uploads_batch_after_batch() {
+        let root = tempfile::tempdir().unwrap();
+        store(root.path(), FIRST, b"first");
+        store(root.path(), SECOND, b"second");
+        let progress_file = root.path().join("progress");
+        let stub = bucket().await;
+        let s3 = stub.s3_client();
+
+        let mut migration =
+            Migration::start(options(root.path(), progress_file.clone(), false)).unwrap();
+        assert!(!migration.is_complete());
+
+        migration.upload(&s3, 1).await.unwrap();
+        assert_eq!(migration.report().uploaded, 1);
+        assert_eq!(stub.requests().len(), 1);
+        assert!(!migration.is_complete());
+
+        migration.upload(&s3, 1).await.unwrap();
+        assert!(migration.is_complete());
+        let report = migration.finish();
+        assert_eq!(report.uploaded, 2);
+        assert_eq!(report.uploaded_bytes, 11);
+        assert_eq!(
+            std::fs::read_to_string(&progress_file)
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn budget_permits_are_bounded() {
+        assert_eq!(budget_permits(0, 16), 1);
+        assert_eq!(budget_permits(1025, 16), 2);
+        assert_eq!(budget_permits(1 << 40, 16), 16);
+    }
+}
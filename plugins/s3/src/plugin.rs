@@ -1,162 +1,691 @@
-use std::{sync::RwLock, time::Duration};
-
-use futures::TryStreamExt;
-use rusoto_core::Region;
-use rusoto_credential::StaticProvider;
-use rusoto_s3::{DeleteObjectRequest, GetObjectRequest, PutObjectRequest, S3Client, S3};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Read,
+    os::unix::fs::FileExt,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use orthanc_plugin_bindings::{
+    declare_plugin, instance, jobs,
+    metrics::{self as orthanc_metrics, MetricsType},
+    plugin::{Context, Plugin},
+    query_retrieve::{self, ResourceType},
+    rest::{Request, Response, RestError, Router},
+    storage_commitment, webdav,
+};
+use serde::Serialize;
 use tracing::{debug, info, warn};
 
-use crate::config::Config;
+use crate::{
+    audit::{AuditError, AuditOptions, Auditor},
+    client::{ClientOptions, PutOptions, S3Client, S3Error},
+    commitment::StorageCommitment,
+    config::Config,
+    credentials::CredentialsProvider,
+    diagnostics,
+    events::{ChangeType, Event, EventSink},
+    index::Index,
+    jobs::{
+        AuditJob, AuditJobState, IndexRebuildJob, IndexRebuildJobState, MigrationJob,
+        MigrationJobState, StorageClassJob, StorageClassJobState, SweepJob, SweepJobState,
+    },
+    layout::object_key,
+    limits::{Lane, Limits, Permit, QueueStats},
+    metrics::{self, Operation, METRICS},
+    query_retrieve::QueryRetrieve,
+    reader::InstanceReader,
+    staging::{Staging, StagingOptions},
+    transport,
+    trash::{LegalHoldRequest, Trash, TrashError, TrashOptions},
+    webdav::WebDav,
+};
+
+//
+// The plugin state is set once initialization completed and immutable afterwards. Callbacks are
+// only registered after the state is set, so they never observe a partially initialized plugin
+// and read the state without taking any lock.
+//
+declare_plugin!(STATE: PluginState);
+
+/// Runtime owning the background tasks, only taken by `OrthancPluginFinalize` to shut it down.
+static RUNTIME: Mutex<Option<tokio::runtime::Runtime>> = Mutex::new(None);
+
+pub struct PluginState {
+    runtime: tokio::runtime::Handle,
+    /// Orthanc, for the services of the bindings.
+    host: Context,
+    config: Config,
+    s3: S3Client,
+    limits: Arc<Limits>,
+    auditor: Arc<Auditor>,
+    trash: Option<Arc<Trash>>,
+    staging: Option<Arc<Staging>>,
+    query_retrieve: Option<QueryRetrieve>,
+    events: EventSink,
+    reader: InstanceReader,
+    started: SystemTime,
+}
+
+/// Wrapper struct for a callback function whose FFI will be generated automatically by `bindgen`.
+#[repr(C)]
+struct OnChangeParams {
+    callback: orthanc_plugin_bindings::OrthancPluginOnChangeCallback,
+}
+
+#[repr(C)]
+struct OrthancPluginStorageAreaParams {
+    create: orthanc_plugin_bindings::OrthancPluginStorageCreate,
+    read: orthanc_plugin_bindings::OrthancPluginStorageRead,
+    remove: orthanc_plugin_bindings::OrthancPluginStorageRemove,
+    free: orthanc_plugin_bindings::OrthancPluginFree,
+}
+
+#[repr(C)]
+struct OrthancPluginStorageArea2Params {
+    create: orthanc_plugin_bindings::OrthancPluginStorageCreate,
+    whole: orthanc_plugin_bindings::OrthancPluginStorageReadWhole,
+    range: orthanc_plugin_bindings::OrthancPluginStorageReadRange,
+    remove: orthanc_plugin_bindings::OrthancPluginStorageRemove,
+}
 
-lazy_static! {
-    static ref GLOBAL_STATE: RwLock<AppState> = {
+impl Plugin for PluginState {
+    /// Metrics are published with the services of Orthanc 1.5.4.
+    const MINIMAL_ORTHANC_VERSION: (u32, u32, u32) = (1, 5, 4);
+
+    fn initialize(orthanc: Context) -> Result<Self, Box<dyn std::error::Error>> {
         dotenv::dotenv().ok();
         if std::env::var("RUST_LOG").is_err() {
             std::env::set_var("RUST_LOG", "s3=debug")
         }
-        tracing_subscriber::fmt::init();
+        tracing_subscriber::fmt::try_init().ok();
+
+        info!("initializing");
 
-        let config: Config = envy::from_env().expect("unable to load s3 configuration");
+        let config: Config = envy::from_env()?;
         info!("config - {:#?}", &config);
+        //
+        // A single client is shared so that refreshed credentials are cached across requests
+        //
+        let s3 = S3Client::try_from(&config)?;
         let runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
-            .build()
-            .expect("unable to start tokio runtime");
+            .build()?;
+        let handle = runtime.handle().clone();
+        *RUNTIME.lock().expect("runtime lock poisoned") = Some(runtime);
+
+        let buckets: Vec<_> = handle.block_on(async {
+            metrics::timed(Operation::ListBuckets, s3.list_buckets())
+                .await
+                .map_err(|e| format!("unable to discover storage buckets - {}", e))
+        })?;
+
+        info!("discovered buckets - {buckets:#?}");
+
+        let staging = match config.s3_staging_directory.clone() {
+            Some(directory) => {
+                let (staging, receiver) = Staging::new(
+                    s3.clone(),
+                    StagingOptions {
+                        bucket: config.s3_bucket.to_owned(),
+                        directory,
+                        workers: config.s3_staging_workers,
+                    },
+                )?;
+                let staging = Arc::new(staging);
+
+                //
+                // Attachments staged before a crash are queued before Orthanc can read them
+                //
+                let recovered = staging.recover()?;
+                if recovered > 0 {
+                    info!("recovered {} staged attachments", recovered);
+                }
+
+                handle.spawn(staging.clone().run(receiver));
+                info!(
+                    "staging attachments in '{}'",
+                    staging.options().directory.display()
+                );
+                Some(staging)
+            }
+            None => None,
+        };
+
+        let auditor = Arc::new(Auditor::new(
+            orthanc,
+            s3.clone(),
+            staging.clone(),
+            AuditOptions {
+                bucket: config.s3_bucket.to_owned(),
+                delete_orphans: config.s3_audit_delete_orphans,
+                grace_period: Duration::from_secs(config.s3_audit_grace_period_secs),
+            },
+        ));
+
+        //
+        // Scheduled audits and sweeps run as Orthanc jobs, which can only be submitted once
+        // Orthanc started, hence after the first interval
+        //
+        if let Some(interval) = config.s3_audit_interval_secs {
+            let auditor = auditor.clone();
+            let runtime = handle.clone();
+            handle.spawn(async move {
+                let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    if auditor.is_running() {
+                        warn!("skipping scheduled storage audit, an audit is still running");
+                        continue;
+                    }
+                    let delete_orphans = auditor.options().delete_orphans;
+                    let job = AuditJob::new(auditor.clone(), runtime.clone(), delete_orphans);
+                    match jobs::submit(orthanc, job, 0) {
+                        Ok(id) => info!("submitted scheduled storage audit as job {}", id),
+                        Err(e) => warn!("unable to submit scheduled storage audit - {}", e),
+                    }
+                }
+            });
+            info!("scheduled storage audit every {} seconds", interval);
+        }
+
+        let trash = config.s3_trash_retention_days.map(|retention_days| {
+            let trash = Arc::new(Trash::new(
+                orthanc,
+                s3.clone(),
+                TrashOptions {
+                    bucket: config.s3_bucket.to_owned(),
+                    retention: Duration::from_secs(retention_days * 24 * 60 * 60),
+                },
+            ));
+
+            let sweeper = trash.clone();
+            let runtime = handle.clone();
+            let interval = config.s3_trash_sweep_interval_secs;
+            handle.spawn(async move {
+                let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    match jobs::submit(orthanc, SweepJob::new(sweeper.clone(), runtime.clone()), 0)
+                    {
+                        Ok(id) => info!("submitted trash sweep as job {}", id),
+                        Err(e) => warn!("unable to submit trash sweep - {}", e),
+                    }
+                }
+            });
+
+            info!("retaining removed attachments for {} days", retention_days);
+            trash
+        });
+
+        let limits = Arc::new(Limits::from_config(&config));
+
+        let reader = InstanceReader {
+            context: orthanc,
+            s3: s3.clone(),
+            bucket: config.s3_bucket.to_owned(),
+            runtime: handle.clone(),
+            limits: limits.clone(),
+            staging: staging.clone(),
+        };
 
-        let app_state = AppState {
-            runtime: Some(runtime),
-            context: None,
+        let query_retrieve = match config.s3_query_retrieve_index.as_ref() {
+            Some(path) => {
+                let index = Index::open(path)
+                    .map_err(|e| format!("unable to open '{}' - {}", path.display(), e))?;
+                info!("answering C-FIND and C-MOVE from '{}'", path.display());
+                Some(QueryRetrieve {
+                    context: orthanc,
+                    index: Arc::new(index),
+                    reader: reader.clone(),
+                    max_answers: config.s3_query_retrieve_max_answers,
+                })
+            }
+            None => None,
+        };
+
+        let events = {
+            let query_retrieve = query_retrieve.clone();
+            EventSink::start(&handle, move |event| {
+                handle_change(query_retrieve.as_ref(), event)
+            })
+        };
+
+        Ok(PluginState {
+            runtime: handle,
+            host: orthanc,
+            limits,
+            auditor,
+            trash,
+            staging,
+            query_retrieve,
+            events,
+            reader,
+            started: SystemTime::now(),
+            s3,
             config,
+        })
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn register(&'static self, orthanc: Context) -> Result<(), Box<dyn std::error::Error>> {
+        let context = orthanc.as_ptr();
+
+        let params = Box::new(OnChangeParams {
+            callback: Some(on_change),
+        });
+
+        let params: *const std::ffi::c_void = Box::into_raw(params) as *mut std::ffi::c_void;
+        unsafe {
+            let invoker = (*context).InvokeService;
+            invoker.unwrap()(
+                context,
+                orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RegisterOnChangeCallback,
+                params,
+            );
+        }
+
+        info!("successfully registered 'onchange' callbacks");
+
+        //
+        // Range reads and Orthanc allocated buffers need the storage area of Orthanc 1.9.0
+        //
+        if orthanc.check_version(1, 9, 0) {
+            let params = Box::new(OrthancPluginStorageArea2Params {
+                create: Some(storage_create),
+                whole: Some(storage_read_whole),
+                range: Some(storage_read_range),
+                remove: Some(storage_remove),
+            });
+            let params: *const std::ffi::c_void = Box::into_raw(params) as *mut std::ffi::c_void;
+            unsafe {
+                orthanc.invoke(
+                    orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RegisterStorageArea2,
+                    params,
+                );
+            }
+        } else {
+            warn!(
+                "Orthanc {} predates the storage area with range reads, attachments are read whole",
+                orthanc.orthanc_version()
+            );
+            let params = Box::new(OrthancPluginStorageAreaParams {
+                create: Some(storage_create),
+                read: Some(storage_read),
+                remove: Some(storage_remove),
+                free: Some(storage_free),
+            });
+            let params: *const std::ffi::c_void = Box::into_raw(params) as *mut std::ffi::c_void;
+            unsafe {
+                orthanc.invoke(
+                    orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RegisterStorageArea,
+                    params,
+                );
+            }
+        }
+
+        info!("successfully registered 'storage' callbacks");
+
+        match self.routes().register(orthanc) {
+            Ok(()) => info!("successfully registered 'rest' callbacks"),
+            Err(e) => warn!("unable to register the REST routes - {}", e),
+        }
+
+        if let Err(e) = orthanc_metrics::register_refresh(orthanc, move || self.refresh_metrics()) {
+            warn!("unable to register the metrics - {}", e);
+        }
+
+        info!("successfully registered 'metrics' callbacks");
+
+        self.register_jobs(orthanc);
+
+        if let Some(query_retrieve) = self.query_retrieve.as_ref() {
+            self.register_query_retrieve(orthanc, query_retrieve);
+        }
+
+        if let Some(uri) = self.config.s3_webdav_uri.as_deref() {
+            match webdav::register_collection(orthanc, uri, WebDav::new(self.reader.clone())) {
+                Ok(()) => info!("successfully registered the WebDAV collection at '{}'", uri),
+                Err(e) => warn!("unable to mount the WebDAV collection at '{}' - {}", uri, e),
+            }
+        }
+
+        if self.config.s3_storage_commitment {
+            let commitment = StorageCommitment {
+                reader: self.reader.clone(),
+            };
+            match storage_commitment::register(orthanc, commitment) {
+                Ok(()) => info!("successfully registered 'storage commitment' callbacks"),
+                Err(e) => warn!("unable to register the storage commitment SCP - {}", e),
+            }
+        }
+
+        info!("initialization complete");
+        Ok(())
+    }
+
+    fn finalize(&'static self) {
+        let runtime = RUNTIME.lock().expect("runtime lock poisoned").take();
+        let runtime = match runtime {
+            Some(runtime) => runtime,
+            None => return,
         };
 
-        RwLock::new(app_state)
-    };
-}
+        let deadline = Duration::from_secs(self.config.s3_shutdown_timeout_secs);
+        let remaining = runtime.block_on(self.events.drain(deadline));
+        if remaining > 0 {
+            warn!(
+                "{} changes were not handled, the query/retrieve index may need a rebuild",
+                remaining
+            );
+        }
 
-pub struct AppState {
-    runtime: Option<tokio::runtime::Runtime>,
-    config: Config,
-    context: Option<OrthancContext>,
+        //
+        // Pending uploads are waited for up to the deadline, the remaining ones are still in the
+        // staging directory and uploaded on the next start
+        //
+        if let Some(staging) = self.staging.as_ref() {
+            info!("draining staged uploads for up to {:?}", deadline);
+            let remaining = runtime.block_on(staging.drain(deadline));
+            if remaining > 0 {
+                warn!(
+                    "{} staged uploads did not complete, they resume on the next start",
+                    remaining
+                );
+            }
+        }
+
+        //
+        // Scheduled audits and sweeps are idempotent, they are simply dropped
+        //
+        runtime.shutdown_background();
+
+        info!("finalized");
+    }
 }
 
-/// Wrapper struct for a callback function whose FFI will be generated automatically by `bindgen`.
-#[repr(C)]
-struct OnChangeParams {
-    callback: orthanc_plugin_bindings::OrthancPluginOnChangeCallback,
+impl PluginState {
+    /// Resume the jobs saved by Orthanc before it stopped.
+    fn register_jobs(&'static self, orthanc: Context) {
+        let registered = jobs::register_unserializer(orthanc, move |state: AuditJobState| {
+            Ok(AuditJob::new(
+                self.auditor.clone(),
+                self.runtime.clone(),
+                state.delete_orphans,
+            ))
+        });
+        if let Err(e) = registered {
+            warn!("unable to register the audit jobs - {}", e);
+        }
+
+        let registered = jobs::register_unserializer(orthanc, move |state: MigrationJobState| {
+            Ok(MigrationJob::new(
+                self.s3.clone(),
+                self.runtime.clone(),
+                &self.config.s3_bucket,
+                state,
+            ))
+        });
+        if let Err(e) = registered {
+            warn!("unable to register the migration jobs - {}", e);
+        }
+
+        let registered =
+            jobs::register_unserializer(orthanc, move |state: StorageClassJobState| {
+                Ok(StorageClassJob::new(
+                    orthanc,
+                    self.s3.clone(),
+                    self.runtime.clone(),
+                    &self.config.s3_bucket,
+                    state,
+                ))
+            });
+        if let Err(e) = registered {
+            warn!("unable to register the storage class jobs - {}", e);
+        }
+
+        if let Some(trash) = self.trash.as_ref() {
+            let registered = jobs::register_unserializer(orthanc, move |_: SweepJobState| {
+                Ok(SweepJob::new(trash.clone(), self.runtime.clone()))
+            });
+            if let Err(e) = registered {
+                warn!("unable to register the trash sweep jobs - {}", e);
+            }
+        }
+
+        if let Some(query_retrieve) = self.query_retrieve.as_ref() {
+            let registered =
+                jobs::register_unserializer(orthanc, move |_: IndexRebuildJobState| {
+                    Ok(IndexRebuildJob::new(query_retrieve.clone()))
+                });
+            if let Err(e) = registered {
+                warn!("unable to register the index rebuild jobs - {}", e);
+            }
+        }
+
+        info!("successfully registered 'jobs' unserializers");
+    }
+
+    fn register_query_retrieve(&self, orthanc: Context, query_retrieve: &QueryRetrieve) {
+        let indexing = query_retrieve.clone();
+        let registered = instance::register_on_stored_instance(orthanc, move |instance, id| {
+            indexing.index_stored(instance, id)
+        });
+        match registered {
+            Ok(()) => info!("successfully registered 'stored instance' callbacks"),
+            Err(e) => warn!("unable to index the stored instances - {}", e),
+        }
+
+        match query_retrieve::register_find(orthanc, query_retrieve.clone()) {
+            Ok(()) => info!("successfully registered 'C-FIND' callbacks"),
+            Err(e) => warn!("unable to register the C-FIND handler - {}", e),
+        }
+
+        //
+        // Moved instances are sent with `/modalities/{id}/store-straight`
+        //
+        if !orthanc.check_version(1, 6, 1) {
+            warn!(
+                "Orthanc {} cannot send the instances of a C-MOVE, C-MOVE is left to Orthanc",
+                orthanc.orthanc_version()
+            );
+            return;
+        }
+        match query_retrieve::register_move(orthanc, query_retrieve.clone()) {
+            Ok(()) => info!("successfully registered 'C-MOVE' callbacks"),
+            Err(e) => warn!("unable to register the C-MOVE handler - {}", e),
+        }
+    }
 }
 
 #[repr(C)]
-struct OrthancPluginStorageArea2Params {
-    create: orthanc_plugin_bindings::OrthancPluginStorageCreate,
-    whole: orthanc_plugin_bindings::OrthancPluginStorageReadWhole,
-    range: orthanc_plugin_bindings::OrthancPluginStorageReadRange,
-    remove: orthanc_plugin_bindings::OrthancPluginStorageRemove,
+struct CreateBufferParams {
+    target: *mut orthanc_plugin_bindings::OrthancPluginMemoryBuffer64,
+    size: usize,
 }
 
-struct OrthancContext(*mut orthanc_plugin_bindings::OrthancPluginContext);
-unsafe impl Send for OrthancContext {}
-unsafe impl Sync for OrthancContext {}
+/// Destination of a whole attachment, allocated once its size is known.
+trait WholeBuffer {
+    fn allocate(&mut self, size: u64) -> Option<&mut [u8]>;
 
-#[allow(clippy::not_unsafe_ptr_arg_deref)]
-#[no_mangle]
-pub extern "C" fn OrthancPluginInitialize(
-    context: *mut orthanc_plugin_bindings::OrthancPluginContext,
-) -> i32 {
-    info!("initializing");
+    /// Release the buffer after a failed read.
+    fn release(&mut self);
+}
 
-    let mut app_state = GLOBAL_STATE.try_write().expect("unable to obtain lock");
-    app_state.context = Some(OrthancContext(context));
+/// Buffer allocated by Orthanc, for `RegisterStorageArea2`.
+struct MemoryBuffer {
+    context: Context,
+    target: *mut orthanc_plugin_bindings::OrthancPluginMemoryBuffer64,
+}
 
-    let s3 = S3Client::try_from(&app_state.config).expect("failed to create s3 client");
-    let buckets: Vec<_> = app_state
-        .runtime
-        .as_ref()
-        .unwrap()
-        .block_on(async move {
-            match s3.list_buckets().await {
-                Ok(resp) => match resp.buckets {
-                    Some(buckets) => Ok(buckets),
-                    None => Err("empty body".to_string()),
-                },
-                Err(e) => Err(format!("{}", e)),
+impl WholeBuffer for MemoryBuffer {
+    fn allocate(&mut self, size: u64) -> Option<&mut [u8]> {
+        let params = CreateBufferParams {
+            target: self.target,
+            size: usize::try_from(size).ok()?,
+        };
+
+        unsafe {
+            let error = self.context.invoke(
+                orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_CreateMemoryBuffer64,
+                &params as *const CreateBufferParams as *const std::ffi::c_void,
+            );
+            if error != 0 {
+                return None;
             }
-        })
-        .expect("unable to discover storage buckets")
-        .into_iter()
-        .map(|b| b.name.unwrap())
-        .collect();
 
-    info!("discovered buckets - {buckets:#?}");
+            if params.size == 0 {
+                return Some(&mut []);
+            }
 
-    let params = Box::new(OnChangeParams {
-        callback: Some(on_change),
-    });
+            let data = (*self.target).data as *mut u8;
+            (!data.is_null()).then(|| std::slice::from_raw_parts_mut(data, params.size))
+        }
+    }
 
-    let params: *const std::ffi::c_void = Box::into_raw(params) as *mut std::ffi::c_void;
-    unsafe {
-        let invoker = (*context).InvokeService;
-        invoker.unwrap()(
-            context,
-            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RegisterOnChangeCallback,
-            params,
-        );
+    fn release(&mut self) {
+        unsafe {
+            if !(*self.target).data.is_null() {
+                self.context.free((*self.target).data);
+                (*self.target).data = std::ptr::null_mut();
+                (*self.target).size = 0;
+            }
+        }
     }
+}
 
-    info!("successfully registered 'onchange' callbacks");
+/// Size of the length prefix of a [`PluginBuffer`].
+const PLUGIN_BUFFER_HEADER: usize = std::mem::size_of::<u64>();
 
-    let params = Box::new(OrthancPluginStorageArea2Params {
-        create: Some(storage_create),
-        whole: Some(storage_read_whole),
-        range: Some(storage_read_range),
-        remove: Some(storage_remove),
-    });
-    let params: *const std::ffi::c_void = Box::into_raw(params) as *mut std::ffi::c_void;
-    unsafe {
-        let invoker = (*context).InvokeService;
-        invoker.unwrap()(
-            context,
-            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RegisterStorageArea2,
-            params,
-        );
+/// Buffer allocated by the plugin and released by Orthanc with [`storage_free`], for the
+/// `RegisterStorageArea` of Orthanc versions before 1.9.0.
+///
+/// The allocation is prefixed with its length, since [`storage_free`] only receives a pointer.
+struct PluginBuffer {
+    content: *mut *mut std::ffi::c_void,
+    size: *mut i64,
+}
+
+impl WholeBuffer for PluginBuffer {
+    fn allocate(&mut self, size: u64) -> Option<&mut [u8]> {
+        let len = usize::try_from(size).ok()?;
+        let layout = plugin_buffer_layout(len)?;
+
+        unsafe {
+            let allocation = std::alloc::alloc(layout);
+            if allocation.is_null() {
+                return None;
+            }
+            (allocation as *mut u64).write(size);
+
+            let data = allocation.add(PLUGIN_BUFFER_HEADER);
+            *self.content = data as *mut std::ffi::c_void;
+            *self.size = size as i64;
+            Some(std::slice::from_raw_parts_mut(data, len))
+        }
     }
 
-    info!("successfully registered 'storage' callbacks");
+    fn release(&mut self) {
+        unsafe {
+            storage_free(*self.content);
+            *self.content = std::ptr::null_mut();
+            *self.size = 0;
+        }
+    }
+}
 
-    info!("initialization complete");
-    0
+fn plugin_buffer_layout(len: usize) -> Option<std::alloc::Layout> {
+    std::alloc::Layout::from_size_align(
+        len.checked_add(PLUGIN_BUFFER_HEADER)?,
+        std::mem::align_of::<u64>(),
+    )
+    .ok()
 }
 
-#[no_mangle]
-pub extern "C" fn OrthancPluginFinalize() {
-    let mut app_state = GLOBAL_STATE.try_write().expect("unable to obtain lock");
-    if let Some(runtime) = app_state.runtime.take() {
-        runtime.shutdown_timeout(Duration::from_secs(5));
+extern "C" fn storage_free(buffer: *mut std::ffi::c_void) {
+    if buffer.is_null() {
+        return;
     }
 
-    //
-    // Give background runtime time to clean up
-    //
-    std::thread::sleep(Duration::from_secs(5));
-
-    info!("finalized");
+    unsafe {
+        let allocation = (buffer as *mut u8).sub(PLUGIN_BUFFER_HEADER);
+        let len = (allocation as *const u64).read() as usize;
+        if let Some(layout) = plugin_buffer_layout(len) {
+            std::alloc::dealloc(allocation, layout);
+        }
+    }
 }
 
-#[no_mangle]
-pub extern "C" fn OrthancPluginGetName() -> *const u8 {
-    info!("OrthancPluginGetName");
-    "s3\0".as_ptr()
+/// Wait for a free slot of `lane`, failing with an Orthanc timeout once the queue timeout elapsed.
+fn acquire(
+    app_state: &PluginState,
+    lane: Lane,
+) -> Result<Permit<'_>, orthanc_plugin_bindings::OrthancPluginErrorCode> {
+    app_state
+        .runtime
+        .block_on(app_state.limits.acquire(lane))
+        .map_err(|e| {
+            warn!("{}", e);
+            orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Timeout
+        })
 }
 
-#[no_mangle]
-pub extern "C" fn OrthancPluginGetVersion() -> *const u8 {
-    info!("OrthancPluginGetVersion");
-    "1.0.0\0".as_ptr()
+/// Serve a whole attachment that has not been uploaded yet from the staging directory.
+fn read_staged_whole(
+    buffer: &mut impl WholeBuffer,
+    uuid: &str,
+    mut file: File,
+) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
+    let size = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            warn!("unable to read staged '{}' - {}", uuid, e);
+            return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin;
+        }
+    };
+
+    let data = match buffer.allocate(size) {
+        Some(data) => data,
+        None => {
+            warn!("unable to allocate {} bytes for '{}'", size, uuid);
+            return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_NotEnoughMemory;
+        }
+    };
+
+    if let Err(e) = file.read_exact(data) {
+        warn!("unable to read staged '{}' - {}", uuid, e);
+        buffer.release();
+        return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin;
+    }
+
+    info!("read staged object {}", uuid);
+    0
 }
 
-#[repr(C)]
-struct CreateBufferParams {
+/// Serve a range of an attachment that has not been uploaded yet from the staging directory.
+fn read_staged_range(
     target: *mut orthanc_plugin_bindings::OrthancPluginMemoryBuffer64,
-    size: usize,
+    uuid: &str,
+    file: File,
+    range_start: u64,
+) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
+    let buffer = unsafe {
+        std::slice::from_raw_parts_mut((*target).data as *mut u8, (*target).size as usize)
+    };
+
+    match file.read_exact_at(buffer, range_start) {
+        Ok(_) => {
+            info!("read staged ranged object {}", uuid);
+            0
+        }
+        Err(e) => {
+            warn!("unable to read staged '{}' - {}", uuid, e);
+            orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin
+        }
+    }
 }
 
 extern "C" fn storage_read_range(
@@ -166,12 +695,10 @@ extern "C" fn storage_read_range(
     range_start: u64,
 ) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
     info!("storage_read_whole called {}", plugin_type);
-    match GLOBAL_STATE.try_read() {
-        Ok(app_state) => {
-            info!("aquired lock for storage read range");
-
+    match STATE.get() {
+        Some(app_state) => {
             let config = &app_state.config;
-            let s3 = S3Client::try_from(config).expect("failed to create s3 client");
+            let s3 = app_state.s3.clone();
 
             let range_size = unsafe { (*target).size };
 
@@ -184,37 +711,49 @@ extern "C" fn storage_read_range(
                 Ok(cstr) => {
                     let uuid = cstr.to_string();
 
-                    let get_req = GetObjectRequest {
-                        bucket: config.s3_bucket.to_owned(),
-                        key: uuid.to_owned(),
-                        ..Default::default()
+                    if let Some(file) = app_state.staging.as_ref().and_then(|s| s.cached(&uuid)) {
+                        return read_staged_range(target, &uuid, file, range_start);
+                    }
+
+                    let _permit = match acquire(app_state, Lane::Read) {
+                        Ok(permit) => permit,
+                        Err(error) => return error,
                     };
 
+                    let key = object_key(&uuid);
+                    let range = range_start..range_start + range_size;
+
                     info!("performing get object");
-                    let content = app_state.runtime.as_ref().unwrap().block_on(async move {
-                        match s3.get_object(get_req).await {
-                            Ok(mut resp) => match resp.body.take() {
-                                Some(body) => {
-                                    Ok(body.map_ok(|b| b.to_vec()).try_concat().await.unwrap())
-                                }
-                                None => Err("empty body".to_string()),
-                            },
-                            Err(e) => Err(format!("{}", e)),
-                        }
+                    let content = app_state.runtime.block_on(async move {
+                        metrics::timed(
+                            Operation::GetObjectRange,
+                            s3.get_object(&config.s3_bucket, &key, Some(range)),
+                        )
+                        .await
                     });
 
-                    if let Err(e) = content {
-                        warn!("{}", e);
-                        return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin;
-                    }
+                    let content = match content {
+                        Ok(content) if content.len() as u64 == range_size => content,
+                        Ok(content) => {
+                            warn!(
+                                "short range read of '{}' - expected {} bytes, got {}",
+                                uuid,
+                                range_size,
+                                content.len()
+                            );
+                            return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin;
+                        }
+                        Err(e) => {
+                            warn!("{}", e);
+                            return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin;
+                        }
+                    };
 
-                    let content = content.unwrap();
+                    METRICS.add_downloaded_bytes(content.len() as u64);
 
                     unsafe {
                         let data = (*target).data as *mut u8;
-
-                        let content = content.as_ptr().add(range_start as usize);
-                        std::ptr::copy_nonoverlapping(content, data, range_size as usize);
+                        std::ptr::copy_nonoverlapping(content.as_ptr(), data, content.len());
                     }
 
                     info!("read ranged object {}", &uuid);
@@ -227,8 +766,8 @@ extern "C" fn storage_read_range(
                 }
             }
         }
-        Err(e) => {
-            warn!("unable to get application state - {}", e);
+        None => {
+            warn!("storage request received before initialization");
             orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin
         }
     }
@@ -240,65 +779,95 @@ extern "C" fn storage_read_whole(
     plugin_type: orthanc_plugin_bindings::OrthancPluginContentType,
 ) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
     info!("storage_read_whole called {}", plugin_type);
-    match GLOBAL_STATE.try_read() {
-        Ok(app_state) => {
-            info!("aquired lock for storage read whole");
+    read_whole(uuid, |context| MemoryBuffer { context, target })
+}
+
+extern "C" fn storage_read(
+    content: *mut *mut ::std::os::raw::c_void,
+    size: *mut i64,
+    uuid: *const ::std::os::raw::c_char,
+    plugin_type: orthanc_plugin_bindings::OrthancPluginContentType,
+) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
+    info!("storage_read called {}", plugin_type);
+    read_whole(uuid, |_| PluginBuffer { content, size })
+}
 
-            let context = app_state.context.as_ref().unwrap().0;
+fn read_whole<B: WholeBuffer>(
+    uuid: *const ::std::os::raw::c_char,
+    buffer: impl FnOnce(Context) -> B,
+) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
+    match STATE.get() {
+        Some(app_state) => {
+            let mut buffer = buffer(app_state.host);
             let config = &app_state.config;
-            let s3 = S3Client::try_from(config).expect("failed to create s3 client");
+            let s3 = app_state.s3.clone();
 
             match unsafe { std::ffi::CStr::from_ptr(uuid) }.to_str() {
                 Ok(cstr) => {
                     let uuid = cstr.to_string();
-                    let get_req = GetObjectRequest {
-                        bucket: config.s3_bucket.to_owned(),
-                        key: uuid.to_owned(),
-                        ..Default::default()
-                    };
 
-                    info!("performing get_object");
-                    let content = app_state.runtime.as_ref().unwrap().block_on(async move {
-                        match s3.get_object(get_req).await {
-                            Ok(mut resp) => match resp.body.take() {
-                                Some(body) => {
-                                    Ok(body.map_ok(|b| b.to_vec()).try_concat().await.unwrap())
-                                }
-                                None => Err("empty body".to_string()),
-                            },
-                            Err(e) => Err(format!("{}", e)),
-                        }
-                    });
-
-                    if let Err(e) = content {
-                        warn!("{}", e);
-                        return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin;
+                    //
+                    // Staged attachments are local reads, only S3 reads are limited
+                    //
+                    if let Some(file) = app_state.staging.as_ref().and_then(|s| s.cached(&uuid)) {
+                        return read_staged_whole(&mut buffer, &uuid, file);
                     }
 
-                    let content = content.unwrap();
+                    let _permit = match acquire(app_state, Lane::Read) {
+                        Ok(permit) => permit,
+                        Err(error) => return error,
+                    };
 
-                    let params = Box::new(CreateBufferParams {
-                        target,
-                        size: content.len(),
-                    });
+                    let key = object_key(&uuid);
 
-                    let params: *const std::ffi::c_void =
-                        Box::into_raw(params) as *mut std::ffi::c_void;
-                    unsafe {
-                        let invoker = (*context).InvokeService;
-                        invoker.unwrap()(
-                            context,
-                            orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_CreateMemoryBuffer64,
-                            params,
-                        );
-                        let _ = Box::from_raw(params as *mut std::ffi::c_void);
-                    }
-
-                    unsafe {
-                        let data = (*target).data as *mut u8;
+                    info!("performing get_object");
+                    //
+                    // The body is streamed straight into the Orthanc buffer, so that large
+                    // objects are not held twice in memory
+                    //
+                    let size = app_state.runtime.block_on(async move {
+                        metrics::timed(Operation::GetObject, async {
+                            let stream = s3.get_object_stream(&config.s3_bucket, &key).await?;
+                            let size = match stream.content_length() {
+                                Some(size) => size,
+                                None => s3
+                                    .head_object(&config.s3_bucket, &key)
+                                    .await?
+                                    .content_length
+                                    .and_then(|size| u64::try_from(size).ok())
+                                    .ok_or_else(|| {
+                                        S3Error::InvalidResponse(
+                                            "object without content length".to_string(),
+                                        )
+                                    })?,
+                            };
+
+                            let data = buffer.allocate(size).ok_or_else(|| {
+                                S3Error::InvalidResponse(format!(
+                                    "unable to allocate {} bytes",
+                                    size
+                                ))
+                            })?;
+
+                            match stream.read_into(data).await {
+                                Ok(read) if read as u64 == size => Ok(size),
+                                Ok(read) => Err(S3Error::InvalidResponse(format!(
+                                    "expected {} bytes, got {}",
+                                    size, read
+                                ))),
+                                Err(e) => Err(e),
+                            }
+                            .inspect_err(|_| buffer.release())
+                        })
+                        .await
+                    });
 
-                        let content_ptr = content.as_ptr();
-                        std::ptr::copy_nonoverlapping(content_ptr, data, content.len());
+                    match size {
+                        Ok(size) => METRICS.add_downloaded_bytes(size),
+                        Err(e) => {
+                            warn!("unable to read '{}' - {}", uuid, e);
+                            return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin;
+                        }
                     }
 
                     info!("read object {}", &uuid);
@@ -311,8 +880,8 @@ extern "C" fn storage_read_whole(
                 }
             }
         }
-        Err(e) => {
-            warn!("unable to get application state - {}", e);
+        None => {
+            warn!("storage request received before initialization");
             orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin
         }
     }
@@ -324,27 +893,80 @@ extern "C" fn storage_remove(
 ) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
     info!("storage_remove called {}", plugin_type);
 
-    match GLOBAL_STATE.try_read() {
-        Ok(app_state) => {
-            info!("aquired lock for storage remove");
-
+    match STATE.get() {
+        Some(app_state) => {
             let config = &app_state.config;
-            let s3 = S3Client::try_from(config).expect("failed to create s3 client");
+            let s3 = app_state.s3.clone();
 
             match unsafe { std::ffi::CStr::from_ptr(uuid) }.to_str() {
                 Ok(cstr) => {
                     let uuid = cstr.to_string();
-                    let put_req = DeleteObjectRequest {
-                        bucket: config.s3_bucket.to_owned(),
-                        key: uuid.to_owned(),
-                        ..Default::default()
+
+                    //
+                    // Without trash, a staged attachment is discarded before it is ever uploaded.
+                    // Once uploaded, its object is deleted below
+                    //
+                    if let (Some(staging), None) = (app_state.staging.as_ref(), &app_state.trash) {
+                        match app_state.runtime.block_on(staging.cancel(&uuid)) {
+                            Ok(true) => {
+                                info!("removed staged DICOM {}", &uuid);
+                                return 0;
+                            }
+                            Ok(false) => {}
+                            Err(e) => {
+                                warn!("could not discard staged '{}' - {}", uuid, e);
+                                return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin;
+                            }
+                        }
+                    }
+
+                    let _permit = match acquire(app_state, Lane::Remove) {
+                        Ok(permit) => permit,
+                        Err(error) => return error,
                     };
 
+                    //
+                    // With trash, a staged attachment is uploaded first so that it can be
+                    // trashed, and no upload completes after its removal
+                    //
+                    if let (Some(staging), Some(_)) = (app_state.staging.as_ref(), &app_state.trash)
+                    {
+                        let uploaded = app_state.runtime.block_on(staging.upload(&uuid));
+                        if let Err(e) = uploaded {
+                            warn!("could not upload staged '{}' before removal - {}", uuid, e);
+                            return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin;
+                        }
+                    }
+
+                    if let Some(trash) = app_state.trash.as_ref() {
+                        info!("moving object to trash");
+                        let discarded = app_state
+                            .runtime
+                            .block_on(trash.discard(&uuid, plugin_type));
+
+                        return match discarded {
+                            Ok(_) => {
+                                info!("trashed DICOM {}", &uuid);
+                                0
+                            }
+                            Err(e) => {
+                                warn!("could not move instance '{}' to trash - {}", uuid, e);
+                                orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin
+                            }
+                        };
+                    }
+
+                    let key = object_key(&uuid);
                     let uuid_async = uuid.clone();
 
                     info!("deleting object");
-                    app_state.runtime.as_ref().unwrap().block_on(async move {
-                        if let Err(e) = s3.delete_object(put_req).await {
+                    app_state.runtime.block_on(async move {
+                        if let Err(e) = metrics::timed(
+                            Operation::DeleteObject,
+                            s3.delete_object(&config.s3_bucket, &key),
+                        )
+                        .await
+                        {
                             warn!(
                                 "could not delete instance '{}' from storage - {}",
                                 uuid_async, e
@@ -361,8 +983,8 @@ extern "C" fn storage_remove(
                 }
             }
         }
-        Err(e) => {
-            warn!("unable to get application state - {}", e);
+        None => {
+            warn!("storage request received before initialization");
             orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin
         }
     }
@@ -377,11 +999,21 @@ extern "C" fn storage_create(
     info!("storage_create called {}", plugin_type);
     use std::slice::*;
 
-    match GLOBAL_STATE.try_read() {
-        Ok(app_state) => {
-            info!("aquired lock for storage create");
+    match STATE.get() {
+        Some(app_state) => {
+            //
+            // Staged attachments are uploaded by the staging workers, outside of the write lane
+            //
+            let _permit = match app_state.staging {
+                Some(_) => None,
+                None => match acquire(app_state, Lane::Write) {
+                    Ok(permit) => Some(permit),
+                    Err(error) => return error,
+                },
+            };
+
             let config = &app_state.config;
-            let s3 = S3Client::try_from(config).expect("failed to create s3 client");
+            let s3 = app_state.s3.clone();
 
             match unsafe { std::ffi::CStr::from_ptr(uuid) }.to_str() {
                 Ok(cstr) => {
@@ -390,21 +1022,36 @@ extern "C" fn storage_create(
                     let content = content as *const u8;
                     let safe_content = unsafe { from_raw_parts(content, size as usize) };
 
-                    let put_req = PutObjectRequest {
-                        bucket: config.s3_bucket.to_owned(),
-                        key: uuid.to_owned(),
-                        body: Some(safe_content.to_vec().into()),
-                        ..Default::default()
-                    };
+                    if let Some(staging) = app_state.staging.as_ref() {
+                        return match staging.stage(&uuid, safe_content) {
+                            Ok(_) => {
+                                info!("staged DICOM {}", &uuid);
+                                0
+                            }
+                            Err(e) => {
+                                warn!("{}", e);
+                                orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin
+                            }
+                        };
+                    }
+
+                    let key = object_key(&uuid);
+                    let body = safe_content.to_vec();
 
                     let uuid_async = uuid.clone();
                     info!("uploading object");
-                    app_state.runtime.as_ref().unwrap().block_on(async move {
-                        if let Err(e) = s3.put_object(put_req).await {
-                            warn!(
+                    app_state.runtime.block_on(async move {
+                        match metrics::timed(
+                            Operation::PutObject,
+                            s3.put_object(&config.s3_bucket, &key, body, PutOptions::default()),
+                        )
+                        .await
+                        {
+                            Ok(_) => METRICS.add_uploaded_bytes(size as u64),
+                            Err(e) => warn!(
                                 "could not upload instance to storage '{}' from storage - {}",
                                 uuid_async, e
-                            );
+                            ),
                         }
                     });
 
@@ -417,13 +1064,280 @@ extern "C" fn storage_create(
                 }
             }
         }
-        Err(e) => {
-            warn!("unable to get application state - {}", e);
+        None => {
+            warn!("storage request received before initialization");
             orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin
         }
     }
 }
 
+/// Answer of a failed trash or legal hold request.
+fn trash_error(e: TrashError) -> RestError {
+    let status = match e {
+        TrashError::NotFound(_) => 404,
+        TrashError::NotRestorable(_, _) => 409,
+        _ => {
+            warn!("trash request failed - {}", e);
+            500
+        }
+    };
+
+    RestError::new(status, e.to_string())
+}
+
+/// Whether the GET argument `name` of a request is `true`.
+fn flag(request: &Request, name: &str) -> bool {
+    request.argument(name) == Some("true")
+}
+
+#[derive(Serialize)]
+struct Stats {
+    version: &'static str,
+    uptime_secs: u64,
+    audit_running: bool,
+    trash_enabled: bool,
+    staging_pending: Option<usize>,
+    queues: BTreeMap<&'static str, QueueStats>,
+    metrics: BTreeMap<String, f32>,
+}
+
+impl PluginState {
+    /// Routes under `/s3`, registered once the state is set so that they never observe a
+    /// partially initialized plugin.
+    fn routes(&'static self) -> Router {
+        let router = Router::new()
+            .get("/s3/audit", move |_| match self.auditor.last_report() {
+                Some(report) => Response::json(&report),
+                None => Err(RestError::not_found("no audit has run yet")),
+            })
+            .post("/s3/audit", move |request| self.audit(request))
+            .post("/s3/migrate", move |request| self.migrate(request))
+            .post("/s3/storage-class", move |request| {
+                self.change_storage_class(request)
+            })
+            .get("/s3/health", move |_| {
+                let health = self
+                    .runtime
+                    .block_on(diagnostics::health(&self.s3, &self.config.s3_bucket));
+                if health.healthy {
+                    Response::json(&health)
+                } else {
+                    warn!("storage health check failed - {:?}", health.error);
+                    Ok(Response::json(&health)?.status(503))
+                }
+            })
+            //
+            // Secrets are redacted when the configuration is serialized
+            //
+            .get("/s3/config", move |_| Response::json(&self.config))
+            .get("/s3/stats", move |_| Response::json(&self.stats()))
+            .get("/s3/objects/([^/]+)", move |request| {
+                let uuid = request.group(0).unwrap_or_default();
+                let info = self
+                    .runtime
+                    .block_on(diagnostics::object_info(
+                        &self.s3,
+                        &self.config.s3_bucket,
+                        uuid,
+                    ))
+                    .map_err(|e| {
+                        warn!("object lookup failed - {}", e);
+                        RestError::internal(e)
+                    })?;
+                match info {
+                    Some(info) => Response::json(&info),
+                    None => Err(RestError::not_found(format!(
+                        "no object stored for attachment '{}'",
+                        uuid
+                    ))),
+                }
+            });
+
+        let router = match self.query_retrieve.as_ref() {
+            Some(query_retrieve) => router.post("/s3/index/rebuild", move |_| {
+                let id = submit_index_rebuild(self.host, query_retrieve)
+                    .map_err(|e| RestError::internal(e.to_string()))?;
+                Response::json(&serde_json::json!({ "ID": id, "Path": format!("/jobs/{}", id) }))
+            }),
+            None => router,
+        };
+
+        match self.trash.as_ref() {
+            Some(trash) => self.trash_routes(router, trash),
+            None => router,
+        }
+    }
+
+    fn trash_routes(&'static self, router: Router, trash: &'static Arc<Trash>) -> Router {
+        let runtime = &self.runtime;
+        router
+            .get("/s3/trash", move |_| {
+                Response::json(&runtime.block_on(trash.list()).map_err(trash_error)?)
+            })
+            .post("/s3/trash/([^/]+)/restore", move |request| {
+                let uuid = request.group(0).unwrap_or_default();
+                Response::json(&runtime.block_on(trash.restore(uuid)).map_err(trash_error)?)
+            })
+            .get("/s3/legal-holds", move |_| {
+                Response::json(&runtime.block_on(trash.legal_holds()).map_err(trash_error)?)
+            })
+            .put("/s3/legal-holds/([^/]+)", move |request| {
+                let study = request.group(0).unwrap_or_default();
+                let hold = match request.body() {
+                    [] => LegalHoldRequest::default(),
+                    _ => request.json()?,
+                };
+                Response::json(
+                    &runtime
+                        .block_on(trash.place_legal_hold(study, hold.attachments))
+                        .map_err(trash_error)?,
+                )
+            })
+            .delete("/s3/legal-holds/([^/]+)", move |request| {
+                let study = request.group(0).unwrap_or_default();
+                runtime
+                    .block_on(trash.release_legal_hold(study))
+                    .map_err(trash_error)?;
+                Ok(Response::bytes("application/json", b"{}".to_vec()))
+            })
+    }
+
+    /// Run an audit, or submit it as a job with `asynchronous=true`.
+    fn audit(&'static self, request: &Request) -> Result<Response, RestError> {
+        let delete_orphans = flag(request, "delete-orphans");
+
+        if flag(request, "asynchronous") {
+            if self.auditor.is_running() {
+                return Err(RestError::new(409, "an audit is already running"));
+            }
+            let job = AuditJob::new(self.auditor.clone(), self.runtime.clone(), delete_orphans);
+            let id = jobs::submit(self.host, job, 0).map_err(|e| {
+                warn!("unable to submit storage audit - {}", e);
+                RestError::internal(e.to_string())
+            })?;
+            return Response::json(
+                &serde_json::json!({ "ID": id, "Path": format!("/jobs/{}", id) }),
+            );
+        }
+
+        match self.runtime.block_on(self.auditor.run(delete_orphans)) {
+            Ok(report) => Response::json(&report),
+            Err(e @ AuditError::AlreadyRunning) => Err(RestError::new(409, e.to_string())),
+            Err(e) => {
+                warn!("storage audit failed - {}", e);
+                Err(RestError::internal(e.to_string()))
+            }
+        }
+    }
+
+    /// Submit the migration of a filesystem storage area as a job.
+    fn migrate(&'static self, request: &Request) -> Result<Response, RestError> {
+        let state: MigrationJobState = request.json()?;
+        if !state.storage_directory.is_dir() {
+            return Err(RestError::bad_request(format!(
+                "'{}' is not a directory",
+                state.storage_directory.display()
+            )));
+        }
+
+        let job = MigrationJob::new(
+            self.s3.clone(),
+            self.runtime.clone(),
+            &self.config.s3_bucket,
+            state,
+        );
+        let id = jobs::submit(self.host, job, 0).map_err(|e| {
+            warn!("unable to submit migration - {}", e);
+            RestError::internal(e.to_string())
+        })?;
+        info!("submitted migration as job {}", id);
+        Response::json(&serde_json::json!({ "ID": id, "Path": format!("/jobs/{}", id) }))
+    }
+
+    fn change_storage_class(&'static self, request: &Request) -> Result<Response, RestError> {
+        let state: StorageClassJobState = request.json()?;
+        if state.storage_class.is_empty() {
+            return Err(RestError::bad_request("missing storage class"));
+        }
+        if state.studies.is_empty() {
+            return Err(RestError::bad_request("no study to move"));
+        }
+
+        let job = StorageClassJob::new(
+            self.host,
+            self.s3.clone(),
+            self.runtime.clone(),
+            &self.config.s3_bucket,
+            state,
+        );
+        let id = jobs::submit(self.host, job, 0).map_err(|e| {
+            warn!("unable to submit storage class change - {}", e);
+            RestError::internal(e.to_string())
+        })?;
+        info!("submitted storage class change as job {}", id);
+        Response::json(&serde_json::json!({ "ID": id, "Path": format!("/jobs/{}", id) }))
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_secs: self
+                .started
+                .elapsed()
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            audit_running: self.auditor.is_running(),
+            trash_enabled: self.trash.is_some(),
+            staging_pending: self.staging.as_ref().map(|s| s.pending()),
+            queues: self.limits.stats().into_iter().collect(),
+            metrics: METRICS.snapshot().into_iter().collect(),
+        }
+    }
+
+    fn refresh_metrics(&self) {
+        publish_metrics(
+            self.host,
+            &self.limits,
+            self.staging.as_deref(),
+            &self.events,
+        );
+    }
+}
+
+/// Set every metric of the plugin in Orthanc, which renders them on
+/// `/tools/metrics-prometheus`.
+fn publish_metrics(
+    orthanc: Context,
+    limits: &Limits,
+    staging: Option<&Staging>,
+    events: &EventSink,
+) {
+    let values = METRICS
+        .snapshot()
+        .into_iter()
+        .chain(limits.snapshot())
+        .chain(staging.map(|s| ("s3_staging_pending".to_string(), s.pending() as f32)))
+        .chain([
+            ("s3_events_queue_depth".to_string(), events.depth() as f32),
+            (
+                "s3_events_lag_ms".to_string(),
+                events.lag().as_millis() as f32,
+            ),
+        ]);
+    for (name, value) in values {
+        orthanc_metrics::set_value(orthanc, &name, value, MetricsType::Default);
+    }
+}
+
+fn submit_index_rebuild(
+    orthanc: Context,
+    query_retrieve: &QueryRetrieve,
+) -> Result<String, orthanc_plugin_bindings::context::OrthancError> {
+    let id = jobs::submit(orthanc, IndexRebuildJob::new(query_retrieve.clone()), 0)?;
+    info!("rebuilding the index as job {}", id);
+    Ok(id)
+}
+
 extern "C" fn on_change(
     change_type: orthanc_plugin_bindings::OrthancPluginChangeType,
     resource_type: orthanc_plugin_bindings::OrthancPluginResourceType,
@@ -446,25 +1360,330 @@ extern "C" fn on_change(
         change_type, resource_type, resource_id
     );
 
+    if let Some(state) = STATE.get() {
+        state
+            .events
+            .push(Event::new(change_type.into(), resource_type, resource_id));
+    }
+
     orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
 }
 
-impl TryFrom<&Config> for rusoto_s3::S3Client {
+/// Handle a change notified by Orthanc, off the thread of Orthanc.
+fn handle_change(query_retrieve: Option<&QueryRetrieve>, event: Event) {
+    let query_retrieve = match query_retrieve {
+        Some(query_retrieve) => query_retrieve,
+        None => return,
+    };
+
+    match (event.change_type, event.resource_id.as_deref()) {
+        //
+        // Instances stored before the index was created are indexed once Orthanc started,
+        // since jobs cannot be submitted earlier
+        //
+        (ChangeType::OrthancStarted, None) => match query_retrieve.index.is_empty() {
+            Ok(true) => match submit_index_rebuild(query_retrieve.context, query_retrieve) {
+                Ok(id) => info!("submitted the initial index rebuild as job {}", id),
+                Err(e) => warn!("unable to submit the initial index rebuild - {}", e),
+            },
+            Ok(false) => {}
+            Err(e) => warn!("unable to read the index - {}", e),
+        },
+        //
+        // New instances are indexed by the stored instance callback
+        //
+        (ChangeType::Deleted, Some(id)) => {
+            if let Some(level) = ResourceType::from_raw(event.resource_type) {
+                query_retrieve.remove(level, id);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl TryFrom<&Config> for S3Client {
     type Error = Box<dyn std::error::Error>;
 
     fn try_from(config: &Config) -> Result<Self, Self::Error> {
-        Ok(Self::new_with(
-            rusoto_core::request::HttpClient::new()?,
-            StaticProvider::new(
-                config.s3_access_key.to_owned(),
-                config.s3_secret_key.to_owned(),
-                None,
-                None,
-            ),
-            Region::Custom {
-                name: config.s3_region.to_owned(),
+        Ok(Self::new(
+            transport::http_client(config)?,
+            CredentialsProvider::from_config(config)?,
+            ClientOptions {
                 endpoint: config.s3_endpoint.to_owned(),
+                region: config.s3_region.to_owned(),
+                path_style: config.s3_force_path_style,
+                request_timeout: config.s3_request_timeout_secs.map(Duration::from_secs),
             },
-        ))
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        ffi::{c_void, CString},
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::*;
+    use crate::stub::{Response, Stub};
+
+    const OBJECT: &[u8] = b"0123456789";
+
+    /// Layout of the parameters of the `SetMetricsValue` service.
+    #[repr(C)]
+    struct SetMetricsValueParams {
+        name: *const std::ffi::c_char,
+        value: f32,
+        metrics_type: orthanc_plugin_bindings::OrthancPluginMetricsType,
+    }
+
+    thread_local! {
+        /// Metrics set on this thread, rendered as Orthanc exposes them to Prometheus.
+        static EXPOSITION: std::cell::RefCell<String> = const { std::cell::RefCell::new(String::new()) };
+    }
+
+    /// Orthanc services of the stand-in context: buffers are allocated like [`PluginBuffer`],
+    /// every other service succeeds without doing anything.
+    unsafe extern "C" fn invoke_service(
+        _context: *mut orthanc_plugin_bindings::OrthancPluginContext,
+        service: orthanc_plugin_bindings::_OrthancPluginService,
+        params: *const c_void,
+    ) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
+        if service
+            == orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_CreateMemoryBuffer64
+        {
+            let params = &*(params as *const CreateBufferParams);
+            let mut data = std::ptr::null_mut();
+            let mut size = 0;
+            let mut buffer = PluginBuffer {
+                content: &mut data,
+                size: &mut size,
+            };
+            if buffer.allocate(params.size as u64).is_none() {
+                return orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_NotEnoughMemory;
+            }
+            (*params.target).data = data;
+            (*params.target).size = params.size as u64;
+        } else if service
+            == orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_SetMetricsValue
+        {
+            let params = &*(params as *const SetMetricsValueParams);
+            let name = std::ffi::CStr::from_ptr(params.name).to_str().unwrap();
+            EXPOSITION.with(|exposition| {
+                exposition
+                    .borrow_mut()
+                    .push_str(&format!("{} {}\n", name, params.value))
+            });
+        }
+        0
+    }
+
+    fn orthanc_context() -> *mut orthanc_plugin_bindings::OrthancPluginContext {
+        let version = CString::new("mainline").unwrap();
+        Box::into_raw(Box::new(orthanc_plugin_bindings::OrthancPluginContext {
+            pluginsManager: std::ptr::null_mut(),
+            orthancVersion: version.into_raw(),
+            Free: Some(storage_free),
+            InvokeService: Some(invoke_service),
+        }))
+    }
+
+    fn s3_object(request: &crate::stub::Request) -> Response {
+        match (request.method.as_str(), request.path()) {
+            ("GET", "/") => Response::ok().body(
+                "<ListAllMyBucketsResult><Buckets><Bucket><Name>bucket</Name></Bucket>\
+                 </Buckets></ListAllMyBucketsResult>",
+            ),
+            ("PUT", _) => Response::ok().header("etag", "\"781e5e245d69b566979b86e28d23f2c7\""),
+            ("GET", _) => match request.headers.get("range") {
+                Some(range) => {
+                    let (start, end) = range.trim_start_matches("bytes=").split_once('-').unwrap();
+                    let range = start.parse::<usize>().unwrap()..end.parse::<usize>().unwrap() + 1;
+                    Response::status(206).body(&OBJECT[range])
+                }
+                None => Response::ok().body(OBJECT),
+            },
+            _ => Response::status(404),
+        }
+    }
+
+    fn read_whole(uuid: &CString) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
+        let mut target = orthanc_plugin_bindings::OrthancPluginMemoryBuffer64 {
+            data: std::ptr::null_mut(),
+            size: 0,
+        };
+        let error = storage_read_whole(&mut target, uuid.as_ptr(), 1);
+        if error == 0 {
+            let content = unsafe {
+                std::slice::from_raw_parts(target.data as *const u8, target.size as usize)
+            };
+            assert_eq!(content, OBJECT);
+            storage_free(target.data);
+        }
+        error
+    }
+
+    fn read_range(uuid: &CString) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
+        let mut content = [0u8; 4];
+        let mut target = orthanc_plugin_bindings::OrthancPluginMemoryBuffer64 {
+            data: content.as_mut_ptr() as *mut c_void,
+            size: content.len() as u64,
+        };
+        let error = storage_read_range(&mut target, uuid.as_ptr(), 1, 2);
+        if error == 0 {
+            assert_eq!(&content, b"2345");
+        }
+        error
+    }
+
+    fn create(uuid: &CString) -> orthanc_plugin_bindings::OrthancPluginErrorCode {
+        storage_create(
+            uuid.as_ptr(),
+            OBJECT.as_ptr() as *const c_void,
+            OBJECT.len() as i64,
+            1,
+        )
+    }
+
+    /// Storage callbacks racing with `OrthancPluginInitialize` fail cleanly until the plugin is
+    /// initialized, and always succeed afterwards.
+    #[test]
+    fn storage_callbacks_race_with_initialization() {
+        let server = tokio::runtime::Runtime::new().unwrap();
+        let stub = server.block_on(Stub::start(s3_object));
+        for (name, value) in [
+            ("S3_ENDPOINT", stub.url()),
+            ("S3_FORCE_PATH_STYLE", "true".to_string()),
+            ("S3_ACCESS_KEY", "AKIDEXAMPLE".to_string()),
+            ("S3_SECRET_KEY", "secret".to_string()),
+            ("S3_BUCKET", "bucket".to_string()),
+            ("S3_REGION", "us-east-1".to_string()),
+            ("RUST_LOG", "s3=warn".to_string()),
+        ] {
+            std::env::set_var(name, value);
+        }
+
+        const THREADS: usize = 16;
+        const CALLS_AFTER_INITIALIZATION: usize = 20;
+
+        let initialized = Arc::new(AtomicBool::new(false));
+        let refused = Arc::new(AtomicUsize::new(0));
+        let callers: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let initialized = initialized.clone();
+                let refused = refused.clone();
+                thread::spawn(move || {
+                    let uuid = CString::new(format!("00000000-0000-0000-0000-{:012}", thread))
+                        .unwrap();
+                    let callbacks: [fn(&CString) -> _; 3] = [create, read_whole, read_range];
+                    let mut calls_after_initialization = 0;
+                    for callback in callbacks.iter().cycle() {
+                        let after_initialization = initialized.load(Ordering::Acquire);
+                        let error = callback(&uuid);
+                        if after_initialization {
+                            assert_eq!(error, 0);
+                            calls_after_initialization += 1;
+                            if calls_after_initialization == CALLS_AFTER_INITIALIZATION {
+                                break;
+                            }
+                        } else if error != 0 {
+                            assert_eq!(
+                                error,
+                                orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_StorageAreaPlugin
+                            );
+                            refused.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(STATE.initialize(orthanc_context(), "s3", "test"), 0);
+        initialized.store(true, Ordering::Release);
+
+        for caller in callers {
+            caller.join().expect("storage callback panicked");
+        }
+        assert!(refused.load(Ordering::Relaxed) > 0);
+        assert!(stub
+            .requests()
+            .iter()
+            .any(|request| request.method == "PUT"));
+
+        STATE.finalize();
+    }
+
+    #[test]
+    fn metrics_are_exposed() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let stub = runtime.block_on(Stub::start(s3_object));
+        let directory = tempfile::tempdir().unwrap();
+        let (staging, _uploads) = Staging::new(
+            stub.s3_client(),
+            StagingOptions {
+                bucket: "bucket".to_string(),
+                directory: directory.path().to_path_buf(),
+                workers: 1,
+            },
+        )
+        .unwrap();
+        staging.stage("staged", OBJECT).unwrap();
+        let config: Config = envy::from_iter([
+            ("S3_ENDPOINT".to_string(), stub.url()),
+            ("S3_BUCKET".to_string(), "bucket".to_string()),
+            ("S3_REGION".to_string(), "us-east-1".to_string()),
+            ("S3_MAX_CONCURRENT_READS".to_string(), "2".to_string()),
+        ])
+        .unwrap();
+        let limits = Limits::from_config(&config);
+        let events = EventSink::start(runtime.handle(), |_| {});
+        METRICS.add_cache_lookup(true);
+
+        EXPOSITION.with(|exposition| exposition.borrow_mut().clear());
+        let orthanc = unsafe { Context::from_raw(orthanc_context()) };
+        publish_metrics(orthanc, &limits, Some(&staging), &events);
+        let exposition = EXPOSITION.with(|exposition| exposition.take());
+
+        let metrics: HashMap<&str, f32> = exposition
+            .lines()
+            .map(|line| {
+                let (name, value) = line.split_once(' ').unwrap();
+                (name, value.parse().unwrap())
+            })
+            .collect();
+        for name in [
+            "s3_put_object_success_total",
+            "s3_retries_total",
+            "s3_read_in_flight",
+            "s3_read_queue_depth",
+            "s3_read_queue_timeouts_total",
+            "s3_write_in_flight",
+            "s3_remove_queue_depth",
+            "s3_cache_hits_total",
+            "s3_cache_misses_total",
+            "s3_cache_hit_ratio",
+            "s3_events_queue_depth",
+            "s3_events_lag_ms",
+        ] {
+            assert!(
+                metrics.contains_key(name),
+                "{} missing from\n{}",
+                name,
+                exposition
+            );
+        }
+        assert_eq!(metrics["s3_staging_pending"], 1.0);
+        assert_eq!(metrics["s3_events_queue_depth"], 0.0);
+        assert!((0.0..=1.0).contains(&metrics["s3_cache_hit_ratio"]));
+        // Prometheus metric names
+        assert!(metrics.keys().all(|name| {
+            name.chars().enumerate().all(|(i, c)| {
+                c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+            })
+        }));
     }
 }
@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, error::Error, sync::Arc};
+
+use orthanc_plugin_bindings::{
+    api::OrthancApi,
+    instance::{self, DicomInstance},
+    plugin::Context,
+    query_retrieve::{
+        FindAnswers, FindHandler, FindQuery, MoveDriver, MoveHandler, MoveItems, MoveRequest,
+        ResourceType,
+    },
+};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::{
+    index::{self, Index, IndexedInstance, MoveItem},
+    reader::InstanceReader,
+};
+
+/// `/modalities?expand`
+#[derive(Deserialize)]
+struct Modality {
+    #[serde(rename = "AET")]
+    aet: String,
+}
+
+/// Query/Retrieve SCP answering C-FIND from the local [`Index`] and fulfilling C-MOVE with the
+/// objects of the bucket.
+#[derive(Clone)]
+pub struct QueryRetrieve {
+    pub context: Context,
+    pub index: Arc<Index>,
+    pub reader: InstanceReader,
+    /// Maximum number of answers to a C-FIND, the answer is marked incomplete beyond.
+    pub max_answers: Option<usize>,
+}
+
+impl QueryRetrieve {
+    /// Record the instance `id` stored by Orthanc, with the tags of its DICOM file.
+    pub fn index_stored(&self, instance: &DicomInstance, id: &str) -> Result<(), Box<dyn Error>> {
+        self.record(id, serde_json::from_value(instance.simplified_json()?)?)
+    }
+
+    /// Record the instance `id` already stored by Orthanc, with the tags it answers.
+    pub fn index_instance(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        let tags =
+            OrthancApi::new(self.context).get_json(&format!("/instances/{}/tags?simplify", id))?;
+        self.record(id, tags)
+    }
+
+    fn record(
+        &self,
+        id: &str,
+        tags: BTreeMap<String, serde_json::Value>,
+    ) -> Result<(), Box<dyn Error>> {
+        let api = OrthancApi::new(self.context);
+        let instance = api.instance(id)?;
+        let series = api.series(&instance.parent_series)?;
+        let study = api.study(&series.parent_study)?;
+        let attachment = match instance.file_uuid {
+            Some(uuid) => uuid,
+            None => api.attachment_info("instances", id, "dicom")?.uuid,
+        };
+
+        //
+        // Only textual tags are indexed, sequences are dropped
+        //
+        let tags = tags
+            .into_iter()
+            .filter_map(|(name, value)| match value {
+                serde_json::Value::String(value) => Some((name, value)),
+                _ => None,
+            })
+            .collect();
+
+        self.index.insert(&IndexedInstance {
+            orthanc_patient: study.parent_patient,
+            orthanc_study: series.parent_study,
+            orthanc_series: instance.parent_series,
+            orthanc_instance: instance.id,
+            attachment,
+            tags,
+        })?;
+
+        debug!("indexed instance {}", id);
+        Ok(())
+    }
+
+    /// Forget the resource `id` deleted by Orthanc.
+    pub fn remove(&self, level: ResourceType, id: &str) {
+        match self.index.remove(level, id) {
+            Ok(0) => {}
+            Ok(removed) => debug!("removed {} instances of {} from the index", removed, id),
+            Err(e) => warn!("unable to remove {} from the index - {}", id, e),
+        }
+    }
+
+    /// Name of the Orthanc modality configured with `aet`.
+    fn modality(&self, aet: &str) -> Result<String, Box<dyn Error>> {
+        let modalities: BTreeMap<String, Modality> =
+            OrthancApi::new(self.context).get_json("/modalities?expand")?;
+        modalities
+            .into_iter()
+            .find(|(_, modality)| modality.aet == aet)
+            .map(|(name, _)| name)
+            .ok_or_else(|| format!("unknown move destination {}", aet).into())
+    }
+}
+
+impl FindHandler for QueryRetrieve {
+    fn find(
+        &self,
+        query: &FindQuery,
+        answers: &FindAnswers,
+        issuer_aet: &str,
+        _called_aet: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let tags = query.tags()?;
+        let level = index::parse_level(
+            tags.iter()
+                .find(|tag| tag.name == "QueryRetrieveLevel")
+                .map(|tag| tag.value.as_str())
+                .unwrap_or_default(),
+        )?;
+
+        //
+        // One more answer than allowed tells whether the answer is incomplete
+        //
+        let mut matches = self
+            .index
+            .find(level, &tags, self.max_answers.map(|max| max + 1))?;
+        let incomplete = self.max_answers.is_some_and(|max| matches.len() > max);
+        if let Some(max) = self.max_answers {
+            matches.truncate(max);
+        }
+
+        for tags in &matches {
+            let mut answer: serde_json::Map<_, _> = tags
+                .iter()
+                .map(|(name, value)| (name.clone(), serde_json::Value::from(value.as_str())))
+                .collect();
+            answer.insert("QueryRetrieveLevel".into(), level.as_str().into());
+            answer.insert("SpecificCharacterSet".into(), "ISO_IR 192".into());
+
+            let dicom = instance::create_dicom(
+                self.context,
+                &serde_json::Value::Object(answer),
+                orthanc_plugin_bindings::OrthancPluginCreateDicomFlags_OrthancPluginCreateDicomFlags_None,
+            )?;
+            answers.add(&dicom)?;
+        }
+        if incomplete {
+            answers.mark_incomplete()?;
+        }
+
+        info!(
+            "answered {} {} matches to {}",
+            matches.len(),
+            level.as_str(),
+            issuer_aet
+        );
+        Ok(())
+    }
+}
+
+impl MoveHandler for QueryRetrieve {
+    fn create(&self, request: &MoveRequest) -> Result<Box<dyn MoveDriver>, Box<dyn Error>> {
+        let modality = self.modality(&request.target_aet)?;
+        let items = self.index.instances(request)?;
+        info!(
+            "moving {} instances to {} for {}",
+            items.len(),
+            modality,
+            request.originator_aet
+        );
+
+        let source = self.clone();
+        Ok(Box::new(MoveItems::new(items, move |item: MoveItem| {
+            let content = source
+                .reader
+                .read(&item.orthanc_instance, &item.attachment)?;
+            OrthancApi::new(source.context).post(
+                &format!("/modalities/{}/store-straight", modality),
+                &content,
+            )?;
+            debug!("moved {} to {}", item.sop_instance_uid, modality);
+            Ok(())
+        })))
+    }
+}
@@ -0,0 +1,142 @@
+use std::{error::Error, sync::Arc};
+
+use orthanc_plugin_bindings::{api::OrthancApi, plugin::Context};
+use tracing::debug;
+
+use crate::{
+    client::S3Client,
+    layout::object_key,
+    limits::{Lane, Limits},
+    metrics::{self, Operation, METRICS},
+    staging::Staging,
+};
+
+/// Offset of the `DICM` prefix of a DICOM file, objects without it are compressed by Orthanc.
+const DICM_OFFSET: usize = 128;
+
+/// Reads the DICOM files of instances straight from the staging directory or the bucket, for the
+/// services serving them outside of the storage area.
+///
+/// Objects that Orthanc compressed before storing them (`StorageCompression`) are read through
+/// Orthanc instead.
+#[derive(Clone)]
+pub struct InstanceReader {
+    pub context: Context,
+    pub s3: S3Client,
+    pub bucket: String,
+    pub runtime: tokio::runtime::Handle,
+    pub limits: Arc<Limits>,
+    pub staging: Option<Arc<Staging>>,
+}
+
+impl InstanceReader {
+    /// DICOM file of the instance `orthanc_instance`, stored as the attachment `attachment`.
+    pub fn read(
+        &self,
+        orthanc_instance: &str,
+        attachment: &str,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let content = match self
+            .staging
+            .as_ref()
+            .and_then(|staging| staging.cached(attachment))
+        {
+            Some(mut file) => {
+                let mut content = Vec::new();
+                std::io::Read::read_to_end(&mut file, &mut content)?;
+                content
+            }
+            //
+            // Only downloads count against the read lane, like the storage area reads
+            //
+            None => {
+                let key = object_key(attachment);
+                let content = self.runtime.block_on(async {
+                    let _permit = self.limits.acquire(Lane::Read).await?;
+                    metrics::timed(
+                        Operation::GetObject,
+                        self.s3.get_object(&self.bucket, &key, None),
+                    )
+                    .await
+                    .map_err(Box::<dyn Error>::from)
+                })?;
+                METRICS.add_downloaded_bytes(content.len() as u64);
+                content
+            }
+        };
+
+        if content.get(DICM_OFFSET..DICM_OFFSET + 4) == Some(b"DICM") {
+            return Ok(content);
+        }
+
+        debug!(
+            "object {} is compressed, reading {} through Orthanc",
+            attachment, orthanc_instance
+        );
+        Ok(OrthancApi::new(self.context).instance_file(orthanc_instance)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::PutOptions,
+        config::Config,
+        staging::StagingOptions,
+        stub::{Orthanc, Stub},
+    };
+
+    const UPLOADED: &str = "0a1b2c3d-0000-4000-8000-000000000001";
+    const STAGED: &str = "0a1b2c3d-0000-4000-8000-000000000002";
+
+    fn dicom() -> Vec<u8> {
+        let mut file = vec![0; DICM_OFFSET];
+        file.extend_from_slice(b"DICM");
+        file
+    }
+
+    #[test]
+    fn staged_reads_do_not_wait_for_the_read_lane() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (stub, _bucket) = runtime.block_on(Stub::bucket());
+        let s3 = stub.s3_client();
+        runtime
+            .block_on(s3.put_object("bucket", UPLOADED, dicom(), PutOptions::default()))
+            .unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let (staging, _uploads) = Staging::new(
+            s3.clone(),
+            StagingOptions {
+                bucket: "bucket".to_string(),
+                directory: directory.path().to_path_buf(),
+                workers: 1,
+            },
+        )
+        .unwrap();
+        staging.stage(STAGED, &dicom()).unwrap();
+        let config: Config = envy::from_iter([
+            ("S3_ENDPOINT".to_string(), stub.url()),
+            ("S3_BUCKET".to_string(), "bucket".to_string()),
+            ("S3_REGION".to_string(), "us-east-1".to_string()),
+            ("S3_MAX_CONCURRENT_READS".to_string(), "1".to_string()),
+            ("S3_QUEUE_TIMEOUT_SECS".to_string(), "0".to_string()),
+        ])
+        .unwrap();
+        let reader = InstanceReader {
+            context: Orthanc::start().context(),
+            s3,
+            bucket: "bucket".to_string(),
+            runtime: runtime.handle().clone(),
+            limits: Arc::new(Limits::from_config(&config)),
+            staging: Some(Arc::new(staging)),
+        };
+
+        let permit = runtime.block_on(reader.limits.acquire(Lane::Read)).unwrap();
+        assert_eq!(reader.read("staged", STAGED).unwrap(), dicom());
+        assert!(reader.read("uploaded", UPLOADED).is_err());
+
+        drop(permit);
+        assert_eq!(reader.read("uploaded", UPLOADED).unwrap(), dicom());
+    }
+}
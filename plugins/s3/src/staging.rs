@@ -0,0 +1,468 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{mpsc, Notify, Semaphore};
+use tracing::{info, warn};
+
+use crate::{
+    client::{PutOptions, S3Client, S3Error},
+    layout::object_key,
+    metrics::{self, Operation, METRICS},
+};
+
+/// Suffix of attachments being written, they are discarded when recovering.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct StagingOptions {
+    pub bucket: String,
+    pub directory: PathBuf,
+    /// Number of concurrent background uploads.
+    pub workers: usize,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StagingError {
+    #[error("unable to stage '{0}' - {1}")]
+    Io(String, std::io::Error),
+    #[error("upload failed - {0}")]
+    Storage(#[from] S3Error),
+    #[error("not staging '{0}', the plugin is shutting down")]
+    Closed(String),
+}
+
+/// Write-behind staging of new attachments.
+///
+/// Attachments are committed to a local directory and acknowledged to Orthanc right away, then
+/// uploaded to the bucket by background workers, retrying until the upload succeeds. Staged
+/// attachments are served from the directory until their upload completed, and attachments
+/// left in the directory by a crash are uploaded again by [`Staging::recover`].
+pub struct Staging {
+    s3: S3Client,
+    options: StagingOptions,
+    /// Staged attachments, an upload holds the lock of its attachment.
+    pending: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    queue: mpsc::UnboundedSender<String>,
+    /// Set once the plugin shuts down, no attachment is staged afterwards.
+    closed: AtomicBool,
+    /// Notified whenever the last pending upload completed.
+    drained: Notify,
+}
+
+impl Staging {
+    /// Create the staging area, the receiver must be passed to [`Staging::run`].
+    pub fn new(
+        s3: S3Client,
+        options: StagingOptions,
+    ) -> Result<(Self, mpsc::UnboundedReceiver<String>), StagingError> {
+        std::fs::create_dir_all(&options.directory)
+            .map_err(|e| StagingError::Io(options.directory.display().to_string(), e))?;
+
+        let (queue, receiver) = mpsc::unbounded_channel();
+        Ok((
+            Self {
+                s3,
+                options,
+                pending: Mutex::new(HashMap::new()),
+                queue,
+                closed: AtomicBool::new(false),
+                drained: Notify::new(),
+            },
+            receiver,
+        ))
+    }
+
+    pub fn options(&self) -> &StagingOptions {
+        &self.options
+    }
+
+    /// Number of attachments waiting to be uploaded.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().expect("staging lock poisoned").len()
+    }
+
+    fn path(&self, uuid: &str) -> PathBuf {
+        self.options.directory.join(uuid)
+    }
+
+    /// Durably write an attachment to the staging directory and queue its upload.
+    pub fn stage(&self, uuid: &str, content: &[u8]) -> Result<(), StagingError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(StagingError::Closed(uuid.to_owned()));
+        }
+
+        let path = self.path(uuid);
+        let partial = self
+            .options
+            .directory
+            .join(format!("{}{}", uuid, PARTIAL_SUFFIX));
+        let io = |e| StagingError::Io(uuid.to_owned(), e);
+
+        //
+        // Written under a temporary name and renamed once synced, so that a crash never leaves
+        // a truncated attachment behind
+        //
+        let mut file = File::create(&partial).map_err(io)?;
+        file.write_all(content).map_err(io)?;
+        file.sync_all().map_err(io)?;
+        std::fs::rename(&partial, &path).map_err(io)?;
+        sync_directory(&self.options.directory).map_err(io)?;
+
+        self.enqueue(uuid);
+        Ok(())
+    }
+
+    fn enqueue(&self, uuid: &str) {
+        self.pending
+            .lock()
+            .expect("staging lock poisoned")
+            .entry(uuid.to_owned())
+            .or_default();
+        if self.queue.send(uuid.to_owned()).is_err() {
+            warn!("staging workers stopped, '{}' is uploaded on restart", uuid);
+        }
+    }
+
+    /// Whether the attachment `uuid` is staged and not uploaded yet.
+    pub fn is_staged(&self, uuid: &str) -> bool {
+        self.pending
+            .lock()
+            .expect("staging lock poisoned")
+            .contains_key(uuid)
+    }
+
+    /// Open a staged attachment that has not been uploaded yet.
+    ///
+    /// The attachment stays readable through the returned file even if its upload completes
+    /// in the meantime.
+    pub fn open(&self, uuid: &str) -> Option<File> {
+        if !self.is_staged(uuid) {
+            return None;
+        }
+        File::open(self.path(uuid)).ok()
+    }
+
+    /// Staged copy of an attachment about to be read, which spares a download from the bucket.
+    /// The lookup is recorded in the cache metrics.
+    pub fn cached(&self, uuid: &str) -> Option<File> {
+        let file = self.open(uuid);
+        METRICS.add_cache_lookup(file.is_some());
+        file
+    }
+
+    /// Queue the upload of attachments left in the staging directory, e.g. after a crash.
+    pub fn recover(&self) -> Result<usize, StagingError> {
+        let directory = &self.options.directory;
+        let entries = std::fs::read_dir(directory)
+            .map_err(|e| StagingError::Io(directory.display().to_string(), e))?;
+
+        let mut recovered = 0;
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(PARTIAL_SUFFIX) {
+                warn!("discarding partially staged attachment '{}'", name);
+                let _ = std::fs::remove_file(entry.path());
+            } else if entry.file_type().map(|t| t.is_file()).unwrap_or(false) {
+                self.enqueue(&name);
+                recovered += 1;
+            }
+        }
+
+        Ok(recovered)
+    }
+
+    /// Upload queued attachments until the queue is closed, retrying failed uploads.
+    pub async fn run(self: Arc<Self>, mut receiver: mpsc::UnboundedReceiver<String>) {
+        let workers = Arc::new(Semaphore::new(self.options.workers.max(1)));
+        while let Some(uuid) = receiver.recv().await {
+            let permit = workers
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("staging semaphore is never closed");
+            let staging = self.clone();
+            tokio::spawn(async move {
+                let mut delay = Duration::from_secs(1);
+                while let Err(e) = staging.upload(&uuid).await {
+                    warn!(
+                        "staged upload of '{}' failed, retrying in {:?} - {}",
+                        uuid, delay, e
+                    );
+                    METRICS.add_retry();
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    /// Upload a staged attachment now, e.g. before Orthanc removes it.
+    ///
+    /// Does nothing when the attachment is not staged or already uploaded.
+    pub async fn upload(&self, uuid: &str) -> Result<(), StagingError> {
+        let lock = match self
+            .pending
+            .lock()
+            .expect("staging lock poisoned")
+            .get(uuid)
+        {
+            Some(lock) => lock.clone(),
+            None => return Ok(()),
+        };
+        let _guard = lock.lock().await;
+
+        let path = self.path(uuid);
+        let content = match tokio::fs::read(&path).await {
+            Ok(content) => content,
+            //
+            // Uploaded by a concurrent call while waiting for the lock
+            //
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.complete(uuid);
+                return Ok(());
+            }
+            Err(e) => return Err(StagingError::Io(uuid.to_owned(), e)),
+        };
+
+        let size = content.len() as u64;
+        metrics::timed(
+            Operation::PutObject,
+            self.s3.put_object(
+                &self.options.bucket,
+                &object_key(uuid),
+                content,
+                PutOptions::default(),
+            ),
+        )
+        .await?;
+        METRICS.add_uploaded_bytes(size);
+
+        self.complete(uuid);
+        std::fs::remove_file(&path).map_err(|e| StagingError::Io(uuid.to_owned(), e))?;
+        info!("uploaded staged attachment {}", uuid);
+
+        Ok(())
+    }
+
+    /// Discard a staged attachment instead of uploading it, e.g. when Orthanc removes it.
+    ///
+    /// Waits for an upload in progress. Returns `false` when the attachment is not staged or
+    /// was already uploaded, its object must then be removed from the bucket.
+    pub async fn cancel(&self, uuid: &str) -> Result<bool, StagingError> {
+        let lock = match self
+            .pending
+            .lock()
+            .expect("staging lock poisoned")
+            .get(uuid)
+        {
+            Some(lock) => lock.clone(),
+            None => return Ok(false),
+        };
+        let _guard = lock.lock().await;
+
+        let removed = match std::fs::remove_file(self.path(uuid)) {
+            Ok(()) => true,
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => return Err(StagingError::Io(uuid.to_owned(), e)),
+        };
+        self.complete(uuid);
+        if removed {
+            info!("discarded staged attachment {}", uuid);
+        }
+
+        Ok(removed)
+    }
+
+    fn complete(&self, uuid: &str) {
+        let mut pending = self.pending.lock().expect("staging lock poisoned");
+        pending.remove(uuid);
+        if pending.is_empty() {
+            self.drained.notify_waiters();
+        }
+    }
+
+    /// Stop staging new attachments and wait up to `deadline` for the pending uploads.
+    ///
+    /// Returns the number of uploads that did not complete in time. Their attachments stay in
+    /// the staging directory and are uploaded by [`Staging::recover`] on the next start.
+    pub async fn drain(&self, deadline: Duration) -> usize {
+        self.closed.store(true, Ordering::Release);
+
+        let drained = async {
+            loop {
+                let notified = self.drained.notified();
+                if self.pending() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(deadline, drained).await;
+
+        self.pending()
+    }
+}
+
+fn sync_directory(directory: &Path) -> std::io::Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{Response, Stub};
+
+    async fn staging(stub: &Stub, directory: &Path) -> Staging {
+        let (staging, _receiver) = Staging::new(
+            stub.s3_client(),
+            StagingOptions {
+                bucket: "bucket".to_string(),
+                directory: directory.to_path_buf(),
+                workers: 1,
+            },
+        )
+        .unwrap();
+        staging
+    }
+
+    #[tokio::test]
+    async fn cancelled_attachments_are_never_uploaded() {
+        let stub = Stub::start(|_| Response::ok()).await;
+        let directory = tempfile::tempdir().unwrap();
+        let staging = staging(&stub, directory.path()).await;
+
+        staging.stage("0a1b2c3d", b"DICM").unwrap();
+        assert!(staging.is_staged("0a1b2c3d"));
+
+        assert!(staging.cancel("0a1b2c3d").await.unwrap());
+        assert!(!staging.is_staged("0a1b2c3d"));
+        assert!(!directory.path().join("0a1b2c3d").exists());
+
+        //
+        // A worker picking up the queued attachment afterwards has nothing to upload
+        //
+        staging.upload("0a1b2c3d").await.unwrap();
+        assert!(stub.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uploaded_attachments_are_not_cancelled() {
+        let stub = Stub::start(|_| Response::ok()).await;
+        let directory = tempfile::tempdir().unwrap();
+        let staging = staging(&stub, directory.path()).await;
+
+        staging.stage("0a1b2c3d", b"DICM").unwrap();
+        staging.upload("0a1b2c3d").await.unwrap();
+
+        assert!(!staging.cancel("0a1b2c3d").await.unwrap());
+        assert_eq!(stub.requests().len(), 1);
+        assert_eq!(stub.requests()[0].method, "PUT");
+    }
+
+    /// Staging area whose queued attachments are uploaded by a background worker.
+    fn running(stub: &Stub, directory: &Path) -> Arc<Staging> {
+        let (staging, receiver) = Staging::new(
+            stub.s3_client(),
+            StagingOptions {
+                bucket: "bucket".to_string(),
+                directory: directory.to_path_buf(),
+                workers: 2,
+            },
+        )
+        .unwrap();
+        let staging = Arc::new(staging);
+        tokio::spawn(staging.clone().run(receiver));
+        staging
+    }
+
+    #[tokio::test]
+    async fn attachments_left_by_a_crash_are_uploaded_on_startup() {
+        let (stub, bucket) = Stub::bucket().await;
+        let directory = tempfile::tempdir().unwrap();
+        std::fs::write(directory.path().join("0a1b2c3d"), b"DICM").unwrap();
+        std::fs::write(directory.path().join("4e5f6a7b"), b"DICM").unwrap();
+        std::fs::write(
+            directory.path().join(format!("8c9d0e1f{}", PARTIAL_SUFFIX)),
+            b"DI",
+        )
+        .unwrap();
+
+        let staging = running(&stub, directory.path());
+        assert_eq!(staging.recover().unwrap(), 2);
+        assert!(staging.is_staged("0a1b2c3d"));
+        assert!(!staging.is_staged("8c9d0e1f"));
+
+        assert_eq!(staging.drain(Duration::from_secs(5)).await, 0);
+        let objects = bucket.lock().unwrap();
+        assert_eq!(objects.keys().collect::<Vec<_>>(), ["0a1b2c3d", "4e5f6a7b"]);
+        assert_eq!(objects["0a1b2c3d"].body, b"DICM");
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn partially_staged_attachments_are_discarded_on_startup() {
+        let stub = Stub::start(|_| Response::ok()).await;
+        let directory = tempfile::tempdir().unwrap();
+        let partial = directory.path().join(format!("0a1b2c3d{}", PARTIAL_SUFFIX));
+        std::fs::write(&partial, b"DI").unwrap();
+
+        let staging = staging(&stub, directory.path()).await;
+        assert_eq!(staging.recover().unwrap(), 0);
+        assert!(!partial.exists());
+        assert_eq!(staging.pending(), 0);
+        assert!(staging.upload("0a1b2c3d").await.is_ok());
+        assert!(stub.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_pending_uploads() {
+        let (stub, bucket) = Stub::bucket().await;
+        let directory = tempfile::tempdir().unwrap();
+        let staging = running(&stub, directory.path());
+
+        staging.stage("0a1b2c3d", b"DICM").unwrap();
+        staging.stage("4e5f6a7b", b"DICM").unwrap();
+        assert_eq!(staging.drain(Duration::from_secs(5)).await, 0);
+
+        assert_eq!(bucket.lock().unwrap().len(), 2);
+        assert!(matches!(
+            staging.stage("8c9d0e1f", b"DICM"),
+            Err(StagingError::Closed(_))
+        ));
+        assert!(!directory.path().join("8c9d0e1f").exists());
+    }
+
+    #[tokio::test]
+    async fn unfinished_uploads_stay_staged_after_shutdown() {
+        let stub = Stub::start(|_| Response::status(503)).await;
+        let directory = tempfile::tempdir().unwrap();
+        let staging = running(&stub, directory.path());
+
+        staging.stage("0a1b2c3d", b"DICM").unwrap();
+        assert_eq!(staging.drain(Duration::from_millis(200)).await, 1);
+
+        //
+        // Left for the next start
+        //
+        assert_eq!(
+            std::fs::read(directory.path().join("0a1b2c3d")).unwrap(),
+            b"DICM"
+        );
+        assert!(stub
+            .requests()
+            .iter()
+            .all(|request| request.method == "PUT"));
+    }
+}
@@ -0,0 +1,740 @@
+//! Minimal HTTP server standing in for S3 and the AWS endpoints in tests, and a stand-in for
+//! the REST API of Orthanc.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_void, CStr},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+use orthanc_plugin_bindings::{
+    _OrthancPluginService, plugin::Context, OrthancPluginContext, OrthancPluginErrorCode,
+    OrthancPluginMemoryBuffer, OrthancPluginWebDavCollection,
+};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    client::{ClientOptions, S3Client},
+    credentials::CredentialsProvider,
+};
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// Path and query string, as sent.
+    pub target: String,
+    /// Lowercase header names.
+    pub headers: BTreeMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn ok() -> Self {
+        Self::status(200)
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+/// Server answering every request with its handler, and recording the requests.
+pub struct Stub {
+    pub address: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Stub {
+    /// Start the server on the current tokio runtime.
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    if let Some(request) = serve(stream, handler.as_ref()).await {
+                        recorded.lock().unwrap().push(request);
+                    }
+                });
+            }
+        });
+
+        Self { address, requests }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Client of a path-style bucket served by the stub.
+    pub fn s3_client(&self) -> S3Client {
+        S3Client::new(
+            reqwest::Client::new(),
+            CredentialsProvider::from_keys("AKIDEXAMPLE", "secret"),
+            ClientOptions {
+                endpoint: self.url(),
+                region: "us-east-1".to_string(),
+                path_style: true,
+                request_timeout: None,
+            },
+        )
+        .unwrap()
+    }
+}
+
+/// Object of the bucket served by [`Stub::bucket`].
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub body: Vec<u8>,
+    /// `content-type`, `x-amz-storage-class` and `x-amz-meta-*` headers, lowercase.
+    pub headers: BTreeMap<String, String>,
+    pub last_modified: DateTime<Utc>,
+}
+
+/// Objects of the bucket served by [`Stub::bucket`], by key.
+pub type Bucket = Arc<Mutex<BTreeMap<String, StoredObject>>>;
+
+impl Stub {
+    /// Server standing in for a path-style bucket kept in memory, which supports uploads,
+    /// copies, range downloads, HEAD, DELETE and single-page listings.
+    pub async fn bucket() -> (Self, Bucket) {
+        let bucket = Bucket::default();
+        let objects = bucket.clone();
+        let stub = Self::start(move |request| serve_bucket(&objects, request)).await;
+        (stub, bucket)
+    }
+}
+
+fn serve_bucket(bucket: &Bucket, request: &Request) -> Response {
+    let mut objects = bucket.lock().unwrap();
+    let path = percent_decode(request.path());
+    let key = match path.trim_start_matches('/').split_once('/') {
+        Some((_, key)) if !key.is_empty() => key.to_string(),
+        _ => return list_bucket(&objects, request),
+    };
+
+    match request.method.as_str() {
+        "PUT" => {
+            let copied = match request.headers.get("x-amz-copy-source") {
+                Some(source) => {
+                    let source = percent_decode(source);
+                    let source = source.trim_start_matches('/').split_once('/').map(|s| s.1);
+                    match source.and_then(|source| objects.get(source)) {
+                        Some(source) => Some(source.clone()),
+                        None => return Response::status(404),
+                    }
+                }
+                None => None,
+            };
+            let replace = request
+                .headers
+                .get("x-amz-metadata-directive")
+                .is_none_or(|directive| directive == "REPLACE");
+            let stored_headers = |headers: &BTreeMap<String, String>| {
+                headers
+                    .iter()
+                    .filter(|(name, _)| {
+                        name.starts_with("x-amz-meta-")
+                            || *name == "content-type"
+                            || *name == "x-amz-storage-class"
+                    })
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect::<BTreeMap<_, _>>()
+            };
+
+            let object = match copied {
+                Some(mut source) => {
+                    if replace {
+                        source.headers = stored_headers(&request.headers);
+                    } else if let Some(class) = request.headers.get("x-amz-storage-class") {
+                        source
+                            .headers
+                            .insert("x-amz-storage-class".to_string(), class.clone());
+                    }
+                    source
+                }
+                None => StoredObject {
+                    body: request.body.clone(),
+                    headers: stored_headers(&request.headers),
+                    last_modified: Utc::now(),
+                },
+            };
+            let etag = e_tag(&object.body);
+            let copy = request.headers.contains_key("x-amz-copy-source");
+            objects.insert(
+                key,
+                StoredObject {
+                    last_modified: Utc::now(),
+                    ..object
+                },
+            );
+            match copy {
+                true => Response::ok().body(format!(
+                    "<CopyObjectResult><ETag>{}</ETag></CopyObjectResult>",
+                    etag
+                )),
+                false => Response::ok().header("etag", &etag),
+            }
+        }
+        "GET" | "HEAD" => match objects.get(&key) {
+            Some(object) => {
+                let mut response = Response::ok().header("etag", &e_tag(&object.body)).header(
+                    "last-modified",
+                    &object
+                        .last_modified
+                        .format("%a, %d %b %Y %H:%M:%S GMT")
+                        .to_string(),
+                );
+                for (name, value) in &object.headers {
+                    response = response.header(name, value);
+                }
+                let range = request
+                    .headers
+                    .get("range")
+                    .and_then(|range| range.strip_prefix("bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .and_then(|(start, end)| {
+                        Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+                    });
+                match range {
+                    Some((start, end)) => {
+                        let end = usize::min(end + 1, object.body.len());
+                        Response {
+                            status: 206,
+                            ..response.body(object.body[start..end].to_vec())
+                        }
+                    }
+                    None => response.body(object.body.clone()),
+                }
+            }
+            None => Response::status(404)
+                .body("<Error><Code>NoSuchKey</Code><Message>no such key</Message></Error>"),
+        },
+        "DELETE" => {
+            objects.remove(&key);
+            Response::status(204)
+        }
+        _ => Response::status(405),
+    }
+}
+
+fn list_bucket(objects: &BTreeMap<String, StoredObject>, request: &Request) -> Response {
+    let prefix = request
+        .target
+        .split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix("prefix="))
+        .map(percent_decode)
+        .unwrap_or_default();
+
+    let contents: String = objects
+        .iter()
+        .filter(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, object)| {
+            format!(
+                "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag>\
+                 <Size>{}</Size></Contents>",
+                key,
+                object.last_modified.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                e_tag(&object.body),
+                object.body.len()
+            )
+        })
+        .collect();
+    Response::ok().body(format!(
+        "<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+        contents
+    ))
+}
+
+fn e_tag(body: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(Md5::digest(body)))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], value.get(i + 1..i + 3)) {
+            (b'%', Some(hex)) if u8::from_str_radix(hex, 16).is_ok() => {
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Answer a single request and close the connection.
+async fn serve(mut stream: TcpStream, handler: &Handler) -> Option<Request> {
+    let mut buffer = Vec::new();
+    let header_end = loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        let mut chunk = [0; 4096];
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    let headers: BTreeMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+
+    let length: usize = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = buffer[header_end + 4..].to_vec();
+    while body.len() < length {
+        let mut chunk = [0; 4096];
+        let read = stream.read(&mut chunk).await.ok()?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    let request = Request {
+        method,
+        target,
+        headers,
+        body,
+    };
+    let response = handler(&request);
+
+    //
+    // HEAD answers announce the length of the body they do not send
+    //
+    let mut head = format!("HTTP/1.1 {} Stub\r\nconnection: close\r\n", response.status);
+    if !response
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+    {
+        head.push_str(&format!("content-length: {}\r\n", response.body.len()));
+    }
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await.ok()?;
+    if request.method != "HEAD" {
+        stream.write_all(&response.body).await.ok()?;
+    }
+    stream.shutdown().await.ok();
+    Some(request)
+}
+
+/// Orthanc answering the REST calls of the plugin with the JSON documents it serves, unknown
+/// resources are not found. POST and DELETE calls are recorded, and the WebDAV collection the
+/// plugin registers can be browsed through its callbacks.
+///
+/// Orthanc invokes its services with the plugin context, the first field of the stand-in.
+#[repr(C)]
+pub struct Orthanc {
+    context: OrthancPluginContext,
+    resources: Mutex<BTreeMap<String, Vec<u8>>>,
+    calls: Mutex<Vec<(&'static str, String, Vec<u8>)>>,
+    webdav: Mutex<Option<RegisterWebDavCollectionParams>>,
+}
+
+/// Item of a folder listed through the WebDAV callbacks, `size` is `None` for subfolders.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebDavItem {
+    pub name: String,
+    pub size: Option<u64>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RegisterWebDavCollectionParams {
+    uri: *const c_char,
+    is_existing_folder: orthanc_plugin_bindings::OrthancPluginWebDavIsExistingFolderCallback,
+    list_folder: orthanc_plugin_bindings::OrthancPluginWebDavListFolderCallback,
+    retrieve_file: orthanc_plugin_bindings::OrthancPluginWebDavRetrieveFileCallback,
+    store_file: orthanc_plugin_bindings::OrthancPluginWebDavStoreFileCallback,
+    create_folder: orthanc_plugin_bindings::OrthancPluginWebDavCreateFolderCallback,
+    delete_item: orthanc_plugin_bindings::OrthancPluginWebDavDeleteItemCallback,
+    payload: *mut c_void,
+}
+
+#[repr(C)]
+struct RestApiGetParams {
+    target: *mut OrthancPluginMemoryBuffer,
+    uri: *const c_char,
+}
+
+#[repr(C)]
+struct RestApiPostPutParams {
+    target: *mut OrthancPluginMemoryBuffer,
+    uri: *const c_char,
+    body: *const c_void,
+    body_size: u32,
+}
+
+/// Size of the header recording the size of the buffers allocated by the stand-in.
+const BUFFER_HEADER: usize = 16;
+
+impl Orthanc {
+    /// Stand-in living as long as the tests, like the context of a plugin.
+    pub fn start() -> &'static Self {
+        Box::leak(Box::new(Self {
+            context: OrthancPluginContext {
+                pluginsManager: std::ptr::null_mut(),
+                orthancVersion: c"mainline".as_ptr(),
+                Free: Some(free),
+                InvokeService: Some(invoke_service),
+            },
+            resources: Mutex::new(BTreeMap::new()),
+            calls: Mutex::new(Vec::new()),
+            webdav: Mutex::new(None),
+        }))
+    }
+
+    pub fn context(&'static self) -> Context {
+        unsafe { Context::from_raw(&self.context as *const _ as *mut OrthancPluginContext) }
+    }
+
+    /// Answer `document` to GET and POST calls of `uri`.
+    pub fn serve(&self, uri: &str, document: serde_json::Value) -> &Self {
+        self.resources
+            .lock()
+            .unwrap()
+            .insert(uri.to_string(), document.to_string().into_bytes());
+        self
+    }
+
+    /// Forget the resources under `uri`, e.g. a study and its attachments.
+    pub fn remove(&self, uri: &str) {
+        self.resources
+            .lock()
+            .unwrap()
+            .retain(|served, _| !served.starts_with(uri));
+    }
+
+    /// Study `study` with a single series and the DICOM attachments of its `instances`, given
+    /// as (instance, attachment uuid).
+    pub fn study(&self, study: &str, instances: &[(&str, &str)]) -> &Self {
+        self.serve(&format!("/studies/{}/attachments", study), json!([]));
+        self.serve(&format!("/studies/{}/series", study), json!([]));
+        self.serve(
+            &format!("/studies/{}/instances", study),
+            instances
+                .iter()
+                .map(|(instance, _)| json!({ "ID": instance }))
+                .collect(),
+        );
+        for (instance, uuid) in instances {
+            self.serve(
+                &format!("/instances/{}/attachments", instance),
+                json!(["dicom"]),
+            );
+            self.serve(
+                &format!("/instances/{}/attachments/dicom/info", instance),
+                json!({
+                    "Uuid": uuid,
+                    "ContentType": 1,
+                    "CompressedSize": 0,
+                    "UncompressedSize": 0,
+                }),
+            );
+        }
+        self
+    }
+
+    /// POST and DELETE calls, with their method, uri and body.
+    pub fn calls(&self) -> Vec<(&'static str, String, Vec<u8>)> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn webdav(&self) -> RegisterWebDavCollectionParams {
+        self.webdav
+            .lock()
+            .unwrap()
+            .expect("no WebDAV collection is registered")
+    }
+
+    /// Whether `path` is a folder of the registered WebDAV collection.
+    pub fn webdav_is_folder(&self, path: &[&str]) -> Result<bool, OrthancPluginErrorCode> {
+        let webdav = self.webdav();
+        let items = PathItems::new(path);
+        let mut is_existing = 0;
+        let error = unsafe {
+            webdav.is_existing_folder.unwrap()(
+                &mut is_existing,
+                items.size(),
+                items.as_ptr(),
+                webdav.payload,
+            )
+        };
+        success(error).map(|()| is_existing != 0)
+    }
+
+    /// Content of the folder `path` of the registered WebDAV collection, as Orthanc lists it
+    /// to answer a PROPFIND, `None` when the folder does not exist.
+    pub fn webdav_list(
+        &self,
+        path: &[&str],
+    ) -> Result<Option<Vec<WebDavItem>>, OrthancPluginErrorCode> {
+        let webdav = self.webdav();
+        let items = PathItems::new(path);
+        let mut listed = Vec::<WebDavItem>::new();
+        let mut is_existing = 0;
+        let error = unsafe {
+            webdav.list_folder.unwrap()(
+                &mut is_existing,
+                &mut listed as *mut _ as *mut OrthancPluginWebDavCollection,
+                Some(add_file),
+                Some(add_folder),
+                items.size(),
+                items.as_ptr(),
+                webdav.payload,
+            )
+        };
+        success(error).map(|()| (is_existing != 0).then_some(listed))
+    }
+
+    /// Content of the file `path` of the registered WebDAV collection, as Orthanc retrieves it
+    /// to answer a GET, `None` when the file does not exist.
+    pub fn webdav_retrieve(
+        &self,
+        path: &[&str],
+    ) -> Result<Option<Vec<u8>>, OrthancPluginErrorCode> {
+        let webdav = self.webdav();
+        let items = PathItems::new(path);
+        let mut retrieved = None::<Vec<u8>>;
+        let error = unsafe {
+            webdav.retrieve_file.unwrap()(
+                &mut retrieved as *mut _ as *mut OrthancPluginWebDavCollection,
+                Some(retrieve_file),
+                items.size(),
+                items.as_ptr(),
+                webdav.payload,
+            )
+        };
+        success(error).map(|()| retrieved)
+    }
+
+    fn answer(&self, uri: &str, target: *mut OrthancPluginMemoryBuffer) -> OrthancPluginErrorCode {
+        match self.resources.lock().unwrap().get(uri) {
+            Some(document) => {
+                unsafe {
+                    (*target).data = allocate(document);
+                    (*target).size = document.len() as u32;
+                }
+                orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+            }
+            None => {
+                orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_UnknownResource
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn invoke_service(
+    context: *mut OrthancPluginContext,
+    service: _OrthancPluginService,
+    params: *const c_void,
+) -> OrthancPluginErrorCode {
+    let orthanc = &*(context as *const Orthanc);
+    let uri = |uri: *const c_char| CStr::from_ptr(uri).to_string_lossy().to_string();
+
+    match service {
+        orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RestApiGet
+        | orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RestApiGetAfterPlugins => {
+            let params = &*(params as *const RestApiGetParams);
+            orthanc.answer(&uri(params.uri), params.target)
+        }
+        orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RestApiPost
+        | orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RestApiPostAfterPlugins => {
+            let params = &*(params as *const RestApiPostPutParams);
+            let uri = uri(params.uri);
+            let body =
+                std::slice::from_raw_parts(params.body as *const u8, params.body_size as usize);
+            orthanc
+                .calls
+                .lock()
+                .unwrap()
+                .push(("POST", uri.clone(), body.to_vec()));
+            orthanc.answer(&uri, params.target)
+        }
+        orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RestApiDelete
+        | orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RestApiDeleteAfterPlugins => {
+            let uri = uri(params as *const c_char);
+            orthanc.remove(&uri);
+            orthanc.calls.lock().unwrap().push(("DELETE", uri, Vec::new()));
+            orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+        }
+        orthanc_plugin_bindings::_OrthancPluginService__OrthancPluginService_RegisterWebDavCollection => {
+            let mut params = *(params as *const RegisterWebDavCollectionParams);
+            //
+            // Like Orthanc, only the callbacks and their payload are kept
+            //
+            params.uri = std::ptr::null();
+            *orthanc.webdav.lock().unwrap() = Some(params);
+            orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+        }
+        _ => orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_NotImplemented,
+    }
+}
+
+fn success(error: OrthancPluginErrorCode) -> Result<(), OrthancPluginErrorCode> {
+    match error {
+        orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success => Ok(()),
+        error => Err(error),
+    }
+}
+
+/// Path of a WebDAV callback, as the array of C strings Orthanc passes.
+struct PathItems {
+    _items: Vec<std::ffi::CString>,
+    pointers: Vec<*const c_char>,
+}
+
+impl PathItems {
+    fn new(path: &[&str]) -> Self {
+        let items: Vec<_> = path
+            .iter()
+            .map(|item| std::ffi::CString::new(*item).unwrap())
+            .collect();
+        let pointers = items.iter().map(|item| item.as_ptr()).collect();
+        Self {
+            _items: items,
+            pointers,
+        }
+    }
+
+    fn size(&self) -> u32 {
+        self.pointers.len() as u32
+    }
+
+    fn as_ptr(&self) -> *const *const c_char {
+        self.pointers.as_ptr()
+    }
+}
+
+unsafe extern "C" fn add_file(
+    collection: *mut OrthancPluginWebDavCollection,
+    name: *const c_char,
+    size: u64,
+    _mime_type: *const c_char,
+    _date_time: *const c_char,
+) -> OrthancPluginErrorCode {
+    (*(collection as *mut Vec<WebDavItem>)).push(WebDavItem {
+        name: CStr::from_ptr(name).to_string_lossy().to_string(),
+        size: Some(size),
+    });
+    orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+}
+
+unsafe extern "C" fn add_folder(
+    collection: *mut OrthancPluginWebDavCollection,
+    name: *const c_char,
+    _date_time: *const c_char,
+) -> OrthancPluginErrorCode {
+    (*(collection as *mut Vec<WebDavItem>)).push(WebDavItem {
+        name: CStr::from_ptr(name).to_string_lossy().to_string(),
+        size: None,
+    });
+    orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+}
+
+unsafe extern "C" fn retrieve_file(
+    collection: *mut OrthancPluginWebDavCollection,
+    data: *const c_void,
+    size: u64,
+    _mime_type: *const c_char,
+    _date_time: *const c_char,
+) -> OrthancPluginErrorCode {
+    *(collection as *mut Option<Vec<u8>>) =
+        Some(std::slice::from_raw_parts(data as *const u8, size as usize).to_vec());
+    orthanc_plugin_bindings::OrthancPluginErrorCode_OrthancPluginErrorCode_Success
+}
+
+fn allocate(content: &[u8]) -> *mut c_void {
+    let layout = std::alloc::Layout::from_size_align(BUFFER_HEADER + content.len(), 16).unwrap();
+    unsafe {
+        let allocation = std::alloc::alloc(layout);
+        (allocation as *mut usize).write(content.len());
+        let data = allocation.add(BUFFER_HEADER);
+        std::ptr::copy_nonoverlapping(content.as_ptr(), data, content.len());
+        data as *mut c_void
+    }
+}
+
+unsafe extern "C" fn free(data: *mut c_void) {
+    let allocation = (data as *mut u8).sub(BUFFER_HEADER);
+    let len = (allocation as *const usize).read();
+    std::alloc::dealloc(
+        allocation,
+        std::alloc::Layout::from_size_align(BUFFER_HEADER + len, 16).unwrap(),
+    );
+}
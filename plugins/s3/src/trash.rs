@@ -0,0 +1,615 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use orthanc_plugin_bindings::{api::OrthancApi, plugin::Context};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    audit::{self, AuditError},
+    client::{PutOptions, S3Client},
+    layout::{
+        legal_hold_key, object_key, trash_key, uuid_from_trash_key, LEGAL_HOLD_PREFIX, TRASH_PREFIX,
+    },
+    metrics::{self, Operation},
+};
+
+const CONTENT_TYPE_METADATA: &str = "orthanc-content-type";
+
+#[derive(Debug, Clone)]
+pub struct TrashOptions {
+    pub bucket: String,
+    /// Trashed objects are purged once they have been in the trash for this long.
+    pub retention: Duration,
+}
+
+/// Legal hold on an Orthanc study, its attachments are never purged from the trash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LegalHold {
+    pub study: String,
+    pub placed: String,
+    pub attachments: BTreeSet<String>,
+}
+
+/// Body of `PUT /s3/legal-holds/<study>`: attachments held besides those of the study, e.g. of
+/// a study already removed from Orthanc.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LegalHoldRequest {
+    #[serde(default)]
+    pub attachments: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashedObject {
+    pub uuid: String,
+    pub key: String,
+    pub size: i64,
+    pub deleted: Option<String>,
+    pub purge_after: Option<String>,
+    pub held: bool,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SweepReport {
+    pub purged: Vec<String>,
+    pub held: Vec<String>,
+    pub failures: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrashError {
+    #[error("storage request failed - {0}")]
+    Storage(String),
+    #[error("orthanc request failed - {0}")]
+    Orthanc(String),
+    #[error("'{0}' not found")]
+    NotFound(String),
+    #[error("'{0}' cannot be restored - {1}")]
+    NotRestorable(String, String),
+}
+
+impl From<AuditError> for TrashError {
+    fn from(e: AuditError) -> Self {
+        match e {
+            AuditError::Storage(e) => Self::Storage(e),
+            e => Self::Orthanc(format!("{}", e)),
+        }
+    }
+}
+
+/// Retention area for removed attachments.
+///
+/// Removed attachments are moved under [`TRASH_PREFIX`] and purged by [`Trash::sweep`] once
+/// the retention period elapsed, unless they belong to a study under legal hold.
+pub struct Trash {
+    context: Context,
+    s3: S3Client,
+    options: TrashOptions,
+}
+
+impl Trash {
+    pub fn new(context: Context, s3: S3Client, options: TrashOptions) -> Self {
+        Self {
+            context,
+            s3,
+            options,
+        }
+    }
+
+    /// Move the attachment `uuid` into the trash.
+    ///
+    /// The original object is only deleted once the copy in the trash exists.
+    pub async fn discard(
+        &self,
+        uuid: &str,
+        content_type: orthanc_plugin_bindings::OrthancPluginContentType,
+    ) -> Result<(), TrashError> {
+        let metadata =
+            HashMap::from([(CONTENT_TYPE_METADATA.to_string(), content_type.to_string())]);
+
+        metrics::timed(
+            Operation::CopyObject,
+            self.s3.copy_object(
+                &self.options.bucket,
+                &object_key(uuid),
+                &trash_key(uuid),
+                &metadata,
+            ),
+        )
+        .await
+        .map_err(|e| TrashError::Storage(format!("{}", e)))?;
+
+        self.delete(&object_key(uuid)).await
+    }
+
+    /// List the content of the trash.
+    pub async fn list(&self) -> Result<Vec<TrashedObject>, TrashError> {
+        let held = self.held_attachments().await?;
+        let objects =
+            audit::list_objects(&self.s3, &self.options.bucket, Some(TRASH_PREFIX)).await?;
+
+        Ok(objects
+            .into_iter()
+            .filter_map(|o| {
+                let uuid = uuid_from_trash_key(&o.key)?.to_string();
+                //
+                // The copy in the trash is created when the attachment is discarded
+                //
+                let deleted = o.last_modified.as_deref().and_then(parse_timestamp);
+
+                Some(TrashedObject {
+                    held: held.contains(&uuid),
+                    uuid,
+                    key: o.key,
+                    size: o.size,
+                    deleted: deleted.map(|d| d.to_rfc3339()),
+                    purge_after: deleted.and_then(|d| {
+                        chrono::Duration::from_std(self.options.retention)
+                            .ok()
+                            .map(|r| (d + r).to_rfc3339())
+                    }),
+                })
+            })
+            .collect())
+    }
+
+    /// Restore a trashed DICOM instance by importing it into Orthanc again.
+    ///
+    /// Orthanc forgets about an attachment once it is removed, moving the object back is not
+    /// enough, the instance is re-imported and gets a new attachment. Returns the answer of
+    /// Orthanc to the import.
+    pub async fn restore(&self, uuid: &str) -> Result<serde_json::Value, TrashError> {
+        let key = trash_key(uuid);
+
+        let head = metrics::timed(
+            Operation::HeadObject,
+            self.s3.head_object(&self.options.bucket, &key),
+        )
+        .await
+        .map_err(|_| TrashError::NotFound(uuid.to_string()))?;
+
+        let content_type = head
+            .metadata
+            .get(CONTENT_TYPE_METADATA)
+            .and_then(|c| c.parse::<u32>().ok());
+        if content_type
+            != Some(
+                orthanc_plugin_bindings::OrthancPluginContentType_OrthancPluginContentType_Dicom,
+            )
+        {
+            return Err(TrashError::NotRestorable(
+                uuid.to_string(),
+                "only DICOM attachments can be restored".to_string(),
+            ));
+        }
+
+        let content = metrics::timed(
+            Operation::GetObject,
+            self.s3.get_object(&self.options.bucket, &key, None),
+        )
+        .await
+        .map_err(|e| TrashError::Storage(format!("{}", e)))?;
+
+        let context = self.context;
+        let answer = tokio::task::spawn_blocking(move || {
+            OrthancApi::new(context).post("/instances", &content)
+        })
+        .await
+        .map_err(|e| TrashError::Orthanc(format!("{}", e)))?
+        .map_err(|e| {
+            TrashError::NotRestorable(uuid.to_string(), format!("import failed - {}", e))
+        })?;
+
+        self.delete(&key).await?;
+        info!("restored trashed attachment {}", uuid);
+
+        serde_json::from_slice(&answer).map_err(|e| TrashError::Orthanc(format!("{}", e)))
+    }
+
+    /// Purge trashed objects older than the retention period that are not under legal hold.
+    pub async fn sweep(&self) -> Result<SweepReport, TrashError> {
+        let mut report = SweepReport::default();
+        let expired = self.expired().await?;
+        self.purge(expired, &mut report).await;
+
+        info!(
+            "trash sweep complete - {} purged, {} held, {} failures",
+            report.purged.len(),
+            report.held.len(),
+            report.failures.len()
+        );
+
+        Ok(report)
+    }
+
+    /// Trashed objects older than the retention period, with their legal holds refreshed.
+    pub async fn expired(&self) -> Result<Vec<TrashedObject>, TrashError> {
+        self.refresh_legal_holds().await?;
+
+        let cutoff = DateTime::<Utc>::from(SystemTime::now())
+            - chrono::Duration::from_std(self.options.retention)
+                .unwrap_or_else(|_| chrono::Duration::max_value());
+
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .filter(|trashed| {
+                trashed
+                    .deleted
+                    .as_deref()
+                    .and_then(parse_timestamp)
+                    .map(|d| d < cutoff)
+                    .unwrap_or(false)
+            })
+            .collect())
+    }
+
+    /// Purge the `expired` objects that are not under legal hold, recording them in `report`.
+    pub async fn purge(&self, expired: Vec<TrashedObject>, report: &mut SweepReport) {
+        for trashed in expired {
+            if trashed.held {
+                report.held.push(trashed.uuid);
+                continue;
+            }
+
+            match self.delete(&trashed.key).await {
+                Ok(_) => report.purged.push(trashed.uuid),
+                Err(e) => {
+                    warn!("could not purge '{}' - {}", trashed.key, e);
+                    report.failures.push(trashed.uuid);
+                }
+            }
+        }
+    }
+
+    pub async fn legal_holds(&self) -> Result<Vec<LegalHold>, TrashError> {
+        let objects =
+            audit::list_objects(&self.s3, &self.options.bucket, Some(LEGAL_HOLD_PREFIX)).await?;
+
+        let mut holds = Vec::with_capacity(objects.len());
+        for key in objects.into_iter().map(|o| o.key) {
+            let content = metrics::timed(
+                Operation::GetObject,
+                self.s3.get_object(&self.options.bucket, &key, None),
+            )
+            .await
+            .map_err(|e| TrashError::Storage(format!("{}", e)))?;
+
+            match serde_json::from_slice(&content) {
+                Ok(hold) => holds.push(hold),
+                Err(e) => warn!("ignoring malformed legal hold '{}' - {}", key, e),
+            }
+        }
+
+        Ok(holds)
+    }
+
+    /// Place a legal hold on the Orthanc study `study`, recording its current attachments and
+    /// `attachments`. A study removed from Orthanc keeps the attachments already held, and is
+    /// otherwise held with the uuids of its attachments, as listed by the trash.
+    pub async fn place_legal_hold(
+        &self,
+        study: &str,
+        mut attachments: BTreeSet<String>,
+    ) -> Result<LegalHold, TrashError> {
+        match self.study_attachments(study).await? {
+            Some(current) => attachments.extend(current),
+            None => {
+                if let Some(hold) = self.legal_hold(study).await? {
+                    attachments.extend(hold.attachments);
+                }
+                if attachments.is_empty() {
+                    return Err(TrashError::NotFound(study.to_string()));
+                }
+                info!(
+                    "study {} is not in Orthanc, holding its listed attachments",
+                    study
+                );
+            }
+        }
+
+        let hold = LegalHold {
+            study: study.to_string(),
+            placed: DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
+            attachments,
+        };
+
+        self.save_legal_hold(&hold).await?;
+        info!("placed legal hold on study {}", study);
+        Ok(hold)
+    }
+
+    async fn legal_hold(&self, study: &str) -> Result<Option<LegalHold>, TrashError> {
+        let content = match metrics::timed(
+            Operation::GetObject,
+            self.s3
+                .get_object(&self.options.bucket, &legal_hold_key(study), None),
+        )
+        .await
+        {
+            Ok(content) => content,
+            Err(e) if e.is_not_found() => return Ok(None),
+            Err(e) => return Err(TrashError::Storage(format!("{}", e))),
+        };
+
+        serde_json::from_slice(&content)
+            .map(Some)
+            .map_err(|e| TrashError::Storage(format!("malformed legal hold - {}", e)))
+    }
+
+    pub async fn release_legal_hold(&self, study: &str) -> Result<(), TrashError> {
+        self.delete(&legal_hold_key(study)).await?;
+        info!("released legal hold on study {}", study);
+        Ok(())
+    }
+
+    /// Record attachments added to held studies since the hold was placed. Studies that no
+    /// longer exist keep the attachments recorded last.
+    async fn refresh_legal_holds(&self) -> Result<(), TrashError> {
+        for mut hold in self.legal_holds().await? {
+            if let Some(attachments) = self.study_attachments(&hold.study).await? {
+                if !attachments.is_subset(&hold.attachments) {
+                    hold.attachments.extend(attachments);
+                    self.save_legal_hold(&hold).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn held_attachments(&self) -> Result<BTreeSet<String>, TrashError> {
+        Ok(self
+            .legal_holds()
+            .await?
+            .into_iter()
+            .flat_map(|h| h.attachments)
+            .collect())
+    }
+
+    async fn save_legal_hold(&self, hold: &LegalHold) -> Result<(), TrashError> {
+        let body = serde_json::to_vec(hold).map_err(|e| TrashError::Storage(format!("{}", e)))?;
+        let options = PutOptions {
+            content_type: Some("application/json".to_string()),
+            ..Default::default()
+        };
+
+        metrics::timed(
+            Operation::PutObject,
+            self.s3.put_object(
+                &self.options.bucket,
+                &legal_hold_key(&hold.study),
+                body,
+                options,
+            ),
+        )
+        .await
+        .map_err(|e| TrashError::Storage(format!("{}", e)))?;
+
+        Ok(())
+    }
+
+    /// Attachment uuids of a study, its series and its instances.
+    async fn study_attachments(&self, study: &str) -> Result<Option<BTreeSet<String>>, TrashError> {
+        let context = self.context;
+        let study = study.to_string();
+
+        let attachments =
+            tokio::task::spawn_blocking(move || audit::study_attachments(context, &study))
+                .await
+                .map_err(|e| TrashError::Orthanc(format!("{}", e)))??;
+
+        Ok(attachments)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), TrashError> {
+        metrics::timed(
+            Operation::DeleteObject,
+            self.s3.delete_object(&self.options.bucket, key),
+        )
+        .await
+        .map_err(|e| TrashError::Storage(format!("{}", e)))?;
+
+        Ok(())
+    }
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use orthanc_plugin_bindings::{
+        OrthancPluginContentType_OrthancPluginContentType_Dicom as DICOM,
+        OrthancPluginContentType_OrthancPluginContentType_DicomAsJson as DICOM_AS_JSON,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::stub::{Bucket, Orthanc, StoredObject, Stub};
+
+    const RESTORED: &str = "0a1b2c3d-0000-4000-8000-000000000001";
+    const EXPIRED: &str = "0a1b2c3d-0000-4000-8000-000000000002";
+    const JSON: &str = "0a1b2c3d-0000-4000-8000-000000000003";
+    const RECENT: &str = "0a1b2c3d-0000-4000-8000-000000000004";
+    const HELD: &str = "0a1b2c3d-0000-4000-8000-000000000005";
+    const FREE: &str = "0a1b2c3d-0000-4000-8000-000000000006";
+
+    fn trash(stub: &Stub, orthanc: &'static Orthanc, retention: Duration) -> Trash {
+        Trash::new(
+            orthanc.context(),
+            stub.s3_client(),
+            TrashOptions {
+                bucket: "bucket".to_string(),
+                retention,
+            },
+        )
+    }
+
+    fn store(bucket: &Bucket, uuid: &str, content: &[u8]) {
+        bucket.lock().unwrap().insert(
+            object_key(uuid),
+            StoredObject {
+                body: content.to_vec(),
+                headers: Default::default(),
+                last_modified: Utc::now(),
+            },
+        );
+    }
+
+    fn keys(bucket: &Bucket) -> Vec<String> {
+        bucket.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn uuids(uuids: &[&str]) -> BTreeSet<String> {
+        uuids.iter().map(|uuid| uuid.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn holds_removed_studies_by_their_attachments() {
+        let (stub, _) = Stub::bucket().await;
+        let orthanc = Orthanc::start();
+        orthanc.study("study", &[("instance", "first")]);
+        let trash = trash(&stub, orthanc, Duration::ZERO);
+
+        let hold = trash
+            .place_legal_hold("study", uuids(&["second"]))
+            .await
+            .unwrap();
+        assert_eq!(hold.attachments, uuids(&["first", "second"]));
+
+        assert!(matches!(
+            trash.place_legal_hold("removed", BTreeSet::new()).await,
+            Err(TrashError::NotFound(_))
+        ));
+        let hold = trash
+            .place_legal_hold("removed", uuids(&["third"]))
+            .await
+            .unwrap();
+        assert_eq!(hold.attachments, uuids(&["third"]));
+
+        //
+        // The attachments already held stay held once the study is removed
+        //
+        orthanc.remove("/studies/study");
+        let hold = trash
+            .place_legal_hold("study", uuids(&["fourth"]))
+            .await
+            .unwrap();
+        assert_eq!(hold.attachments, uuids(&["first", "second", "fourth"]));
+
+        let holds = trash.legal_holds().await.unwrap();
+        assert_eq!(holds.len(), 2);
+        assert_eq!(trash.held_attachments().await.unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn discarded_attachments_are_restored_or_purged() {
+        let (stub, bucket) = Stub::bucket().await;
+        let orthanc = Orthanc::start();
+        orthanc.serve(
+            "/instances",
+            json!({ "ID": "restored-instance", "Status": "Success" }),
+        );
+        store(&bucket, RESTORED, b"restored content");
+        store(&bucket, EXPIRED, b"expired content");
+        store(&bucket, JSON, b"{}");
+        let trash = trash(&stub, orthanc, Duration::ZERO);
+
+        trash.discard(RESTORED, DICOM).await.unwrap();
+        trash.discard(EXPIRED, DICOM).await.unwrap();
+        trash.discard(JSON, DICOM_AS_JSON).await.unwrap();
+        assert_eq!(
+            keys(&bucket),
+            [trash_key(RESTORED), trash_key(EXPIRED), trash_key(JSON)]
+        );
+
+        let listed = trash.list().await.unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed[0].uuid, RESTORED);
+        assert_eq!(listed[0].key, trash_key(RESTORED));
+        assert_eq!(listed[0].size, 16);
+        assert!(!listed[0].held);
+        let deleted = parse_timestamp(listed[0].deleted.as_deref().unwrap()).unwrap();
+        assert!(Utc::now() - deleted < chrono::Duration::minutes(1));
+        assert_eq!(listed[0].purge_after, listed[0].deleted);
+
+        //
+        // Restoring imports the instance again and empties its place in the trash
+        //
+        let answer = trash.restore(RESTORED).await.unwrap();
+        assert_eq!(answer["ID"], "restored-instance");
+        let calls = orthanc.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].1, "/instances");
+        assert_eq!(calls[0].2, b"restored content");
+        assert!(matches!(
+            trash.restore(JSON).await,
+            Err(TrashError::NotRestorable(_, _))
+        ));
+        assert!(matches!(
+            trash.restore(RESTORED).await,
+            Err(TrashError::NotFound(_))
+        ));
+
+        let report = trash.sweep().await.unwrap();
+        assert_eq!(report.purged, [EXPIRED, JSON]);
+        assert!(report.held.is_empty());
+        assert!(report.failures.is_empty());
+        assert!(keys(&bucket).is_empty());
+    }
+
+    #[tokio::test]
+    async fn attachments_are_kept_until_the_retention_elapsed() {
+        let (stub, bucket) = Stub::bucket().await;
+        let orthanc = Orthanc::start();
+        store(&bucket, RECENT, b"recent content");
+        let trash = trash(&stub, orthanc, Duration::from_secs(86400));
+
+        trash.discard(RECENT, DICOM).await.unwrap();
+
+        let report = trash.sweep().await.unwrap();
+        assert!(report.purged.is_empty());
+        assert_eq!(keys(&bucket), [trash_key(RECENT)]);
+    }
+
+    #[tokio::test]
+    async fn legal_holds_block_the_purge() {
+        let (stub, bucket) = Stub::bucket().await;
+        let orthanc = Orthanc::start();
+        orthanc.study("study", &[("instance", HELD)]);
+        store(&bucket, HELD, b"held content");
+        store(&bucket, FREE, b"free content");
+        let trash = trash(&stub, orthanc, Duration::ZERO);
+
+        trash
+            .place_legal_hold("study", BTreeSet::new())
+            .await
+            .unwrap();
+        trash.discard(HELD, DICOM).await.unwrap();
+        trash.discard(FREE, DICOM).await.unwrap();
+        orthanc.remove("/studies/study");
+
+        let listed = trash.list().await.unwrap();
+        assert!(listed.iter().any(|t| t.uuid == HELD && t.held));
+        assert!(listed.iter().any(|t| t.uuid == FREE && !t.held));
+
+        let report = trash.sweep().await.unwrap();
+        assert_eq!(report.purged, [FREE]);
+        assert_eq!(report.held, [HELD]);
+        assert_eq!(keys(&bucket), [legal_hold_key("study"), trash_key(HELD)]);
+
+        trash.release_legal_hold("study").await.unwrap();
+        let report = trash.sweep().await.unwrap();
+        assert_eq!(report.purged, [HELD]);
+        assert!(keys(&bucket).is_empty());
+    }
+}
@@ -0,0 +1,669 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    error::Error,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use orthanc_plugin_bindings::{
+    api::{Instance, MainDicomTags, OrthancApi, Patient, Series, Study},
+    webdav::{WebDavCollection, WebDavFile, WebDavFolder, WebDavWrite},
+};
+use tracing::{debug, info};
+
+use crate::reader::InstanceReader;
+
+/// Folder holding the `Patient/Study/Series/instance.dcm` tree.
+pub const PATIENTS: &str = "Patients";
+
+/// Folder whose files are imported into Orthanc.
+pub const UPLOAD: &str = "Upload";
+
+/// Date of the resources Orthanc does not date.
+const EPOCH: &str = "19700101T000000";
+
+/// Characters of the Orthanc identifier appended to the names shared by several resources.
+const DISAMBIGUATION: usize = 8;
+
+/// How long a listing of the tree is reused, long enough to span the callbacks of a request.
+const LISTING_TTL: Duration = Duration::from_secs(5);
+
+/// File or folder of the tree, named after the main DICOM tags of its resource.
+#[derive(Debug, Clone)]
+struct Node {
+    name: String,
+    id: String,
+    date_time: String,
+    size: u64,
+    attachment: Option<String>,
+}
+
+/// Read-only WebDAV view of the bucket, with an upload folder importing files into Orthanc.
+///
+/// Folders and files are named after their main DICOM tags, e.g.
+/// `Patients/P001 - DOE^JOHN/20220101 - CT CHEST/CT - AXIAL/<SOPInstanceUID>.dcm`, and each
+/// file is streamed from the bucket on retrieval. Subfolders of the upload folder only exist
+/// until the plugin stops, as the uploaded files themselves are never kept.
+pub struct WebDav {
+    reader: InstanceReader,
+    upload_folders: Mutex<BTreeSet<Vec<String>>>,
+    listings: Listings,
+}
+
+/// Children of a folder of the tree by depth and Orthanc identifier of the folder.
+type ListingKey = (usize, String);
+
+/// Children of a folder and when they were listed.
+type Listing = (Instant, Arc<Vec<Node>>);
+
+/// Listings of the tree shared by the callbacks of WebDAV requests, which resolve the whole
+/// path of the items they serve, e.g. a PROPFIND checks that the folder exists then lists it.
+struct Listings {
+    ttl: Duration,
+    entries: Mutex<HashMap<ListingKey, Listing>>,
+}
+
+impl Listings {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Listing of `key`, from `list` unless it was listed less than the TTL ago.
+    fn get_or_list<F>(&self, key: ListingKey, list: F) -> Result<Arc<Vec<Node>>, Box<dyn Error>>
+    where
+        F: FnOnce() -> Result<Vec<Node>, Box<dyn Error>>,
+    {
+        if let Some((listed, nodes)) = self
+            .entries
+            .lock()
+            .expect("listings lock poisoned")
+            .get(&key)
+        {
+            if listed.elapsed() < self.ttl {
+                return Ok(nodes.clone());
+            }
+        }
+
+        //
+        // Orthanc is queried without holding the lock, concurrent requests may both list
+        //
+        let nodes = Arc::new(list()?);
+        let mut entries = self.entries.lock().expect("listings lock poisoned");
+        entries.retain(|_, (listed, _)| listed.elapsed() < self.ttl);
+        entries.insert(key, (Instant::now(), nodes.clone()));
+        Ok(nodes)
+    }
+
+    fn clear(&self) {
+        self.entries.lock().expect("listings lock poisoned").clear();
+    }
+}
+
+impl WebDav {
+    pub fn new(reader: InstanceReader) -> Self {
+        Self {
+            reader,
+            upload_folders: Mutex::new(BTreeSet::new()),
+            listings: Listings::new(LISTING_TTL),
+        }
+    }
+
+    fn api(&self) -> OrthancApi {
+        OrthancApi::new(self.reader.context)
+    }
+
+    /// Folders below `Patients`, down to `depth` levels, the files of a series at the fourth level.
+    fn children(
+        &self,
+        parent: Option<&Node>,
+        depth: usize,
+    ) -> Result<Arc<Vec<Node>>, Box<dyn Error>> {
+        let key = (
+            depth,
+            parent.map(|parent| parent.id.clone()).unwrap_or_default(),
+        );
+        self.listings.get_or_list(key, || self.list(parent, depth))
+    }
+
+    fn list(&self, parent: Option<&Node>, depth: usize) -> Result<Vec<Node>, Box<dyn Error>> {
+        let api = self.api();
+        let nodes = match (depth, parent) {
+            (0, _) => api
+                .get_json::<Vec<Patient>>("/patients?expand")?
+                .into_iter()
+                .map(|patient| {
+                    folder(
+                        &patient.main_dicom_tags,
+                        &["PatientID", "PatientName"],
+                        patient.id,
+                        patient.last_update,
+                    )
+                })
+                .collect(),
+            (1, Some(patient)) => api
+                .get_json::<Vec<Study>>(&format!("/patients/{}/studies?expand", patient.id))?
+                .into_iter()
+                .map(|study| {
+                    folder(
+                        &study.main_dicom_tags,
+                        &["StudyDate", "StudyDescription"],
+                        study.id,
+                        study.last_update,
+                    )
+                })
+                .collect(),
+            (2, Some(study)) => api
+                .get_json::<Vec<Series>>(&format!("/studies/{}/series?expand", study.id))?
+                .into_iter()
+                .map(|series| {
+                    folder(
+                        &series.main_dicom_tags,
+                        &["Modality", "SeriesDescription"],
+                        series.id,
+                        series.last_update,
+                    )
+                })
+                .collect(),
+            (3, Some(series)) => api
+                .get_json::<Vec<Instance>>(&format!("/series/{}/instances?expand", series.id))?
+                .into_iter()
+                .map(|instance| Node {
+                    name: name(&instance.main_dicom_tags, &["SOPInstanceUID"], &instance.id),
+                    id: instance.id,
+                    date_time: series.date_time.clone(),
+                    size: instance.file_size,
+                    attachment: instance.file_uuid,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(disambiguate(nodes, depth == 3))
+    }
+
+    /// Node at `path` below `Patients`.
+    fn resolve(&self, path: &[String]) -> Result<Option<Node>, Box<dyn Error>> {
+        let mut node = None;
+        for (depth, item) in path.iter().enumerate() {
+            node = self
+                .children(node.as_ref(), depth)?
+                .iter()
+                .find(|child| &child.name == item)
+                .cloned();
+            if node.is_none() {
+                return Ok(None);
+            }
+        }
+        Ok(node)
+    }
+
+    fn is_upload_folder(&self, path: &[String]) -> bool {
+        path.is_empty()
+            || self
+                .upload_folders
+                .lock()
+                .expect("upload folders lock poisoned")
+                .contains(path)
+    }
+}
+
+impl WebDavCollection for WebDav {
+    fn is_existing_folder(&self, path: &[String]) -> Result<bool, Box<dyn Error>> {
+        match path.split_first() {
+            None => Ok(true),
+            Some((root, path)) if root == UPLOAD => Ok(self.is_upload_folder(path)),
+            Some((root, path)) if root == PATIENTS => {
+                Ok(path.len() < 4 && (path.is_empty() || self.resolve(path)?.is_some()))
+            }
+            Some(_) => Ok(false),
+        }
+    }
+
+    fn list_folder(&self, path: &[String], folder: &WebDavFolder) -> Result<bool, Box<dyn Error>> {
+        match path.split_first() {
+            None => {
+                folder.add_folder(PATIENTS, EPOCH)?;
+                folder.add_folder(UPLOAD, EPOCH)?;
+                Ok(true)
+            }
+            Some((root, path)) if root == UPLOAD => {
+                if !self.is_upload_folder(path) {
+                    return Ok(false);
+                }
+                let folders = self
+                    .upload_folders
+                    .lock()
+                    .expect("upload folders lock poisoned");
+                for subfolder in folders.iter() {
+                    if subfolder.len() == path.len() + 1 && subfolder.starts_with(path) {
+                        folder.add_folder(&subfolder[path.len()], EPOCH)?;
+                    }
+                }
+                Ok(true)
+            }
+            Some((root, path)) if root == PATIENTS && path.len() < 4 => {
+                let parent = if path.is_empty() {
+                    None
+                } else {
+                    match self.resolve(path)? {
+                        Some(parent) => Some(parent),
+                        None => return Ok(false),
+                    }
+                };
+                for child in self.children(parent.as_ref(), path.len())?.iter() {
+                    match path.len() {
+                        3 => folder.add_file(
+                            &child.name,
+                            child.size,
+                            Some("application/dicom"),
+                            &child.date_time,
+                        )?,
+                        _ => folder.add_folder(&child.name, &child.date_time)?,
+                    }
+                }
+                Ok(true)
+            }
+            Some(_) => Ok(false),
+        }
+    }
+
+    fn retrieve_file(&self, path: &[String]) -> Result<Option<WebDavFile>, Box<dyn Error>> {
+        let path = match path.split_first() {
+            Some((root, path)) if root == PATIENTS && path.len() == 4 => path,
+            _ => return Ok(None),
+        };
+        let instance = match self.resolve(path)? {
+            Some(instance) => instance,
+            None => return Ok(None),
+        };
+
+        let attachment = match instance.attachment {
+            Some(attachment) => attachment,
+            None => {
+                self.api()
+                    .attachment_info("instances", &instance.id, "dicom")?
+                    .uuid
+            }
+        };
+        let content = self.reader.read(&instance.id, &attachment)?;
+        debug!("served {} over WebDAV", instance.id);
+
+        Ok(Some(WebDavFile {
+            content,
+            mime_type: Some("application/dicom".to_owned()),
+            date_time: instance.date_time,
+        }))
+    }
+
+    fn store_file(&self, path: &[String], content: &[u8]) -> Result<WebDavWrite, Box<dyn Error>> {
+        match path.split_first() {
+            Some((root, path)) if root == UPLOAD && !path.is_empty() => {
+                self.api().post("/instances", content)?;
+                self.listings.clear();
+                info!("imported '{}' uploaded over WebDAV", path.join("/"));
+                Ok(WebDavWrite::Done)
+            }
+            _ => Ok(WebDavWrite::ReadOnly),
+        }
+    }
+
+    fn create_folder(&self, path: &[String]) -> Result<WebDavWrite, Box<dyn Error>> {
+        match path.split_first() {
+            Some((root, path)) if root == UPLOAD && !path.is_empty() => {
+                let mut folders = self
+                    .upload_folders
+                    .lock()
+                    .expect("upload folders lock poisoned");
+                for depth in 1..=path.len() {
+                    folders.insert(path[..depth].to_vec());
+                }
+                Ok(WebDavWrite::Done)
+            }
+            _ => Ok(WebDavWrite::ReadOnly),
+        }
+    }
+
+    fn delete_item(&self, path: &[String]) -> Result<WebDavWrite, Box<dyn Error>> {
+        match path.split_first() {
+            Some((root, path)) if root == UPLOAD && !path.is_empty() => {
+                self.upload_folders
+                    .lock()
+                    .expect("upload folders lock poisoned")
+                    .retain(|folder| !folder.starts_with(path));
+                Ok(WebDavWrite::Done)
+            }
+            _ => Ok(WebDavWrite::ReadOnly),
+        }
+    }
+}
+
+fn folder(tags: &MainDicomTags, keys: &[&str], id: String, last_update: Option<String>) -> Node {
+    Node {
+        name: name(tags, keys, &id),
+        id,
+        date_time: last_update.unwrap_or_else(|| EPOCH.to_owned()),
+        size: 0,
+        attachment: None,
+    }
+}
+
+/// Non-empty values of `keys` joined with ` - `, the Orthanc identifier when all are empty.
+fn name(tags: &MainDicomTags, keys: &[&str], id: &str) -> String {
+    let name = keys
+        .iter()
+        .filter_map(|key| tags.get(*key))
+        .map(|value| sanitize(value))
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>()
+        .join(" - ");
+    if name.is_empty() {
+        id.to_owned()
+    } else {
+        name
+    }
+}
+
+/// `value` without the characters that cannot appear in a file name.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+/// Append the start of the Orthanc identifier to the names shared by several nodes, and the
+/// `.dcm` extension to files.
+fn disambiguate(mut nodes: Vec<Node>, files: bool) -> Vec<Node> {
+    let mut counts = BTreeMap::new();
+    for node in &nodes {
+        *counts.entry(node.name.clone()).or_insert(0) += 1;
+    }
+    for node in &mut nodes {
+        if counts[&node.name] > 1 {
+            let short = node.id.get(..DISAMBIGUATION).unwrap_or(&node.id);
+            node.name = format!("{} ({})", node.name, short);
+        }
+        if files {
+            node.name.push_str(".dcm");
+        }
+    }
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use orthanc_plugin_bindings::webdav;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        client::PutOptions,
+        config::Config,
+        limits::Limits,
+        staging::{Staging, StagingOptions},
+        stub::{Orthanc, Stub},
+    };
+
+    const UPLOADED: &str = "0a1b2c3d-0000-4000-8000-000000000001";
+    const STAGED: &str = "0a1b2c3d-0000-4000-8000-000000000002";
+
+    fn node(name: &str, id: &str) -> Node {
+        Node {
+            name: name.to_owned(),
+            id: id.to_owned(),
+            date_time: EPOCH.to_owned(),
+            size: 0,
+            attachment: None,
+        }
+    }
+
+    fn names(nodes: &[Node]) -> Vec<&str> {
+        nodes.iter().map(|node| node.name.as_str()).collect()
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("DOE^JOHN"), "DOE^JOHN");
+        assert_eq!(sanitize(" CT/CHEST\\ABDO: W "), "CT_CHEST_ABDO_ W");
+        assert_eq!(sanitize("A\tB\nC\u{7f}"), "A_B_C_");
+        assert_eq!(sanitize("Crâne éàü"), "Crâne éàü");
+        assert_eq!(sanitize("  "), "");
+    }
+
+    #[test]
+    fn names_after_the_tags() {
+        let tags: MainDicomTags = serde_json::from_value(serde_json::json!({
+            "PatientID": "P001",
+            "PatientName": " ",
+            "StudyDescription": "CT/CHEST",
+        }))
+        .unwrap();
+
+        assert_eq!(name(&tags, &["PatientID", "PatientName"], "id"), "P001");
+        assert_eq!(
+            name(&tags, &["PatientID", "StudyDescription"], "id"),
+            "P001 - CT_CHEST"
+        );
+        assert_eq!(name(&tags, &["PatientName", "StudyDate"], "id"), "id");
+    }
+
+    #[test]
+    fn disambiguates_shared_names() {
+        let nodes = disambiguate(
+            vec![
+                node("CT", "0123456789abcdef"),
+                node("MR", "fedcba9876543210"),
+                node("CT", "abc"),
+            ],
+            false,
+        );
+        assert_eq!(names(&nodes), vec!["CT (01234567)", "MR", "CT (abc)"]);
+    }
+
+    #[test]
+    fn disambiguates_files_before_their_extension() {
+        let nodes = disambiguate(
+            vec![
+                node("1.2.3", "0123456789abcdef"),
+                node("1.2.3", "fedcba9876543210"),
+                node("1.2.4", "aaaaaaaabbbbbbbb"),
+            ],
+            true,
+        );
+        assert_eq!(
+            names(&nodes),
+            vec!["1.2.3 (01234567).dcm", "1.2.3 (fedcba98).dcm", "1.2.4.dcm"]
+        );
+    }
+
+    #[test]
+    fn reuses_listings_until_they_expire() {
+        let listings = Listings::new(Duration::from_secs(60));
+        let calls = Cell::new(0);
+        let list = || {
+            calls.set(calls.get() + 1);
+            Ok(vec![node("P001", "patient")])
+        };
+
+        let first = listings.get_or_list((0, String::new()), list).unwrap();
+        let second = listings.get_or_list((0, String::new()), list).unwrap();
+        assert_eq!(calls.get(), 1);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        listings
+            .get_or_list((1, "patient".to_owned()), list)
+            .unwrap();
+        assert_eq!(calls.get(), 2, "other folders are listed separately");
+
+        listings.clear();
+        listings.get_or_list((0, String::new()), list).unwrap();
+        assert_eq!(calls.get(), 3);
+
+        let expired = Listings::new(Duration::ZERO);
+        expired.get_or_list((0, String::new()), list).unwrap();
+        expired.get_or_list((0, String::new()), list).unwrap();
+        assert_eq!(calls.get(), 5);
+    }
+
+    #[test]
+    fn does_not_keep_failed_listings() {
+        let listings = Listings::new(Duration::from_secs(60));
+        assert!(listings
+            .get_or_list((0, String::new()), || Err("unavailable".into()))
+            .is_err());
+        let nodes = listings
+            .get_or_list((0, String::new()), || Ok(vec![node("P001", "patient")]))
+            .unwrap();
+        assert_eq!(names(&nodes), vec!["P001"]);
+    }
+
+    /// DICOM file ending with `content`.
+    fn dicom(content: &[u8]) -> Vec<u8> {
+        let mut file = vec![0; 128];
+        file.extend_from_slice(b"DICM");
+        file.extend_from_slice(content);
+        file
+    }
+
+    /// Files below `path`, listed folder by folder like a WebDAV client would.
+    fn walk(orthanc: &Orthanc, path: &mut Vec<String>, files: &mut Vec<(String, u64)>) {
+        let items: Vec<&str> = path.iter().map(String::as_str).collect();
+        assert!(orthanc.webdav_is_folder(&items).unwrap(), "{:?}", items);
+        for item in orthanc.webdav_list(&items).unwrap().unwrap() {
+            path.push(item.name);
+            match item.size {
+                Some(size) => files.push((path.join("/"), size)),
+                None => walk(orthanc, path, files),
+            }
+            path.pop();
+        }
+    }
+
+    #[test]
+    fn serves_the_bucket_through_the_callbacks() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (stub, _bucket) = runtime.block_on(Stub::bucket());
+        let s3 = stub.s3_client();
+        runtime
+            .block_on(s3.put_object(
+                "bucket",
+                UPLOADED,
+                dicom(b"uploaded"),
+                PutOptions::default(),
+            ))
+            .unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let (staging, _uploads) = Staging::new(
+            s3.clone(),
+            StagingOptions {
+                bucket: "bucket".to_string(),
+                directory: directory.path().to_path_buf(),
+                workers: 1,
+            },
+        )
+        .unwrap();
+        staging.stage(STAGED, &dicom(b"staged")).unwrap();
+        let config: Config = envy::from_iter([
+            ("S3_ENDPOINT".to_string(), stub.url()),
+            ("S3_BUCKET".to_string(), "bucket".to_string()),
+            ("S3_REGION".to_string(), "us-east-1".to_string()),
+        ])
+        .unwrap();
+
+        let orthanc = Orthanc::start();
+        orthanc
+            .serve(
+                "/patients?expand",
+                json!([{
+                    "ID": "patient",
+                    "MainDicomTags": { "PatientID": "P001", "PatientName": "DOE^JOHN" },
+                }]),
+            )
+            .serve(
+                "/patients/patient/studies?expand",
+                json!([{
+                    "ID": "study",
+                    "ParentPatient": "patient",
+                    "MainDicomTags": { "StudyDate": "20220101", "StudyDescription": "CT CHEST" },
+                }]),
+            )
+            .serve(
+                "/studies/study/series?expand",
+                json!([{
+                    "ID": "series",
+                    "ParentStudy": "study",
+                    "MainDicomTags": { "Modality": "CT", "SeriesDescription": "AXIAL" },
+                }]),
+            )
+            .serve(
+                "/series/series/instances?expand",
+                json!([
+                    {
+                        "ID": "uploaded",
+                        "ParentSeries": "series",
+                        "MainDicomTags": { "SOPInstanceUID": "1.2.3.1" },
+                        "FileSize": dicom(b"uploaded").len(),
+                        "FileUuid": UPLOADED,
+                    },
+                    {
+                        "ID": "staged",
+                        "ParentSeries": "series",
+                        "MainDicomTags": { "SOPInstanceUID": "1.2.3.2" },
+                        "FileSize": dicom(b"staged").len(),
+                        "FileUuid": STAGED,
+                    },
+                ]),
+            );
+        let reader = InstanceReader {
+            context: orthanc.context(),
+            s3,
+            bucket: "bucket".to_string(),
+            runtime: runtime.handle().clone(),
+            limits: Arc::new(Limits::from_config(&config)),
+            staging: Some(Arc::new(staging)),
+        };
+        webdav::register_collection(orthanc.context(), "/s3-webdav", WebDav::new(reader)).unwrap();
+
+        let mut files = Vec::new();
+        walk(orthanc, &mut Vec::new(), &mut files);
+        let series = "Patients/P001 - DOE^JOHN/20220101 - CT CHEST/CT - AXIAL";
+        assert_eq!(
+            files,
+            [
+                (
+                    format!("{}/1.2.3.1.dcm", series),
+                    dicom(b"uploaded").len() as u64
+                ),
+                (
+                    format!("{}/1.2.3.2.dcm", series),
+                    dicom(b"staged").len() as u64
+                ),
+            ]
+        );
+
+        let path = |file: &'static str| series.split('/').chain([file]).collect::<Vec<_>>();
+        assert_eq!(
+            orthanc.webdav_retrieve(&path("1.2.3.1.dcm")).unwrap(),
+            Some(dicom(b"uploaded"))
+        );
+        assert_eq!(
+            orthanc.webdav_retrieve(&path("1.2.3.2.dcm")).unwrap(),
+            Some(dicom(b"staged"))
+        );
+        assert_eq!(orthanc.webdav_retrieve(&path("1.2.3.3.dcm")).unwrap(), None);
+        assert!(!orthanc.webdav_is_folder(&["Patients", "P002"]).unwrap());
+        assert_eq!(orthanc.webdav_list(&["Patients", "P002"]).unwrap(), None);
+        assert_eq!(orthanc.webdav_list(&[UPLOAD]).unwrap(), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,20 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Config {
+    /// SQLite database holding the worklist items.
+    pub worklist_database: Option<PathBuf>,
+    /// Directory of JSON worklist items, used when no database is configured.
+    pub worklist_directory: Option<PathBuf>,
+    /// Default lifetime of new items, items never expire when 0.
+    #[serde(default = "default_expiry_hours")]
+    pub worklist_expiry_hours: u64,
+    /// Maximum number of items answered to a C-FIND, the answer is marked incomplete beyond.
+    pub worklist_max_answers: Option<usize>,
+}
+
+fn default_expiry_hours() -> u64 {
+    24
+}
@@ -0,0 +1,224 @@
+use std::{
+    error::Error,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use orthanc_plugin_bindings::{
+    declare_plugin, instance,
+    plugin::{Context, Plugin},
+    rest::{Response, RestError, Router},
+    worklist::{self, WorklistAnswers, WorklistHandler, WorklistQuery},
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    store::{self, Tags, WorklistItem, WorklistStore},
+};
+
+declare_plugin!(STATE: WorklistPlugin);
+
+pub struct WorklistPlugin {
+    config: Config,
+    store: Arc<dyn WorklistStore>,
+}
+
+/// Body of `POST /worklists`.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NewItem {
+    tags: Tags,
+    /// Lifetime of the item, `WORKLIST_EXPIRY_HOURS` when unset and no expiry when 0.
+    expiry_hours: Option<u64>,
+}
+
+/// Serves the worklist C-FIND requests from the store.
+struct Worklist {
+    context: Context,
+    store: Arc<dyn WorklistStore>,
+    max_answers: Option<usize>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// DICOM file of a worklist item, as matched and answered by Orthanc.
+fn item_dicom(context: Context, item: &WorklistItem) -> Result<Vec<u8>, Box<dyn Error>> {
+    let tags = serde_json::Value::Object(item.tags.clone());
+    instance::create_dicom(
+        context,
+        &tags,
+        orthanc_plugin_bindings::OrthancPluginCreateDicomFlags_OrthancPluginCreateDicomFlags_None,
+    )
+    .map_err(|e| format!("unable to encode worklist item {} - {}", item.id, e).into())
+}
+
+impl WorklistHandler for Worklist {
+    fn find(
+        &self,
+        query: &WorklistQuery,
+        answers: &WorklistAnswers,
+        issuer_aet: &str,
+        _called_aet: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let now = now();
+        if let Err(e) = self.store.purge_expired(now) {
+            warn!("unable to purge expired worklist items - {}", e);
+        }
+
+        let mut answered = 0;
+        for item in self.store.list(now)? {
+            let dicom = match item_dicom(self.context, &item) {
+                Ok(dicom) => dicom,
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+            if !query.is_match(&dicom)? {
+                continue;
+            }
+
+            if self.max_answers.is_some_and(|max| answered >= max) {
+                answers.mark_incomplete()?;
+                break;
+            }
+            answers.add(query, &dicom)?;
+            answered += 1;
+        }
+
+        info!("answered {} worklist items to {}", answered, issuer_aet);
+        Ok(())
+    }
+}
+
+impl Plugin for WorklistPlugin {
+    fn initialize(_orthanc: Context) -> Result<Self, Box<dyn Error>> {
+        dotenv::dotenv().ok();
+        if std::env::var("RUST_LOG").is_err() {
+            std::env::set_var("RUST_LOG", "worklist=info")
+        }
+        tracing_subscriber::fmt::try_init().ok();
+
+        let config: Config = envy::from_env()?;
+        info!("config - {:#?}", &config);
+
+        let store: Arc<dyn WorklistStore> = store::open(&config)?.into();
+        let purged = store.purge_expired(now())?;
+        if purged > 0 {
+            info!("purged {} expired worklist items", purged);
+        }
+
+        Ok(WorklistPlugin { config, store })
+    }
+
+    fn register(&'static self, orthanc: Context) -> Result<(), Box<dyn Error>> {
+        let handler = Worklist {
+            context: orthanc,
+            store: self.store.clone(),
+            max_answers: self.config.worklist_max_answers,
+        };
+        if let Err(e) = worklist::register_worklist(orthanc, handler) {
+            warn!("unable to register the worklist handler - {}", e);
+        }
+
+        let registered = Router::new()
+            .get("/worklists", |_| {
+                let now = now();
+                self.store.purge_expired(now).map_err(internal)?;
+                Response::json(&self.store.list(now).map_err(internal)?)
+            })
+            .post("/worklists", move |request| {
+                self.create(orthanc, request.json()?)
+            })
+            .get("/worklists/([-0-9a-zA-Z_.]+)", |request| {
+                let id = request.param::<String>(0)?;
+                match self.store.get(&id).map_err(internal)? {
+                    Some(item) if !item.is_expired(now()) => Response::json(&item),
+                    _ => Err(RestError::not_found(format!(
+                        "unknown worklist item {}",
+                        id
+                    ))),
+                }
+            })
+            .delete("/worklists/([-0-9a-zA-Z_.]+)", |request| {
+                let id = request.param::<String>(0)?;
+                if !self.store.delete(&id).map_err(internal)? {
+                    return Err(RestError::not_found(format!(
+                        "unknown worklist item {}",
+                        id
+                    )));
+                }
+                info!("deleted worklist item {}", id);
+                Ok(Response::text(""))
+            })
+            .register(orthanc);
+        if let Err(e) = registered {
+            warn!("unable to register the worklist REST API - {}", e);
+        }
+        Ok(())
+    }
+}
+
+impl WorklistPlugin {
+    fn create(&self, orthanc: Context, new_item: NewItem) -> Result<Response, RestError> {
+        let created = now();
+        let expiry_hours = new_item
+            .expiry_hours
+            .unwrap_or(self.config.worklist_expiry_hours);
+        let expires = expires(created, expiry_hours).ok_or_else(|| {
+            RestError::bad_request(format!("invalid ExpiryHours {}", expiry_hours))
+        })?;
+        let item = WorklistItem {
+            id: uuid::Uuid::new_v4().to_string(),
+            tags: new_item.tags,
+            created,
+            expires,
+        };
+
+        //
+        // Rejected now rather than skipped on every C-FIND
+        //
+        item_dicom(orthanc, &item).map_err(|e| RestError::bad_request(e.to_string()))?;
+
+        self.store.insert(&item).map_err(internal)?;
+        info!("created worklist item {}", item.id);
+        Response::json(&item)
+    }
+}
+
+/// Expiry time of an item created at `created`, `None` when it would not fit the store.
+fn expires(created: u64, expiry_hours: u64) -> Option<Option<u64>> {
+    if expiry_hours == 0 {
+        return Some(None);
+    }
+    expiry_hours
+        .checked_mul(3600)
+        .and_then(|seconds| created.checked_add(seconds))
+        .filter(|&expires| expires <= i64::MAX as u64)
+        .map(Some)
+}
+
+fn internal(e: store::StoreError) -> RestError {
+    RestError::internal(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_expiry() {
+        assert_eq!(expires(1_000, 0), Some(None));
+        assert_eq!(expires(1_000, 2), Some(Some(8_200)));
+        assert_eq!(expires(0, u64::MAX / 3600), None);
+        assert_eq!(expires(1_000, u64::MAX), None);
+        assert_eq!(expires(u64::MAX, 1), None);
+    }
+}
@@ -0,0 +1,427 @@
+use std::{
+    fs::File,
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::Config;
+
+/// Worklist tags by name, as accepted by `/tools/create-dicom`.
+pub type Tags = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("database error - {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("unable to access '{0}' - {1}")]
+    Io(String, std::io::Error),
+    #[error("invalid worklist item '{0}' - {1}")]
+    Json(String, serde_json::Error),
+    #[error("either WORKLIST_DATABASE or WORKLIST_DIRECTORY must be configured")]
+    NotConfigured,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct WorklistItem {
+    #[serde(rename = "ID")]
+    pub id: String,
+    pub tags: Tags,
+    /// Creation time, in seconds since the epoch.
+    pub created: u64,
+    /// Expiry time, in seconds since the epoch, `None` if the item never expires.
+    pub expires: Option<u64>,
+}
+
+impl WorklistItem {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.map(|expires| expires <= now).unwrap_or(false)
+    }
+}
+
+/// Persistence of the worklist items.
+pub trait WorklistStore: Send + Sync {
+    /// Items that are not expired at `now`.
+    fn list(&self, now: u64) -> Result<Vec<WorklistItem>, StoreError>;
+
+    fn get(&self, id: &str) -> Result<Option<WorklistItem>, StoreError>;
+
+    fn insert(&self, item: &WorklistItem) -> Result<(), StoreError>;
+
+    /// Delete an item, `false` if it does not exist.
+    fn delete(&self, id: &str) -> Result<bool, StoreError>;
+
+    /// Delete the items expired at `now`, returning their number.
+    fn purge_expired(&self, now: u64) -> Result<usize, StoreError>;
+}
+
+/// Open the store selected by the configuration.
+pub fn open(config: &Config) -> Result<Box<dyn WorklistStore>, StoreError> {
+    match (&config.worklist_database, &config.worklist_directory) {
+        (Some(database), _) => Ok(Box::new(SqliteStore::open(database.clone())?)),
+        (None, Some(directory)) => Ok(Box::new(DirectoryStore::open(directory.clone())?)),
+        (None, None) => Err(StoreError::NotConfigured),
+    }
+}
+
+/// Items stored in a SQLite database.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: PathBuf) -> Result<Self, StoreError> {
+        let connection = Connection::open(&path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS worklist (
+                id TEXT PRIMARY KEY,
+                tags TEXT NOT NULL,
+                created INTEGER NOT NULL,
+                expires INTEGER
+            )",
+            [],
+        )?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn item(
+        id: String,
+        tags: String,
+        created: i64,
+        expires: Option<i64>,
+    ) -> Result<WorklistItem, StoreError> {
+        let tags = serde_json::from_str(&tags).map_err(|e| StoreError::Json(id.clone(), e))?;
+        Ok(WorklistItem {
+            id,
+            tags,
+            created: created as u64,
+            expires: expires.map(|expires| expires as u64),
+        })
+    }
+}
+
+impl WorklistStore for SqliteStore {
+    fn list(&self, now: u64) -> Result<Vec<WorklistItem>, StoreError> {
+        let connection = self.connection.lock().expect("worklist lock poisoned");
+        let mut statement = connection.prepare(
+            "SELECT id, tags, created, expires FROM worklist
+             WHERE expires IS NULL OR expires > ?1 ORDER BY created",
+        )?;
+        let rows = statement
+            .query_map(params![now as i64], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        rows.into_iter()
+            .map(|(id, tags, created, expires)| Self::item(id, tags, created, expires))
+            .collect()
+    }
+
+    fn get(&self, id: &str) -> Result<Option<WorklistItem>, StoreError> {
+        let connection = self.connection.lock().expect("worklist lock poisoned");
+        let row = connection
+            .query_row(
+                "SELECT id, tags, created, expires FROM worklist WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+
+        row.map(|(id, tags, created, expires)| Self::item(id, tags, created, expires))
+            .transpose()
+    }
+
+    fn insert(&self, item: &WorklistItem) -> Result<(), StoreError> {
+        let tags =
+            serde_json::to_string(&item.tags).map_err(|e| StoreError::Json(item.id.clone(), e))?;
+        let connection = self.connection.lock().expect("worklist lock poisoned");
+        connection.execute(
+            "INSERT INTO worklist (id, tags, created, expires) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                item.id,
+                tags,
+                item.created as i64,
+                item.expires.map(|expires| expires as i64)
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn delete(&self, id: &str) -> Result<bool, StoreError> {
+        let connection = self.connection.lock().expect("worklist lock poisoned");
+        let deleted = connection.execute("DELETE FROM worklist WHERE id = ?1", params![id])?;
+        Ok(deleted > 0)
+    }
+
+    fn purge_expired(&self, now: u64) -> Result<usize, StoreError> {
+        let connection = self.connection.lock().expect("worklist lock poisoned");
+        let deleted = connection.execute(
+            "DELETE FROM worklist WHERE expires IS NOT NULL AND expires <= ?1",
+            params![now as i64],
+        )?;
+        Ok(deleted)
+    }
+}
+
+/// Items stored as `<id>.json` files in a directory.
+///
+/// Files holding only the tags of an item, e.g. dropped by an external system, are served as
+/// items that never expire, with the file name as their ID.
+pub struct DirectoryStore {
+    directory: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ItemFile {
+    Item(WorklistItem),
+    Tags(Tags),
+}
+
+impl DirectoryStore {
+    pub fn open(directory: PathBuf) -> Result<Self, StoreError> {
+        std::fs::create_dir_all(&directory)
+            .map_err(|e| StoreError::Io(directory.display().to_string(), e))?;
+        Ok(Self { directory })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.directory.join(format!("{}.json", id))
+    }
+
+    fn read(&self, id: &str) -> Result<Option<WorklistItem>, StoreError> {
+        let path = self.path(id);
+        let content = match std::fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StoreError::Io(path.display().to_string(), e)),
+        };
+
+        let item = match serde_json::from_slice(&content)
+            .map_err(|e| StoreError::Json(id.to_owned(), e))?
+        {
+            ItemFile::Item(item) => item,
+            ItemFile::Tags(tags) => WorklistItem {
+                id: id.to_owned(),
+                tags,
+                created: 0,
+                expires: None,
+            },
+        };
+        Ok(Some(item))
+    }
+
+    fn all(&self) -> Result<Vec<WorklistItem>, StoreError> {
+        let entries = std::fs::read_dir(&self.directory)
+            .map_err(|e| StoreError::Io(self.directory.display().to_string(), e))?;
+
+        let mut items = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+            let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(id) => id.to_owned(),
+                None => continue,
+            };
+
+            //
+            // A single invalid file must not prevent serving the other items
+            //
+            match self.read(&id) {
+                Ok(Some(item)) => items.push(item),
+                Ok(None) => {}
+                Err(e) => warn!("skipping worklist item - {}", e),
+            }
+        }
+
+        items.sort_by_key(|item| item.created);
+        Ok(items)
+    }
+}
+
+impl WorklistStore for DirectoryStore {
+    fn list(&self, now: u64) -> Result<Vec<WorklistItem>, StoreError> {
+        Ok(self
+            .all()?
+            .into_iter()
+            .filter(|item| !item.is_expired(now))
+            .collect())
+    }
+
+    fn get(&self, id: &str) -> Result<Option<WorklistItem>, StoreError> {
+        self.read(id)
+    }
+
+    fn insert(&self, item: &WorklistItem) -> Result<(), StoreError> {
+        let path = self.path(&item.id);
+        let partial = self.directory.join(format!("{}.json.partial", item.id));
+        let io = |e| StoreError::Io(path.display().to_string(), e);
+
+        let content =
+            serde_json::to_vec_pretty(item).map_err(|e| StoreError::Json(item.id.clone(), e))?;
+        let mut file = File::create(&partial).map_err(io)?;
+        file.write_all(&content).map_err(io)?;
+        file.sync_all().map_err(io)?;
+        std::fs::rename(&partial, &path).map_err(io)
+    }
+
+    fn delete(&self, id: &str) -> Result<bool, StoreError> {
+        let path = self.path(id);
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(StoreError::Io(path.display().to_string(), e)),
+        }
+    }
+
+    fn purge_expired(&self, now: u64) -> Result<usize, StoreError> {
+        let mut purged = 0;
+        for item in self.all()? {
+            if item.is_expired(now) && self.delete(&item.id)? {
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str, created: u64, expires: Option<u64>) -> WorklistItem {
+        let mut tags = Tags::new();
+        tags.insert("PatientID".to_owned(), id.into());
+        WorklistItem {
+            id: id.to_owned(),
+            tags,
+            created,
+            expires,
+        }
+    }
+
+    fn ids(items: &[WorklistItem]) -> Vec<&str> {
+        items.iter().map(|item| item.id.as_str()).collect()
+    }
+
+    fn check_crud(store: &dyn WorklistStore) {
+        store.insert(&item("b", 20, None)).unwrap();
+        store.insert(&item("a", 10, Some(100))).unwrap();
+
+        assert_eq!(ids(&store.list(50).unwrap()), vec!["a", "b"]);
+        let a = store.get("a").unwrap().unwrap();
+        assert_eq!(a.created, 10);
+        assert_eq!(a.expires, Some(100));
+        assert_eq!(a.tags["PatientID"], "a");
+        assert!(store.get("c").unwrap().is_none());
+
+        assert!(store.delete("b").unwrap());
+        assert!(!store.delete("b").unwrap());
+        assert_eq!(ids(&store.list(50).unwrap()), vec!["a"]);
+    }
+
+    fn check_expiry(store: &dyn WorklistStore) {
+        store.insert(&item("expired", 1, Some(100))).unwrap();
+        store.insert(&item("later", 2, Some(101))).unwrap();
+        store.insert(&item("never", 3, None)).unwrap();
+
+        assert_eq!(ids(&store.list(100).unwrap()), vec!["later", "never"]);
+        assert!(store.get("expired").unwrap().is_some());
+
+        assert_eq!(store.purge_expired(100).unwrap(), 1);
+        assert!(store.get("expired").unwrap().is_none());
+        assert_eq!(store.purge_expired(100).unwrap(), 0);
+        assert_eq!(store.purge_expired(u64::MAX >> 1).unwrap(), 1);
+        assert_eq!(ids(&store.list(u64::MAX >> 1).unwrap()), vec!["never"]);
+    }
+
+    #[test]
+    fn sqlite_store_crud() {
+        let directory = tempfile::tempdir().unwrap();
+        check_crud(&SqliteStore::open(directory.path().join("worklist.db")).unwrap());
+    }
+
+    #[test]
+    fn sqlite_store_purges_expired_items() {
+        let directory = tempfile::tempdir().unwrap();
+        check_expiry(&SqliteStore::open(directory.path().join("worklist.db")).unwrap());
+    }
+
+    #[test]
+    fn sqlite_store_persists_items() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("worklist.db");
+        SqliteStore::open(path.clone())
+            .unwrap()
+            .insert(&item("a", 10, None))
+            .unwrap();
+
+        let store = SqliteStore::open(path).unwrap();
+        assert_eq!(ids(&store.list(0).unwrap()), vec!["a"]);
+    }
+
+    #[test]
+    fn sqlite_store_rejects_duplicate_ids() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = SqliteStore::open(directory.path().join("worklist.db")).unwrap();
+        store.insert(&item("a", 10, None)).unwrap();
+        assert!(matches!(
+            store.insert(&item("a", 20, None)),
+            Err(StoreError::Database(_))
+        ));
+    }
+
+    #[test]
+    fn directory_store_crud() {
+        let directory = tempfile::tempdir().unwrap();
+        check_crud(&DirectoryStore::open(directory.path().to_owned()).unwrap());
+    }
+
+    #[test]
+    fn directory_store_purges_expired_items() {
+        let directory = tempfile::tempdir().unwrap();
+        check_expiry(&DirectoryStore::open(directory.path().to_owned()).unwrap());
+    }
+
+    #[test]
+    fn directory_store_creates_the_directory() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("nested").join("worklists");
+        let store = DirectoryStore::open(path.clone()).unwrap();
+        store.insert(&item("a", 10, None)).unwrap();
+        assert!(path.join("a.json").is_file());
+        assert!(!path.join("a.json.partial").exists());
+    }
+
+    #[test]
+    fn directory_store_serves_tags_files_and_skips_invalid_ones() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = DirectoryStore::open(directory.path().to_owned()).unwrap();
+        std::fs::write(
+            directory.path().join("external.json"),
+            r#"{"PatientID": "42", "PatientName": "DOE^JOHN"}"#,
+        )
+        .unwrap();
+        std::fs::write(directory.path().join("invalid.json"), "not json").unwrap();
+        std::fs::write(directory.path().join("notes.txt"), "ignored").unwrap();
+
+        let items = store.list(u64::MAX).unwrap();
+        assert_eq!(ids(&items), vec!["external"]);
+        assert_eq!(items[0].tags["PatientName"], "DOE^JOHN");
+        assert_eq!(items[0].expires, None);
+        assert!(matches!(store.get("invalid"), Err(StoreError::Json(..))));
+        assert_eq!(store.purge_expired(u64::MAX).unwrap(), 0);
+    }
+}